@@ -36,140 +36,153 @@ fn ch(e: u32, f: u32, g: u32) -> u32 {
 	return (e & f) ^ ((~e) & g);
 }
 
-@group(0) @binding(0) var<storage, read> inputHeader: array<u32, 20>;
-@group(0) @binding(1) var<storage, read> inputTarget: array<u32, 8>;
-@group(0) @binding(2) var<storage, read_write> outputHeader: array<u32, 20>;
-
-const workgroupSize: u32 = 256u;
-const numWorkgroups: u32 = 64u;
-const numThreads: u32 = workgroupSize * numWorkgroups;
-
-fn sha256_80byte(m: array<u32, 20>) -> array<u32, 8> {
-	// padding
-	var message = array<u32, 32>(
-		m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7],
-		m[8], m[9], m[10], m[11], m[12], m[13], m[14], m[15],
-		m[16], m[17], m[18], m[19], 0x00000080, 0, 0, 0, 0,
-		0, 0, 0, 0, 0, 0, swap_endianess32(640u)
-	);
-
-	var hash = array<u32, 8> (
-		0x6a09e667u, 0xbb67ae85u, 0x3c6ef372u, 0xa54ff53au,
-		0x510e527fu, 0x9b05688cu, 0x1f83d9abu, 0x5be0cd19u
-	);
-
-	var k = array<u32, 64> (
-		0x428a2f98u, 0x71374491u, 0xb5c0fbcfu, 0xe9b5dba5u, 0x3956c25bu, 0x59f111f1u, 0x923f82a4u, 0xab1c5ed5u,
-		0xd807aa98u, 0x12835b01u, 0x243185beu, 0x550c7dc3u, 0x72be5d74u, 0x80deb1feu, 0x9bdc06a7u, 0xc19bf174u,
-		0xe49b69c1u, 0xefbe4786u, 0x0fc19dc6u, 0x240ca1ccu, 0x2de92c6fu, 0x4a7484aau, 0x5cb0a9dcu, 0x76f988dau,
-		0x983e5152u, 0xa831c66du, 0xb00327c8u, 0xbf597fc7u, 0xc6e00bf3u, 0xd5a79147u, 0x06ca6351u, 0x14292967u,
-		0x27b70a85u, 0x2e1b2138u, 0x4d2c6dfcu, 0x53380d13u, 0x650a7354u, 0x766a0abbu, 0x81c2c92eu, 0x92722c85u,
-		0xa2bfe8a1u, 0xa81a664bu, 0xc24b8b70u, 0xc76c51a3u, 0xd192e819u, 0xd6990624u, 0xf40e3585u, 0x106aa070u,
-		0x19a4c116u, 0x1e376c08u, 0x2748774cu, 0x34b0bcb5u, 0x391c0cb3u, 0x4ed8aa4au, 0x5b9cca4fu, 0x682e6ff3u,
-		0x748f82eeu, 0x78a5636fu, 0x84c87814u, 0x8cc70208u, 0x90befffau, 0xa4506cebu, 0xbef9a3f7u, 0xc67178f2u
-	);
-
-	var w: array<u32, 64> = array<u32, 64>();
-
-	for (var i = 0u; i < 2u; i++) {
-		let chunk_index = i * 16u;
+@group(0) @binding(0) var<storage, read> inputMidstates: array<Midstate, maxHeaders>;
+@group(0) @binding(1) var<storage, read> inputTargets: array<array<u32, 8>, maxHeaders>;
+@group(0) @binding(2) var<storage, read_write> output: Output;
+@group(0) @binding(3) var<storage, read> inputShareTargets: array<array<u32, 8>, maxHeaders>;
+@group(0) @binding(4) var<storage, read> inputNonces: Nonces;
+/// Set once a dispatch of the pipeline finds a block, so it and the ones
+/// queued behind it stop searching a stale header
+@group(0) @binding(5) var<storage, read_write> found: atomic<u32>;
+
+/// The header after its first 64 bytes are hashed on the CPU
+struct Midstate {
+	state: array<u32, 8>,
+	/// The header words between the first block and the nonce
+	tail: array<u32, 3>,
+}
 
-		var a = hash[0];
-		var b = hash[1];
-		var c = hash[2];
-		var d = hash[3];
-		var e = hash[4];
-		var f = hash[5];
-		var g = hash[6];
-		var h = hash[7];
+struct Output {
+	/// How many hashes met the share target but not the target, which can be
+	/// more than `hits` holds
+	shares: atomic<u32>,
+	/// How many hashes met the target
+	blocks: atomic<u32>,
+	/// How many nonces were hashed before a block was found
+	searched: atomic<u32>,
+	/// The share hits from the front and the block hits from the back, so a
+	/// flood of shares can't crowd out a block
+	hits: array<Hit, maxHits>,
+}
 
-		for (var j = 0u; j < 16u; j++){
-			w[j] = swap_endianess32(message[chunk_index + j]);
-		}
+/// A nonce whose hash met the share target or the target
+struct Hit {
+	/// The index of the header the nonce was searched in
+	header: u32,
+	nonce: u32,
+	/// Either `shareHit` or `blockHit`
+	kind: u32,
+}
 
-		for (var j = 16u; j < 64u; j++){
-			w[j] = w[j - 16u] + g0(w[j - 15u]) + w[j - 7u] + g1(w[j - 2u]);
-		}
+/// The nonces searched in each header by a dispatch
+struct Nonces {
+	start: u32,
+	count: u32,
+	/// How many of the inputs hold a header
+	headers: u32,
+}
 
-		for (var j = 0u; j < 64u; j++){
-			let t2 = s0(a) + maj(a, b, c);
-			let t1 = h + s1(e) + ch(e, f, g) + k[j] + w[j];
-
-			h = g;
-			g = f;
-			f = e;
-			e = d + t1;
-			d = c;
-			c = b;
-			b = a;
-			a = t1 + t2;
-		}
+/// The second hash, unless its last word showed it's above the targets
+struct Hash {
+	words: array<u32, 8>,
+	below: bool,
+}
 
-		hash[0] += a;
-		hash[1] += b;
-		hash[2] += c;
-		hash[3] += d;
-		hash[4] += e;
-		hash[5] += f;
-		hash[6] += g;
-		hash[7] += h;
+// `workgroupSize` is declared by the hasher when it builds the pipeline, since
+// it has to be known at compile time, along with `maxHeaders`, `maxHits` and
+// `maxBlocks` from the buffer sizes in options.rs
+
+const shareHit: u32 = 0u;
+const blockHit: u32 = 1u;
+
+/// The round constants, private rather than const so they can be indexed at
+/// runtime
+var<private> k: array<u32, 64> = array<u32, 64>(
+	0x428a2f98u, 0x71374491u, 0xb5c0fbcfu, 0xe9b5dba5u, 0x3956c25bu, 0x59f111f1u, 0x923f82a4u, 0xab1c5ed5u,
+	0xd807aa98u, 0x12835b01u, 0x243185beu, 0x550c7dc3u, 0x72be5d74u, 0x80deb1feu, 0x9bdc06a7u, 0xc19bf174u,
+	0xe49b69c1u, 0xefbe4786u, 0x0fc19dc6u, 0x240ca1ccu, 0x2de92c6fu, 0x4a7484aau, 0x5cb0a9dcu, 0x76f988dau,
+	0x983e5152u, 0xa831c66du, 0xb00327c8u, 0xbf597fc7u, 0xc6e00bf3u, 0xd5a79147u, 0x06ca6351u, 0x14292967u,
+	0x27b70a85u, 0x2e1b2138u, 0x4d2c6dfcu, 0x53380d13u, 0x650a7354u, 0x766a0abbu, 0x81c2c92eu, 0x92722c85u,
+	0xa2bfe8a1u, 0xa81a664bu, 0xc24b8b70u, 0xc76c51a3u, 0xd192e819u, 0xd6990624u, 0xf40e3585u, 0x106aa070u,
+	0x19a4c116u, 0x1e376c08u, 0x2748774cu, 0x34b0bcb5u, 0x391c0cb3u, 0x4ed8aa4au, 0x5b9cca4fu, 0x682e6ff3u,
+	0x748f82eeu, 0x78a5636fu, 0x84c87814u, 0x8cc70208u, 0x90befffau, 0xa4506cebu, 0xbef9a3f7u, 0xc67178f2u
+);
+
+/// The initial hash value
+const initialHash = array<u32, 8>(
+	0x6a09e667u, 0xbb67ae85u, 0x3c6ef372u, 0xa54ff53au,
+	0x510e527fu, 0x9b05688cu, 0x1f83d9abu, 0x5be0cd19u
+);
+
+fn schedule(block: array<u32, 16>) -> array<u32, 64> {
+	// only variables can be indexed at runtime
+	var message = block;
+	var w: array<u32, 64>;
+
+	for (var j = 0u; j < 16u; j++) {
+		w[j] = message[j];
 	}
 
-	hash[0] = swap_endianess32(hash[0]);
-	hash[1] = swap_endianess32(hash[1]);
-	hash[2] = swap_endianess32(hash[2]);
-	hash[3] = swap_endianess32(hash[3]);
-	hash[4] = swap_endianess32(hash[4]);
-	hash[5] = swap_endianess32(hash[5]);
-	hash[6] = swap_endianess32(hash[6]);
-	hash[7] = swap_endianess32(hash[7]);
+	for (var j = 16u; j < 64u; j++) {
+		w[j] = w[j - 16u] + g0(w[j - 15u]) + w[j - 7u] + g1(w[j - 2u]);
+	}
 
-	return hash;
+	return w;
 }
 
-fn sha256_32byte(m: array<u32, 8>) -> array<u32, 8> {
-	// padding
-	var message = array<u32, 16>(
-		m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7],
-		0x00000080, 0, 0, 0, 0, 0, 0, swap_endianess32(256u)
-	);
-
-	var hash = array<u32, 8>(
-		0x6a09e667u, 0xbb67ae85u, 0x3c6ef372u, 0xa54ff53au,
-		0x510e527fu, 0x9b05688cu, 0x1f83d9abu, 0x5be0cd19u
-	);
-
-	var k = array<u32, 64> (
-		0x428a2f98u, 0x71374491u, 0xb5c0fbcfu, 0xe9b5dba5u, 0x3956c25bu, 0x59f111f1u, 0x923f82a4u, 0xab1c5ed5u,
-		0xd807aa98u, 0x12835b01u, 0x243185beu, 0x550c7dc3u, 0x72be5d74u, 0x80deb1feu, 0x9bdc06a7u, 0xc19bf174u,
-		0xe49b69c1u, 0xefbe4786u, 0x0fc19dc6u, 0x240ca1ccu, 0x2de92c6fu, 0x4a7484aau, 0x5cb0a9dcu, 0x76f988dau,
-		0x983e5152u, 0xa831c66du, 0xb00327c8u, 0xbf597fc7u, 0xc6e00bf3u, 0xd5a79147u, 0x06ca6351u, 0x14292967u,
-		0x27b70a85u, 0x2e1b2138u, 0x4d2c6dfcu, 0x53380d13u, 0x650a7354u, 0x766a0abbu, 0x81c2c92eu, 0x92722c85u,
-		0xa2bfe8a1u, 0xa81a664bu, 0xc24b8b70u, 0xc76c51a3u, 0xd192e819u, 0xd6990624u, 0xf40e3585u, 0x106aa070u,
-		0x19a4c116u, 0x1e376c08u, 0x2748774cu, 0x34b0bcb5u, 0x391c0cb3u, 0x4ed8aa4au, 0x5b9cca4fu, 0x682e6ff3u,
-		0x748f82eeu, 0x78a5636fu, 0x84c87814u, 0x8cc70208u, 0x90befffau, 0xa4506cebu, 0xbef9a3f7u, 0xc67178f2u
-	);
+fn compress(state: array<u32, 8>, block: array<u32, 16>) -> array<u32, 8> {
+	var w = schedule(block);
 
-	var w: array<u32, 64> = array<u32, 64>();
+	var a = state[0];
+	var b = state[1];
+	var c = state[2];
+	var d = state[3];
+	var e = state[4];
+	var f = state[5];
+	var g = state[6];
+	var h = state[7];
 
-	for (var j = 0u; j < 16u; j++){
-		w[j] = swap_endianess32(message[j]);
-	}
+	for (var j = 0u; j < 64u; j++) {
+		let t2 = s0(a) + maj(a, b, c);
+		let t1 = h + s1(e) + ch(e, f, g) + k[j] + w[j];
 
-	for (var j = 16u; j < 64u; j++){
-		w[j] = w[j - 16u] + g0(w[j - 15u]) + w[j - 7u] + g1(w[j - 2u]);
+		h = g;
+		g = f;
+		f = e;
+		e = d + t1;
+		d = c;
+		c = b;
+		b = a;
+		a = t1 + t2;
 	}
 
-	var a = hash[0];
-	var b = hash[1];
-	var c = hash[2];
-	var d = hash[3];
-	var e = hash[4];
-	var f = hash[5];
-	var g = hash[6];
-	var h = hash[7];
+	return array<u32, 8>(
+		state[0] + a, state[1] + b, state[2] + c, state[3] + d,
+		state[4] + e, state[5] + f, state[6] + g, state[7] + h
+	);
+}
 
-	for (var j = 0u; j < 64u; j++){
+/// Hashes the 32-byte state of the first hash, giving little-endian words
+/// like the target's.
+///
+/// The last word only depends on the first 61 rounds, so the rest are
+/// skipped when it's already above `top`.
+fn second_hash(first: array<u32, 8>, top: u32) -> Hash {
+	var w = schedule(array<u32, 16>(
+		first[0], first[1], first[2], first[3], first[4], first[5], first[6], first[7],
+		0x80000000u, 0u, 0u, 0u, 0u, 0u, 0u, 256u
+	));
+
+	var a = initialHash[0];
+	var b = initialHash[1];
+	var c = initialHash[2];
+	var d = initialHash[3];
+	var e = initialHash[4];
+	var f = initialHash[5];
+	var g = initialHash[6];
+	var h = initialHash[7];
+
+	for (var j = 0u; j < 64u; j++) {
 		let t2 = s0(a) + maj(a, b, c);
 		let t1 = h + s1(e) + ch(e, f, g) + k[j] + w[j];
 
@@ -181,27 +194,26 @@ fn sha256_32byte(m: array<u32, 8>) -> array<u32, 8> {
 		c = b;
 		b = a;
 		a = t1 + t2;
+
+		// e moves to h over the last three rounds
+		if (j == 60u && swap_endianess32(initialHash[7] + e) > top) {
+			return Hash(array<u32, 8>(), false);
+		}
 	}
 
-	hash[0] += a;
-	hash[1] += b;
-	hash[2] += c;
-	hash[3] += d;
-	hash[4] += e;
-	hash[5] += f;
-	hash[6] += g;
-	hash[7] += h;
-
-	hash[0] = swap_endianess32(hash[0]);
-	hash[1] = swap_endianess32(hash[1]);
-	hash[2] = swap_endianess32(hash[2]);
-	hash[3] = swap_endianess32(hash[3]);
-	hash[4] = swap_endianess32(hash[4]);
-	hash[5] = swap_endianess32(hash[5]);
-	hash[6] = swap_endianess32(hash[6]);
-	hash[7] = swap_endianess32(hash[7]);
-
-	return hash;
+	return Hash(
+		array<u32, 8>(
+			swap_endianess32(initialHash[0] + a),
+			swap_endianess32(initialHash[1] + b),
+			swap_endianess32(initialHash[2] + c),
+			swap_endianess32(initialHash[3] + d),
+			swap_endianess32(initialHash[4] + e),
+			swap_endianess32(initialHash[5] + f),
+			swap_endianess32(initialHash[6] + g),
+			swap_endianess32(initialHash[7] + h)
+		),
+		true
+	);
 }
 
 /// Checks if hash < target
@@ -256,40 +268,69 @@ fn meets_target(hash: array<u32, 8>, targ: array<u32, 8>) -> bool {
 }
 
 @compute @workgroup_size(workgroupSize, 1, 1)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-	if (global_id.x >= 1) {
-		return;
-	}
+fn main(
+	@builtin(global_invocation_id) global_id: vec3<u32>,
+	@builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+	let count = inputNonces.count;
+	// the headers' nonces are searched as one range, so a short range still
+	// keeps every thread busy
+	let total = count * inputNonces.headers;
+	let numThreads = workgroupSize * num_workgroups.x;
+
+	// every thread searches every numThreads-th nonce of the dispatch
+	var index: u32 = global_id.x;
+	var searched: u32 = 0u;
+
+	while (index < total) {
+		// this or an earlier dispatch found the block
+		if (atomicLoad(&found) != 0u) {
+			break;
+		}
 
-	var localHeader: array<u32, 20>;
+		let header = index / count;
+		let nonce = inputNonces.start + index % count;
+		let midstate = inputMidstates[header];
+		// hashes above both targets are thrown away after checking one word
+		let top = max(inputTargets[header][7], inputShareTargets[header][7]);
 
-	for (var i = 0u; i < 20u; i++) {
-		localHeader[i] = inputHeader[i];
-	}
+		// the nonce is little-endian in the header
+		let block = array<u32, 16>(
+			midstate.tail[0], midstate.tail[1], midstate.tail[2], swap_endianess32(nonce),
+			0x80000000u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 640u
+		);
 
-	var hash = array<u32, 8> (
-		0, 0xffffffff, 0, 0, 0, 0, 0, 0
-	);
+		let hash = second_hash(compress(midstate.state, block), top);
 
-	let index: u32 = global_id.x;
-	var nonce: u32 = index;
+		searched += 1u;
 
-	loop {
-		localHeader[19] = nonce;
+		if (hash.below) {
+			// the rest are only counted
+			if (meets_target(hash.words, inputTargets[header])) {
+				let slot = atomicAdd(&output.blocks, 1u);
 
-		// double sha256
-		let hash = sha256_32byte(sha256_80byte(localHeader));
+				atomicStore(&found, 1u);
 
-		if (meets_target(hash, inputTarget)) {
-			outputHeader = localHeader;
+				if (slot < maxBlocks) {
+					output.hits[maxHits - 1u - slot] = Hit(header, nonce, blockHit);
+				}
+			} else if (meets_target(hash.words, inputShareTargets[header])) {
+				let slot = atomicAdd(&output.shares, 1u);
 
-			break;
+				if (slot < maxHits - maxBlocks) {
+					output.hits[slot] = Hit(header, nonce, shareHit);
+				}
+			}
 		}
 
-		if (0xffffffffu - nonce < numThreads) {
+		if (total - index <= numThreads) {
 			break;
-		} else {
-			nonce += numThreads;
 		}
+
+		index += numThreads;
+	}
+
+	if (searched != 0u) {
+		atomicAdd(&output.searched, searched);
 	}
 }
@@ -0,0 +1,185 @@
+//! Hashing backends that the miner searches headers with.
+
+pub mod sha256;
+pub mod simd;
+
+use std::{
+	fmt,
+	ops::Range,
+	sync::atomic::{AtomicU32, Ordering},
+};
+
+use bitcoin::hashes::Hash as _;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::Error;
+
+/// How many nonces the CPU searches before checking for a new job
+const CPU_BATCH_SIZE: u32 = 1 << 26;
+
+/// Searches headers for nonces that meet a target.
+pub trait Backend: fmt::Debug + Send + Sync {
+	/// Searches `header` over `nonces` for hashes that meet `target` or
+	/// `share_target`.
+	///
+	/// The search may stop once a hash meets `target`, but no hit past the
+	/// last one that did is reported, so the next search can resume after it.
+	///
+	/// # Errors
+	/// Returns an error if the backend fails to search.
+	fn search(
+		&self,
+		header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		share_target: bitcoin::Target,
+	) -> Result<Hits, Error>;
+
+	/// How many nonces to search at a time, since the miner only checks for
+	/// new jobs between searches.
+	fn batch_size(&self) -> u32;
+}
+
+/// What a search found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hits {
+	/// Every nonce whose hash met the target or the share target, in nonce
+	/// order
+	pub hits: Vec<Hit>,
+	/// How many hashes met the share target, which can include some that
+	/// weren't reported as hits
+	pub shares: u64,
+	/// How many nonces were searched
+	pub hashes: u64,
+}
+
+impl Hits {
+	/// The nonces whose hashes met the target, in order.
+	pub fn blocks(&self) -> impl Iterator<Item = u32> + '_ {
+		self.hits
+			.iter()
+			.filter(|hit| hit.kind == HitKind::Block)
+			.map(|hit| hit.nonce)
+	}
+}
+
+/// A nonce whose hash met the share target or the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+	pub nonce: u32,
+	pub kind: HitKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitKind {
+	/// The hash met the share target but not the target
+	Share,
+	/// The hash met the target
+	Block,
+}
+
+/// Searches on every CPU core with rayon, hashing as many nonces at once as
+/// the implementation has lanes.
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+	implementation: simd::Implementation,
+}
+
+impl Cpu {
+	/// Searches with `implementation`, or returns `None` if this CPU doesn't
+	/// support it.
+	#[must_use]
+	pub fn new(implementation: simd::Implementation) -> Option<Self> {
+		implementation
+			.is_supported()
+			.then_some(Self { implementation })
+	}
+
+	#[must_use]
+	pub fn implementation(&self) -> simd::Implementation {
+		self.implementation
+	}
+}
+
+impl Default for Cpu {
+	/// Searches with the fastest implementation this CPU supports.
+	fn default() -> Self {
+		Self {
+			implementation: simd::Implementation::detect(),
+		}
+	}
+}
+
+impl Backend for Cpu {
+	fn search(
+		&self,
+		header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		share_target: bitcoin::Target,
+	) -> Result<Hits, Error> {
+		let midstate = sha256::Midstate::new(&header);
+		// hashes above both targets are thrown away after checking one word
+		let top = sha256::top_word(target).max(sha256::top_word(share_target));
+		// the lowest nonce that met the target, past which nothing is searched
+		let first = AtomicU32::new(u32::MAX);
+		#[allow(clippy::cast_possible_truncation)]
+		let lanes = self.implementation.lanes() as u32;
+		let chunks = (nonces.end - nonces.start).div_ceil(lanes);
+		let found = (0..chunks)
+			.into_par_iter()
+			.flat_map_iter(|chunk| {
+				let start = nonces.start + chunk * lanes;
+				let mut hashes = [[0; 8]; simd::MAX_LANES];
+				let mut hits = Vec::new();
+
+				if start > first.load(Ordering::Relaxed) {
+					return hits;
+				}
+
+				self.implementation.hash(&midstate, start, &mut hashes);
+
+				// the last chunk can run past the end of the range
+				for (nonce, hash) in (start..nonces.end).zip(&hashes[..lanes as usize]) {
+					if hash[7].swap_bytes() > top {
+						continue;
+					}
+
+					let hash = bitcoin::BlockHash::from_byte_array(sha256::to_bytes(hash));
+					let kind = if target.is_met_by(hash) {
+						first.fetch_min(nonce, Ordering::Relaxed);
+						HitKind::Block
+					} else if share_target.is_met_by(hash) {
+						HitKind::Share
+					} else {
+						continue;
+					};
+
+					hits.push((Hit { nonce, kind }, share_target.is_met_by(hash)));
+				}
+
+				hits
+			})
+			.collect::<Vec<_>>();
+		// every chunk up to the first block was searched, and the ones after
+		// it are searched again once the miner resumes
+		let end = first
+			.into_inner()
+			.checked_add(1)
+			.map_or(nonces.end, |end| end.min(nonces.end));
+		let found = found
+			.into_iter()
+			.filter(|(hit, _)| hit.nonce < end)
+			.collect::<Vec<_>>();
+
+		Ok(Hits {
+			shares: found.iter().filter(|(_, share)| *share).count() as u64,
+			hits: found.into_iter().map(|(hit, _)| hit).collect(),
+			hashes: u64::from(end - nonces.start),
+		})
+	}
+
+	fn batch_size(&self) -> u32 {
+		CPU_BATCH_SIZE
+	}
+}
@@ -0,0 +1,249 @@
+//! SHA-256d for searching block headers, which only hashes the part of the
+//! header that changes with the nonce.
+
+/// The round constants
+pub(crate) const K: [u32; 64] = [
+	0x428a_2f98,
+	0x7137_4491,
+	0xb5c0_fbcf,
+	0xe9b5_dba5,
+	0x3956_c25b,
+	0x59f1_11f1,
+	0x923f_82a4,
+	0xab1c_5ed5,
+	0xd807_aa98,
+	0x1283_5b01,
+	0x2431_85be,
+	0x550c_7dc3,
+	0x72be_5d74,
+	0x80de_b1fe,
+	0x9bdc_06a7,
+	0xc19b_f174,
+	0xe49b_69c1,
+	0xefbe_4786,
+	0x0fc1_9dc6,
+	0x240c_a1cc,
+	0x2de9_2c6f,
+	0x4a74_84aa,
+	0x5cb0_a9dc,
+	0x76f9_88da,
+	0x983e_5152,
+	0xa831_c66d,
+	0xb003_27c8,
+	0xbf59_7fc7,
+	0xc6e0_0bf3,
+	0xd5a7_9147,
+	0x06ca_6351,
+	0x1429_2967,
+	0x27b7_0a85,
+	0x2e1b_2138,
+	0x4d2c_6dfc,
+	0x5338_0d13,
+	0x650a_7354,
+	0x766a_0abb,
+	0x81c2_c92e,
+	0x9272_2c85,
+	0xa2bf_e8a1,
+	0xa81a_664b,
+	0xc24b_8b70,
+	0xc76c_51a3,
+	0xd192_e819,
+	0xd699_0624,
+	0xf40e_3585,
+	0x106a_a070,
+	0x19a4_c116,
+	0x1e37_6c08,
+	0x2748_774c,
+	0x34b0_bcb5,
+	0x391c_0cb3,
+	0x4ed8_aa4a,
+	0x5b9c_ca4f,
+	0x682e_6ff3,
+	0x748f_82ee,
+	0x78a5_636f,
+	0x84c8_7814,
+	0x8cc7_0208,
+	0x90be_fffa,
+	0xa450_6ceb,
+	0xbef9_a3f7,
+	0xc671_78f2,
+];
+
+/// The initial hash value
+pub const H: [u32; 8] = [
+	0x6a09_e667,
+	0xbb67_ae85,
+	0x3c6e_f372,
+	0xa54f_f53a,
+	0x510e_527f,
+	0x9b05_688c,
+	0x1f83_d9ab,
+	0x5be0_cd19,
+];
+
+/// Runs the compression function on one 64-byte block, given as big-endian words.
+// the names follow FIPS 180-4
+#[allow(clippy::many_single_char_names)]
+pub fn compress(state: &mut [u32; 8], block: &[u32; 16]) {
+	let mut w = [0; 64];
+
+	w[..16].copy_from_slice(block);
+
+	for i in 16..64 {
+		let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+		let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+
+		w[i] = w[i - 16]
+			.wrapping_add(s0)
+			.wrapping_add(w[i - 7])
+			.wrapping_add(s1);
+	}
+
+	let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+	for i in 0..64 {
+		let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+		let ch = (e & f) ^ (!e & g);
+		let t1 = h
+			.wrapping_add(s1)
+			.wrapping_add(ch)
+			.wrapping_add(K[i])
+			.wrapping_add(w[i]);
+		let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+		let maj = (a & b) ^ (a & c) ^ (b & c);
+		let t2 = s0.wrapping_add(maj);
+
+		h = g;
+		g = f;
+		f = e;
+		e = d.wrapping_add(t1);
+		d = c;
+		c = b;
+		b = a;
+		a = t1.wrapping_add(t2);
+	}
+
+	for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+		*word = word.wrapping_add(value);
+	}
+}
+
+/// A header's hashing state after its first 64 bytes, which don't depend on
+/// the nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Midstate {
+	/// The state after the first block
+	pub state: [u32; 8],
+	/// The end of the merkle root, the time and the bits, which come before
+	/// the nonce in the second block
+	pub tail: [u32; 3],
+}
+
+impl Midstate {
+	/// Hashes the first block of `header`.
+	#[must_use]
+	pub fn new(header: &[u8; 80]) -> Self {
+		let mut state = H;
+
+		compress(&mut state, &words(&header[..64]));
+
+		Self {
+			state,
+			tail: [
+				word(&header[64..68]),
+				word(&header[68..72]),
+				word(&header[72..76]),
+			],
+		}
+	}
+
+	/// The second block of the header with `nonce`, padded to 80 bytes.
+	#[must_use]
+	pub fn block(&self, nonce: u32) -> [u32; 16] {
+		let mut block = [0; 16];
+
+		block[..3].copy_from_slice(&self.tail);
+		// the nonce is little-endian in the header
+		block[3] = nonce.swap_bytes();
+		block[4] = 0x8000_0000;
+		block[15] = 80 * 8;
+		block
+	}
+
+	/// The state words of the double SHA-256 of the header with `nonce`.
+	#[must_use]
+	pub fn hash_words(&self, nonce: u32) -> [u32; 8] {
+		let mut first = self.state;
+
+		compress(&mut first, &self.block(nonce));
+
+		second_hash(&first)
+	}
+
+	/// The double SHA-256 of the header with `nonce`, in the byte order of a
+	/// [`bitcoin::BlockHash`].
+	#[must_use]
+	pub fn hash(&self, nonce: u32) -> [u8; 32] {
+		to_bytes(&self.hash_words(nonce))
+	}
+
+	/// The hash of the header with `nonce`, unless its most significant word
+	/// is already above `top`, so most nonces are rejected without building
+	/// or comparing the full hash.
+	#[must_use]
+	pub fn hash_below(&self, nonce: u32, top: u32) -> Option<[u8; 32]> {
+		let hash = self.hash_words(nonce);
+
+		(hash[7].swap_bytes() <= top).then(|| to_bytes(&hash))
+	}
+}
+
+/// The most significant 32 bits of a target, to compare with
+/// [`Midstate::hash_below`].
+#[must_use]
+pub fn top_word(target: bitcoin::Target) -> u32 {
+	let bytes = target.to_le_bytes();
+
+	u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]])
+}
+
+/// Hashes the 32-byte state of the first hash again.
+fn second_hash(first: &[u32; 8]) -> [u32; 8] {
+	let mut block = [0; 16];
+
+	block[..8].copy_from_slice(first);
+	block[8] = 0x8000_0000;
+	block[15] = 32 * 8;
+
+	let mut state = H;
+
+	compress(&mut state, &block);
+	state
+}
+
+fn word(bytes: &[u8]) -> u32 {
+	u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn words(bytes: &[u8]) -> [u32; 16] {
+	let mut words = [0; 16];
+
+	for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+		*word = self::word(chunk);
+	}
+
+	words
+}
+
+/// The bytes of a hash from its state words, in the byte order of a
+/// [`bitcoin::BlockHash`].
+#[must_use]
+pub fn to_bytes(state: &[u32; 8]) -> [u8; 32] {
+	let mut bytes = [0; 32];
+
+	for (chunk, word) in bytes.chunks_exact_mut(4).zip(state) {
+		chunk.copy_from_slice(&word.to_be_bytes());
+	}
+
+	bytes
+}
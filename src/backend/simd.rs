@@ -0,0 +1,557 @@
+//! SHA-256d over several nonces at once with SIMD lanes, or with the x86 SHA
+//! extensions, picked at runtime.
+
+use std::{fmt, str::FromStr};
+
+use super::sha256::Midstate;
+
+/// The most nonces any implementation hashes at once
+pub const MAX_LANES: usize = 16;
+
+/// A way to hash nonces on the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Implementation {
+	/// Portable code, one nonce at a time
+	Scalar,
+	/// The x86 SHA extensions, one nonce at a time
+	ShaNi,
+	/// 4 nonces at a time
+	Sse2,
+	/// 8 nonces at a time
+	Avx2,
+	/// 16 nonces at a time
+	Avx512,
+}
+
+impl Implementation {
+	/// Every implementation, fastest first.
+	pub const ALL: [Self; 5] = [
+		Self::Avx512,
+		Self::ShaNi,
+		Self::Avx2,
+		Self::Sse2,
+		Self::Scalar,
+	];
+
+	/// The fastest implementation this CPU supports.
+	#[must_use]
+	pub fn detect() -> Self {
+		Self::ALL
+			.into_iter()
+			.find(|implementation| implementation.is_supported())
+			.unwrap_or(Self::Scalar)
+	}
+
+	/// Whether this CPU supports the implementation.
+	#[must_use]
+	pub fn is_supported(self) -> bool {
+		#[cfg(target_arch = "x86_64")]
+		{
+			match self {
+				Self::Scalar => true,
+				Self::ShaNi => {
+					is_x86_feature_detected!("sha")
+						&& is_x86_feature_detected!("sse4.1")
+						&& is_x86_feature_detected!("ssse3")
+				}
+				Self::Sse2 => is_x86_feature_detected!("sse2"),
+				Self::Avx2 => is_x86_feature_detected!("avx2"),
+				Self::Avx512 => is_x86_feature_detected!("avx512f"),
+			}
+		}
+
+		#[cfg(not(target_arch = "x86_64"))]
+		{
+			self == Self::Scalar
+		}
+	}
+
+	/// How many nonces are hashed at once.
+	#[must_use]
+	pub fn lanes(self) -> usize {
+		match self {
+			Self::Scalar | Self::ShaNi => 1,
+			Self::Sse2 => 4,
+			Self::Avx2 => 8,
+			Self::Avx512 => 16,
+		}
+	}
+
+	/// Hashes the header of `midstate` with the nonces from `start`, writing
+	/// the state words of each double SHA-256 to the first
+	/// [`lanes`](Self::lanes) entries of `hashes`. Nonces past [`u32::MAX`]
+	/// wrap around.
+	///
+	/// # Panics
+	/// Panics if this CPU doesn't support the implementation.
+	pub fn hash(self, midstate: &Midstate, start: u32, hashes: &mut [[u32; 8]; MAX_LANES]) {
+		assert!(self.is_supported(), "{self} is not supported on this CPU");
+
+		match self {
+			Self::Scalar => hashes[0] = midstate.hash_words(start),
+			// SAFETY: the CPU supports the features these need, checked above
+			#[cfg(target_arch = "x86_64")]
+			Self::ShaNi => unsafe { hashes[0] = x86::hash_sha_ni(midstate, start) },
+			#[cfg(target_arch = "x86_64")]
+			Self::Sse2 => unsafe { x86::hash_sse2(midstate, start, hashes) },
+			#[cfg(target_arch = "x86_64")]
+			Self::Avx2 => unsafe { x86::hash_avx2(midstate, start, hashes) },
+			#[cfg(target_arch = "x86_64")]
+			Self::Avx512 => unsafe { x86::hash_avx512(midstate, start, hashes) },
+			#[cfg(not(target_arch = "x86_64"))]
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl fmt::Display for Implementation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Scalar => "scalar",
+			Self::ShaNi => "sha-ni",
+			Self::Sse2 => "sse2",
+			Self::Avx2 => "avx2",
+			Self::Avx512 => "avx512",
+		})
+	}
+}
+
+impl FromStr for Implementation {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::ALL
+			.into_iter()
+			.find(|implementation| implementation.to_string() == s)
+			.ok_or_else(|| format!("unknown implementation {s}"))
+	}
+}
+
+// the lane helpers have to be inlined into the functions that enable their
+// features, or the intrinsics become calls
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::inline_always)]
+mod x86 {
+	use std::arch::x86_64::{
+		__m128i, __m256i, __m512i, _mm256_add_epi32, _mm256_and_si256, _mm256_andnot_si256,
+		_mm256_loadu_si256, _mm256_or_si256, _mm256_set1_epi32, _mm256_slli_epi32,
+		_mm256_srli_epi32, _mm256_storeu_si256, _mm256_xor_si256, _mm512_add_epi32,
+		_mm512_and_si512, _mm512_andnot_si512, _mm512_loadu_si512, _mm512_or_si512,
+		_mm512_ror_epi32, _mm512_set1_epi32, _mm512_srli_epi32, _mm512_storeu_si512,
+		_mm512_xor_si512, _mm_add_epi32, _mm_alignr_epi8, _mm_and_si128, _mm_andnot_si128,
+		_mm_blend_epi16, _mm_loadu_si128, _mm_or_si128, _mm_set1_epi32, _mm_sha256msg1_epu32,
+		_mm_sha256msg2_epu32, _mm_sha256rnds2_epu32, _mm_shuffle_epi32, _mm_slli_epi32,
+		_mm_srli_epi32, _mm_storeu_si128, _mm_xor_si128,
+	};
+
+	use super::MAX_LANES;
+	use crate::backend::sha256::{Midstate, H, K};
+
+	/// A vector of 32-bit lanes, each holding the same word of a different
+	/// nonce's hash.
+	///
+	/// The methods call intrinsics without checking for CPU support, so
+	/// vectors are only made inside functions that enable their features.
+	trait Lanes: Copy {
+		const COUNT: usize;
+
+		fn splat(value: u32) -> Self;
+		/// Reads [`Lanes::COUNT`] words.
+		fn load(words: &[u32]) -> Self;
+		/// Writes [`Lanes::COUNT`] words.
+		fn store(self, words: &mut [u32]);
+		fn add(self, other: Self) -> Self;
+		fn ch(e: Self, f: Self, g: Self) -> Self;
+		fn maj(a: Self, b: Self, c: Self) -> Self;
+		fn sigma0(self) -> Self;
+		fn sigma1(self) -> Self;
+		fn big_sigma0(self) -> Self;
+		fn big_sigma1(self) -> Self;
+	}
+
+	/// Implements [`Lanes`] with the SSE2 or AVX2 intrinsics, which have no
+	/// rotate so it's built from two shifts.
+	macro_rules! shifted_lanes {
+		(
+			$name:ident($vector:ty, $count:literal),
+			$add:ident, $and:ident, $andnot:ident, $or:ident, $xor:ident,
+			$srli:ident, $slli:ident, $set1:ident, $loadu:ident, $storeu:ident
+		) => {
+			#[derive(Clone, Copy)]
+			struct $name($vector);
+
+			macro_rules! rotr {
+				($x:expr, $r:literal, $l:literal) => {
+					$or($srli::<$r>($x), $slli::<$l>($x))
+				};
+			}
+
+			#[allow(clippy::cast_possible_wrap, clippy::cast_ptr_alignment)]
+			impl Lanes for $name {
+				const COUNT: usize = $count;
+
+				#[inline(always)]
+				fn splat(value: u32) -> Self {
+					unsafe { Self($set1(value as i32)) }
+				}
+
+				#[inline(always)]
+				fn load(words: &[u32]) -> Self {
+					assert!(words.len() >= Self::COUNT);
+					unsafe { Self($loadu(words.as_ptr().cast())) }
+				}
+
+				#[inline(always)]
+				fn store(self, words: &mut [u32]) {
+					assert!(words.len() >= Self::COUNT);
+					unsafe { $storeu(words.as_mut_ptr().cast(), self.0) }
+				}
+
+				#[inline(always)]
+				fn add(self, other: Self) -> Self {
+					unsafe { Self($add(self.0, other.0)) }
+				}
+
+				#[inline(always)]
+				fn ch(e: Self, f: Self, g: Self) -> Self {
+					unsafe { Self($xor($and(e.0, f.0), $andnot(e.0, g.0))) }
+				}
+
+				#[inline(always)]
+				fn maj(a: Self, b: Self, c: Self) -> Self {
+					unsafe { Self($or($and(a.0, b.0), $and(c.0, $or(a.0, b.0)))) }
+				}
+
+				#[inline(always)]
+				fn sigma0(self) -> Self {
+					let x = self.0;
+					unsafe { Self($xor($xor(rotr!(x, 7, 25), rotr!(x, 18, 14)), $srli::<3>(x))) }
+				}
+
+				#[inline(always)]
+				fn sigma1(self) -> Self {
+					let x = self.0;
+					unsafe {
+						Self($xor(
+							$xor(rotr!(x, 17, 15), rotr!(x, 19, 13)),
+							$srli::<10>(x),
+						))
+					}
+				}
+
+				#[inline(always)]
+				fn big_sigma0(self) -> Self {
+					let x = self.0;
+					unsafe {
+						Self($xor(
+							$xor(rotr!(x, 2, 30), rotr!(x, 13, 19)),
+							rotr!(x, 22, 10),
+						))
+					}
+				}
+
+				#[inline(always)]
+				fn big_sigma1(self) -> Self {
+					let x = self.0;
+					unsafe {
+						Self($xor(
+							$xor(rotr!(x, 6, 26), rotr!(x, 11, 21)),
+							rotr!(x, 25, 7),
+						))
+					}
+				}
+			}
+		};
+	}
+
+	shifted_lanes!(
+		Sse2(__m128i, 4),
+		_mm_add_epi32,
+		_mm_and_si128,
+		_mm_andnot_si128,
+		_mm_or_si128,
+		_mm_xor_si128,
+		_mm_srli_epi32,
+		_mm_slli_epi32,
+		_mm_set1_epi32,
+		_mm_loadu_si128,
+		_mm_storeu_si128
+	);
+
+	shifted_lanes!(
+		Avx2(__m256i, 8),
+		_mm256_add_epi32,
+		_mm256_and_si256,
+		_mm256_andnot_si256,
+		_mm256_or_si256,
+		_mm256_xor_si256,
+		_mm256_srli_epi32,
+		_mm256_slli_epi32,
+		_mm256_set1_epi32,
+		_mm256_loadu_si256,
+		_mm256_storeu_si256
+	);
+
+	#[derive(Clone, Copy)]
+	struct Avx512(__m512i);
+
+	#[allow(clippy::cast_possible_wrap, clippy::cast_ptr_alignment)]
+	impl Lanes for Avx512 {
+		const COUNT: usize = 16;
+
+		#[inline(always)]
+		fn splat(value: u32) -> Self {
+			unsafe { Self(_mm512_set1_epi32(value as i32)) }
+		}
+
+		#[inline(always)]
+		fn load(words: &[u32]) -> Self {
+			assert!(words.len() >= Self::COUNT);
+			unsafe { Self(_mm512_loadu_si512(words.as_ptr().cast())) }
+		}
+
+		#[inline(always)]
+		fn store(self, words: &mut [u32]) {
+			assert!(words.len() >= Self::COUNT);
+			unsafe { _mm512_storeu_si512(words.as_mut_ptr().cast(), self.0) }
+		}
+
+		#[inline(always)]
+		fn add(self, other: Self) -> Self {
+			unsafe { Self(_mm512_add_epi32(self.0, other.0)) }
+		}
+
+		#[inline(always)]
+		fn ch(e: Self, f: Self, g: Self) -> Self {
+			unsafe {
+				Self(_mm512_xor_si512(
+					_mm512_and_si512(e.0, f.0),
+					_mm512_andnot_si512(e.0, g.0),
+				))
+			}
+		}
+
+		#[inline(always)]
+		fn maj(a: Self, b: Self, c: Self) -> Self {
+			unsafe {
+				Self(_mm512_or_si512(
+					_mm512_and_si512(a.0, b.0),
+					_mm512_and_si512(c.0, _mm512_or_si512(a.0, b.0)),
+				))
+			}
+		}
+
+		#[inline(always)]
+		fn sigma0(self) -> Self {
+			let x = self.0;
+			unsafe {
+				Self(_mm512_xor_si512(
+					_mm512_xor_si512(_mm512_ror_epi32::<7>(x), _mm512_ror_epi32::<18>(x)),
+					_mm512_srli_epi32::<3>(x),
+				))
+			}
+		}
+
+		#[inline(always)]
+		fn sigma1(self) -> Self {
+			let x = self.0;
+			unsafe {
+				Self(_mm512_xor_si512(
+					_mm512_xor_si512(_mm512_ror_epi32::<17>(x), _mm512_ror_epi32::<19>(x)),
+					_mm512_srli_epi32::<10>(x),
+				))
+			}
+		}
+
+		#[inline(always)]
+		fn big_sigma0(self) -> Self {
+			let x = self.0;
+			unsafe {
+				Self(_mm512_xor_si512(
+					_mm512_xor_si512(_mm512_ror_epi32::<2>(x), _mm512_ror_epi32::<13>(x)),
+					_mm512_ror_epi32::<22>(x),
+				))
+			}
+		}
+
+		#[inline(always)]
+		fn big_sigma1(self) -> Self {
+			let x = self.0;
+			unsafe {
+				Self(_mm512_xor_si512(
+					_mm512_xor_si512(_mm512_ror_epi32::<6>(x), _mm512_ror_epi32::<11>(x)),
+					_mm512_ror_epi32::<25>(x),
+				))
+			}
+		}
+	}
+
+	// the names follow FIPS 180-4
+	#[allow(clippy::many_single_char_names)]
+	#[inline(always)]
+	fn compress<V: Lanes>(state: &mut [V; 8], block: &[V; 16]) {
+		let mut w = [V::splat(0); 64];
+
+		w[..16].copy_from_slice(block);
+
+		for i in 16..64 {
+			w[i] = w[i - 16]
+				.add(w[i - 15].sigma0())
+				.add(w[i - 7])
+				.add(w[i - 2].sigma1());
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+		for (k, w) in K.iter().zip(w) {
+			let t1 = h
+				.add(e.big_sigma1())
+				.add(V::ch(e, f, g))
+				.add(V::splat(*k))
+				.add(w);
+			let t2 = a.big_sigma0().add(V::maj(a, b, c));
+
+			h = g;
+			g = f;
+			f = e;
+			e = d.add(t1);
+			d = c;
+			c = b;
+			b = a;
+			a = t1.add(t2);
+		}
+
+		for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+			*word = word.add(value);
+		}
+	}
+
+	/// Hashes [`Lanes::COUNT`] nonces from `start`, with the same steps as
+	/// [`Midstate::hash_words`].
+	#[inline(always)]
+	fn hash_lanes<V: Lanes>(midstate: &Midstate, start: u32, hashes: &mut [[u32; 8]; MAX_LANES]) {
+		let mut nonces = [0; MAX_LANES];
+
+		for (i, nonce) in (0..).zip(&mut nonces) {
+			*nonce = start.wrapping_add(i).swap_bytes();
+		}
+
+		let mut block = midstate.block(0).map(V::splat);
+
+		block[3] = V::load(&nonces);
+
+		let mut first = midstate.state.map(V::splat);
+
+		compress(&mut first, &block);
+
+		let mut block = [V::splat(0); 16];
+
+		block[..8].copy_from_slice(&first);
+		block[8] = V::splat(0x8000_0000);
+		block[15] = V::splat(32 * 8);
+
+		let mut state = H.map(V::splat);
+
+		compress(&mut state, &block);
+
+		// transpose the words of every lane into a hash per nonce
+		let mut words = [0; MAX_LANES];
+
+		for (i, word) in state.into_iter().enumerate() {
+			word.store(&mut words);
+
+			for (hash, word) in hashes.iter_mut().zip(&words[..V::COUNT]) {
+				hash[i] = *word;
+			}
+		}
+	}
+
+	#[target_feature(enable = "sse2")]
+	pub(super) unsafe fn hash_sse2(
+		midstate: &Midstate,
+		start: u32,
+		hashes: &mut [[u32; 8]; MAX_LANES],
+	) {
+		hash_lanes::<Sse2>(midstate, start, hashes);
+	}
+
+	#[target_feature(enable = "avx2")]
+	pub(super) unsafe fn hash_avx2(
+		midstate: &Midstate,
+		start: u32,
+		hashes: &mut [[u32; 8]; MAX_LANES],
+	) {
+		hash_lanes::<Avx2>(midstate, start, hashes);
+	}
+
+	#[target_feature(enable = "avx512f")]
+	pub(super) unsafe fn hash_avx512(
+		midstate: &Midstate,
+		start: u32,
+		hashes: &mut [[u32; 8]; MAX_LANES],
+	) {
+		hash_lanes::<Avx512>(midstate, start, hashes);
+	}
+
+	#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+	pub(super) unsafe fn hash_sha_ni(midstate: &Midstate, nonce: u32) -> [u32; 8] {
+		let mut first = midstate.state;
+
+		compress_sha_ni(&mut first, &midstate.block(nonce));
+
+		let mut block = [0; 16];
+
+		block[..8].copy_from_slice(&first);
+		block[8] = 0x8000_0000;
+		block[15] = 32 * 8;
+
+		let mut state = H;
+
+		compress_sha_ni(&mut state, &block);
+		state
+	}
+
+	/// The compression function with the SHA extensions, which work on the
+	/// state as `ABEF` and `CDGH` halves.
+	#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+	#[allow(clippy::cast_ptr_alignment)]
+	unsafe fn compress_sha_ni(state: &mut [u32; 8], block: &[u32; 16]) {
+		let dcba = _mm_loadu_si128(state.as_ptr().cast());
+		let hgfe = _mm_loadu_si128(state.as_ptr().add(4).cast());
+		let badc = _mm_shuffle_epi32::<0xb1>(dcba);
+		let efgh = _mm_shuffle_epi32::<0x1b>(hgfe);
+		let mut abef = _mm_alignr_epi8::<8>(badc, efgh);
+		let mut cdgh = _mm_blend_epi16::<0xf0>(efgh, badc);
+		let (abef_save, cdgh_save) = (abef, cdgh);
+		let mut w = [0, 1, 2, 3].map(|i: usize| _mm_loadu_si128(block.as_ptr().add(i * 4).cast()));
+
+		for i in 0..16 {
+			if i >= 4 {
+				let t1 = _mm_sha256msg1_epu32(w[i % 4], w[(i + 1) % 4]);
+				let t2 = _mm_alignr_epi8::<4>(w[(i + 3) % 4], w[(i + 2) % 4]);
+
+				w[i % 4] = _mm_sha256msg2_epu32(_mm_add_epi32(t1, t2), w[(i + 3) % 4]);
+			}
+
+			let k = _mm_add_epi32(w[i % 4], _mm_loadu_si128(K.as_ptr().add(i * 4).cast()));
+
+			cdgh = _mm_sha256rnds2_epu32(cdgh, abef, k);
+			abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32::<0x0e>(k));
+		}
+
+		abef = _mm_add_epi32(abef, abef_save);
+		cdgh = _mm_add_epi32(cdgh, cdgh_save);
+
+		let feba = _mm_shuffle_epi32::<0x1b>(abef);
+		let dchg = _mm_shuffle_epi32::<0xb1>(cdgh);
+
+		_mm_storeu_si128(
+			state.as_mut_ptr().cast(),
+			_mm_blend_epi16::<0xf0>(feba, dchg),
+		);
+		_mm_storeu_si128(
+			state.as_mut_ptr().add(4).cast(),
+			_mm_alignr_epi8::<8>(dchg, feba),
+		);
+	}
+}
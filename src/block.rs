@@ -1,5 +1,9 @@
 use std::ops::Range;
 
+use bitcoin::{
+	consensus::Decodable,
+	hashes::{sha256d, Hash as _},
+};
 use serde::{de, Deserialize};
 
 type Hex<const L: usize> = [u8; L];
@@ -8,8 +12,8 @@ type Hash = Hex<32>;
 #[derive(Debug, Deserialize)]
 pub struct Template {
 	pub version: i32,
-	#[serde(with = "hex::serde", rename = "previousblockhash")]
-	pub previous_block: Hash,
+	#[serde(rename = "previousblockhash")]
+	pub previous_block: bitcoin::BlockHash,
 	pub transactions: Vec<Transaction>,
 	#[serde(rename = "longpollid")]
 	pub longpoll_id: String,
@@ -23,16 +27,132 @@ pub struct Template {
 	pub coinbase_value: u64,
 	#[serde(deserialize_with = "hex_range", rename = "noncerange")]
 	pub nonce_range: Range<u32>,
+	pub height: u32,
+	/// The witness commitment output, present when the template has segwit transactions
+	#[serde(default, rename = "default_witness_commitment")]
+	pub witness_commitment: Option<bitcoin::ScriptBuf>,
+}
+
+impl Template {
+	/// The target that block hashes must meet, parsed from `target`.
+	#[must_use]
+	pub fn target(&self) -> bitcoin::Target {
+		// displayed as a big-endian number
+		bitcoin::Target::from_be_bytes(self.target)
+	}
+
+	/// The compact target for the block header, parsed from `bits`.
+	#[must_use]
+	pub fn bits(&self) -> bitcoin::CompactTarget {
+		bitcoin::CompactTarget::from_consensus(u32::from_be_bytes(self.bits))
+	}
+
+	/// Builds the coinbase transaction paying the block reward to `script_pubkey`,
+	/// with `extranonce` after the BIP34 height in the scriptSig.
+	#[must_use]
+	pub fn coinbase(
+		&self,
+		script_pubkey: bitcoin::ScriptBuf,
+		extranonce: impl AsRef<bitcoin::script::PushBytes>,
+	) -> bitcoin::Transaction {
+		self.coinbase_with_outputs(
+			vec![bitcoin::TxOut {
+				value: bitcoin::Amount::from_sat(self.coinbase_value),
+				script_pubkey,
+			}],
+			extranonce,
+		)
+	}
+
+	/// Builds the coinbase transaction with the given payouts, which should add
+	/// up to `coinbase_value`, followed by the witness commitment if there is one.
+	#[must_use]
+	pub fn coinbase_with_outputs(
+		&self,
+		mut output: Vec<bitcoin::TxOut>,
+		extranonce: impl AsRef<bitcoin::script::PushBytes>,
+	) -> bitcoin::Transaction {
+		let mut witness = bitcoin::Witness::new();
+
+		// segwit blocks commit to the witness merkle root in the coinbase, with
+		// an all-zero witness reserved value
+		if let Some(commitment) = &self.witness_commitment {
+			output.push(bitcoin::TxOut {
+				value: bitcoin::Amount::ZERO,
+				script_pubkey: commitment.clone(),
+			});
+			witness.push([0; 32]);
+		}
+
+		bitcoin::Transaction {
+			version: bitcoin::transaction::Version::ONE,
+			lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
+			input: vec![bitcoin::TxIn {
+				previous_output: bitcoin::OutPoint::null(),
+				// BIP34 height, followed by the extranonce so the script is at
+				// least two bytes even when it's empty
+				script_sig: bitcoin::script::Builder::new()
+					.push_int(i64::from(self.height))
+					.push_slice(extranonce)
+					.into_script(),
+				sequence: bitcoin::Sequence::MAX,
+				witness,
+			}],
+			output,
+		}
+	}
+
+	/// Decodes the template's transactions, which follow the coinbase in the block.
+	///
+	/// # Errors
+	/// Returns an error if a transaction is invalid.
+	pub fn transactions(
+		&self,
+	) -> Result<Vec<bitcoin::Transaction>, bitcoin::consensus::encode::Error> {
+		self.transactions
+			.iter()
+			.map(|transaction| bitcoin::Transaction::consensus_decode(&mut &transaction.data[..]))
+			.collect()
+	}
+
+	/// The hashes needed to compute the merkle root from the coinbase txid, as
+	/// sent in Stratum jobs.
+	#[must_use]
+	pub fn merkle_branch(&self) -> Vec<[u8; 32]> {
+		let mut hashes = self
+			.transactions
+			.iter()
+			.map(|transaction| transaction.id.to_byte_array())
+			.collect::<Vec<_>>();
+		let mut branch = Vec::new();
+
+		// each level is the coinbase's ancestor followed by `hashes`, so the first
+		// hash is its sibling and the rest are paired up for the next level
+		while let Some((sibling, rest)) = hashes.split_first() {
+			branch.push(*sibling);
+			hashes = rest
+				.chunks(2)
+				.map(|pair| {
+					let mut data = [0; 64];
+
+					data[..32].copy_from_slice(&pair[0]);
+					data[32..].copy_from_slice(pair.get(1).unwrap_or(&pair[0]));
+					sha256d::Hash::hash(&data).to_byte_array()
+				})
+				.collect();
+		}
+
+		branch
+	}
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Transaction {
-	#[serde(with = "hex::serde", rename = "txid")]
-	pub id: Hash,
+	#[serde(rename = "txid")]
+	pub id: bitcoin::Txid,
 	#[serde(with = "hex::serde")]
 	pub data: Vec<u8>,
-	#[serde(with = "hex::serde")]
-	pub hash: Hash,
+	pub hash: bitcoin::Wtxid,
 	pub fee: u64,
 	pub weight: u32,
 }
@@ -1,12 +1,19 @@
 use std::fmt;
 
-use crate::{gpu, rpc};
+use crate::{gpu, rpc, stratum};
 
 #[derive(Debug)]
 pub enum Error {
 	Gpu(gpu::Error),
 	Rpc(rpc::Error),
+	Stratum(stratum::Error),
 	Bitcoin(bitcoin::consensus::encode::Error),
+	Network {
+		expected: bitcoin::Network,
+		found: String,
+	},
+	/// A custom [`Backend`](crate::backend::Backend) failed to search
+	Backend(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl fmt::Display for Error {
@@ -14,7 +21,12 @@ impl fmt::Display for Error {
 		match self {
 			Self::Gpu(e) => write!(f, "gpu error: {e}"),
 			Self::Rpc(e) => write!(f, "rpc error: {e}"),
+			Self::Stratum(e) => write!(f, "stratum error: {e}"),
 			Self::Bitcoin(e) => write!(f, "bitcoin error: {e}"),
+			Self::Network { expected, found } => {
+				write!(f, "node is on chain {found}, expected {expected}")
+			}
+			Self::Backend(e) => write!(f, "backend error: {e}"),
 		}
 	}
 }
@@ -33,6 +45,12 @@ impl From<rpc::Error> for Error {
 	}
 }
 
+impl From<stratum::Error> for Error {
+	fn from(value: stratum::Error) -> Self {
+		Self::Stratum(value)
+	}
+}
+
 impl From<bitcoin::consensus::encode::Error> for Error {
 	fn from(value: bitcoin::consensus::encode::Error) -> Self {
 		Self::Bitcoin(value)
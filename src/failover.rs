@@ -0,0 +1,313 @@
+//! Mining several upstreams in priority order, failing over between them.
+
+use std::{
+	sync::{mpsc, Arc},
+	time::{Duration, Instant},
+};
+
+use crate::{work, Error, Upstream};
+
+/// How often upstreams are checked when no jobs arrive
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How many shares are counted before a pool's reject rate is checked
+const SHARE_WINDOW: u64 = 20;
+/// The largest fraction of shares in a window that a pool can reject
+const MAX_REJECT_RATE: f64 = 0.25;
+/// How long a pool that rejected too many shares is avoided before it's tried again
+const RETRY_INTERVAL: Duration = Duration::from_mins(5);
+/// How long it takes to cycle through both upstreams when splitting the hash
+/// rate, by default
+pub const SPLIT_PERIOD: Duration = Duration::from_mins(1);
+
+/// Upstreams in priority order, mining the first healthy one.
+///
+/// A pool is unhealthy while it's disconnected, or for a while after it
+/// rejects too many shares. Mining moves back to a higher priority upstream
+/// as soon as it recovers.
+#[derive(Debug)]
+pub struct Failover {
+	pub upstreams: Vec<Upstream>,
+	/// The percent of the time spent on the second healthy upstream, to split
+	/// the hash rate between two of them
+	pub split: Option<f64>,
+	/// How long it takes to cycle through both upstreams when splitting
+	pub split_period: Duration,
+}
+
+/// What an upstream's thread reports.
+enum Event {
+	Job(usize, work::Job),
+	Stopped(usize, Error),
+}
+
+/// What is known about an upstream.
+#[derive(Default)]
+struct State {
+	/// The newest job, mined as soon as the upstream is picked
+	job: Option<work::Job>,
+	/// Set once the upstream has failed for good
+	stopped: bool,
+	/// The pool session shares are counted for
+	session: usize,
+	/// Accepted and rejected shares before the current window
+	baseline: (u64, u64),
+	/// When the pool rejected too many shares
+	failed: Option<Instant>,
+}
+
+/// A pool session's share counts.
+struct Shares {
+	/// Tells sessions apart, since counts start over on every connection
+	session: usize,
+	accepted: u64,
+	rejected: u64,
+	closed: bool,
+}
+
+impl Failover {
+	#[must_use]
+	pub fn new(upstreams: Vec<Upstream>) -> Self {
+		Self {
+			upstreams,
+			split: None,
+			split_period: SPLIT_PERIOD,
+		}
+	}
+
+	/// Spends `percent` of the time mining the second healthy upstream, or
+	/// the first one if it's the only one left.
+	#[must_use]
+	pub fn with_split(mut self, percent: f64) -> Self {
+		self.split = Some(percent.clamp(0.0, 100.0));
+		self
+	}
+
+	/// Runs every upstream, sending jobs from the one being mined.
+	///
+	/// # Errors
+	/// Returns the last error once every upstream has failed for good.
+	pub fn run(&self, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		let (events, receiver) = mpsc::channel();
+
+		std::thread::scope(|s| {
+			for (index, upstream) in self.upstreams.iter().enumerate() {
+				let events = events.clone();
+
+				s.spawn(move || {
+					let (sender, receiver) = mpsc::channel();
+
+					s.spawn({
+						let events = events.clone();
+
+						move || {
+							for job in receiver {
+								let _ = events.send(Event::Job(index, job));
+							}
+						}
+					});
+
+					let Err(e) = upstream.run(&sender);
+
+					let _ = events.send(Event::Stopped(index, e));
+				});
+			}
+
+			drop(events);
+			self.forward(&receiver, jobs)
+		})
+	}
+
+	/// Picks which upstream to mine whenever something changes, sending its
+	/// newest job when it's a different one.
+	fn forward(
+		&self,
+		events: &mpsc::Receiver<Event>,
+		jobs: &mpsc::Sender<work::Job>,
+	) -> Result<!, Error> {
+		let start = Instant::now();
+		let mut states = self
+			.upstreams
+			.iter()
+			.map(|_| State::default())
+			.collect::<Vec<_>>();
+		let mut current = None;
+		let mut stopped = 0;
+
+		loop {
+			let timeout = self.next_switch(start.elapsed()).min(CHECK_INTERVAL);
+			let updated = match events.recv_timeout(timeout) {
+				Ok(Event::Job(index, job)) => {
+					states[index].job = Some(job);
+					Some(index)
+				}
+				Ok(Event::Stopped(index, e)) => {
+					tracing::error!(error = %e, upstream = %self.upstreams[index], "upstream failed");
+
+					states[index].stopped = true;
+					stopped += 1;
+
+					if stopped == states.len() {
+						return Err(e);
+					}
+
+					None
+				}
+				Err(_) => None,
+			};
+
+			let now = Instant::now();
+			let healthy = states
+				.iter_mut()
+				.enumerate()
+				.filter_map(|(index, state)| {
+					state
+						.is_healthy(&self.upstreams[index], now)
+						.then_some(index)
+				})
+				.take(2)
+				.collect::<Vec<_>>();
+			let picked = match healthy[..] {
+				[_, second] if self.on_second(now - start) => Some(second),
+				[first, ..] => Some(first),
+				[] => None,
+			};
+
+			if picked == current {
+				if let Some(index) = updated.filter(|&index| Some(index) == current) {
+					if let Some(job) = states[index].job.clone() {
+						let _ = jobs.send(job);
+					}
+				}
+
+				continue;
+			}
+
+			if let Some(index) = picked {
+				tracing::info!(upstream = %self.upstreams[index], "mining upstream");
+
+				if let Some(job) = states[index].job.clone() {
+					let _ = jobs.send(job);
+				}
+			} else {
+				tracing::warn!("no upstream is available");
+			}
+
+			current = picked;
+		}
+	}
+
+	/// Whether the second upstream is being mined at `elapsed` into the split.
+	#[must_use]
+	pub fn on_second(&self, elapsed: Duration) -> bool {
+		let Some(percent) = self.split else {
+			return false;
+		};
+
+		let period = self.split_period.as_secs_f64();
+
+		elapsed.as_secs_f64() % period < period * percent / 100.0
+	}
+
+	/// How long until the split moves to the other upstream, which is never
+	/// without a split or when it's all on one of them.
+	#[must_use]
+	pub fn next_switch(&self, elapsed: Duration) -> Duration {
+		let Some(percent) = self
+			.split
+			.filter(|percent| *percent > 0.0 && *percent < 100.0)
+		else {
+			return Duration::MAX;
+		};
+
+		let period = self.split_period.as_secs_f64();
+		let phase = elapsed.as_secs_f64() % period;
+		let boundary = period * percent / 100.0;
+		let remaining = if phase < boundary {
+			boundary - phase
+		} else {
+			period - phase
+		};
+
+		Duration::from_secs_f64(remaining)
+	}
+}
+
+impl State {
+	/// Whether the upstream can be mined, which for pools means the session
+	/// is open and hasn't rejected too many shares lately.
+	fn is_healthy(&mut self, upstream: &Upstream, now: Instant) -> bool {
+		if self.stopped {
+			return false;
+		}
+
+		let Some(job) = &self.job else {
+			return false;
+		};
+
+		// nodes don't reject shares, and reconnect on their own
+		let Some(shares) = shares(job) else {
+			return true;
+		};
+
+		if shares.closed {
+			return false;
+		}
+
+		if shares.session != self.session {
+			self.session = shares.session;
+			self.baseline = (0, 0);
+			self.failed = None;
+		}
+
+		if let Some(failed) = self.failed {
+			if now - failed < RETRY_INTERVAL {
+				return false;
+			}
+
+			tracing::info!(upstream = %upstream, "retrying pool");
+
+			self.failed = None;
+			self.baseline = (shares.accepted, shares.rejected);
+		}
+
+		let accepted = shares.accepted - self.baseline.0;
+		let rejected = shares.rejected - self.baseline.1;
+
+		if accepted + rejected < SHARE_WINDOW {
+			return true;
+		}
+
+		self.baseline = (shares.accepted, shares.rejected);
+
+		#[allow(clippy::cast_precision_loss)]
+		let rate = rejected as f64 / (accepted + rejected) as f64;
+
+		if rate > MAX_REJECT_RATE {
+			tracing::warn!(upstream = %upstream, accepted, rejected, "pool rejected too many shares");
+
+			self.failed = Some(now);
+			return false;
+		}
+
+		true
+	}
+}
+
+/// The share counts of the session a job came from, or `None` for blocks.
+fn shares(job: &work::Job) -> Option<Shares> {
+	match &job.kind {
+		work::Kind::Block { .. } => None,
+		work::Kind::Stratum(work) => Some(Shares {
+			session: Arc::as_ptr(&work.client) as usize,
+			accepted: work.client.accepted(),
+			rejected: work.client.rejected(),
+			closed: work.client.is_closed(),
+		}),
+		work::Kind::StratumV2(work) => Some(Shares {
+			session: Arc::as_ptr(&work.client) as usize,
+			accepted: work.client.accepted(),
+			rejected: work.client.rejected(),
+			closed: work.client.is_closed(),
+		}),
+	}
+}
@@ -0,0 +1,356 @@
+//! A getwork server, for legacy miners that search a single header at a time.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{TcpListener, TcpStream},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+	block, rpc,
+	stratum::{lock, merkle_root},
+};
+
+/// How many templates are kept around for late solutions
+const MAX_ROUNDS: usize = 16;
+/// How long to wait before retrying a failed template request
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How many work units are kept per template, dropping the oldest first
+const MAX_UNITS: usize = 1024;
+/// The largest request body accepted, which is plenty for a solved header
+const MAX_BODY_SIZE: usize = 4096;
+/// The size of the header once padded to a whole number of SHA-256 blocks
+const DATA_SIZE: usize = 128;
+
+/// Serves work units built from the node's templates over HTTP JSON-RPC, and
+/// submits a block for every solved header.
+///
+/// Each `getwork` call returns a header with its own extranonce in the
+/// coinbase, so miners never search the same work twice. The header is
+/// padded to 128 bytes, with every 4-byte word byte-swapped, as legacy
+/// miners expect.
+///
+/// Only the newest work units of each template are kept, so a solution for
+/// one handed out long ago is rejected as stale.
+#[derive(Debug)]
+pub struct Getwork {
+	rpc: rpc::Client,
+	payout: bitcoin::Address,
+	rounds: Mutex<Rounds>,
+}
+
+#[derive(Debug, Default)]
+struct Rounds {
+	/// Recent templates, newest last
+	rounds: VecDeque<Round>,
+	next_extranonce: u64,
+}
+
+/// A template, along with the work units handed out for it.
+#[derive(Debug)]
+struct Round {
+	template: block::Template,
+	/// The transactions after the coinbase
+	transactions: Vec<bitcoin::Transaction>,
+	merkle_branch: Vec<[u8; 32]>,
+	/// The coinbase of each work unit, by the merkle root it makes
+	units: HashMap<bitcoin::TxMerkleNode, bitcoin::Transaction>,
+	/// The merkle roots of the work units, oldest first
+	order: VecDeque<bitcoin::TxMerkleNode>,
+}
+
+impl Getwork {
+	/// Creates a server paying block rewards to `payout`.
+	#[must_use]
+	pub fn new(rpc: rpc::Client, payout: bitcoin::Address) -> Self {
+		Self {
+			rpc,
+			payout,
+			rounds: Mutex::default(),
+		}
+	}
+
+	/// Fetches templates from the node with longpoll, so new work units are
+	/// built from the newest one.
+	pub fn poll(&self) -> ! {
+		let mut poll_id = None;
+
+		loop {
+			let mut template = match self.rpc.get_block_template(poll_id.as_deref()) {
+				Ok(template) => template,
+				Err(e) => {
+					tracing::warn!(error = %e, "failed to fetch block template");
+					std::thread::sleep(RETRY_INTERVAL);
+					continue;
+				}
+			};
+
+			poll_id = Some(std::mem::take(&mut template.longpoll_id));
+
+			if let Err(e) = self.update(template) {
+				tracing::warn!(error = %e, "invalid block template");
+			}
+		}
+	}
+
+	/// Makes a template the current one that work units are built from.
+	///
+	/// # Errors
+	/// Returns an error if the template contains an invalid transaction.
+	pub fn update(
+		&self,
+		template: block::Template,
+	) -> Result<(), bitcoin::consensus::encode::Error> {
+		let transactions = template.transactions()?;
+		let mut rounds = lock(&self.rounds);
+
+		// solutions for the previous block can't be submitted anymore
+		if rounds
+			.rounds
+			.back()
+			.is_none_or(|round| round.template.previous_block != template.previous_block)
+		{
+			rounds.rounds.clear();
+		} else if rounds.rounds.len() == MAX_ROUNDS {
+			rounds.rounds.pop_front();
+		}
+
+		tracing::debug!(height = template.height, "new getwork template");
+
+		rounds.rounds.push_back(Round {
+			merkle_branch: template.merkle_branch(),
+			template,
+			transactions,
+			units: HashMap::new(),
+			order: VecDeque::new(),
+		});
+
+		Ok(())
+	}
+
+	/// Accepts miners on `listener`, serving each connection on its own thread.
+	pub fn listen(self: &Arc<Self>, listener: &TcpListener) -> ! {
+		loop {
+			match listener.accept() {
+				Ok((stream, peer)) => {
+					let getwork = Arc::clone(self);
+
+					std::thread::spawn(move || {
+						if let Err(e) = getwork.serve(stream) {
+							tracing::debug!(error = %e, %peer, "getwork connection closed");
+						}
+					});
+				}
+				Err(e) => tracing::warn!(error = %e, "failed to accept getwork connection"),
+			}
+		}
+	}
+
+	/// Serves HTTP/1.1 requests on a connection until it is closed.
+	fn serve(&self, stream: TcpStream) -> io::Result<()> {
+		let mut reader = BufReader::new(stream);
+
+		loop {
+			let mut line = String::new();
+
+			if reader.read_line(&mut line)? == 0 {
+				return Ok(());
+			}
+
+			let mut length = 0;
+
+			loop {
+				line.clear();
+				reader.read_line(&mut line)?;
+
+				let header = line.trim_end();
+
+				if header.is_empty() {
+					break;
+				}
+
+				if let Some((name, value)) = header.split_once(':') {
+					if name.eq_ignore_ascii_case("content-length") {
+						length = value.trim().parse().unwrap_or(0);
+					}
+				}
+			}
+
+			if length > MAX_BODY_SIZE {
+				write!(
+					reader.get_mut(),
+					"HTTP/1.1 413 Payload Too Large\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+				)?;
+
+				// the body is never read, so the connection can't be reused
+				return reader.get_mut().flush();
+			}
+
+			let mut body = vec![0; length];
+
+			reader.read_exact(&mut body)?;
+
+			let response = match serde_json::from_slice::<Value>(&body) {
+				Ok(request) => self.handle(&request),
+				Err(e) => json!({
+					"result": null,
+					"error": { "code": -32700, "message": e.to_string() },
+					"id": null,
+				}),
+			}
+			.to_string();
+
+			write!(
+				reader.get_mut(),
+				"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+				response.len()
+			)?;
+			reader.get_mut().flush()?;
+		}
+	}
+
+	/// Answers a JSON-RPC request, which hands out work without parameters
+	/// and takes a solved header with one.
+	fn handle(&self, request: &Value) -> Value {
+		let id = &request["id"];
+		let result = match (request["method"].as_str(), &request["params"]) {
+			(Some("getwork"), Value::Array(params)) if !params.is_empty() => {
+				Ok(Value::Bool(self.submit(&params[0])))
+			}
+			(Some("getwork"), _) => self.work().ok_or((-9, "no block template yet")),
+			_ => Err((-32601, "method not found")),
+		};
+
+		match result {
+			Ok(result) => json!({ "result": result, "error": null, "id": id }),
+			Err((code, message)) => json!({
+				"result": null,
+				"error": { "code": code, "message": message },
+				"id": id,
+			}),
+		}
+	}
+
+	/// Builds a work unit from the newest template, with the next extranonce.
+	fn work(&self) -> Option<Value> {
+		let mut rounds = lock(&self.rounds);
+		let extranonce = rounds.next_extranonce;
+
+		rounds.next_extranonce += 1;
+
+		let round = rounds.rounds.back_mut()?;
+		let template = &round.template;
+		let coinbase = template.coinbase(self.payout.script_pubkey(), extranonce.to_be_bytes());
+		let mut stripped = coinbase.clone();
+
+		// the merkle root commits to the coinbase without its witness
+		stripped.input[0].witness.clear();
+
+		let header = bitcoin::block::Header {
+			version: bitcoin::block::Version::from_consensus(template.version),
+			prev_blockhash: template.previous_block,
+			merkle_root: merkle_root(
+				&bitcoin::consensus::serialize(&stripped),
+				&round.merkle_branch,
+			),
+			time: template.current_time,
+			bits: template.bits(),
+			nonce: 0,
+		};
+		let target = template.target();
+
+		if round.order.len() == MAX_UNITS {
+			if let Some(oldest) = round.order.pop_front() {
+				round.units.remove(&oldest);
+			}
+		}
+
+		round.order.push_back(header.merkle_root);
+		round.units.insert(header.merkle_root, coinbase);
+
+		Some(json!({
+			"data": hex::encode(encode_data(&header)),
+			"target": hex::encode(target.to_le_bytes()),
+		}))
+	}
+
+	/// Submits the block for a solved header, returning whether the node
+	/// accepted it.
+	fn submit(&self, data: &Value) -> bool {
+		let Some(header) = data
+			.as_str()
+			.and_then(|data| hex::decode(data).ok())
+			.and_then(|data| decode_data(&data))
+		else {
+			tracing::debug!("invalid getwork data");
+			return false;
+		};
+
+		let hash = header.block_hash();
+		let rounds = lock(&self.rounds);
+		let Some((round, coinbase)) = rounds.rounds.iter().find_map(|round| {
+			let coinbase = round.units.get(&header.merkle_root)?;
+
+			(round.template.previous_block == header.prev_blockhash).then_some((round, coinbase))
+		}) else {
+			tracing::debug!(?hash, "solution for unknown or stale work");
+			return false;
+		};
+
+		if !round.template.target().is_met_by(hash) {
+			tracing::debug!(?hash, "solution does not meet the target");
+			return false;
+		}
+
+		let mut txdata = Vec::with_capacity(round.transactions.len() + 1);
+
+		txdata.push(coinbase.clone());
+		txdata.extend(round.transactions.iter().cloned());
+		drop(rounds);
+
+		tracing::info!(?hash, "found block hash");
+
+		match self.rpc.submit_block(&bitcoin::Block { header, txdata }) {
+			Ok(()) => {
+				tracing::info!(?hash, "block accepted");
+				true
+			}
+			Err(e) => {
+				tracing::error!(?hash, error = %e, "block rejected");
+				false
+			}
+		}
+	}
+}
+
+/// Pads a header like the last SHA-256 block of its first hash, swapping the
+/// bytes of every word.
+fn encode_data(header: &bitcoin::block::Header) -> [u8; DATA_SIZE] {
+	let mut data = [0; DATA_SIZE];
+
+	data[..80].copy_from_slice(&bitcoin::consensus::serialize(header));
+	data[80] = 0x80;
+	// the message length in bits
+	data[124..].copy_from_slice(&(80u32 * 8).to_be_bytes());
+
+	for word in data.chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	data
+}
+
+/// The header in getwork data, ignoring the padding.
+fn decode_data(data: &[u8]) -> Option<bitcoin::block::Header> {
+	let mut header = data.get(..80)?.to_vec();
+
+	for word in header.chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	bitcoin::consensus::deserialize(&header).ok()
+}
@@ -0,0 +1,306 @@
+use std::{
+	fmt,
+	ops::Range,
+	str::FromStr,
+	sync::Mutex,
+	thread,
+	time::{Duration, Instant},
+};
+
+use super::{Error, Hasher, Tuner};
+use crate::{
+	backend::{Backend, Hits},
+	miner::format_rate,
+};
+
+/// Lists every adapter on this machine, in the order [`Selector::Index`]
+/// counts them.
+#[must_use]
+pub fn adapters() -> Vec<wgpu::AdapterInfo> {
+	enumerate().iter().map(wgpu::Adapter::get_info).collect()
+}
+
+fn enumerate() -> Vec<wgpu::Adapter> {
+	wgpu::Instance::default().enumerate_adapters(wgpu::Backends::all())
+}
+
+/// Picks adapters out of [`adapters`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+	/// The adapter at this position in the list
+	Index(usize),
+	/// Every adapter on this backend
+	Backend(wgpu::Backend),
+	/// Every adapter whose name contains this, ignoring case
+	Name(String),
+}
+
+impl Selector {
+	const BACKENDS: [wgpu::Backend; 5] = [
+		wgpu::Backend::Vulkan,
+		wgpu::Backend::Metal,
+		wgpu::Backend::Dx12,
+		wgpu::Backend::Gl,
+		wgpu::Backend::BrowserWebGpu,
+	];
+
+	fn matches(&self, index: usize, info: &wgpu::AdapterInfo) -> bool {
+		match self {
+			Self::Index(i) => *i == index,
+			Self::Backend(backend) => info.backend == *backend,
+			Self::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+		}
+	}
+}
+
+impl fmt::Display for Selector {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Index(index) => write!(f, "{index}"),
+			Self::Backend(backend) => f.write_str(backend.to_str()),
+			Self::Name(name) => f.write_str(name),
+		}
+	}
+}
+
+impl FromStr for Selector {
+	type Err = String;
+
+	/// Parses an index, a backend name like `vulkan` or `gl`, or otherwise
+	/// part of an adapter name.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.is_empty() {
+			return Err("empty adapter selector".to_string());
+		}
+
+		if let Ok(index) = s.parse() {
+			return Ok(Self::Index(index));
+		}
+
+		Ok(Self::BACKENDS
+			.into_iter()
+			.find(|backend| backend.to_str().eq_ignore_ascii_case(s))
+			.map_or_else(|| Self::Name(s.to_string()), Self::Backend))
+	}
+}
+
+/// Searches on several GPUs at once, giving each a disjoint part of every
+/// nonce range.
+#[derive(Debug)]
+pub struct Devices {
+	hashers: Vec<Hasher>,
+	/// Each device's hash rate over its last search, which sizes its part of
+	/// the next one
+	rates: Mutex<Vec<f64>>,
+}
+
+impl Devices {
+	/// Creates a hasher on every adapter matched by any of `selectors`, or on
+	/// the default adapter if there are none.
+	///
+	/// # Errors
+	/// Returns an error if a selector matches no adapter, or if a hasher fails
+	/// to initialize.
+	pub fn select(selectors: &[Selector]) -> Result<Self, Error> {
+		if selectors.is_empty() {
+			return Ok(Self::from(Hasher::new()?));
+		}
+
+		let adapters = enumerate();
+		let infos = adapters
+			.iter()
+			.map(wgpu::Adapter::get_info)
+			.collect::<Vec<_>>();
+		let mut selected = vec![false; adapters.len()];
+
+		for selector in selectors {
+			let mut matched = false;
+
+			for (index, info) in infos.iter().enumerate() {
+				if selector.matches(index, info) {
+					selected[index] = true;
+					matched = true;
+				}
+			}
+
+			if !matched {
+				return Err(Error::NoMatch(selector.clone()));
+			}
+		}
+
+		let hashers = adapters
+			.iter()
+			.zip(selected)
+			.filter(|(_, selected)| *selected)
+			.map(|(adapter, _)| Hasher::from_adapter(adapter))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		if hashers.is_empty() {
+			return Err(Error::NoAdapter);
+		}
+
+		Ok(Self::new(hashers))
+	}
+
+	fn new(hashers: Vec<Hasher>) -> Self {
+		for hasher in &hashers {
+			let info = hasher.info();
+
+			tracing::info!(
+				name = info.name,
+				backend = info.backend.to_str(),
+				"mining on gpu"
+			);
+		}
+
+		Self {
+			rates: Mutex::new(vec![0.0; hashers.len()]),
+			hashers,
+		}
+	}
+
+	/// Tunes every hasher for its adapter.
+	///
+	/// # Errors
+	/// Returns an error if a hasher fails to search while benchmarking.
+	pub fn tune(&mut self, tuner: &Tuner) -> Result<(), Error> {
+		for hasher in &mut self.hashers {
+			tuner.tune(hasher)?;
+		}
+
+		Ok(())
+	}
+
+	/// The hashers, in the order they were listed.
+	#[must_use]
+	pub fn hashers(&self) -> &[Hasher] {
+		&self.hashers
+	}
+
+	/// Each device's hash rate over its last search, or 0 before it has
+	/// searched.
+	///
+	/// # Panics
+	/// Panics if a search panicked while holding the rates.
+	#[must_use]
+	pub fn hash_rates(&self) -> Vec<f64> {
+		self.rates.lock().unwrap().clone()
+	}
+}
+
+impl Backend for Devices {
+	fn search(
+		&self,
+		header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		share_target: bitcoin::Target,
+	) -> Result<Hits, crate::Error> {
+		let parts = split(nonces, &self.hash_rates());
+		let results = thread::scope(|s| {
+			let handles = self
+				.hashers
+				.iter()
+				.zip(parts)
+				.map(|(hasher, part)| {
+					s.spawn(move || {
+						let start = Instant::now();
+						let hits = hasher.search(header, part, target, share_target);
+
+						(hits, start.elapsed())
+					})
+				})
+				.collect::<Vec<_>>();
+
+			handles
+				.into_iter()
+				.map(|handle| match handle.join() {
+					Ok(result) => result,
+					Err(panic) => std::panic::resume_unwind(panic),
+				})
+				.collect::<Vec<_>>()
+		});
+
+		let mut total = Hits::default();
+		let mut rates = self.rates.lock().unwrap();
+		let mut found = false;
+
+		for ((hasher, (hits, elapsed)), rate) in self.hashers.iter().zip(results).zip(&mut *rates) {
+			let hits = hits?;
+
+			*rate = hash_rate(hits.hashes, elapsed);
+
+			// the parts are in nonce order, so the hits and hashes after a
+			// block are found again once the miner resumes after it
+			if !found {
+				found = hits.blocks().next().is_some();
+				total.hashes += hits.hashes;
+				total.shares += hits.shares;
+				total.hits.extend(hits.hits);
+			}
+
+			tracing::info!(
+				name = hasher.info().name,
+				rate = *rate,
+				rate_pretty = format_rate(*rate),
+				"device hash rate"
+			);
+		}
+
+		Ok(total)
+	}
+
+	fn batch_size(&self) -> u32 {
+		self.hashers
+			.iter()
+			.fold(0, |size, hasher| size.saturating_add(hasher.batch_size()))
+	}
+}
+
+impl From<Hasher> for Devices {
+	fn from(value: Hasher) -> Self {
+		Self::new(vec![value])
+	}
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn hash_rate(hashes: u64, elapsed: Duration) -> f64 {
+	hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Splits `nonces` into one part for each rate, sized by how fast the device
+/// is, or evenly until every device has a rate.
+#[allow(
+	clippy::cast_possible_truncation,
+	clippy::cast_precision_loss,
+	clippy::cast_sign_loss
+)]
+fn split(nonces: Range<u32>, rates: &[f64]) -> Vec<Range<u32>> {
+	let even = rates.iter().any(|rate| *rate <= 0.0);
+	let total = if even {
+		rates.len() as f64
+	} else {
+		rates.iter().sum()
+	};
+	let len = f64::from(nonces.end - nonces.start);
+	let mut start = nonces.start;
+	let mut sum = 0.0;
+
+	rates
+		.iter()
+		.enumerate()
+		.map(|(index, rate)| {
+			sum += if even { 1.0 } else { *rate };
+
+			let end = if index + 1 == rates.len() {
+				nonces.end
+			} else {
+				nonces.start + (len * sum / total) as u32
+			};
+			let part = start..end;
+
+			start = end;
+			part
+		})
+		.collect()
+}
@@ -1,30 +1,124 @@
+mod devices;
 mod options;
+mod pipeline;
+mod tune;
 
-use std::fmt;
+use std::{
+	fmt,
+	ops::Range,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
 
+use bitcoin::hashes::Hash as _;
 use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
 use wgpu::util::DeviceExt as _;
 
+use self::pipeline::Slot;
+pub use self::{
+	devices::{adapters, Devices, Selector},
+	pipeline::{Job, Pipeline},
+	tune::Tuner,
+};
+pub use crate::backend::HitKind;
+use crate::backend::{self, Backend, Hits};
+
+/// How many dispatches can be in flight at once
+const PIPELINE_DEPTH: usize = 3;
+
+/// How the hasher splits up its work, which a [`Tuner`] can pick for an
+/// adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+	/// How many threads are in a workgroup
+	pub workgroup_size: u32,
+	/// How many workgroups a dispatch runs
+	pub workgroups: u32,
+	/// How many nonces a dispatch searches
+	pub dispatch_size: u32,
+	/// How many nonces the GPU searches before checking for a new job
+	pub batch_size: u32,
+}
+
+impl Default for Config {
+	/// 64 workgroups of 256 threads, each searching 1024 nonces a dispatch.
+	fn default() -> Self {
+		Self {
+			workgroup_size: 256,
+			workgroups: 64,
+			dispatch_size: 1 << 24,
+			batch_size: 1 << 30,
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct Hasher {
+	info: wgpu::AdapterInfo,
+	config: Config,
 	device: wgpu::Device,
 	compute_pipeline: wgpu::ComputePipeline,
-	bind_group: wgpu::BindGroup,
-	input_header_buffer: wgpu::Buffer,
-	input_target_buffer: wgpu::Buffer,
-	output_buffer: wgpu::Buffer,
-	mappable_buffer: wgpu::Buffer,
+	/// The buffers of each dispatch in flight, locked by a [`Pipeline`]
+	slots: Mutex<Vec<Slot>>,
+	/// Set by the shader once a pipeline's dispatch finds a block
+	found_buffer: wgpu::Buffer,
 	queue: wgpu::Queue,
+	pipeline_layout: wgpu::PipelineLayout,
+	/// Only set if the device supports timestamp queries
+	timestamps: Option<Timestamps>,
 
-	_pipeline_layout: wgpu::PipelineLayout,
 	_bind_group_layout: wgpu::BindGroupLayout,
-	_shader: wgpu::ShaderModule,
+	shader: wgpu::ShaderModule,
+}
+
+/// Where the start and end of a timed dispatch are written.
+#[derive(Debug)]
+struct Timestamps {
+	query_set: wgpu::QuerySet,
+	resolve_buffer: wgpu::Buffer,
+	mappable_buffer: wgpu::Buffer,
+}
+
+/// What the GPU found while searching a job's headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+	/// The nonces whose hashes met the share target or the target, in no
+	/// particular order
+	pub hits: Vec<Hit>,
+	/// How many hits didn't fit in the output buffer, so only their count is
+	/// known
+	pub dropped: u64,
+	/// How many nonces were hashed, summed over the headers, which stops
+	/// short of the range once a block is found
+	pub searched: u64,
+}
+
+impl Output {
+	/// Whether a hit met the target.
+	#[must_use]
+	pub fn found(&self) -> bool {
+		self.hits.iter().any(|hit| hit.kind == HitKind::Block)
+	}
+}
+
+/// A nonce whose hash met the share target or the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+	/// The index of the header in the [`Job`]
+	pub header: usize,
+	pub nonce: u32,
+	pub kind: HitKind,
 }
 
 #[derive(Debug)]
 pub enum Error {
 	NoAdapter,
+	/// No adapter matched the selector
+	NoMatch(Selector),
 	NoDevice,
+	/// None of the tuner's configs are supported by the device
+	NoConfig,
 	BufferAsync(wgpu::BufferAsyncError),
 }
 
@@ -32,7 +126,9 @@ impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Self::NoAdapter => write!(f, "no adapter found"),
+			Self::NoMatch(selector) => write!(f, "no adapter matches {selector}"),
 			Self::NoDevice => write!(f, "no device found"),
+			Self::NoConfig => write!(f, "no supported config to tune"),
 			Self::BufferAsync(e) => write!(f, "buffer async error: {e}"),
 		}
 	}
@@ -41,52 +137,31 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {}
 
 impl Hasher {
+	/// Creates a hasher on the default adapter.
+	///
 	/// # Errors
 	/// Returns an error if the GPU hasher fails to initialize.
 	pub fn new() -> Result<Self, Error> {
 		let instance = wgpu::Instance::default();
 
-		let adapter = Self::request_adapter(&instance)?;
-		let (device, queue) = Self::request_device(&adapter)?;
-		let shader = device.create_shader_module(options::SHADER_DESC);
+		Self::from_adapter(&Self::request_adapter(&instance)?)
+	}
+
+	/// Creates a hasher on `adapter`, such as one from [`Devices`].
+	///
+	/// # Errors
+	/// Returns an error if the GPU hasher fails to initialize.
+	pub fn from_adapter(adapter: &wgpu::Adapter) -> Result<Self, Error> {
+		let config = Config::default();
+		let (device, queue) = Self::request_device(adapter)?;
+		let shader = device.create_shader_module(options::shader_desc(config.workgroup_size));
 
 		let bind_group_layout = device.create_bind_group_layout(&options::BIND_GROUP_LAYOUT);
 
-		let input_header_buffer = device.create_buffer_init(&options::INPUT_HEADER_DESC);
-		let input_target_buffer = device.create_buffer_init(&options::INPUT_TARGET_DESC);
-		let output_buffer = device.create_buffer(&options::OUTPUT_DESC);
-		let mappable_buffer = device.create_buffer(&options::MAPPABLE_DESC);
-
-		let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-			label: Some("Compute Bind Group"),
-			layout: &bind_group_layout,
-			entries: &[
-				wgpu::BindGroupEntry {
-					binding: 0,
-					resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-						buffer: &input_header_buffer,
-						offset: 0,
-						size: None,
-					}),
-				},
-				wgpu::BindGroupEntry {
-					binding: 1,
-					resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-						buffer: &input_target_buffer,
-						offset: 0,
-						size: None,
-					}),
-				},
-				wgpu::BindGroupEntry {
-					binding: 2,
-					resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-						buffer: &output_buffer,
-						offset: 0,
-						size: None,
-					}),
-				},
-			],
-		});
+		let found_buffer = device.create_buffer_init(&options::FOUND_DESC);
+		let slots = (0..PIPELINE_DEPTH)
+			.map(|_| Slot::new(&device, &bind_group_layout, &found_buffer))
+			.collect();
 
 		let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
 			label: Some("Compute Pipeline Layout"),
@@ -94,102 +169,319 @@ impl Hasher {
 			push_constant_ranges: &[],
 		});
 
-		let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-			label: Some("Compute Pipeline"),
-			layout: Some(&pipeline_layout),
-			module: &shader,
-			entry_point: "main",
-		});
+		let compute_pipeline = Self::create_pipeline(&device, &pipeline_layout, &shader);
+		let timestamps = device
+			.features()
+			.contains(wgpu::Features::TIMESTAMP_QUERY)
+			.then(|| Timestamps {
+				query_set: device.create_query_set(&options::QUERY_SET_DESC),
+				resolve_buffer: device.create_buffer(&options::TIMESTAMP_RESOLVE_DESC),
+				mappable_buffer: device.create_buffer(&options::TIMESTAMP_MAPPABLE_DESC),
+			});
 
 		Ok(Self {
+			info: adapter.get_info(),
+			config,
 			device,
 			compute_pipeline,
-			bind_group,
-			input_header_buffer,
-			input_target_buffer,
-			output_buffer,
-			mappable_buffer,
+			slots: Mutex::new(slots),
+			found_buffer,
 			queue,
-			_pipeline_layout: pipeline_layout,
+			pipeline_layout,
+			timestamps,
 			_bind_group_layout: bind_group_layout,
-			_shader: shader,
+			shader,
 		})
 	}
 
+	/// The adapter this hasher runs on.
+	#[must_use]
+	pub fn info(&self) -> &wgpu::AdapterInfo {
+		&self.info
+	}
+
+	#[must_use]
+	pub fn config(&self) -> Config {
+		self.config
+	}
+
+	/// Splits up the work according to `config`, rebuilding the pipeline if
+	/// the workgroup size changed.
+	pub fn set_config(&mut self, config: Config) {
+		if config.workgroup_size != self.config.workgroup_size {
+			self.shader = self
+				.device
+				.create_shader_module(options::shader_desc(config.workgroup_size));
+			self.compute_pipeline =
+				Self::create_pipeline(&self.device, &self.pipeline_layout, &self.shader);
+		}
+
+		self.config = config;
+	}
+
+	/// Whether the device can run `config`.
+	#[must_use]
+	pub fn supports(&self, config: Config) -> bool {
+		let limits = self.device.limits();
+
+		config.workgroup_size > 0
+			&& config.workgroups > 0
+			&& config.workgroup_size <= limits.max_compute_workgroup_size_x
+			&& config.workgroup_size <= limits.max_compute_invocations_per_workgroup
+			&& config.workgroups <= limits.max_compute_workgroups_per_dimension
+	}
+
+	/// Starts a pipeline of dispatches, waiting for any other pipeline on
+	/// this hasher to be dropped.
+	///
+	/// # Panics
+	/// Panics if another pipeline panicked.
+	#[must_use]
+	pub fn pipeline(&self) -> Pipeline<'_> {
+		Pipeline::new(self, self.slots.lock().unwrap())
+	}
+
+	/// Searches `block` over `nonces` for hashes that meet `target` or
+	/// `share_target`.
+	///
+	/// The range is split into dispatches that are kept in flight, and the
+	/// search stops once one finds a nonce meeting `target`.
+	///
 	/// # Errors
 	/// Returns an error if the buffer fails to map.
-	pub fn process(&self, block: [u8; 80], target: [u8; 32]) -> Result<[u8; 80], Error> {
-		let command = self.create_command_buffer(block, target);
-		let idx = self.queue.submit(Some(command));
+	pub fn process(
+		&self,
+		block: [u8; 80],
+		nonces: Range<u32>,
+		target: [u8; 32],
+		share_target: [u8; 32],
+	) -> Result<Output, Error> {
+		self.process_job(&Job::new(&block, target, share_target), nonces)
+	}
 
-		self.wait_for(idx).map_err(Error::BufferAsync)
+	/// Searches every header of `job` over `nonces`, in the same dispatches.
+	///
+	/// Each dispatch searches as many nonces in each header as fit in the
+	/// dispatch size, and the search stops once one finds a nonce meeting its
+	/// header's target, along with the dispatches already in flight.
+	///
+	/// # Errors
+	/// Returns an error if the buffer fails to map.
+	///
+	/// # Panics
+	/// Panics if `job` is empty, or has more headers than the dispatch size.
+	#[allow(clippy::cast_possible_truncation)]
+	pub fn process_job(&self, job: &Job, nonces: Range<u32>) -> Result<Output, Error> {
+		// a job holds at most `Job::MAX_HEADERS`
+		let span = (self.config.dispatch_size / job.len().max(1) as u32).max(1);
+		let mut pipeline = self.pipeline();
+		let mut output = Output::default();
+		let mut start = nonces.start;
+
+		loop {
+			// keep every slot busy until the range is covered
+			while !pipeline.is_full() && start < nonces.end && !output.found() {
+				let end = start + (nonces.end - start).min(span);
+
+				pipeline.submit(job, start..end);
+				start = end;
+			}
+
+			let Some(dispatch) = pipeline.wait()? else {
+				return Ok(output);
+			};
+
+			output.hits.extend(dispatch.hits);
+			output.dropped += dispatch.dropped;
+			output.searched += dispatch.searched;
+		}
 	}
 
-	fn wait_for(&self, idx: wgpu::SubmissionIndex) -> Result<[u8; 80], wgpu::BufferAsyncError> {
-		let buffer_slice = self.mappable_buffer.slice(..);
+	/// Times a dispatch of `count` nonces, on the GPU's clock if it supports
+	/// timestamps.
+	fn time_dispatch(&self, count: u32) -> Result<Duration, Error> {
+		// nothing meets a zero target, so every thread searches all of its nonces
+		let job = Job::new(&[0; 80], [0; 32], [0; 32]);
+		let mut pipeline = self.pipeline();
+		let start = Instant::now();
+
+		pipeline.dispatch(&job, 0..count, true);
+		pipeline.wait()?;
+
+		let elapsed = start.elapsed();
+		let Some(timestamps) = &self.timestamps else {
+			return Ok(elapsed);
+		};
+
+		let buffer_slice = timestamps.mappable_buffer.slice(..);
 		let (tx, rx) = oneshot::channel();
 
 		buffer_slice.map_async(wgpu::MapMode::Read, |res| {
 			tx.send(res).unwrap();
 		});
 
-		self.device
-			.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+		self.device.poll(wgpu::Maintain::Wait);
 
-		rx.recv().unwrap()?;
+		rx.recv().unwrap().map_err(Error::BufferAsync)?;
 
 		let data = buffer_slice.get_mapped_range();
-
-		let block: &[u8] = &data;
-		let block = block.try_into().unwrap();
+		let begin = u64::from_le_bytes(data[..8].try_into().unwrap());
+		let end = u64::from_le_bytes(data[8..16].try_into().unwrap());
 
 		drop(data);
-		self.mappable_buffer.unmap();
-
-		Ok(block)
+		timestamps.mappable_buffer.unmap();
+
+		// each tick is this many nanoseconds
+		let period = f64::from(self.queue.get_timestamp_period());
+		#[allow(
+			clippy::cast_possible_truncation,
+			clippy::cast_precision_loss,
+			clippy::cast_sign_loss
+		)]
+		let nanos = (end.saturating_sub(begin) as f64 * period) as u64;
+
+		Ok(Duration::from_nanos(nanos))
 	}
 
-	fn create_command_buffer(&self, input: [u8; 80], target: [u8; 32]) -> wgpu::CommandBuffer {
-		// overwrite the input buffer with the new input
-		self.queue
-			.write_buffer(&self.input_header_buffer, 0, &input);
-
-		// overwrite the input size buffer with the new input
-		self.queue
-			.write_buffer(&self.input_target_buffer, 0, &target);
-
+	fn create_command_buffer(&self, slot: &Slot, timed: bool) -> wgpu::CommandBuffer {
 		let mut encoder = self
 			.device
 			.create_command_encoder(&wgpu::CommandEncoderDescriptor {
 				label: Some("Compute Encoder"),
 			});
 
+		let timestamps = self.timestamps.as_ref().filter(|_| timed);
+
 		{
 			let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
 				label: Some("Compute Pass"),
-				timestamp_writes: None,
+				timestamp_writes: timestamps.map(|timestamps| wgpu::ComputePassTimestampWrites {
+					query_set: &timestamps.query_set,
+					beginning_of_pass_write_index: Some(0),
+					end_of_pass_write_index: Some(1),
+				}),
 			});
 
 			compute_pass.set_pipeline(&self.compute_pipeline);
-			compute_pass.set_bind_group(0, &self.bind_group, &[]);
-			// NOTE: when modifying this value, also change `numWorkgroups` in sha256.wgsl
-			compute_pass.dispatch_workgroups(64, 1, 1);
+			compute_pass.set_bind_group(0, &slot.bind_group, &[]);
+			compute_pass.dispatch_workgroups(self.config.workgroups, 1, 1);
+		}
+
+		if let Some(timestamps) = timestamps {
+			encoder.resolve_query_set(&timestamps.query_set, 0..2, &timestamps.resolve_buffer, 0);
+			encoder.copy_buffer_to_buffer(
+				&timestamps.resolve_buffer,
+				0,
+				&timestamps.mappable_buffer,
+				0,
+				16,
+			);
 		}
 
-		encoder.copy_buffer_to_buffer(&self.output_buffer, 0, &self.mappable_buffer, 0, 80);
+		encoder.copy_buffer_to_buffer(
+			&slot.output_buffer,
+			0,
+			&slot.mappable_buffer,
+			0,
+			options::OUTPUT_SIZE as u64,
+		);
 		encoder.finish()
 	}
 
+	fn create_pipeline(
+		device: &wgpu::Device,
+		layout: &wgpu::PipelineLayout,
+		shader: &wgpu::ShaderModule,
+	) -> wgpu::ComputePipeline {
+		device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+			label: Some("Compute Pipeline"),
+			layout: Some(layout),
+			module: shader,
+			entry_point: "main",
+		})
+	}
+
 	fn request_adapter(instance: &wgpu::Instance) -> Result<wgpu::Adapter, Error> {
 		block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
 			.ok_or(Error::NoAdapter)
 	}
 
 	fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue), Error> {
+		let descriptor = wgpu::DeviceDescriptor {
+			// timestamps are only used to tune the hasher, so it works without them
+			required_features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
+			..Default::default()
+		};
 		let (device, queue) =
-			block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
-				.map_err(|_| Error::NoDevice)?;
+			block_on(adapter.request_device(&descriptor, None)).map_err(|_| Error::NoDevice)?;
 
 		Ok((device, queue))
 	}
 }
+
+impl Backend for Hasher {
+	fn search(
+		&self,
+		header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		share_target: bitcoin::Target,
+	) -> Result<Hits, crate::Error> {
+		let start = nonces.start;
+		let output = self.process(
+			header,
+			nonces,
+			target.to_le_bytes(),
+			share_target.to_le_bytes(),
+		)?;
+		// the GPU's hits are checked, so a broken shader doesn't go unnoticed
+		let mut hits = output
+			.hits
+			.iter()
+			.filter_map(|hit| {
+				let mut solved = header;
+				solved[76..80].copy_from_slice(&hit.nonce.to_le_bytes());
+
+				let hash = bitcoin::BlockHash::hash(&solved);
+				let valid = match hit.kind {
+					HitKind::Share => share_target.is_met_by(hash),
+					HitKind::Block => target.is_met_by(hash),
+				};
+
+				if !valid {
+					tracing::error!(nonce = hit.nonce, kind = ?hit.kind, "gpu found an invalid hit");
+				}
+
+				valid.then_some((
+					backend::Hit {
+						nonce: hit.nonce,
+						kind: hit.kind,
+					},
+					share_target.is_met_by(hash),
+				))
+			})
+			.collect::<Vec<_>>();
+
+		hits.sort_unstable_by_key(|(hit, _)| hit.nonce);
+
+		let mut hashes = output.searched;
+
+		// the threads past the last block stopped partway, so their hits and
+		// hashes are found again once the miner resumes after it
+		if let Some(last) = hits.iter().rposition(|(hit, _)| hit.kind == HitKind::Block) {
+			hits.truncate(last + 1);
+			hashes = hashes.min(u64::from(hits[last].0.nonce - start) + 1);
+		}
+
+		Ok(Hits {
+			// the dropped hits can't be checked, but are almost always shares
+			shares: output.dropped + hits.iter().filter(|(_, share)| *share).count() as u64,
+			hits: hits.into_iter().map(|(hit, _)| hit).collect(),
+			hashes,
+		})
+	}
+
+	fn batch_size(&self) -> u32 {
+		self.config.batch_size
+	}
+}
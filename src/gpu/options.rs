@@ -1,12 +1,21 @@
 use std::borrow::Cow;
 
-pub const SHADER_DESC: wgpu::ShaderModuleDescriptor = wgpu::ShaderModuleDescriptor {
-	label: Some("SHA-256 Shader"),
-	source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(concat!(
-		env!("CARGO_MANIFEST_DIR"),
-		"/shaders/sha256.wgsl"
-	)))),
-};
+const SHADER: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/sha256.wgsl"));
+
+/// The shader with `workgroupSize` and the buffer sizes declared, since WGSL
+/// needs them at compile time
+pub fn shader_desc(workgroup_size: u32) -> wgpu::ShaderModuleDescriptor<'static> {
+	wgpu::ShaderModuleDescriptor {
+		label: Some("SHA-256 Shader"),
+		source: wgpu::ShaderSource::Wgsl(Cow::Owned(format!(
+			"const workgroupSize: u32 = {workgroup_size}u;\n\
+			 const maxHeaders: u32 = {MAX_HEADERS}u;\n\
+			 const maxHits: u32 = {MAX_HITS}u;\n\
+			 const maxBlocks: u32 = {MAX_BLOCKS}u;\n\
+			 {SHADER}"
+		))),
+	}
+}
 
 pub const BIND_GROUP_LAYOUT: wgpu::BindGroupLayoutDescriptor = wgpu::BindGroupLayoutDescriptor {
 	label: Some("Compute Bind Group Layout"),
@@ -41,38 +50,149 @@ pub const BIND_GROUP_LAYOUT: wgpu::BindGroupLayoutDescriptor = wgpu::BindGroupLa
 			},
 			count: None,
 		},
+		wgpu::BindGroupLayoutEntry {
+			binding: 3,
+			visibility: wgpu::ShaderStages::COMPUTE,
+			ty: wgpu::BindingType::Buffer {
+				ty: wgpu::BufferBindingType::Storage { read_only: true },
+				has_dynamic_offset: false,
+				min_binding_size: None,
+			},
+			count: None,
+		},
+		wgpu::BindGroupLayoutEntry {
+			binding: 4,
+			visibility: wgpu::ShaderStages::COMPUTE,
+			ty: wgpu::BindingType::Buffer {
+				ty: wgpu::BufferBindingType::Storage { read_only: true },
+				has_dynamic_offset: false,
+				min_binding_size: None,
+			},
+			count: None,
+		},
+		wgpu::BindGroupLayoutEntry {
+			binding: 5,
+			visibility: wgpu::ShaderStages::COMPUTE,
+			ty: wgpu::BindingType::Buffer {
+				ty: wgpu::BufferBindingType::Storage { read_only: false },
+				has_dynamic_offset: false,
+				min_binding_size: None,
+			},
+			count: None,
+		},
 	],
 };
 
-pub const INPUT_HEADER_DESC: wgpu::util::BufferInitDescriptor = wgpu::util::BufferInitDescriptor {
-	label: Some("Input Header Buffer"),
-	contents: &[0; 80],
+/// How many headers a dispatch can search
+pub const MAX_HEADERS: usize = 16;
+
+/// The size of a header's midstate, which is the state after its first block
+/// followed by the header words between it and the nonce
+pub const MIDSTATE_SIZE: usize = 44;
+
+/// Each header's midstate
+pub const INPUT_MIDSTATE_DESC: wgpu::util::BufferInitDescriptor =
+	wgpu::util::BufferInitDescriptor {
+		label: Some("Input Midstate Buffer"),
+		contents: &[0; MAX_HEADERS * MIDSTATE_SIZE],
+		usage: wgpu::BufferUsages::from_bits_truncate(
+			wgpu::BufferUsages::COPY_DST.bits() | wgpu::BufferUsages::STORAGE.bits(),
+		),
+	};
+
+/// Each header's target
+pub const INPUT_TARGET_DESC: wgpu::util::BufferInitDescriptor = wgpu::util::BufferInitDescriptor {
+	label: Some("Input Target Buffer"),
+	contents: &[0; MAX_HEADERS * 32],
 	usage: wgpu::BufferUsages::from_bits_truncate(
 		wgpu::BufferUsages::COPY_DST.bits() | wgpu::BufferUsages::STORAGE.bits(),
 	),
 };
 
-pub const INPUT_TARGET_DESC: wgpu::util::BufferInitDescriptor = wgpu::util::BufferInitDescriptor {
-	label: Some("Input Target Buffer"),
-	contents: &[0; 32],
+/// Each header's share target
+pub const INPUT_SHARE_TARGET_DESC: wgpu::util::BufferInitDescriptor =
+	wgpu::util::BufferInitDescriptor {
+		label: Some("Input Share Target Buffer"),
+		contents: &[0; MAX_HEADERS * 32],
+		usage: wgpu::BufferUsages::from_bits_truncate(
+			wgpu::BufferUsages::COPY_DST.bits() | wgpu::BufferUsages::STORAGE.bits(),
+		),
+	};
+
+/// The first nonce of a dispatch, how many it searches in each header and how
+/// many headers there are
+pub const INPUT_NONCES_DESC: wgpu::util::BufferInitDescriptor = wgpu::util::BufferInitDescriptor {
+	label: Some("Input Nonces Buffer"),
+	contents: &[0; 12],
 	usage: wgpu::BufferUsages::from_bits_truncate(
 		wgpu::BufferUsages::COPY_DST.bits() | wgpu::BufferUsages::STORAGE.bits(),
 	),
 };
 
+/// How many hits a dispatch can report, past which they're only counted
+pub const MAX_HITS: usize = 1024;
+
+/// How many of the hits are kept for blocks, at the end
+pub const MAX_BLOCKS: usize = 64;
+
+/// The size of a hit, which is its header index, nonce and kind
+pub const HIT_SIZE: usize = 12;
+
+/// The share and block counts and how many nonces were hashed
+pub const OUTPUT_COUNTS_SIZE: usize = 12;
+
+/// The counts, followed by the hits
+pub const OUTPUT_SIZE: usize = OUTPUT_COUNTS_SIZE + MAX_HITS * HIT_SIZE;
+
 pub const OUTPUT_DESC: wgpu::BufferDescriptor = wgpu::BufferDescriptor {
 	label: Some("Output Buffer"),
-	size: 80,
+	size: OUTPUT_SIZE as u64,
 	// https://github.com/bitflags/bitflags/issues/180
 	usage: wgpu::BufferUsages::from_bits_truncate(
-		wgpu::BufferUsages::COPY_SRC.bits() | wgpu::BufferUsages::STORAGE.bits(),
+		wgpu::BufferUsages::COPY_SRC.bits()
+			| wgpu::BufferUsages::COPY_DST.bits()
+			| wgpu::BufferUsages::STORAGE.bits(),
 	),
 	mapped_at_creation: false,
 };
 
+/// Whether a dispatch of the pipeline found a block, shared by its slots
+pub const FOUND_DESC: wgpu::util::BufferInitDescriptor = wgpu::util::BufferInitDescriptor {
+	label: Some("Found Buffer"),
+	contents: &[0; 4],
+	usage: wgpu::BufferUsages::from_bits_truncate(
+		wgpu::BufferUsages::COPY_DST.bits() | wgpu::BufferUsages::STORAGE.bits(),
+	),
+};
+
 pub const MAPPABLE_DESC: wgpu::BufferDescriptor = wgpu::BufferDescriptor {
 	label: Some("Mappable Buffer"),
-	size: 80,
+	size: OUTPUT_SIZE as u64,
+	usage: wgpu::BufferUsages::from_bits_truncate(
+		wgpu::BufferUsages::MAP_READ.bits() | wgpu::BufferUsages::COPY_DST.bits(),
+	),
+	mapped_at_creation: false,
+};
+
+/// The start and end of a dispatch, when the device supports timestamps
+pub const QUERY_SET_DESC: wgpu::QuerySetDescriptor = wgpu::QuerySetDescriptor {
+	label: Some("Timestamp Query Set"),
+	ty: wgpu::QueryType::Timestamp,
+	count: 2,
+};
+
+pub const TIMESTAMP_RESOLVE_DESC: wgpu::BufferDescriptor = wgpu::BufferDescriptor {
+	label: Some("Timestamp Resolve Buffer"),
+	size: 16,
+	usage: wgpu::BufferUsages::from_bits_truncate(
+		wgpu::BufferUsages::QUERY_RESOLVE.bits() | wgpu::BufferUsages::COPY_SRC.bits(),
+	),
+	mapped_at_creation: false,
+};
+
+pub const TIMESTAMP_MAPPABLE_DESC: wgpu::BufferDescriptor = wgpu::BufferDescriptor {
+	label: Some("Timestamp Mappable Buffer"),
+	size: 16,
 	usage: wgpu::BufferUsages::from_bits_truncate(
 		wgpu::BufferUsages::MAP_READ.bits() | wgpu::BufferUsages::COPY_DST.bits(),
 	),
@@ -0,0 +1,359 @@
+use std::{collections::VecDeque, ops::Range, sync::MutexGuard};
+
+use wgpu::util::DeviceExt as _;
+
+use super::{options, Error, Hasher, Hit, HitKind, Output};
+use crate::backend::sha256::Midstate;
+
+/// The inputs of the headers searched together, prepared once for all of
+/// their dispatches.
+///
+/// Every dispatch searches the same nonces in each header, such as the
+/// variants of a header with different extranonces or versions, and its hits
+/// say which header they're in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Job {
+	/// Each header's midstate followed by its tail words, as the shader reads
+	/// them
+	midstates: Vec<u8>,
+	targets: Vec<u8>,
+	share_targets: Vec<u8>,
+}
+
+impl Job {
+	/// How many headers a job can hold.
+	pub const MAX_HEADERS: usize = options::MAX_HEADERS;
+
+	/// Prepares `header` to be searched for hashes that meet `target` or
+	/// `share_target`, both little-endian.
+	#[must_use]
+	pub fn new(header: &[u8; 80], target: [u8; 32], share_target: [u8; 32]) -> Self {
+		let mut job = Self::default();
+
+		job.push(header, target, share_target);
+		job
+	}
+
+	/// Adds `header` to the job with its own targets, returning the index its
+	/// hits will have.
+	///
+	/// # Panics
+	/// Panics if the job already holds [`Job::MAX_HEADERS`] headers.
+	pub fn push(&mut self, header: &[u8; 80], target: [u8; 32], share_target: [u8; 32]) -> usize {
+		let index = self.len();
+
+		assert!(
+			index < Self::MAX_HEADERS,
+			"a job can't hold more than {} headers",
+			Self::MAX_HEADERS
+		);
+
+		// the first block of the header is the same for every nonce, so it's
+		// only hashed once here
+		let state = Midstate::new(header);
+
+		for word in state.state.iter().chain(&state.tail) {
+			self.midstates.extend_from_slice(&word.to_le_bytes());
+		}
+
+		self.targets.extend_from_slice(&target);
+		self.share_targets.extend_from_slice(&share_target);
+
+		index
+	}
+
+	/// How many headers the job holds.
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.targets.len() / 32
+	}
+
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.targets.is_empty()
+	}
+}
+
+/// The buffers of one dispatch, so the next ones can be written while it runs.
+#[derive(Debug)]
+pub(super) struct Slot {
+	midstate_buffer: wgpu::Buffer,
+	target_buffer: wgpu::Buffer,
+	share_target_buffer: wgpu::Buffer,
+	nonces_buffer: wgpu::Buffer,
+	pub(super) output_buffer: wgpu::Buffer,
+	pub(super) mappable_buffer: wgpu::Buffer,
+	pub(super) bind_group: wgpu::BindGroup,
+}
+
+impl Slot {
+	pub(super) fn new(
+		device: &wgpu::Device,
+		layout: &wgpu::BindGroupLayout,
+		found_buffer: &wgpu::Buffer,
+	) -> Self {
+		let midstate_buffer = device.create_buffer_init(&options::INPUT_MIDSTATE_DESC);
+		let target_buffer = device.create_buffer_init(&options::INPUT_TARGET_DESC);
+		let share_target_buffer = device.create_buffer_init(&options::INPUT_SHARE_TARGET_DESC);
+		let nonces_buffer = device.create_buffer_init(&options::INPUT_NONCES_DESC);
+		let output_buffer = device.create_buffer(&options::OUTPUT_DESC);
+		let mappable_buffer = device.create_buffer(&options::MAPPABLE_DESC);
+
+		let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+			label: Some("Compute Bind Group"),
+			layout,
+			entries: &[
+				wgpu::BindGroupEntry {
+					binding: 0,
+					resource: midstate_buffer.as_entire_binding(),
+				},
+				wgpu::BindGroupEntry {
+					binding: 1,
+					resource: target_buffer.as_entire_binding(),
+				},
+				wgpu::BindGroupEntry {
+					binding: 2,
+					resource: output_buffer.as_entire_binding(),
+				},
+				wgpu::BindGroupEntry {
+					binding: 3,
+					resource: share_target_buffer.as_entire_binding(),
+				},
+				wgpu::BindGroupEntry {
+					binding: 4,
+					resource: nonces_buffer.as_entire_binding(),
+				},
+				wgpu::BindGroupEntry {
+					binding: 5,
+					resource: found_buffer.as_entire_binding(),
+				},
+			],
+		});
+
+		Self {
+			midstate_buffer,
+			target_buffer,
+			share_target_buffer,
+			nonces_buffer,
+			output_buffer,
+			mappable_buffer,
+			bind_group,
+		}
+	}
+
+	#[allow(clippy::cast_possible_truncation)]
+	fn write(&self, queue: &wgpu::Queue, job: &Job, nonces: &Range<u32>) {
+		let mut range = [0; 12];
+
+		range[..4].copy_from_slice(&nonces.start.to_le_bytes());
+		range[4..8].copy_from_slice(&(nonces.end - nonces.start).to_le_bytes());
+		// a job holds at most `MAX_HEADERS`
+		range[8..].copy_from_slice(&(job.len() as u32).to_le_bytes());
+
+		queue.write_buffer(&self.midstate_buffer, 0, &job.midstates);
+		queue.write_buffer(&self.target_buffer, 0, &job.targets);
+		queue.write_buffer(&self.share_target_buffer, 0, &job.share_targets);
+		queue.write_buffer(&self.nonces_buffer, 0, &range);
+		// clear what the previous dispatch found, the hits past the counts are
+		// never read
+		queue.write_buffer(&self.output_buffer, 0, &[0; options::OUTPUT_COUNTS_SIZE]);
+	}
+
+	/// Reads the hits out of the mapped buffer, then unmaps it.
+	fn read(&self) -> Output {
+		let data = self.mappable_buffer.slice(..).get_mapped_range();
+		let count = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+		let hit = |at: usize| {
+			let at = options::OUTPUT_COUNTS_SIZE + at * options::HIT_SIZE;
+			let hit = &data[at..at + options::HIT_SIZE];
+
+			Hit {
+				header: u32::from_le_bytes(hit[..4].try_into().unwrap()) as usize,
+				nonce: u32::from_le_bytes(hit[4..8].try_into().unwrap()),
+				kind: if hit[8..] == [0; 4] {
+					HitKind::Share
+				} else {
+					HitKind::Block
+				},
+			}
+		};
+		let (shares, blocks) = (count(0), count(4));
+		let stored_shares = shares.min(options::MAX_HITS - options::MAX_BLOCKS);
+		let stored_blocks = blocks.min(options::MAX_BLOCKS);
+		// the blocks are stored from the back
+		let output = Output {
+			hits: (0..stored_blocks)
+				.map(|i| hit(options::MAX_HITS - 1 - i))
+				.chain((0..stored_shares).map(hit))
+				.collect(),
+			dropped: (shares - stored_shares + blocks - stored_blocks) as u64,
+			searched: count(8) as u64,
+		};
+
+		drop(data);
+		self.mappable_buffer.unmap();
+
+		output
+	}
+}
+
+/// A dispatch that was submitted but hasn't been read back yet.
+#[derive(Debug)]
+struct InFlight {
+	slot: usize,
+	submission: wgpu::SubmissionIndex,
+	/// Set once the output can be read
+	mapped: oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>,
+}
+
+/// Keeps several dispatches queued on the GPU, so it has work while the
+/// finished ones are read back and the next ones are written.
+///
+/// Dispatches are read back in the order they were submitted. Once one finds
+/// a block, it and every dispatch after it stop searching, since their header
+/// is stale, so the next header needs a new pipeline.
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+	hasher: &'a Hasher,
+	slots: MutexGuard<'a, Vec<Slot>>,
+	in_flight: VecDeque<InFlight>,
+	/// The slot the next dispatch is written to
+	next: usize,
+}
+
+impl<'a> Pipeline<'a> {
+	pub(super) fn new(hasher: &'a Hasher, slots: MutexGuard<'a, Vec<Slot>>) -> Self {
+		// the last pipeline's dispatches have all finished, so nothing else
+		// reads the flag
+		hasher.queue.write_buffer(&hasher.found_buffer, 0, &[0; 4]);
+
+		Self {
+			hasher,
+			slots,
+			in_flight: VecDeque::new(),
+			next: 0,
+		}
+	}
+
+	/// Whether every slot has a dispatch in flight, so one has to be read back
+	/// before the next is submitted.
+	#[must_use]
+	pub fn is_full(&self) -> bool {
+		self.in_flight.len() == self.slots.len()
+	}
+
+	/// How many dispatches haven't been read back.
+	#[must_use]
+	pub fn in_flight(&self) -> usize {
+		self.in_flight.len()
+	}
+
+	/// Queues a dispatch of `nonces` in every header of `job`.
+	///
+	/// # Panics
+	/// Panics if the pipeline is full, if `job` is empty, or if `nonces` in
+	/// every header are more than the hasher's dispatch size.
+	pub fn submit(&mut self, job: &Job, nonces: Range<u32>) {
+		self.dispatch(job, nonces, false);
+	}
+
+	pub(super) fn dispatch(&mut self, job: &Job, nonces: Range<u32>, timed: bool) {
+		let count = u64::from(nonces.end - nonces.start) * job.len() as u64;
+
+		assert!(!self.is_full(), "every slot has a dispatch in flight");
+		assert!(!job.is_empty(), "a job needs a header to search");
+		assert!(
+			count <= u64::from(self.hasher.config.dispatch_size),
+			"{count} nonces don't fit in a dispatch"
+		);
+
+		let slot = &self.slots[self.next];
+
+		slot.write(&self.hasher.queue, job, &nonces);
+
+		let command = self.hasher.create_command_buffer(slot, timed);
+		let submission = self.hasher.queue.submit(Some(command));
+		let (tx, rx) = oneshot::channel();
+
+		// the buffer is mapped once the dispatch finishes, without blocking here
+		slot.mappable_buffer
+			.slice(..)
+			.map_async(wgpu::MapMode::Read, move |res| {
+				tx.send(res).ok();
+			});
+
+		self.in_flight.push_back(InFlight {
+			slot: self.next,
+			submission,
+			mapped: rx,
+		});
+		self.next = (self.next + 1) % self.slots.len();
+	}
+
+	/// Reads back the oldest dispatch, waiting for it to finish, or returns
+	/// `None` if there are none in flight.
+	///
+	/// # Errors
+	/// Returns an error if the output buffer fails to map.
+	pub fn wait(&mut self) -> Result<Option<Output>, Error> {
+		let Some(oldest) = self.in_flight.pop_front() else {
+			return Ok(None);
+		};
+
+		self.hasher
+			.device
+			.poll(wgpu::Maintain::WaitForSubmissionIndex(
+				oldest.submission.clone(),
+			));
+
+		// the callback is only dropped without running if the device is lost
+		let result = oldest
+			.mapped
+			.try_recv()
+			.unwrap_or(Err(wgpu::BufferAsyncError));
+
+		self.read(&oldest, result).map(Some)
+	}
+
+	/// Reads back the oldest dispatch if it has finished, or returns `None`
+	/// without blocking.
+	///
+	/// # Errors
+	/// Returns an error if the output buffer fails to map.
+	pub fn try_wait(&mut self) -> Result<Option<Output>, Error> {
+		let Some(oldest) = self.in_flight.front() else {
+			return Ok(None);
+		};
+
+		self.hasher.device.poll(wgpu::Maintain::Poll);
+
+		let Ok(result) = oldest.mapped.try_recv() else {
+			return Ok(None);
+		};
+		let Some(oldest) = self.in_flight.pop_front() else {
+			return Ok(None);
+		};
+
+		self.read(&oldest, result).map(Some)
+	}
+
+	fn read(
+		&self,
+		dispatch: &InFlight,
+		mapped: Result<(), wgpu::BufferAsyncError>,
+	) -> Result<Output, Error> {
+		mapped.map_err(Error::BufferAsync)?;
+
+		Ok(self.slots[dispatch.slot].read())
+	}
+}
+
+impl Drop for Pipeline<'_> {
+	/// Waits for the dispatches still in flight, so their slots are unmapped
+	/// for the next pipeline.
+	fn drop(&mut self) {
+		while !self.in_flight.is_empty() {
+			self.wait().ok();
+		}
+	}
+}
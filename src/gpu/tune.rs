@@ -0,0 +1,208 @@
+use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Config, Error, Hasher};
+use crate::miner::format_rate;
+
+/// Benchmarks configs on an adapter, picking the fastest one whose dispatches
+/// stay under a latency.
+#[derive(Debug, Clone)]
+pub struct Tuner {
+	/// The longest a dispatch can take, since a new job waits for it to finish
+	pub max_latency: Duration,
+	/// How long the GPU searches before checking for a new job
+	pub batch_duration: Duration,
+	/// The workgroup sizes to try
+	pub workgroup_sizes: Vec<u32>,
+	/// The workgroup counts to try
+	pub workgroups: Vec<u32>,
+	/// The nonces each thread searches in a dispatch to try, in increasing
+	/// order
+	pub nonces_per_thread: Vec<u32>,
+	/// A JSON file that keeps the tuned configs by adapter, so they're only
+	/// benchmarked once
+	pub cache: Option<PathBuf>,
+}
+
+impl Default for Tuner {
+	fn default() -> Self {
+		Self {
+			max_latency: Duration::from_millis(100),
+			batch_duration: Duration::from_secs(1),
+			workgroup_sizes: vec![64, 128, 256, 512],
+			workgroups: vec![16, 64, 256, 1024],
+			nonces_per_thread: vec![16, 64, 256, 1024, 4096],
+			cache: None,
+		}
+	}
+}
+
+/// A tuned config with the hash rate it reached, which sizes its batches.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Tuned {
+	config: Config,
+	hash_rate: f64,
+}
+
+impl Tuner {
+	/// Tunes `hasher` for its adapter, using the cached config if there is
+	/// one.
+	///
+	/// # Errors
+	/// Returns an error if no config is supported, or if the hasher fails to
+	/// search while benchmarking.
+	pub fn tune(&self, hasher: &mut Hasher) -> Result<Config, Error> {
+		let key = cache_key(hasher.info());
+		let mut cache = self.load();
+		let tuned = match cache.get(&key) {
+			Some(tuned) if hasher.supports(tuned.config) => *tuned,
+			_ => {
+				let tuned = self.benchmark(hasher)?;
+
+				cache.insert(key, tuned);
+				self.store(&cache);
+				tuned
+			}
+		};
+		let config = Config {
+			batch_size: self.batch_size(&tuned),
+			..tuned.config
+		};
+
+		hasher.set_config(config);
+
+		tracing::info!(
+			name = hasher.info().name,
+			workgroup_size = config.workgroup_size,
+			workgroups = config.workgroups,
+			dispatch_size = config.dispatch_size,
+			batch_size = config.batch_size,
+			rate_pretty = format_rate(tuned.hash_rate),
+			"tuned gpu"
+		);
+
+		Ok(config)
+	}
+
+	/// Times every candidate config, returning the fastest one under the
+	/// latency, or the quickest one if none are.
+	fn benchmark(&self, hasher: &mut Hasher) -> Result<Tuned, Error> {
+		let mut fastest: Option<Tuned> = None;
+		let mut quickest: Option<(Tuned, Duration)> = None;
+
+		for &workgroup_size in &self.workgroup_sizes {
+			for &workgroups in &self.workgroups {
+				let Some(threads) = workgroup_size.checked_mul(workgroups) else {
+					continue;
+				};
+
+				for &per_thread in &self.nonces_per_thread {
+					let Some(dispatch_size) = threads.checked_mul(per_thread) else {
+						break;
+					};
+					let config = Config {
+						workgroup_size,
+						workgroups,
+						dispatch_size,
+						batch_size: dispatch_size,
+					};
+
+					if !hasher.supports(config) {
+						break;
+					}
+
+					hasher.set_config(config);
+					// the first dispatch can include compiling the pipeline
+					hasher.time_dispatch(dispatch_size)?;
+
+					let latency = hasher.time_dispatch(dispatch_size)?;
+					let tuned = Tuned {
+						config,
+						hash_rate: f64::from(dispatch_size)
+							/ latency.as_secs_f64().max(f64::EPSILON),
+					};
+
+					tracing::debug!(
+						workgroup_size,
+						workgroups,
+						dispatch_size,
+						?latency,
+						rate_pretty = format_rate(tuned.hash_rate),
+						"benchmarked gpu config"
+					);
+
+					if quickest.is_none_or(|(_, quickest)| latency < quickest) {
+						quickest = Some((tuned, latency));
+					}
+
+					// more nonces for each thread only take longer
+					if latency > self.max_latency {
+						break;
+					}
+
+					if fastest.is_none_or(|fastest| tuned.hash_rate > fastest.hash_rate) {
+						fastest = Some(tuned);
+					}
+				}
+			}
+		}
+
+		fastest
+			.or(quickest.map(|(tuned, _)| tuned))
+			.ok_or(Error::NoConfig)
+	}
+
+	/// How many nonces `tuned` searches in the batch duration, in whole
+	/// dispatches.
+	#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+	fn batch_size(&self, tuned: &Tuned) -> u32 {
+		let dispatch_size = tuned.config.dispatch_size;
+		let nonces = (tuned.hash_rate * self.batch_duration.as_secs_f64())
+			.clamp(f64::from(dispatch_size), f64::from(u32::MAX)) as u32;
+
+		nonces - nonces % dispatch_size
+	}
+
+	fn load(&self) -> HashMap<String, Tuned> {
+		let Some(path) = &self.cache else {
+			return HashMap::new();
+		};
+		let Ok(json) = fs::read(path) else {
+			return HashMap::new();
+		};
+
+		serde_json::from_slice(&json).unwrap_or_else(|e| {
+			tracing::warn!(path = %path.display(), error = %e, "ignoring invalid gpu tuning cache");
+			HashMap::new()
+		})
+	}
+
+	fn store(&self, cache: &HashMap<String, Tuned>) {
+		let Some(path) = &self.cache else {
+			return;
+		};
+		let result = path
+			.parent()
+			.filter(|parent| !parent.as_os_str().is_empty())
+			.map_or(Ok(()), fs::create_dir_all)
+			.and_then(|()| fs::write(path, serde_json::to_vec_pretty(cache)?));
+
+		if let Err(e) = result {
+			tracing::warn!(path = %path.display(), error = %e, "failed to write gpu tuning cache");
+		}
+	}
+}
+
+/// Identifies an adapter across runs, changing with its driver.
+fn cache_key(info: &wgpu::AdapterInfo) -> String {
+	format!(
+		"{} {:04x}:{:04x} {} {} {}",
+		info.backend.to_str(),
+		info.vendor,
+		info.device,
+		info.name,
+		info.driver,
+		info.driver_info
+	)
+}
@@ -0,0 +1,40 @@
+use serde::Deserialize;
+
+/// The result of `getblockchaininfo`.
+#[derive(Debug, Deserialize)]
+pub struct Blockchain {
+	pub chain: String,
+	pub blocks: u64,
+	pub headers: u64,
+	#[serde(rename = "verificationprogress")]
+	pub verification_progress: f64,
+	#[serde(rename = "initialblockdownload")]
+	pub initial_block_download: bool,
+}
+
+/// The result of `getmininginfo`.
+#[derive(Debug, Deserialize)]
+pub struct Mining {
+	pub blocks: u64,
+	pub difficulty: f64,
+	#[serde(rename = "networkhashps")]
+	pub network_hash_rate: f64,
+	#[serde(rename = "pooledtx")]
+	pub pooled_transactions: u64,
+}
+
+/// The result of `getnetworkinfo`.
+#[derive(Debug, Deserialize)]
+pub struct Network {
+	pub version: u32,
+	pub subversion: String,
+	pub connections: u32,
+}
+
+/// The result of `loadwallet` and `createwallet`.
+#[derive(Debug, Deserialize)]
+pub struct Wallet {
+	pub name: String,
+	#[serde(default)]
+	pub warnings: Vec<String>,
+}
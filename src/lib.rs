@@ -1,12 +1,22 @@
 #![feature(never_type)]
 #![warn(clippy::pedantic)]
 
+pub mod backend;
 pub mod block;
 pub mod error;
+pub mod failover;
+pub mod getwork;
 pub mod gpu;
+pub mod info;
 pub mod miner;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod rpc;
+pub mod solo;
+pub mod stratum;
+pub mod work;
+pub mod zmq;
 
 pub use error::Error;
-pub use miner::Miner;
+pub use miner::{Miner, Upstream};
 pub use rpc::Client;
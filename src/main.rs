@@ -1,30 +1,228 @@
 #![feature(never_type)]
 
-use clap::Parser;
-use miner::{rpc, Error};
+use std::{
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+	path::PathBuf,
+	sync::Arc,
+	time::Duration,
+};
+
+use clap::{error::ErrorKind, CommandFactory as _, Parser, Subcommand};
+use miner::{
+	backend::{simd::Implementation, Cpu},
+	failover::Failover,
+	gpu, rpc,
+	solo::Solo,
+	stratum, Error, Upstream,
+};
+
+/// The pool url that stands for solo mining on the node
+const SOLO: &str = "solo";
 
 #[derive(Parser)]
-#[command(version, about, author)]
+#[command(version, about, author, subcommand_negates_reqs = true)]
 struct Args {
+	#[command(subcommand)]
+	pub command: Option<Command>,
 	/// RPC username
-	#[arg(short, long, env = "RPC_USERNAME")]
-	pub username: String,
+	#[arg(short, long, env = "RPC_USERNAME", required_unless_present = "pool")]
+	pub username: Option<String>,
 	/// RPC password
-	#[arg(short, long, env = "RPC_PASSWORD")]
-	pub password: String,
+	#[arg(short, long, env = "RPC_PASSWORD", required_unless_present = "pool")]
+	pub password: Option<String>,
 	/// RPC address url
-	#[arg(short, long, env = "RPC_ADDRESS")]
-	pub address: String,
+	#[arg(short, long, env = "RPC_ADDRESS", required_unless_present = "pool")]
+	pub address: Option<String>,
+	/// RPC proxy url, e.g. socks5://127.0.0.1:9050
+	#[arg(long, env = "RPC_PROXY")]
+	pub proxy: Option<String>,
+	/// CA certificates to trust for HTTPS RPC, in PEM format
+	#[arg(long, env = "RPC_CA")]
+	pub ca: Option<PathBuf>,
+	/// Client certificate for HTTPS RPC, in PEM format
+	#[arg(long, env = "RPC_CERT", requires = "key")]
+	pub cert: Option<PathBuf>,
+	/// Client private key for HTTPS RPC, in PEM format
+	#[arg(long, env = "RPC_KEY", requires = "cert")]
+	pub key: Option<PathBuf>,
+	/// Name to verify the RPC server certificate against, instead of the address host
+	#[arg(long, env = "RPC_SERVER_NAME")]
+	pub server_name: Option<String>,
+	/// RPC wallet name
+	#[arg(short, long, env = "RPC_WALLET")]
+	pub wallet: Option<String>,
+	/// ZMQ block notification address, e.g. tcp://127.0.0.1:28332
+	#[arg(short, long, env = "ZMQ_ADDRESS")]
+	pub zmq: Option<String>,
+	/// Address to serve Stratum V1 jobs for external miners on, e.g. 0.0.0.0:3333, proxying the
+	/// pool if one is given
+	#[arg(long, env = "STRATUM_ADDRESS")]
+	pub stratum: Option<String>,
+	/// Starting share difficulty for Stratum V1 workers
+	#[arg(
+		long,
+		env = "STRATUM_DIFFICULTY",
+		value_name = "DIFF",
+		default_value_t = 1.0
+	)]
+	pub stratum_difficulty: f64,
+	/// Percent of rewards paid to the operator by workers mining to their own address
+	#[arg(
+		long,
+		env = "STRATUM_FEE",
+		value_name = "PERCENT",
+		default_value_t = 0.0
+	)]
+	pub stratum_fee: f64,
+	/// Shares per minute to adjust each Stratum V1 worker's difficulty for, or 0 to keep it fixed
+	#[arg(
+		long,
+		env = "STRATUM_SHARE_RATE",
+		value_name = "RATE",
+		default_value_t = 10.0
+	)]
+	pub stratum_share_rate: f64,
+	/// Address to serve Stratum V2 templates from the node on, e.g. 0.0.0.0:8442
+	#[arg(
+		long,
+		env = "TEMPLATE_PROVIDER_ADDRESS",
+		value_name = "ADDR",
+		conflicts_with = "pool"
+	)]
+	pub template_provider: Option<String>,
+	/// Hex secret key that signs the template provider's certificate, or a random one if unset
+	#[arg(
+		long,
+		env = "AUTHORITY_SECRET_KEY",
+		value_name = "HEX",
+		requires = "template_provider"
+	)]
+	pub authority_key: Option<bitcoin::secp256k1::SecretKey>,
+	/// Address to serve getwork for legacy miners on, e.g. 0.0.0.0:8337
+	#[arg(
+		long,
+		env = "GETWORK_ADDRESS",
+		value_name = "ADDR",
+		conflicts_with = "pool"
+	)]
+	pub getwork: Option<String>,
+	/// Stratum pool url, instead of solo mining, e.g. stratum+tcp://pool.example.com:3333, or
+	/// stratum2+tcp://pool.example.com:34254/<authority key> for Stratum V2. Repeat it for backup
+	/// pools in priority order, using solo for the node
+	#[arg(long, env = "POOL_URL", value_delimiter = ',')]
+	pub pool: Vec<String>,
+	/// Pool worker name, repeated for each pool in order, or the last one for the rest
+	#[arg(long, env = "POOL_WORKER", value_delimiter = ',')]
+	pub worker: Vec<String>,
+	/// Pool worker password, repeated like the worker name
+	#[arg(
+		long,
+		env = "POOL_PASSWORD",
+		value_name = "PASSWORD",
+		value_delimiter = ',',
+		default_value = "x"
+	)]
+	pub worker_password: Vec<String>,
+	/// Percent of the hash rate sent to the second available pool instead of the first
+	#[arg(long, env = "POOL_SPLIT", value_name = "PERCENT")]
+	pub split: Option<f64>,
+	/// Open an extended channel with a Stratum V2 pool, building the coinbase locally
+	#[arg(long, env = "POOL_EXTENDED")]
+	pub extended: bool,
+	/// Difficulty of the local shares counted to show the effective hash rate
+	#[arg(
+		long,
+		env = "SHARE_DIFFICULTY",
+		value_name = "DIFF",
+		default_value_t = 1.0
+	)]
+	pub share_difficulty: f64,
 	/// Use the GPU for mining
 	#[arg(short, long)]
 	pub gpu: bool,
+	/// GPU adapters to mine on, by index, name or backend (vulkan, metal, dx12, gl), instead of
+	/// the default one. See `miner gpu list`
+	#[arg(
+		long,
+		env = "GPU_DEVICE",
+		value_name = "DEVICE",
+		value_delimiter = ',',
+		requires = "gpu"
+	)]
+	pub device: Vec<gpu::Selector>,
+	/// Benchmark the GPU to pick how its work is split up, caching the result for each adapter
+	#[arg(long, env = "GPU_TUNE", requires = "gpu")]
+	pub tune: bool,
+	/// Longest a GPU dispatch may take while tuning, in milliseconds
+	#[arg(
+		long,
+		env = "GPU_TUNE_LATENCY",
+		value_name = "MS",
+		default_value_t = 100,
+		requires = "tune"
+	)]
+	pub tune_latency: u64,
+	/// How to hash on the CPU: avx512, sha-ni, avx2, sse2 or scalar, instead of
+	/// the fastest one supported
+	#[arg(long, env = "CPU_HASHER", value_name = "IMPL", conflicts_with = "gpu")]
+	pub cpu: Option<Implementation>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Inspect the GPU adapters
+	Gpu {
+		#[command(subcommand)]
+		command: GpuCommand,
+	},
+}
+
+#[derive(Subcommand)]
+enum GpuCommand {
+	/// List the adapters that can be mined on
+	List,
 }
 
-fn main() -> Result<!, Error> {
+fn main() -> Result<(), Error> {
 	let args = Args::parse();
 
 	tracing_subscriber::fmt().init();
 
+	match &args.command {
+		Some(Command::Gpu {
+			command: GpuCommand::List,
+		}) => {
+			list_adapters();
+			Ok(())
+		}
+		None => {
+			let Err(e) = mine(&args);
+
+			Err(e)
+		}
+	}
+}
+
+/// Prints every adapter with the index that selects it.
+fn list_adapters() {
+	let adapters = gpu::adapters();
+
+	if adapters.is_empty() {
+		println!("no adapters found");
+	}
+
+	for (index, info) in adapters.iter().enumerate() {
+		println!(
+			"{index}: {} [{}, {:?}]",
+			info.name,
+			info.backend.to_str(),
+			info.device_type
+		);
+	}
+}
+
+/// Mines until the upstream fails for good.
+fn mine(args: &Args) -> Result<!, Error> {
 	if !args.gpu {
 		rayon::ThreadPoolBuilder::new()
 			.num_threads(num_cpus::get())
@@ -32,10 +230,278 @@ fn main() -> Result<!, Error> {
 			.unwrap();
 	}
 
-	let rpc = rpc::Client::new(args.address, &args.username, &args.password);
+	let pools = args.pool.iter().filter(|url| *url != SOLO).count();
+	let upstream = match (&args.pool[..], &args.stratum) {
+		([], _) => Upstream::from(solo(args)?),
+		([url], Some(address)) if url != SOLO => proxy(args, address)?,
+		(_, Some(_)) if pools > 0 => {
+			return Err(stratum::Error::Protocol(
+				"only a single Stratum V1 pool can be proxied".to_string(),
+			)
+			.into());
+		}
+		([url], None) if url != SOLO => pool(args, url, 0),
+		(urls, _) => {
+			let mut upstreams = Vec::with_capacity(urls.len());
+			let mut index = 0;
+
+			for url in urls {
+				if url == SOLO {
+					upstreams.push(Upstream::from(solo(args)?));
+				} else {
+					upstreams.push(pool(args, url, index));
+					index += 1;
+				}
+			}
+
+			let mut failover = Failover::new(upstreams);
+
+			if let Some(percent) = args.split {
+				failover = failover.with_split(percent);
+			}
+
+			Upstream::from(failover)
+		}
+	};
+
+	let mut miner = miner::Miner::new(upstream, false);
+
+	if args.gpu {
+		let mut devices = gpu::Devices::select(&args.device)?;
+
+		if args.tune {
+			devices.tune(&gpu::Tuner {
+				max_latency: Duration::from_millis(args.tune_latency),
+				cache: tune_cache(),
+				..Default::default()
+			})?;
+		}
+
+		miner = miner.with_backend(devices);
+	}
+
+	if let Some(implementation) = args.cpu {
+		let Some(cpu) = Cpu::new(implementation) else {
+			Args::command()
+				.error(
+					ErrorKind::InvalidValue,
+					format!("{implementation} is not supported on this CPU"),
+				)
+				.exit()
+		};
+
+		miner = miner.with_backend(cpu);
+	}
+
+	miner
+		.with_share_target(miner::work::target_from_difficulty(args.share_difficulty))
+		.mine()
+}
+
+/// Where tuned GPU configs are kept, in the user's cache directory.
+fn tune_cache() -> Option<PathBuf> {
+	let cache = std::env::var_os("XDG_CACHE_HOME")
+		.map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+		.or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+
+	Some(cache.join("miner").join("gpu.json"))
+}
+
+/// Solo mines on the node, serving its templates to external miners if asked.
+fn solo(args: &Args) -> Result<Solo, Error> {
+	let options = rpc::Options {
+		tls: rpc::Tls {
+			ca: args.ca.clone(),
+			certificate: args.cert.clone(),
+			key: args.key.clone(),
+			server_name: args.server_name.clone(),
+		},
+		proxy: args.proxy.clone(),
+	};
+	// clap requires these unless a pool is given, but the pools can include solo
+	let (Some(address), Some(username), Some(password)) =
+		(&args.address, &args.username, &args.password)
+	else {
+		Args::command()
+			.error(
+				ErrorKind::MissingRequiredArgument,
+				"--address, --username and --password are required when solo mining",
+			)
+			.exit()
+	};
+	let mut rpc = rpc::Client::new(address.clone(), username, password, &options)?;
+
+	if let Some(wallet) = &args.wallet {
+		rpc = rpc.with_wallet(wallet);
+		rpc.ensure_wallet()?;
+	}
 
 	let wallet_address = rpc.get_new_address()?;
-	let miner = miner::Miner::new(rpc, &wallet_address, args.gpu);
+	let mut solo = Solo::new(rpc, &wallet_address);
+
+	if let Some(zmq) = &args.zmq {
+		solo = solo.with_zmq(zmq);
+	}
+
+	if let Some(address) = &args.stratum {
+		let (server, _) = serve_stratum(
+			address,
+			stratum::v1::Templates::new(solo.rpc.clone(), solo.wallet_address.clone())
+				.with_fee(args.stratum_fee),
+			args.stratum_difficulty,
+			args.stratum_share_rate,
+		)?;
+
+		std::thread::spawn(move || stratum::v1::Templates::poll(&server));
+	}
+
+	if let Some(address) = &args.template_provider {
+		let listener = TcpListener::bind(address).map_err(stratum::Error::from)?;
+		let secp = bitcoin::secp256k1::Secp256k1::new();
+		let authority = args.authority_key.map_or_else(
+			|| bitcoin::secp256k1::Keypair::new(&secp, &mut bitcoin::secp256k1::rand::thread_rng()),
+			|key| bitcoin::secp256k1::Keypair::from_secret_key(&secp, &key),
+		);
+		let provider = Arc::new(stratum::v2::TemplateProvider::new(
+			solo.rpc.clone(),
+			&authority,
+		));
+
+		tracing::info!(
+			address,
+			authority = %stratum::v2::AuthorityKey(authority.x_only_public_key().0),
+			"serving stratum v2 templates"
+		);
+
+		std::thread::spawn({
+			let provider = Arc::clone(&provider);
+
+			move || provider.listen(&listener)
+		});
+		std::thread::spawn(move || provider.poll());
+	}
+
+	if let Some(address) = &args.getwork {
+		let listener = TcpListener::bind(address).map_err(stratum::Error::from)?;
+		let getwork = Arc::new(miner::getwork::Getwork::new(
+			solo.rpc.clone(),
+			solo.wallet_address.clone(),
+		));
+
+		tracing::info!(address, "serving getwork");
+
+		std::thread::spawn({
+			let getwork = Arc::clone(&getwork);
+
+			move || getwork.listen(&listener)
+		});
+		std::thread::spawn(move || getwork.poll());
+	}
+
+	Ok(solo)
+}
+
+/// Builds the `index`th pool given, with its own worker name and password.
+fn pool(args: &Args, url: &str, index: usize) -> Upstream {
+	let Some(worker) = args.worker.get(index).or(args.worker.last()).cloned() else {
+		Args::command()
+			.error(
+				ErrorKind::MissingRequiredArgument,
+				"--worker is required when mining on a pool",
+			)
+			.exit()
+	};
+
+	if url.starts_with("stratum2+tcp://") {
+		return Upstream::from(stratum::v2::Pool {
+			url: url.to_string(),
+			worker,
+			channel: if args.extended {
+				stratum::v2::Channel::Extended
+			} else {
+				stratum::v2::Channel::Standard
+			},
+		});
+	}
+
+	// clap always gives at least the default password
+	let password = &args.worker_password;
+
+	Upstream::from(stratum::v1::Pool {
+		url: url.to_string(),
+		worker,
+		password: password
+			.get(index)
+			.unwrap_or(&password[password.len() - 1])
+			.clone(),
+	})
+}
+
+/// Serves the pool to external miners at `address`, mining it through the
+/// proxy so there is still a single pool session.
+fn proxy(args: &Args, address: &str) -> Result<Upstream, Error> {
+	let Upstream::Stratum(pool) = pool(args, &args.pool[0], 0) else {
+		return Err(
+			stratum::Error::Protocol("only Stratum V1 pools can be proxied".to_string()).into(),
+		);
+	};
+	let (server, mut local) = serve_stratum(
+		address,
+		stratum::v1::Proxy::new(pool.clone()),
+		args.stratum_difficulty,
+		args.stratum_share_rate,
+	)?;
+
+	std::thread::spawn(move || {
+		let Err(e) = stratum::v1::Proxy::run(&server);
+
+		tracing::error!(error = %e, "pool refused the proxy");
+		std::process::exit(1);
+	});
+
+	if local.ip().is_unspecified() {
+		local.set_ip(if local.is_ipv4() {
+			Ipv4Addr::LOCALHOST.into()
+		} else {
+			Ipv6Addr::LOCALHOST.into()
+		});
+	}
+
+	Ok(Upstream::from(stratum::v1::Pool {
+		url: format!("stratum+tcp://{local}"),
+		..pool
+	}))
+}
+
+/// Starts a Stratum V1 server with jobs from `source`, returning it along with
+/// the address it accepts workers on.
+fn serve_stratum<S: stratum::v1::Source>(
+	address: &str,
+	source: S,
+	difficulty: f64,
+	share_rate: f64,
+) -> Result<(Arc<stratum::v1::Server<S>>, SocketAddr), stratum::Error> {
+	let listener = TcpListener::bind(address)?;
+	let local = listener.local_addr()?;
+	let mut server = stratum::v1::Server::new(source, difficulty);
+
+	if share_rate > 0.0 {
+		server = server.with_vardiff(stratum::v1::Vardiff {
+			shares_per_minute: share_rate,
+			..Default::default()
+		});
+	}
+
+	let server = Arc::new(server);
+
+	tracing::info!(address, "serving stratum jobs");
+
+	std::thread::spawn({
+		let server = Arc::clone(&server);
+
+		move || server.listen(&listener)
+	});
 
-	miner.mine()
+	Ok((server, local))
 }
@@ -1,265 +1,306 @@
-use std::{ops::Range, str::FromStr as _, sync::mpsc};
+use std::{
+	fmt,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		mpsc,
+	},
+	time::Instant,
+};
+
+use crate::{
+	backend::{Backend, Cpu},
+	failover::Failover,
+	gpu,
+	solo::Solo,
+	stratum, work, Error,
+};
+
+/// Where jobs come from, and where their solutions go.
+#[derive(Debug)]
+pub enum Upstream {
+	/// Block templates from a node
+	Solo(Solo),
+	/// Shares for a Stratum V1 pool
+	Stratum(stratum::v1::Pool),
+	/// Shares for a Stratum V2 pool
+	StratumV2(stratum::v2::Pool),
+	/// Several upstreams in priority order
+	Failover(Failover),
+}
 
-use bitcoin::{consensus::Decodable, hashes::Hash as _};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+impl Upstream {
+	/// Sends jobs until the upstream fails for good.
+	///
+	/// # Errors
+	/// Returns an error if the node is not usable for mining, or if the pool
+	/// does not authorize the worker.
+	pub fn run(&self, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		match self {
+			Self::Solo(solo) => solo.run(jobs),
+			Self::Stratum(pool) => pool.run(jobs).map_err(Error::Stratum),
+			Self::StratumV2(pool) => pool.run(jobs).map_err(Error::Stratum),
+			Self::Failover(failover) => failover.run(jobs),
+		}
+	}
+}
+
+impl fmt::Display for Upstream {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Solo(solo) => f.write_str(&solo.rpc.url),
+			Self::Stratum(pool) => f.write_str(&pool.url),
+			Self::StratumV2(pool) => f.write_str(&pool.url),
+			Self::Failover(failover) => {
+				let names = failover.upstreams.iter().map(ToString::to_string);
+
+				write!(f, "[{}]", names.collect::<Vec<_>>().join(", "))
+			}
+		}
+	}
+}
+
+impl From<Solo> for Upstream {
+	fn from(value: Solo) -> Self {
+		Self::Solo(value)
+	}
+}
+
+impl From<stratum::v1::Pool> for Upstream {
+	fn from(value: stratum::v1::Pool) -> Self {
+		Self::Stratum(value)
+	}
+}
+
+impl From<stratum::v2::Pool> for Upstream {
+	fn from(value: stratum::v2::Pool) -> Self {
+		Self::StratumV2(value)
+	}
+}
 
-use crate::{block, gpu, rpc, Error};
+impl From<Failover> for Upstream {
+	fn from(value: Failover) -> Self {
+		Self::Failover(value)
+	}
+}
 
 #[derive(Debug)]
 pub struct Miner {
-	pub rpc: rpc::Client,
-	pub wallet_address: bitcoin::Address,
-	pub gpu: Option<gpu::Hasher>,
+	pub upstream: Upstream,
+	pub backend: Box<dyn Backend>,
+	/// Hashes that meet this target are counted as shares, which shows the
+	/// effective hash rate even when no solution is found
+	pub share_target: bitcoin::Target,
+	shares: AtomicU64,
 }
 
 impl Miner {
+	/// Creates a miner that searches on the GPU if `gpu` is set, or on every
+	/// CPU core otherwise.
+	///
 	/// # Panics
-	/// Panics if the wallet address is invalid.
-	pub fn new(rpc: rpc::Client, wallet_address: &str, gpu: bool) -> Self {
-		let wallet_address = bitcoin::Address::from_str(wallet_address)
-			.expect("invalid address")
-			.require_network(bitcoin::Network::Bitcoin)
-			.expect("invalid network");
-
-		tracing::info!(address = ?wallet_address, "using wallet address");
-
-		let gpu = if gpu {
-			Some(gpu::Hasher::new().expect("failed to create hasher"))
+	/// Panics if `gpu` is set and the GPU hasher fails to initialize.
+	pub fn new(upstream: impl Into<Upstream>, gpu: bool) -> Self {
+		let backend: Box<dyn Backend> = if gpu {
+			Box::new(gpu::Hasher::new().expect("failed to create hasher"))
 		} else {
-			None
+			Box::new(Cpu::default())
 		};
 
 		Self {
-			rpc,
-			wallet_address,
-			gpu,
+			upstream: upstream.into(),
+			backend,
+			share_target: bitcoin::Target::MAX,
+			shares: AtomicU64::new(0),
 		}
 	}
 
-	/// # Errors
-	/// Returns an error if the RPC client fails to get the block template or submit the block.
-	/// An error is also returned if the GPU hasher fails to process the block.
-	pub fn mine(&self) -> Result<!, Error> {
-		let (tx, rx) = mpsc::channel::<block::Template>();
-		let mut template = self.rpc.get_block_template(None)?;
-		let poll_id = std::mem::take(&mut template.longpoll_id);
-
-		std::thread::scope(|s| {
-			s.spawn(|| self.poll_new_block(&tx, poll_id));
-
-			if let Some(hasher) = &self.gpu {
-				loop {
-					let block = self.mine_block_gpu(hasher, &template, &rx)?;
-
-					self.rpc.submit_block(&block)?;
-
-					template = self.rpc.get_block_template(None)?;
-				}
-			} else {
-				loop {
-					let block = self.mine_block(&template, &rx);
+	/// Searches with `backend` instead.
+	#[must_use]
+	pub fn with_backend(mut self, backend: impl Backend + 'static) -> Self {
+		self.backend = Box::new(backend);
+		self
+	}
 
-					self.rpc.submit_block(&block)?;
+	/// Counts hashes that meet `target` as shares, instead of difficulty 1.
+	#[must_use]
+	pub fn with_share_target(mut self, target: bitcoin::Target) -> Self {
+		self.share_target = target;
+		self
+	}
 
-					template = self.rpc.get_block_template(None)?;
-				}
-			}
-		})
+	/// The number of hashes that have met the share target.
+	#[must_use]
+	pub fn shares(&self) -> u64 {
+		self.shares.load(Ordering::Relaxed)
 	}
 
+	/// Mines jobs from the upstream, submitting every hash that meets a job's target.
+	///
 	/// # Errors
-	/// Returns an error if the GPU hasher fails to process the block.
-	pub fn mine_block_gpu(
-		&self,
-		gpu: &gpu::Hasher,
-		template: &block::Template,
-		new: &mpsc::Receiver<block::Template>,
-	) -> Result<bitcoin::Block, Error> {
-		let (mut target, _, mut block) = self.process_template(template);
-		let mut encoded_header = encode_block_header(&block.header, 0);
-
-		let output_block = loop {
-			let start = std::time::Instant::now();
-			let output_block = gpu.process(encoded_header, target.to_byte_array())?;
-
-			if tracing::enabled!(tracing::Level::INFO) {
-				// we search through 2^32 nonces in each `process` call
-				let hashes = u32::MAX - u32::MIN;
-				let elapsed = start.elapsed();
-
-				tracing::info!(
-					rate = f64::from(hashes) / elapsed.as_secs_f64(),
-					rate_pretty = format_hash_rate(hashes, elapsed),
-					"hash rate"
-				);
-			}
+	/// Returns an error if the upstream fails for good, or if the backend
+	/// fails to search a header.
+	pub fn mine(&self) -> Result<!, Error> {
+		let (tx, rx) = mpsc::channel::<work::Job>();
 
-			// if it's not all zeros, we found one!
-			if output_block != [0; 80] {
-				break output_block;
-			}
+		std::thread::scope(|s| {
+			let upstream = s.spawn(move || self.upstream.run(&tx));
 
-			let message = new.try_recv();
+			self.search(&rx)?;
 
-			// if there's a new block to mine, switch to it
-			if let Ok(template) = message {
-				(target, _, block) = self.process_template(&template);
-				encoded_header = encode_block_header(&block.header, 0);
-			} else {
-				// otherwise, increment timestamp and try again
-				block.header.time += 1;
-				encoded_header[76..80].copy_from_slice(&(block.header.time + 1).to_le_bytes());
+			// the backend only stops once the upstream has returned and dropped its sender
+			match upstream.join() {
+				Ok(result) => result,
+				Err(panic) => std::panic::resume_unwind(panic),
 			}
-		};
-
-		Ok(bitcoin::Block::consensus_decode(&mut &output_block[..])?)
+		})
 	}
 
-	pub fn mine_block(
-		&self,
-		template: &block::Template,
-		new: &mpsc::Receiver<block::Template>,
-	) -> bitcoin::Block {
-		let (mut target, mut nonce_range, mut block) = self.process_template(template);
-		let mut encoded_header = encode_block_header(&block.header, nonce_range.start);
+	/// Searches jobs with the backend until the upstream stops.
+	fn search(&self, jobs: &mpsc::Receiver<work::Job>) -> Result<(), Error> {
+		let Ok(mut job) = jobs.recv() else {
+			return Ok(());
+		};
+		let mut nonces = job.nonce_range.clone();
+		let started = Instant::now();
 
 		loop {
-			let start = std::time::Instant::now();
-
-			let nonce = nonce_range.clone().into_par_iter().find_any(|&nonce| {
-				let mut encoded_header = encoded_header;
-				encoded_header[68..72].copy_from_slice(&nonce.to_le_bytes());
-
-				let hash = bitcoin::BlockHash::hash(&encoded_header);
-
-				hash < target
-			});
-
-			if tracing::enabled!(tracing::Level::INFO) {
-				let hashes = nonce_range.end - nonce_range.start;
-				let elapsed = start.elapsed();
-
-				tracing::info!(
-					rate = f64::from(hashes) / elapsed.as_secs_f64(),
-					rate_pretty = format_hash_rate(hashes, elapsed),
-					"hash rate"
-				);
+			if nonces.is_empty() {
+				job.roll();
+				nonces = job.nonce_range.clone();
 			}
 
-			if let Some(nonce) = nonce {
-				block.header.nonce = nonce;
-
-				tracing::info!(hash = ?block.block_hash(), "found block hash");
-
-				return block;
+			let batch = nonces.start
+				..nonces
+					.end
+					.min(nonces.start.saturating_add(self.backend.batch_size()));
+			let start = Instant::now();
+			let hits = self.backend.search(
+				job.encode_header(),
+				batch.clone(),
+				job.target,
+				self.share_target,
+			)?;
+
+			self.shares.fetch_add(hits.shares, Ordering::Relaxed);
+			self.log_hash_rate(hits.hashes, start, started);
+
+			// the backend may stop at a hit, so the rest of the batch is
+			// searched next
+			nonces.start = hits.blocks().last().map_or(batch.end, |nonce| nonce + 1);
+
+			for nonce in hits.blocks() {
+				let mut header = job.header;
+				header.nonce = nonce;
+
+				submit(&job, header);
+
+				if job.is_block() {
+					// the template is stale now, so wait for the next one
+					let Ok(next) = jobs.recv() else {
+						return Ok(());
+					};
+
+					job = next;
+					nonces = job.nonce_range.clone();
+					break;
+				}
 			}
 
-			// if we didn't find a valid nonce, increase the timestamp
-			block.header.time += 1;
-			encoded_header[76..80].copy_from_slice(&block.header.time.to_le_bytes());
-
-			let message = new.try_recv();
-
-			if let Ok(template) = message {
-				(target, nonce_range, block) = self.process_template(&template);
+			match newest(jobs) {
+				Ok(Some(next)) => {
+					job = next;
+					nonces = job.nonce_range.clone();
+				}
+				Ok(None) => {}
+				Err(mpsc::RecvError) => return Ok(()),
 			}
 		}
 	}
 
-	fn poll_new_block(&self, template_tx: &mpsc::Sender<block::Template>, mut poll_id: String) {
-		loop {
-			let template = self.rpc.get_block_template(Some(&poll_id));
-
-			if let Ok(mut template) = template {
-				poll_id = std::mem::take(&mut template.longpoll_id);
-
-				let _ = template_tx.send(template);
-			}
+	/// Logs the rate of the last `hashes`, along with the effective hash rate
+	/// from the shares found since mining `started`.
+	fn log_hash_rate(&self, hashes: u64, start: Instant, started: Instant) {
+		if !tracing::enabled!(tracing::Level::INFO) {
+			return;
 		}
-	}
-
-	fn process_template(
-		&self,
-		template: &block::Template,
-	) -> (bitcoin::BlockHash, Range<u32>, bitcoin::Block) {
-		let target = bitcoin::BlockHash::from_byte_array(template.target);
-		let nonce_range = template.nonce_range.clone();
-		let block = self.create_block(template);
 
-		(target, nonce_range, block)
+		let elapsed = start.elapsed();
+		let shares = self.shares();
+		// each share takes `difficulty * 2^32` hashes on average
+		#[allow(clippy::cast_precision_loss)]
+		let (rate, effective) = (
+			hashes as f64 / elapsed.as_secs_f64(),
+			shares as f64 * self.share_target.difficulty_float() * 2f64.powi(32)
+				/ started.elapsed().as_secs_f64(),
+		);
+
+		tracing::info!(
+			rate,
+			rate_pretty = format_rate(rate),
+			shares,
+			effective_rate_pretty = format_rate(effective),
+			"hash rate"
+		);
 	}
+}
 
-	fn create_block(&self, template: &block::Template) -> bitcoin::Block {
-		let script_pubkey = self.wallet_address.script_pubkey();
-
-		// Creates the coinbase transaction
-		let transaction = bitcoin::Transaction {
-			version: bitcoin::transaction::Version::ONE,
-			lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-			input: vec![bitcoin::TxIn {
-				previous_output: bitcoin::OutPoint::null(),
-				script_sig: bitcoin::script::Builder::new().push_int(0).into_script(),
-				sequence: bitcoin::Sequence::MAX,
-				witness: bitcoin::Witness::new(),
-			}],
-			output: vec![bitcoin::TxOut {
-				value: bitcoin::Amount::from_sat(template.coinbase_value),
-				script_pubkey,
-			}],
-		};
-
-		bitcoin::Block {
-			header: bitcoin::block::Header {
-				version: bitcoin::block::Version::from_consensus(template.version),
-				prev_blockhash: bitcoin::BlockHash::from_byte_array(template.previous_block),
-				merkle_root: bitcoin::TxMerkleNode::from_byte_array(template.transactions[0].hash),
-				time: template.current_time,
-				bits: bitcoin::CompactTarget::from_consensus(u32::from_le_bytes(template.bits)),
-				nonce: template.nonce_range.start,
-			},
-			txdata: vec![transaction],
+/// The most recent job in the queue, or an error once the upstream has
+/// stopped and there are no jobs left.
+fn newest(jobs: &mpsc::Receiver<work::Job>) -> Result<Option<work::Job>, mpsc::RecvError> {
+	let mut newest = None;
+
+	loop {
+		match jobs.try_recv() {
+			Ok(job) => newest = Some(job),
+			Err(mpsc::TryRecvError::Empty) => return Ok(newest),
+			Err(mpsc::TryRecvError::Disconnected) => {
+				return newest.map(Some).ok_or(mpsc::RecvError);
+			}
 		}
 	}
 }
 
-fn format_hash_rate(hashes: u32, elapsed: std::time::Duration) -> String {
-	let hashes = f64::from(hashes);
-	let elapsed = elapsed.as_secs_f64();
-
-	let mut rate = hashes / elapsed;
-
-	// if the rate is less than 1000, we can just return it
-	// otherwise, format with the higher ones
-	if rate < 1_000.0 {
-		return format!("{rate:.2} H/s");
+/// Sends a solution to wherever its job came from, logging instead of failing
+/// since it may have been made stale in the meantime.
+fn submit(job: &work::Job, header: bitcoin::block::Header) {
+	match &job.kind {
+		work::Kind::Block { rpc, transactions } => {
+			let block = bitcoin::Block {
+				header,
+				txdata: transactions.to_vec(),
+			};
+
+			tracing::info!(hash = ?block.block_hash(), "found block hash");
+
+			match rpc.submit_block(&block) {
+				Ok(()) => tracing::info!(hash = ?block.block_hash(), "block accepted"),
+				Err(e) => tracing::error!(hash = ?block.block_hash(), error = %e, "block rejected"),
+			}
+		}
+		work::Kind::Stratum(work) => {
+			if let Err(e) = work.submit(&header) {
+				tracing::warn!(error = %e, "failed to submit share");
+			}
+		}
+		work::Kind::StratumV2(work) => {
+			if let Err(e) = work.submit(&header) {
+				tracing::warn!(error = %e, "failed to submit share");
+			}
+		}
 	}
+}
 
-	rate /= 1_000.0;
+pub(crate) fn format_rate(mut rate: f64) -> String {
+	const UNITS: [&str; 7] = ["H/s", "KH/s", "MH/s", "GH/s", "TH/s", "PH/s", "EH/s"];
 
-	if rate < 1_000.0 {
-		return format!("{rate:.2} KH/s");
-	}
-
-	rate /= 1_000.0;
+	// scale the rate down until it fits in the current unit
+	for unit in &UNITS[..UNITS.len() - 1] {
+		if rate < 1_000.0 {
+			return format!("{rate:.2} {unit}");
+		}
 
-	if rate < 1_000.0 {
-		return format!("{rate:.2} MH/s");
+		rate /= 1_000.0;
 	}
 
-	rate /= 1_000.0;
-
-	format!("{rate:.2} GH/s")
-}
-
-fn encode_block_header(header: &bitcoin::block::Header, nonce: u32) -> [u8; 80] {
-	let mut data = Vec::with_capacity(4 + 32 + 32 + 4 + 4 + 4);
-
-	data.extend_from_slice(&header.version.to_consensus().to_le_bytes());
-	data.extend_from_slice(&header.prev_blockhash.to_byte_array());
-	data.extend_from_slice(&header.merkle_root.to_byte_array());
-	data.extend_from_slice(&header.time.to_le_bytes());
-	data.extend_from_slice(&header.bits.to_consensus().to_le_bytes());
-	data.extend_from_slice(&nonce.to_le_bytes());
-
-	data.try_into().expect("invalid block header")
+	format!("{rate:.2} {}", UNITS[UNITS.len() - 1])
 }
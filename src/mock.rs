@@ -0,0 +1,642 @@
+//! An in-process stand-in for bitcoind's JSON-RPC server, for testing the
+//! RPC client and the full mining loop without a node.
+//!
+//! The server serves scripted `getblocktemplate` responses (including longpoll),
+//! records `submitblock` calls, and can simulate errors and auth failures. It
+//! can also be served over TLS, and reached through the SOCKS5 and HTTP proxy
+//! stand-ins in [`Proxy`].
+
+use std::{
+	collections::{HashMap, VecDeque},
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Condvar, Mutex, MutexGuard,
+	},
+	time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bitcoin::{consensus::Decodable as _, hashes::Hash as _};
+use serde_json::{json, Value};
+
+use crate::rpc;
+
+/// How long a longpoll request waits for a new template before returning the current one
+const LONGPOLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The easiest target possible, where about half of all hashes are valid
+pub const EASY_BITS: u32 = 0x207f_ffff;
+
+#[derive(Debug)]
+pub struct Server {
+	address: SocketAddr,
+	state: Arc<State>,
+	tls: bool,
+}
+
+#[derive(Debug)]
+struct State {
+	authorization: String,
+	inner: Mutex<Inner>,
+	changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+	template: Option<Value>,
+	longpoll: u64,
+	results: HashMap<String, Value>,
+	errors: HashMap<String, VecDeque<rpc::Error>>,
+	submitted: Vec<bitcoin::Block>,
+}
+
+impl Server {
+	/// Starts a server on a random local port that accepts the given credentials.
+	///
+	/// # Panics
+	/// Panics if the listener cannot be bound.
+	#[must_use]
+	pub fn start(username: &str, password: &str) -> Self {
+		Self::listen(username, password, None)
+	}
+
+	/// Starts a server like [`Server::start`] that only accepts HTTPS connections.
+	///
+	/// # Panics
+	/// Panics if the listener cannot be bound.
+	#[must_use]
+	pub fn start_tls(username: &str, password: &str, config: Arc<rustls::ServerConfig>) -> Self {
+		Self::listen(username, password, Some(config))
+	}
+
+	fn listen(username: &str, password: &str, tls: Option<Arc<rustls::ServerConfig>>) -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock server");
+		let address = listener.local_addr().expect("failed to get local address");
+		let state = Arc::new(State {
+			authorization: format!(
+				"Basic {}",
+				STANDARD.encode(format!("{username}:{password}"))
+			),
+			inner: Mutex::new(Inner {
+				results: default_results(),
+				..Inner::default()
+			}),
+			changed: Condvar::new(),
+		});
+
+		let accept_state = Arc::clone(&state);
+		let tls_scheme = tls.is_some();
+
+		std::thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let state = Arc::clone(&accept_state);
+				let tls = tls.clone();
+
+				std::thread::spawn(move || {
+					let _ = match tls {
+						Some(config) => rustls::ServerConnection::new(config)
+							.map_err(io::Error::other)
+							.and_then(|tls| state.serve(rustls::StreamOwned::new(tls, stream))),
+						None => state.serve(stream),
+					};
+				});
+			}
+		});
+
+		Self {
+			address,
+			state,
+			tls: tls_scheme,
+		}
+	}
+
+	#[must_use]
+	pub fn url(&self) -> String {
+		let scheme = if self.tls { "https" } else { "http" };
+
+		format!("{scheme}://{}", self.address)
+	}
+
+	#[must_use]
+	pub fn address(&self) -> SocketAddr {
+		self.address
+	}
+
+	/// Creates a client for this server with the given credentials.
+	///
+	/// # Panics
+	/// Panics if the client cannot be created.
+	pub fn client(&self, username: &str, password: &str) -> rpc::Client {
+		rpc::Client::new(self.url(), username, password, &rpc::Options::default())
+			.expect("failed to create client")
+	}
+
+	/// Replaces the current block template, waking up any longpoll requests.
+	pub fn push_template(&self, template: Value) {
+		let mut inner = self.state.lock();
+
+		inner.template = Some(template);
+		inner.longpoll += 1;
+
+		self.state.changed.notify_all();
+	}
+
+	/// Replaces the current block template without waking longpoll requests,
+	/// which keep waiting as long as the previous block is the same.
+	pub fn set_template(&self, template: Value) {
+		self.state.lock().template = Some(template);
+	}
+
+	/// Sets the result returned for every call to `method`.
+	pub fn set_result(&self, method: &str, result: Value) {
+		self.state.lock().results.insert(method.to_string(), result);
+	}
+
+	/// Makes the next call to `method` fail with the given error.
+	pub fn fail(&self, method: &str, code: i32, message: &str) {
+		self.state
+			.lock()
+			.errors
+			.entry(method.to_string())
+			.or_default()
+			.push_back(rpc::Error {
+				code,
+				message: message.to_string(),
+			});
+	}
+
+	/// The blocks submitted so far.
+	#[must_use]
+	pub fn submitted(&self) -> Vec<bitcoin::Block> {
+		self.state.lock().submitted.clone()
+	}
+
+	/// Waits until at least `count` blocks have been submitted, and returns them.
+	#[must_use]
+	pub fn wait_for_blocks(&self, count: usize, timeout: Duration) -> Option<Vec<bitcoin::Block>> {
+		let deadline = Instant::now() + timeout;
+		let mut inner = self.state.lock();
+
+		while inner.submitted.len() < count {
+			let remaining = deadline.checked_duration_since(Instant::now())?;
+
+			inner = self
+				.state
+				.changed
+				.wait_timeout(inner, remaining)
+				.unwrap_or_else(std::sync::PoisonError::into_inner)
+				.0;
+		}
+
+		Some(inner.submitted.clone())
+	}
+}
+
+impl State {
+	fn lock(&self) -> MutexGuard<'_, Inner> {
+		self.inner
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+
+	/// Serves HTTP/1.1 requests on a connection until it is closed.
+	fn serve(&self, stream: impl Read + Write) -> io::Result<()> {
+		let mut reader = BufReader::new(stream);
+
+		loop {
+			let mut line = String::new();
+
+			if reader.read_line(&mut line)? == 0 {
+				return Ok(());
+			}
+
+			let mut length = 0;
+			let mut authorization = None;
+
+			loop {
+				line.clear();
+				reader.read_line(&mut line)?;
+
+				let header = line.trim_end();
+
+				if header.is_empty() {
+					break;
+				}
+
+				if let Some((name, value)) = header.split_once(':') {
+					match name.to_ascii_lowercase().as_str() {
+						"content-length" => length = value.trim().parse().unwrap_or(0),
+						"authorization" => authorization = Some(value.trim().to_string()),
+						_ => {}
+					}
+				}
+			}
+
+			let mut body = vec![0; length];
+
+			reader.read_exact(&mut body)?;
+
+			if authorization.as_deref() != Some(self.authorization.as_str()) {
+				write!(
+					reader.get_mut(),
+					"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"jsonrpc\"\r\nContent-Length: 0\r\n\r\n"
+				)?;
+
+				continue;
+			}
+
+			let (status, response) = match serde_json::from_slice::<Value>(&body) {
+				Ok(Value::Array(requests)) => (
+					"200 OK",
+					Value::Array(requests.iter().map(|r| self.handle(r).1).collect()),
+				),
+				Ok(request) => self.handle(&request),
+				Err(e) => (
+					"500 Internal Server Error",
+					error_response(&Value::Null, -32700, &e.to_string()),
+				),
+			};
+
+			let response = response.to_string();
+
+			write!(
+				reader.get_mut(),
+				"HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+				response.len()
+			)?;
+			reader.get_mut().flush()?;
+		}
+	}
+
+	fn handle(&self, request: &Value) -> (&'static str, Value) {
+		let id = &request["id"];
+		let method = request["method"].as_str().unwrap_or_default();
+		let params = &request["params"];
+
+		let mut inner = self.lock();
+
+		if let Some(error) = inner.errors.get_mut(method).and_then(VecDeque::pop_front) {
+			return (
+				"500 Internal Server Error",
+				error_response(id, error.code, &error.message),
+			);
+		}
+
+		let result = match method {
+			"getblocktemplate" => {
+				if let Some(poll_id) = params[0]["longpollid"].as_str() {
+					let deadline = Instant::now() + LONGPOLL_TIMEOUT;
+
+					// wait until the template changes, like bitcoind does
+					while longpoll_id(&inner) == poll_id {
+						let Some(remaining) = deadline.checked_duration_since(Instant::now())
+						else {
+							break;
+						};
+
+						inner = self
+							.changed
+							.wait_timeout(inner, remaining)
+							.unwrap_or_else(std::sync::PoisonError::into_inner)
+							.0;
+					}
+				}
+
+				let Some(mut template) = inner.template.clone() else {
+					return (
+						"500 Internal Server Error",
+						error_response(id, -9, "Bitcoin is not connected!"),
+					);
+				};
+
+				template["longpollid"] = Value::String(longpoll_id(&inner));
+				template
+			}
+			"submitblock" => {
+				let block = params[0]
+					.as_str()
+					.and_then(|data| hex::decode(data).ok())
+					.and_then(|data| bitcoin::Block::consensus_decode(&mut &data[..]).ok());
+
+				let Some(block) = block else {
+					return (
+						"500 Internal Server Error",
+						error_response(id, -22, "Block decode failed"),
+					);
+				};
+
+				inner.submitted.push(block);
+				self.changed.notify_all();
+
+				inner.results.get(method).cloned().unwrap_or(Value::Null)
+			}
+			_ => match inner.results.get(method) {
+				Some(result) => result.clone(),
+				None => {
+					return (
+						"404 Not Found",
+						error_response(id, -32601, "Method not found"),
+					)
+				}
+			},
+		};
+
+		(
+			"200 OK",
+			json!({ "result": result, "error": null, "id": id }),
+		)
+	}
+}
+
+/// A stand-in for a SOCKS5 or HTTP proxy that relays connections to their
+/// destination and counts them.
+#[derive(Debug)]
+pub struct Proxy {
+	url: String,
+	connections: Arc<AtomicUsize>,
+}
+
+impl Proxy {
+	/// Starts a SOCKS5 proxy without authentication on a random local port.
+	///
+	/// # Panics
+	/// Panics if the listener cannot be bound.
+	#[must_use]
+	pub fn socks5() -> Self {
+		Self::listen("socks5", |stream| {
+			let mut reader = BufReader::new(stream.try_clone()?);
+			let mut stream = stream;
+			let mut header = [0; 2];
+
+			// greeting: version, then the supported methods
+			reader.read_exact(&mut header)?;
+			reader.read_exact(&mut vec![0; usize::from(header[1])])?;
+			stream.write_all(&[5, 0])?;
+
+			// request: version, command, reserved, address type
+			let mut request = [0; 4];
+			reader.read_exact(&mut request)?;
+
+			let host = match request[3] {
+				1 => {
+					let mut ip = [0; 4];
+					reader.read_exact(&mut ip)?;
+					std::net::Ipv4Addr::from(ip).to_string()
+				}
+				3 => {
+					let mut length = [0; 1];
+					reader.read_exact(&mut length)?;
+
+					let mut name = vec![0; usize::from(length[0])];
+					reader.read_exact(&mut name)?;
+					String::from_utf8_lossy(&name).into_owned()
+				}
+				_ => return Err(io::Error::other("unsupported address type")),
+			};
+
+			let mut port = [0; 2];
+			reader.read_exact(&mut port)?;
+
+			let upstream = TcpStream::connect((host.as_str(), u16::from_be_bytes(port)))?;
+
+			// ureq misses the wakeup if a SOCKS handshake completes before it starts
+			// waiting on it, so answer with a bit of latency like a real proxy
+			std::thread::sleep(Duration::from_millis(50));
+			stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+			Ok((reader, upstream, Vec::new()))
+		})
+	}
+
+	/// Starts an HTTP proxy on a random local port, which supports both
+	/// `CONNECT` tunnels and plain requests with an absolute url.
+	///
+	/// # Panics
+	/// Panics if the listener cannot be bound.
+	#[must_use]
+	pub fn http() -> Self {
+		Self::listen("http", |stream| {
+			let mut reader = BufReader::new(stream.try_clone()?);
+			let mut stream = stream;
+			let mut request = Vec::new();
+			let mut line = String::new();
+
+			reader.read_line(&mut line)?;
+
+			let mut parts = line.split_whitespace();
+			let method = parts.next().unwrap_or_default().to_string();
+			let target = parts.next().unwrap_or_default().to_string();
+
+			request.extend_from_slice(line.as_bytes());
+
+			loop {
+				line.clear();
+				reader.read_line(&mut line)?;
+				request.extend_from_slice(line.as_bytes());
+
+				if line.trim_end().is_empty() {
+					break;
+				}
+			}
+
+			if method == "CONNECT" {
+				let upstream = TcpStream::connect(target.as_str())?;
+				stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+
+				return Ok((reader, upstream, Vec::new()));
+			}
+
+			// forward the request as-is, since the mock server ignores the path
+			let host = target
+				.strip_prefix("http://")
+				.and_then(|t| t.split('/').next())
+				.ok_or_else(|| io::Error::other("invalid proxy request"))?;
+
+			Ok((reader, TcpStream::connect(host)?, request))
+		})
+	}
+
+	#[must_use]
+	pub fn url(&self) -> String {
+		self.url.clone()
+	}
+
+	/// The number of connections relayed so far.
+	#[must_use]
+	pub fn connections(&self) -> usize {
+		self.connections.load(Ordering::SeqCst)
+	}
+
+	/// Accepts connections, runs the proxy `handshake` on each and then relays
+	/// bytes in both directions. The handshake returns the reader for the
+	/// client, the upstream connection and any bytes to forward first.
+	fn listen<H>(scheme: &str, handshake: H) -> Self
+	where
+		H: Fn(TcpStream) -> io::Result<(BufReader<TcpStream>, TcpStream, Vec<u8>)>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock proxy");
+		let url = format!(
+			"{scheme}://{}",
+			listener.local_addr().expect("failed to get local address")
+		);
+		let connections = Arc::new(AtomicUsize::new(0));
+		let counter = Arc::clone(&connections);
+		let handshake = Arc::new(handshake);
+
+		std::thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let handshake = Arc::clone(&handshake);
+
+				counter.fetch_add(1, Ordering::SeqCst);
+
+				std::thread::spawn(move || -> io::Result<()> {
+					let client = stream.try_clone()?;
+					let (mut reader, mut upstream, initial) = handshake(stream)?;
+					let mut downstream = upstream.try_clone()?;
+					let mut client = client;
+
+					upstream.write_all(&initial)?;
+
+					std::thread::spawn(move || io::copy(&mut downstream, &mut client));
+					io::copy(&mut reader, &mut upstream)?;
+
+					Ok(())
+				});
+			}
+		});
+
+		Self { url, connections }
+	}
+}
+
+/// Builds a `getblocktemplate` result at [`EASY_BITS`], so blocks are found
+/// almost immediately.
+#[must_use]
+pub fn template(height: u32, previous_block: bitcoin::BlockHash) -> Value {
+	let target = bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(EASY_BITS));
+
+	json!({
+		"version": 0x2000_0000,
+		"previousblockhash": previous_block.to_string(),
+		"transactions": [],
+		"longpollid": "",
+		"target": hex::encode(target.to_be_bytes()),
+		"bits": format!("{EASY_BITS:08x}"),
+		"curtime": 1_700_000_000,
+		"coinbasevalue": 312_500_000,
+		"noncerange": "00000000ffffffff",
+		"height": height,
+	})
+}
+
+/// Adds `count` transactions to a template, along with the witness commitment
+/// the node would send for them.
+///
+/// # Panics
+/// Panics if `template` isn't an object.
+pub fn add_transactions(template: &mut Value, count: u8) {
+	let transactions = (0..count)
+		.map(|i| bitcoin::Transaction {
+			version: bitcoin::transaction::Version::TWO,
+			lock_time: bitcoin::absolute::LockTime::ZERO,
+			input: vec![bitcoin::TxIn {
+				previous_output: bitcoin::OutPoint {
+					txid: bitcoin::Txid::from_byte_array([i + 1; 32]),
+					vout: 0,
+				},
+				..Default::default()
+			}],
+			output: vec![bitcoin::TxOut {
+				value: bitcoin::Amount::from_sat(1_000),
+				script_pubkey: bitcoin::ScriptBuf::new(),
+			}],
+		})
+		.collect::<Vec<_>>();
+
+	// the coinbase's wtxid is all zeros
+	let witness_root = bitcoin::merkle_tree::calculate_root(
+		std::iter::once(bitcoin::Wtxid::all_zeros())
+			.chain(transactions.iter().map(bitcoin::Transaction::wtxid))
+			.map(bitcoin::Wtxid::to_raw_hash),
+	)
+	.expect("the coinbase is always there");
+	let commitment = bitcoin::Block::compute_witness_commitment(
+		&bitcoin::hash_types::WitnessMerkleNode::from_raw_hash(witness_root),
+		&[0; 32],
+	);
+	let mut script = [0; 36];
+	script[..4].copy_from_slice(b"\xaa\x21\xa9\xed");
+	script[4..].copy_from_slice(commitment.as_byte_array());
+
+	template["transactions"] = transactions
+		.iter()
+		.map(|transaction| {
+			json!({
+				"data": bitcoin::consensus::encode::serialize_hex(transaction),
+				"txid": transaction.txid(),
+				"hash": transaction.wtxid(),
+				"fee": 0,
+				"weight": transaction.weight().to_wu(),
+			})
+		})
+		.collect();
+	template["default_witness_commitment"] = bitcoin::script::Builder::new()
+		.push_opcode(bitcoin::opcodes::all::OP_RETURN)
+		.push_slice(script)
+		.into_script()
+		.to_hex_string()
+		.into();
+}
+
+fn longpoll_id(inner: &Inner) -> String {
+	let previous = inner
+		.template
+		.as_ref()
+		.and_then(|t| t["previousblockhash"].as_str())
+		.unwrap_or_default();
+
+	format!("{previous}{}", inner.longpoll)
+}
+
+fn error_response(id: &Value, code: i32, message: &str) -> Value {
+	json!({ "result": null, "error": { "code": code, "message": message }, "id": id })
+}
+
+fn default_results() -> HashMap<String, Value> {
+	let zero = bitcoin::BlockHash::all_zeros();
+
+	[
+		(
+			"getblockchaininfo",
+			json!({
+				"chain": "main",
+				"blocks": 0,
+				"headers": 0,
+				"bestblockhash": zero.to_string(),
+				"verificationprogress": 1.0,
+				"initialblockdownload": false,
+			}),
+		),
+		(
+			"getnetworkinfo",
+			json!({ "version": 270_000, "subversion": "/Satoshi:27.0.0/", "connections": 8 }),
+		),
+		(
+			"getmininginfo",
+			json!({ "blocks": 0, "difficulty": 1.0, "networkhashps": 0.0, "pooledtx": 0 }),
+		),
+		(
+			"getnewaddress",
+			json!("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"),
+		),
+		("listwallets", json!([])),
+	]
+	.into_iter()
+	.map(|(method, result)| (method.to_string(), result))
+	.collect()
+}
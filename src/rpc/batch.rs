@@ -0,0 +1,152 @@
+use std::marker::PhantomData;
+
+use serde::de;
+use tracing::instrument;
+
+use super::{Client, Error, Request, Response};
+
+/// A set of requests that are sent to the node in a single HTTP POST.
+///
+/// Each call to [`Batch::push`] returns a typed [`Handle`] that is later used
+/// to take the matching result out of the [`Responses`].
+#[derive(Debug)]
+#[must_use]
+pub struct Batch<'c> {
+	client: &'c Client,
+	requests: Vec<serde_json::Value>,
+	/// Whether any of the requests is a wallet RPC
+	wallet: bool,
+}
+
+/// A typed reference to a request in a [`Batch`].
+#[derive(Debug)]
+pub struct Handle<T> {
+	index: usize,
+	_result: PhantomData<fn() -> T>,
+}
+
+/// The results of a [`Batch`], in the same order as the requests were pushed.
+#[derive(Debug)]
+pub struct Responses {
+	items: Vec<Option<Response<serde_json::Value>>>,
+}
+
+impl<'c> Batch<'c> {
+	pub(super) fn new(client: &'c Client) -> Self {
+		Self {
+			client,
+			requests: Vec::new(),
+			wallet: false,
+		}
+	}
+
+	/// Adds a request to the batch.
+	///
+	/// # Panics
+	/// Panics if the request cannot be serialized.
+	pub fn push<T>(&mut self, request: &Request<'_>) -> Handle<T>
+	where
+		T: de::DeserializeOwned,
+	{
+		let index = self.requests.len();
+
+		// the wallet endpoint serves every other RPC too, so a batch with any
+		// wallet RPC goes there
+		self.wallet |= request.wallet;
+
+		let mut request = serde_json::to_value(request).expect("invalid request");
+
+		// the id is used to match responses, since bitcoind can reply in any order
+		request["id"] = serde_json::Value::String(index.to_string());
+		self.requests.push(request);
+
+		Handle {
+			index,
+			_result: PhantomData,
+		}
+	}
+
+	/// Sends every request in the batch at once.
+	///
+	/// # Errors
+	/// Returns an error if the HTTP request fails, or if the node rejects the
+	/// whole batch instead of answering each request. Errors for individual
+	/// requests are returned by [`Responses::take`].
+	#[instrument(name = "rpc_batch", skip(self), fields(size = self.requests.len()))]
+	pub fn send(self) -> Result<Responses, Error> {
+		let response = match self
+			.client
+			.http
+			.post(&self.client.endpoint(self.wallet))
+			.send_json(&self.requests)
+		{
+			Ok(response) => response,
+			// like single requests, the body of a failed batch still holds the error
+			Err(ureq::Error::Status(status, response)) if status != 401 => response,
+			Err(e) => return Err(e.into()),
+		};
+
+		let body = match response.into_json::<serde_json::Value>()? {
+			serde_json::Value::Array(body) => body,
+			// bitcoind replies with a single error if it can't parse the batch
+			body => {
+				return Err(serde_json::from_value::<Response<serde_json::Value>>(body)
+					.ok()
+					.and_then(|response| response.error)
+					.unwrap_or_else(|| Error {
+						code: 0,
+						message: "not a batch response".to_string(),
+					}));
+			}
+		};
+		let mut items = Vec::with_capacity(self.requests.len());
+
+		items.resize_with(self.requests.len(), || None);
+
+		for response in body {
+			let response = serde_json::from_value::<Response<serde_json::Value>>(response)
+				.map_err(|e| Error {
+					code: 0,
+					message: e.to_string(),
+				})?;
+			let item = response
+				.id
+				.as_str()
+				.and_then(|id| id.parse::<usize>().ok())
+				.and_then(|i| items.get_mut(i))
+				.filter(|item| item.is_none());
+
+			if let Some(item) = item {
+				*item = Some(response);
+			} else {
+				tracing::warn!(id = %response.id, "unmatched batch response");
+			}
+		}
+
+		tracing::info!("batch complete");
+
+		Ok(Responses { items })
+	}
+}
+
+impl Responses {
+	/// Takes the result of a request out of the batch.
+	///
+	/// # Errors
+	/// Returns the error reported by the node for this request, or an error if
+	/// the result was missing or could not be deserialized.
+	// the handle is consumed so the same result cannot be taken twice
+	#[allow(clippy::needless_pass_by_value)]
+	pub fn take<T>(&mut self, handle: Handle<T>) -> Result<T, Error>
+	where
+		T: de::DeserializeOwned,
+	{
+		match self.items.get_mut(handle.index).and_then(Option::take) {
+			Some(response) => response.into_result(),
+			None => Err(Error {
+				code: 0,
+				message: "no response".to_string(),
+			}),
+		}
+	}
+}
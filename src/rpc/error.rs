@@ -8,6 +8,13 @@ pub struct Error {
 	pub message: String,
 }
 
+impl Error {
+	/// `RPC_WALLET_NOT_FOUND`, returned when loading a wallet that does not exist
+	pub const WALLET_NOT_FOUND: i32 = -18;
+	/// `RPC_WALLET_ALREADY_LOADED`, returned when loading a wallet twice
+	pub const WALLET_ALREADY_LOADED: i32 = -35;
+}
+
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "{} (code {})", self.message, self.code)
@@ -25,6 +32,15 @@ impl From<io::Error> for Error {
 	}
 }
 
+impl From<rustls_pemfile::Error> for Error {
+	fn from(value: rustls_pemfile::Error) -> Self {
+		Self {
+			code: 0,
+			message: format!("{value:?}"),
+		}
+	}
+}
+
 impl From<ureq::Error> for Error {
 	fn from(value: ureq::Error) -> Self {
 		Self {
@@ -1,19 +1,36 @@
 mod auth;
+mod batch;
 mod error;
+mod tls;
 
+pub use batch::{Batch, Handle, Responses};
 pub use error::Error;
+pub use tls::Tls;
+
+use std::{borrow::Cow, sync::Arc};
 
 use bitcoin::consensus::Encodable as _;
 use serde::{de, Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::block;
+use crate::{block, info};
 
 #[derive(Debug, Clone)]
 #[must_use]
 pub struct Client {
 	pub http: ureq::Agent,
 	pub url: String,
+	/// The wallet used for wallet RPCs like `getnewaddress`
+	pub wallet: Option<String>,
+}
+
+/// Connection settings for the RPC client.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+	/// TLS settings, used when the url is `https://`
+	pub tls: Tls,
+	/// A `socks5://` or `http://` proxy to connect through
+	pub proxy: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -33,32 +50,153 @@ pub enum Param<'r> {
 }
 
 #[derive(Debug, Serialize)]
+#[must_use]
 pub struct Request<'r> {
 	pub jsonrpc: &'r str,
 	pub id: &'r str,
 	pub method: &'r str,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub params: Option<[Param<'r>; 1]>,
+	/// Whether this is a wallet RPC, which is sent to the wallet endpoint
+	#[serde(skip)]
+	pub wallet: bool,
+}
+
+impl<'r> Request<'r> {
+	pub fn new(method: &'r str, params: Option<[Param<'r>; 1]>) -> Self {
+		Self {
+			jsonrpc: "1.0",
+			id: env!("CARGO_PKG_NAME"),
+			method,
+			params,
+			wallet: false,
+		}
+	}
+
+	/// Creates a `getblocktemplate` request, optionally long-polling on `poll_id`.
+	pub fn block_template(poll_id: Option<&'r str>) -> Self {
+		Self::new(
+			"getblocktemplate",
+			Some([poll_id.map_or_else(
+				|| Param::Option {
+					rules: Some(&["segwit"]),
+					capabilities: Some(&["coinbase/append", "longpoll"]),
+				},
+				|id| Param::Longpoll { id },
+			)]),
+		)
+	}
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Response<T> {
 	pub result: Option<T>,
 	pub error: Option<Error>,
-	pub id: String,
+	/// The id of the request, which is `null` if the node could not parse it
+	pub id: serde_json::Value,
+}
+
+impl Response<serde_json::Value> {
+	/// Converts the response into a typed result.
+	///
+	/// # Errors
+	/// Returns the error reported by the node, or an error if the result could
+	/// not be deserialized.
+	pub fn into_result<T>(self) -> Result<T, Error>
+	where
+		T: de::DeserializeOwned,
+	{
+		if let Some(error) = self.error {
+			return Err(error);
+		}
+
+		// a `null` result is valid for methods like `submitblock`
+		serde_json::from_value(self.result.unwrap_or_default()).map_err(|e| Error {
+			code: 0,
+			message: e.to_string(),
+		})
+	}
 }
 
 impl Client {
-	pub fn new(url: String, username: &str, password: &str) -> Self {
-		let http = ureq::AgentBuilder::new()
+	/// # Errors
+	/// Returns an error if the TLS settings or the proxy url are invalid.
+	pub fn new(
+		url: String,
+		username: &str,
+		password: &str,
+		options: &Options,
+	) -> Result<Self, Error> {
+		let mut http = ureq::AgentBuilder::new()
 			.middleware(auth::Basic::new(username, password))
-			.build();
+			.tls_config(Arc::new(options.tls.client_config()?));
+
+		if let Some(proxy) = &options.proxy {
+			http = http.proxy(ureq::Proxy::new(proxy)?);
+		}
+
+		Ok(Self {
+			http: http.build(),
+			url,
+			wallet: None,
+		})
+	}
+
+	/// Sends wallet RPCs to the `/wallet/<name>` endpoint, which is required
+	/// when the node has more than one wallet loaded.
+	pub fn with_wallet(mut self, name: impl Into<String>) -> Self {
+		self.wallet = Some(name.into());
+		self
+	}
+
+	/// Makes sure the wallet set by [`Client::with_wallet`] is loaded,
+	/// loading or creating it if needed.
+	///
+	/// # Errors
+	/// Returns an error if the wallet cannot be listed, loaded or created.
+	pub fn ensure_wallet(&self) -> Result<(), Error> {
+		let Some(name) = &self.wallet else {
+			return Ok(());
+		};
+
+		if self.list_wallets()?.contains(name) {
+			return Ok(());
+		}
+
+		let wallet = match self.load_wallet(name) {
+			Ok(wallet) => wallet,
+			Err(e) if e.code == Error::WALLET_ALREADY_LOADED => return Ok(()),
+			Err(e) if e.code == Error::WALLET_NOT_FOUND => {
+				tracing::info!(wallet = %name, "creating wallet");
+
+				self.create_wallet(name)?
+			}
+			Err(e) => return Err(e),
+		};
+
+		for warning in &wallet.warnings {
+			tracing::warn!(wallet = %wallet.name, warning, "wallet warning");
+		}
+
+		tracing::info!(wallet = %wallet.name, "loaded wallet");
 
-		Self { http, url }
+		Ok(())
+	}
+
+	/// # Errors
+	/// Returns an error if the request fails.
+	pub fn load_wallet(&self, name: &str) -> Result<info::Wallet, Error> {
+		self.request(&Request::new("loadwallet", Some([Param::String(name)])))
+	}
+
+	/// # Errors
+	/// Returns an error if the request fails.
+	pub fn create_wallet(&self, name: &str) -> Result<info::Wallet, Error> {
+		self.request(&Request::new("createwallet", Some([Param::String(name)])))
 	}
 
 	/// # Errors
-	/// Returns an error if the block fails to encode.
+	/// Returns an error if the request fails or the node rejects the block.
 	///
 	/// # Panics
 	/// Panics if the block fails to encode.
@@ -67,29 +205,41 @@ impl Client {
 		let mut data = vec![];
 		block.consensus_encode(&mut data).unwrap();
 
-		self.request(&Request {
-			jsonrpc: "1.0",
-			id: env!("CARGO_PKG_NAME"),
-			method: "submitblock",
-			params: Some([Param::String(&hex::encode(data))]),
-		})
+		// a `null` result means the block was accepted, otherwise it's the reason
+		// it was rejected (e.g. "high-hash" or "duplicate")
+		let rejection: Option<String> = self.request(&Request::new(
+			"submitblock",
+			Some([Param::String(&hex::encode(data))]),
+		))?;
+
+		match rejection {
+			Some(message) => Err(Error { code: 0, message }),
+			None => Ok(()),
+		}
 	}
 
 	/// # Errors
 	/// Returns an error if the request fails.
 	pub fn get_block_template(&self, poll_id: Option<&str>) -> Result<block::Template, Error> {
-		self.request(&Request {
-			jsonrpc: "1.0",
-			id: env!("CARGO_PKG_NAME"),
-			method: "getblocktemplate",
-			params: Some([poll_id.map_or_else(
-				|| Param::Option {
-					rules: Some(&["segwit"]),
-					capabilities: Some(&["coinbase/append", "longpoll"]),
-				},
-				|id| Param::Longpoll { id },
-			)]),
-		})
+		self.request(&Request::block_template(poll_id))
+	}
+
+	/// Starts a batch of requests that are sent in a single round-trip.
+	pub fn batch(&self) -> Batch<'_> {
+		Batch::new(self)
+	}
+
+	/// The endpoint for a request. Wallet RPCs go to `/wallet/<name>` if a
+	/// wallet is set, and everything else goes to the node url.
+	fn endpoint(&self, wallet: bool) -> Cow<'_, str> {
+		match &self.wallet {
+			Some(name) if wallet => Cow::Owned(format!(
+				"{}/wallet/{}",
+				self.url.trim_end_matches('/'),
+				percent_encode(name)
+			)),
+			_ => Cow::Borrowed(&self.url),
+		}
 	}
 
 	#[instrument(name = "rpc", skip(self))]
@@ -97,52 +247,84 @@ impl Client {
 	where
 		T: de::DeserializeOwned,
 	{
-		let response = self.http.post(&self.url).send_json(request)?;
-
-		tracing::Span::current().record("status", &response.status().to_string());
-
-		let body = response.into_json::<Response<T>>()?;
-
-		let response = match body {
-			Response {
-				result: Some(result),
-				..
-			} => Ok(result),
-			Response {
-				error: Some(error), ..
-			} => Err(error),
-			_ => Err(Error {
-				code: 0,
-				message: "no response".to_string(),
-			}),
+		let response = match self
+			.http
+			.post(&self.endpoint(request.wallet))
+			.send_json(request)
+		{
+			Ok(response) => response,
+			// bitcoind replies to failed requests with an error status,
+			// but the body still holds the error code and message
+			Err(ureq::Error::Status(status, response)) if status != 401 => response,
+			Err(e) => return Err(e.into()),
 		};
 
+		tracing::Span::current().record("status", response.status().to_string());
+
+		let response = response
+			.into_json::<Response<serde_json::Value>>()?
+			.into_result();
+
 		tracing::info!("request complete");
 
 		response
 	}
 }
 
-/// Implements basic RPC methods that take in no parameters
+/// Implements basic RPC methods that take in no parameters, along with a
+/// [`Request`] constructor of the same name for batches
 macro_rules! impl_basic_rpc {
-	($($name:ident, $method:literal -> $result:path),*) => {
+	(wallet: $($name:ident, $method:literal -> $result:ty),*) => {
+		impl_basic_rpc!(@wallet true; $($name, $method -> $result),*);
+	};
+	($($name:ident, $method:literal -> $result:ty),*) => {
+		impl_basic_rpc!(@wallet false; $($name, $method -> $result),*);
+	};
+	(@wallet $wallet:literal; $($name:ident, $method:literal -> $result:ty),*) => {
 		$(
+			impl Request<'_> {
+				#[doc = concat!("Creates a `", $method, "` request.")]
+				pub fn $name() -> Self {
+					Self {
+						wallet: $wallet,
+						..Self::new($method, None)
+					}
+				}
+			}
+
 			impl $crate::rpc::Client {
 				/// # Errors
 				/// Returns an error if the request fails.
 				pub fn $name(&self) -> Result<$result, $crate::rpc::Error> {
-					self.request(&Request {
-						jsonrpc: "1.0",
-						id: env!("CARGO_PKG_NAME"),
-						method: $method,
-						params: None,
-					})
+					self.request(&Request::$name())
 				}
 			}
 		)*
 	};
 }
 
+/// Percent-encodes a url path segment, like a wallet name.
+fn percent_encode(segment: &str) -> String {
+	use std::fmt::Write as _;
+
+	segment.bytes().fold(String::new(), |mut encoded, byte| {
+		if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+			encoded.push(byte as char);
+		} else {
+			let _ = write!(encoded, "%{byte:02X}");
+		}
+
+		encoded
+	})
+}
+
+impl_basic_rpc! {
+	get_blockchain_info, "getblockchaininfo" -> info::Blockchain,
+	get_mining_info, "getmininginfo" -> info::Mining,
+	get_network_info, "getnetworkinfo" -> info::Network,
+	list_wallets, "listwallets" -> Vec<String>
+}
+
 impl_basic_rpc! {
-	get_new_address, "getnewaddress" -> String
+	wallet: get_new_address, "getnewaddress" -> String
 }
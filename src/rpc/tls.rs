@@ -0,0 +1,146 @@
+use std::{fs, io, path::PathBuf, sync::Arc};
+
+use rustls::{
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	client::WebPkiServerVerifier,
+	pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+	DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use super::Error;
+
+/// TLS settings for connecting to a node over HTTPS.
+#[derive(Debug, Default, Clone)]
+pub struct Tls {
+	/// A PEM bundle of CA certificates to trust instead of the built-in roots
+	pub ca: Option<PathBuf>,
+	/// A PEM client certificate chain, for proxies that require client authentication
+	pub certificate: Option<PathBuf>,
+	/// The PEM private key for `certificate`
+	pub key: Option<PathBuf>,
+	/// The name to verify the server certificate against, instead of the url host
+	pub server_name: Option<String>,
+}
+
+impl Tls {
+	/// Builds the rustls client configuration.
+	///
+	/// # Errors
+	/// Returns an error if a file cannot be read or does not contain valid
+	/// certificates or keys.
+	pub fn client_config(&self) -> Result<rustls::ClientConfig, Error> {
+		let mut roots = RootCertStore::empty();
+
+		if let Some(ca) = &self.ca {
+			for certificate in read_certificates(ca)? {
+				roots.add(certificate).map_err(tls_error)?;
+			}
+		} else {
+			roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+		}
+
+		let verifier = WebPkiServerVerifier::builder(Arc::new(roots))
+			.build()
+			.map_err(tls_error)?;
+
+		let builder = if let Some(name) = &self.server_name {
+			let name = ServerName::try_from(name.clone()).map_err(tls_error)?;
+
+			rustls::ClientConfig::builder()
+				.dangerous()
+				.with_custom_certificate_verifier(Arc::new(OverrideServerName {
+					inner: verifier,
+					name,
+				}))
+		} else {
+			rustls::ClientConfig::builder().with_webpki_verifier(verifier)
+		};
+
+		match (&self.certificate, &self.key) {
+			(Some(certificate), Some(key)) => builder
+				.with_client_auth_cert(read_certificates(certificate)?, read_key(key)?)
+				.map_err(tls_error),
+			(None, None) => Ok(builder.with_no_client_auth()),
+			_ => Err(Error {
+				code: 0,
+				message: "a client certificate and key must be provided together".to_string(),
+			}),
+		}
+	}
+}
+
+/// Verifies server certificates against a fixed name, for nodes reached
+/// through an address that is not in their certificate (like an IP or onion).
+#[derive(Debug)]
+struct OverrideServerName {
+	inner: Arc<WebPkiServerVerifier>,
+	name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for OverrideServerName {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		ocsp_response: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		self.inner
+			.verify_server_cert(end_entity, intermediates, &self.name, ocsp_response, now)
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.inner.supported_verify_schemes()
+	}
+}
+
+fn read_certificates(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, Error> {
+	let pem = fs::read(path)?;
+	let certificates = rustls_pemfile::certs(&mut &pem[..]).collect::<Result<Vec<_>, _>>()?;
+
+	if certificates.is_empty() {
+		return Err(Error::from(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("no certificates found in {}", path.display()),
+		)));
+	}
+
+	Ok(certificates)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>, Error> {
+	let pem = fs::read(path)?;
+
+	rustls_pemfile::private_key(&mut &pem[..])?.ok_or_else(|| {
+		Error::from(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("no private key found in {}", path.display()),
+		))
+	})
+}
+
+fn tls_error(error: impl std::fmt::Display) -> Error {
+	Error {
+		code: 0,
+		message: format!("tls error: {error}"),
+	}
+}
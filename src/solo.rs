@@ -0,0 +1,269 @@
+//! Solo mining on block templates from a node.
+
+use std::{str::FromStr as _, sync::mpsc, time::Duration};
+
+use bitcoin::hashes::Hash as _;
+
+use crate::{block, info, miner::format_rate, rpc, work, zmq, Error};
+
+/// How often the node is polled for network statistics
+const STATUS_INTERVAL: Duration = Duration::from_mins(1);
+/// How long to wait before reconnecting to the ZMQ publisher
+const ZMQ_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to go without a ZMQ notification before reconnecting, in case the
+/// publisher went away without closing the connection. Blocks are rarely this
+/// far apart, and longpoll covers any that are announced while reconnecting
+const ZMQ_TIMEOUT: Duration = Duration::from_hours(1);
+
+#[derive(Debug)]
+pub struct Solo {
+	pub rpc: rpc::Client,
+	pub wallet_address: bitcoin::Address,
+	/// The ZMQ publisher address for block notifications, used alongside longpoll
+	pub zmq: Option<String>,
+}
+
+impl Solo {
+	/// # Panics
+	/// Panics if the wallet address is invalid.
+	pub fn new(rpc: rpc::Client, wallet_address: &str) -> Self {
+		let wallet_address = bitcoin::Address::from_str(wallet_address)
+			.expect("invalid address")
+			.require_network(bitcoin::Network::Bitcoin)
+			.expect("invalid network");
+
+		tracing::info!(address = ?wallet_address, "using wallet address");
+
+		Self {
+			rpc,
+			wallet_address,
+			zmq: None,
+		}
+	}
+
+	/// Subscribes to `hashblock` and `rawblock` notifications at `address`,
+	/// so a new template is fetched as soon as the node connects a block.
+	#[must_use]
+	pub fn with_zmq(mut self, address: impl Into<String>) -> Self {
+		self.zmq = Some(address.into());
+		self
+	}
+
+	/// Sends a job for the current template, and another whenever the node
+	/// has a new one.
+	///
+	/// # Errors
+	/// Returns an error if the node is not usable for mining.
+	pub fn run(&self, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		let mut template = self.check_node()?;
+		let poll_id = std::mem::take(&mut template.longpoll_id);
+
+		let _ = jobs.send(self.job(&template)?);
+
+		std::thread::scope(|s| {
+			s.spawn(|| self.poll_status());
+
+			if let Some(address) = &self.zmq {
+				s.spawn(move || self.watch_zmq(jobs, address));
+			}
+
+			self.poll_new_block(jobs, poll_id)
+		})
+	}
+
+	/// Checks that the node is usable for mining, and fetches the first block
+	/// template in the same round-trip.
+	///
+	/// # Errors
+	/// Returns an error if any of the requests fail, or if the node is on a
+	/// different network than the wallet address.
+	pub fn check_node(&self) -> Result<block::Template, Error> {
+		let mut batch = self.rpc.batch();
+		let blockchain = batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+		let network = batch.push::<info::Network>(&rpc::Request::get_network_info());
+		let template = batch.push::<block::Template>(&rpc::Request::block_template(None));
+
+		let mut responses = batch.send()?;
+		let blockchain = responses.take(blockchain)?;
+		let network = responses.take(network)?;
+
+		tracing::info!(
+			version = %network.subversion,
+			connections = network.connections,
+			chain = %blockchain.chain,
+			height = blockchain.blocks,
+			"connected to node"
+		);
+
+		let expected = *self.wallet_address.network();
+
+		if bitcoin::Network::from_core_arg(&blockchain.chain).ok() != Some(expected) {
+			return Err(Error::Network {
+				expected,
+				found: blockchain.chain,
+			});
+		}
+
+		if blockchain.initial_block_download {
+			tracing::warn!(
+				progress = blockchain.verification_progress,
+				"node is still in initial block download"
+			);
+		}
+
+		Ok(responses.take(template)?)
+	}
+
+	/// Builds the job for a template, paying the coinbase to the wallet address.
+	///
+	/// # Errors
+	/// Returns an error if the template contains an invalid transaction.
+	pub fn job(&self, template: &block::Template) -> Result<work::Job, Error> {
+		let block = self.create_block(template)?;
+
+		Ok(work::Job {
+			header: block.header,
+			target: template.target(),
+			nonce_range: template.nonce_range.clone(),
+			kind: work::Kind::Block {
+				rpc: self.rpc.clone(),
+				transactions: block.txdata.into(),
+			},
+		})
+	}
+
+	fn poll_new_block(&self, jobs: &mpsc::Sender<work::Job>, mut poll_id: String) -> ! {
+		loop {
+			let template = self.rpc.get_block_template(Some(&poll_id));
+
+			if let Ok(mut template) = template {
+				poll_id = std::mem::take(&mut template.longpoll_id);
+
+				self.send_job(jobs, &template);
+			}
+		}
+	}
+
+	fn watch_zmq(&self, jobs: &mpsc::Sender<work::Job>, address: &str) {
+		let mut tip = None;
+
+		loop {
+			let topics = [zmq::HASH_BLOCK, zmq::RAW_BLOCK];
+			let mut subscriber = match zmq::Subscriber::connect(address, &topics, ZMQ_TIMEOUT) {
+				Ok(subscriber) => subscriber,
+				Err(e) => {
+					tracing::warn!(error = %e, address, "failed to connect to zmq publisher");
+					std::thread::sleep(ZMQ_RECONNECT_INTERVAL);
+					continue;
+				}
+			};
+
+			tracing::info!(address, "subscribed to zmq block notifications");
+
+			loop {
+				let message = match subscriber.recv() {
+					Ok(message) => message,
+					Err(e) if e.is_timeout() => {
+						tracing::warn!(
+							address,
+							timeout = ?ZMQ_TIMEOUT,
+							"no zmq notification in time, reconnecting"
+						);
+						break;
+					}
+					Err(e) => {
+						tracing::warn!(error = %e, address, "zmq connection lost");
+						break;
+					}
+				};
+
+				let hash = match message.topic.as_str() {
+					zmq::HASH_BLOCK => {
+						<[u8; 32]>::try_from(message.body.as_slice())
+							.ok()
+							.map(|mut hash| {
+								// published in display order
+								hash.reverse();
+								bitcoin::BlockHash::from_byte_array(hash)
+							})
+					}
+					zmq::RAW_BLOCK => message.body.get(..80).map(bitcoin::BlockHash::hash),
+					_ => None,
+				};
+
+				// the same block can be announced on both topics
+				if hash.is_none() || hash == tip {
+					continue;
+				}
+
+				tip = hash;
+
+				tracing::info!(hash = ?tip, sequence = message.sequence, "new block notification");
+
+				match self.rpc.get_block_template(None) {
+					Ok(template) => self.send_job(jobs, &template),
+					Err(e) => tracing::warn!(error = %e, "failed to fetch block template"),
+				}
+			}
+		}
+	}
+
+	fn send_job(&self, jobs: &mpsc::Sender<work::Job>, template: &block::Template) {
+		match self.job(template) {
+			Ok(job) => {
+				let _ = jobs.send(job);
+			}
+			Err(e) => tracing::warn!(error = %e, "invalid block template"),
+		}
+	}
+
+	fn poll_status(&self) {
+		loop {
+			std::thread::sleep(STATUS_INTERVAL);
+
+			let mut batch = self.rpc.batch();
+			let mining = batch.push::<info::Mining>(&rpc::Request::get_mining_info());
+			let network = batch.push::<info::Network>(&rpc::Request::get_network_info());
+
+			let Ok(mut responses) = batch.send() else {
+				continue;
+			};
+
+			if let (Ok(mining), Ok(network)) = (responses.take(mining), responses.take(network)) {
+				tracing::info!(
+					height = mining.blocks,
+					difficulty = mining.difficulty,
+					network_rate = mining.network_hash_rate,
+					network_rate_pretty = format_rate(mining.network_hash_rate),
+					transactions = mining.pooled_transactions,
+					connections = network.connections,
+					"network status"
+				);
+			}
+		}
+	}
+
+	fn create_block(&self, template: &block::Template) -> Result<bitcoin::Block, Error> {
+		let mut txdata = Vec::with_capacity(template.transactions.len() + 1);
+
+		txdata.push(template.coinbase(self.wallet_address.script_pubkey(), [0; 0]));
+		txdata.extend(template.transactions()?);
+
+		let mut block = bitcoin::Block {
+			header: bitcoin::block::Header {
+				version: bitcoin::block::Version::from_consensus(template.version),
+				prev_blockhash: template.previous_block,
+				merkle_root: bitcoin::TxMerkleNode::all_zeros(),
+				time: template.current_time,
+				bits: template.bits(),
+				nonce: template.nonce_range.start,
+			},
+			txdata,
+		};
+
+		// there is always at least the coinbase transaction
+		block.header.merkle_root = block.compute_merkle_root().expect("empty block");
+
+		Ok(block)
+	}
+}
@@ -0,0 +1,73 @@
+//! Pool mining over the Stratum protocol.
+
+pub mod v1;
+pub mod v2;
+
+use std::{
+	fmt, io,
+	sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use bitcoin::hashes::{sha256d, Hash as _};
+
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	Json(serde_json::Error),
+	/// The peer sent something we don't understand
+	Protocol(String),
+	/// The peer answered a request with an error
+	Rejected {
+		code: i64,
+		message: String,
+	},
+	/// The peer refused a Stratum V2 request, with its error code
+	Refused(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "io error: {e}"),
+			Self::Json(e) => write!(f, "json error: {e}"),
+			Self::Protocol(e) => write!(f, "protocol error: {e}"),
+			Self::Rejected { code, message } => write!(f, "rejected ({code}): {message}"),
+			Self::Refused(code) => write!(f, "refused: {code}"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
+
+impl From<serde_json::Error> for Error {
+	fn from(value: serde_json::Error) -> Self {
+		Self::Json(value)
+	}
+}
+
+/// The merkle root of a block with `coinbase`, given the hashes needed to
+/// compute it from the coinbase txid.
+pub(crate) fn merkle_root(coinbase: &[u8], branch: &[[u8; 32]]) -> bitcoin::TxMerkleNode {
+	let mut hash = sha256d::Hash::hash(coinbase).to_byte_array();
+
+	for sibling in branch {
+		let mut data = [0; 64];
+
+		data[..32].copy_from_slice(&hash);
+		data[32..].copy_from_slice(sibling);
+		hash = sha256d::Hash::hash(&data).to_byte_array();
+	}
+
+	bitcoin::TxMerkleNode::from_byte_array(hash)
+}
+
+/// Locks a mutex, ignoring poisoning since every update leaves the state valid.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
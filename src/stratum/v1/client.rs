@@ -0,0 +1,403 @@
+use std::{
+	collections::HashMap,
+	io::BufReader,
+	net::TcpStream,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		mpsc, Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use super::{lock, read, rejection, write, Message, Notify, Work, OTHER, UNAUTHORIZED, USER_AGENT};
+use crate::{stratum::Error, work};
+
+/// How long to wait before reconnecting to the pool
+pub(super) const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A pool to mine shares for, reconnecting whenever the connection drops.
+#[derive(Debug, Clone)]
+pub struct Pool {
+	/// The pool address, e.g. `stratum+tcp://pool.example.com:3333`
+	pub url: String,
+	pub worker: String,
+	pub password: String,
+}
+
+impl Pool {
+	/// Sends a job for every notification from the pool, reconnecting if the
+	/// connection is lost.
+	///
+	/// # Errors
+	/// Returns an error if the pool does not authorize the worker.
+	pub fn run(&self, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		loop {
+			match Client::connect(&self.url, &self.worker, &self.password) {
+				Ok(client) => {
+					tracing::info!(url = %self.url, worker = %self.worker, "connected to pool");
+
+					let Err(e) = client.run(jobs);
+
+					tracing::warn!(error = %e, url = %self.url, "pool connection lost");
+				}
+				Err(e @ Error::Rejected { .. }) => return Err(e),
+				Err(e) => tracing::warn!(error = %e, url = %self.url, "failed to connect to pool"),
+			}
+
+			std::thread::sleep(RECONNECT_INTERVAL);
+		}
+	}
+}
+
+/// A subscribed and authorized session with a pool.
+#[derive(Debug)]
+pub struct Client {
+	worker: String,
+	reader: Mutex<BufReader<TcpStream>>,
+	writer: Mutex<TcpStream>,
+	session: Mutex<Session>,
+	/// The method of each request that hasn't been answered yet, by id
+	pending: Mutex<HashMap<u64, &'static str>>,
+	next_id: AtomicU64,
+	extranonce2: AtomicU64,
+	accepted: AtomicU64,
+	rejected: AtomicU64,
+	closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Session {
+	extranonce1: Vec<u8>,
+	extranonce2_size: usize,
+	difficulty: f64,
+	notify: Option<Arc<Notify>>,
+}
+
+impl Client {
+	/// Connects to a pool, subscribes and authorizes `worker`.
+	///
+	/// # Errors
+	/// Returns an error if the pool cannot be reached, or if it rejects the
+	/// subscription or the worker.
+	pub fn connect(url: &str, worker: &str, password: &str) -> Result<Arc<Self>, Error> {
+		let stream = TcpStream::connect(url.strip_prefix("stratum+tcp://").unwrap_or(url))?;
+
+		stream.set_nodelay(true)?;
+
+		let client = Self {
+			worker: worker.to_string(),
+			reader: Mutex::new(BufReader::new(stream.try_clone()?)),
+			writer: Mutex::new(stream),
+			session: Mutex::new(Session {
+				extranonce1: Vec::new(),
+				extranonce2_size: 0,
+				difficulty: 1.0,
+				notify: None,
+			}),
+			pending: Mutex::default(),
+			next_id: AtomicU64::new(1),
+			extranonce2: AtomicU64::new(0),
+			accepted: AtomicU64::new(0),
+			rejected: AtomicU64::new(0),
+			closed: AtomicBool::new(false),
+		};
+
+		// [[subscriptions], extranonce1, extranonce2_size]
+		let subscription = client.call("mining.subscribe", json!([USER_AGENT]))?;
+		let (extranonce1, extranonce2_size) = parse_extranonce(
+			subscription
+				.as_array()
+				.and_then(|subscription| subscription.get(1..3)),
+		)?;
+
+		{
+			let mut session = lock(&client.session);
+
+			session.extranonce1 = extranonce1;
+			session.extranonce2_size = extranonce2_size;
+		}
+
+		if client.call("mining.authorize", json!([worker, password]))? != Value::Bool(true) {
+			return Err(Error::Rejected {
+				code: UNAUTHORIZED,
+				message: format!("worker {worker} is not authorized"),
+			});
+		}
+
+		// not every pool supports this, so the response is only logged
+		client.request("mining.extranonce.subscribe", json!([]))?;
+
+		Ok(Arc::new(client))
+	}
+
+	/// Reads messages from the pool, sending a new job whenever the work or
+	/// difficulty changes. Only returns once the connection fails.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed or the pool sends an invalid message.
+	pub fn run(self: &Arc<Self>, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		let Err(e) = self.read_jobs(jobs);
+
+		self.closed.store(true, Ordering::Relaxed);
+		Err(e)
+	}
+
+	fn read_jobs(self: &Arc<Self>, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		let mut reader = lock(&self.reader);
+
+		// the first job usually arrives while authorizing
+		if let Some(job) = self.job() {
+			let _ = jobs.send(job);
+		}
+
+		loop {
+			if self.handle(read(&mut *reader)?)? {
+				if let Some(job) = self.job() {
+					let _ = jobs.send(job);
+				}
+			}
+		}
+	}
+
+	/// The current share difficulty.
+	#[must_use]
+	pub fn difficulty(&self) -> f64 {
+		lock(&self.session).difficulty
+	}
+
+	/// The number of shares the pool has accepted.
+	#[must_use]
+	pub fn accepted(&self) -> u64 {
+		self.accepted.load(Ordering::Relaxed)
+	}
+
+	/// The number of shares the pool has rejected.
+	#[must_use]
+	pub fn rejected(&self) -> u64 {
+		self.rejected.load(Ordering::Relaxed)
+	}
+
+	/// Whether the connection has failed, after which [`Client::run`] has returned.
+	#[must_use]
+	pub fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::Relaxed)
+	}
+
+	/// Submits a share for `job_id`. The pool's answer is logged once it arrives.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed.
+	pub fn submit(
+		&self,
+		job_id: &str,
+		extranonce2: &[u8],
+		header: &bitcoin::block::Header,
+	) -> Result<(), Error> {
+		tracing::info!(job = job_id, hash = ?header.block_hash(), "submitting share");
+
+		self.request(
+			"mining.submit",
+			json!([
+				self.worker,
+				job_id,
+				hex::encode(extranonce2),
+				format!("{:08x}", header.time),
+				format!("{:08x}", header.nonce),
+			]),
+		)?;
+
+		Ok(())
+	}
+
+	/// An extranonce2 of `size` bytes that hasn't been used in this session.
+	pub(super) fn next_extranonce2(&self, size: usize) -> Vec<u8> {
+		let counter = self
+			.extranonce2
+			.fetch_add(1, Ordering::Relaxed)
+			.to_le_bytes();
+		let mut extranonce2 = vec![0; size];
+		let len = size.min(counter.len());
+
+		extranonce2[..len].copy_from_slice(&counter[..len]);
+		extranonce2
+	}
+
+	/// The job for the latest notification at the current difficulty.
+	fn job(self: &Arc<Self>) -> Option<work::Job> {
+		let session = lock(&self.session);
+		let work = Work {
+			client: Arc::clone(self),
+			notify: session.notify.clone()?,
+			extranonce1: session.extranonce1.clone(),
+			extranonce2: self.next_extranonce2(session.extranonce2_size),
+		};
+
+		Some(work.into_job(work::target_from_difficulty(session.difficulty)))
+	}
+
+	/// Sends a request and waits for its response, handling any notifications
+	/// that arrive in the meantime.
+	fn call(&self, method: &'static str, params: Value) -> Result<Value, Error> {
+		let id = self.request(method, params)?;
+		let mut reader = lock(&self.reader);
+
+		loop {
+			match read(&mut *reader)? {
+				Message::Response {
+					id: response,
+					result,
+					error,
+				} if response == id => {
+					lock(&self.pending).remove(&id);
+
+					return if error.is_null() {
+						Ok(result)
+					} else {
+						Err(rejection(&error))
+					};
+				}
+				message => {
+					self.handle(message)?;
+				}
+			}
+		}
+	}
+
+	fn request(&self, method: &'static str, params: Value) -> Result<u64, Error> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+		lock(&self.pending).insert(id, method);
+
+		let message = Message::Request {
+			id: id.into(),
+			method: method.to_string(),
+			params,
+		};
+
+		write(&*lock(&self.writer), &message)?;
+
+		Ok(id)
+	}
+
+	/// Handles a message from the pool, returning whether the job changed.
+	fn handle(&self, message: Message) -> Result<bool, Error> {
+		match message {
+			Message::Request { id, method, params } => self.handle_request(id, &method, params),
+			Message::Response { id, result, error } => {
+				let method = id.as_u64().and_then(|id| lock(&self.pending).remove(&id));
+
+				match method {
+					Some("mining.submit") if error.is_null() && result == Value::Bool(true) => {
+						self.accepted.fetch_add(1, Ordering::Relaxed);
+
+						tracing::info!(
+							accepted = self.accepted(),
+							rejected = self.rejected(),
+							"share accepted"
+						);
+					}
+					Some("mining.submit") => {
+						self.rejected.fetch_add(1, Ordering::Relaxed);
+
+						tracing::warn!(
+							reason = %rejection(&error),
+							accepted = self.accepted(),
+							rejected = self.rejected(),
+							"share rejected"
+						);
+					}
+					Some(method) if !error.is_null() => {
+						tracing::debug!(method, error = %rejection(&error), "pool request failed");
+					}
+					_ => {}
+				}
+
+				Ok(false)
+			}
+		}
+	}
+
+	fn handle_request(&self, id: Value, method: &str, params: Value) -> Result<bool, Error> {
+		match method {
+			"mining.notify" => {
+				let notify = Notify::from_params(params)?;
+
+				tracing::debug!(job = %notify.job_id, clean = notify.clean, "new pool job");
+
+				lock(&self.session).notify = Some(Arc::new(notify));
+
+				Ok(true)
+			}
+			"mining.set_difficulty" => {
+				let difficulty = params
+					.get(0)
+					.and_then(Value::as_f64)
+					.filter(|difficulty| *difficulty > 0.0)
+					.ok_or_else(|| Error::Protocol("invalid difficulty".to_string()))?;
+
+				tracing::info!(difficulty, "pool difficulty changed");
+
+				lock(&self.session).difficulty = difficulty;
+
+				Ok(true)
+			}
+			"mining.set_extranonce" => {
+				let (extranonce1, extranonce2_size) =
+					parse_extranonce(params.as_array().and_then(|params| params.get(0..2)))?;
+				let mut session = lock(&self.session);
+
+				session.extranonce1 = extranonce1;
+				session.extranonce2_size = extranonce2_size;
+
+				Ok(true)
+			}
+			"client.show_message" => {
+				tracing::info!(message = %params, "message from pool");
+
+				Ok(false)
+			}
+			"client.reconnect" => Err(Error::Protocol("pool asked us to reconnect".to_string())),
+			"client.get_version" => {
+				write(
+					&*lock(&self.writer),
+					&Message::result(id, USER_AGENT.into()),
+				)?;
+
+				Ok(false)
+			}
+			_ => {
+				tracing::debug!(method, "unsupported pool request");
+
+				if !id.is_null() {
+					write(
+						&*lock(&self.writer),
+						&Message::error(id, OTHER, "unsupported method"),
+					)?;
+				}
+
+				Ok(false)
+			}
+		}
+	}
+}
+
+/// Parses `[extranonce1, extranonce2_size]`.
+fn parse_extranonce(values: Option<&[Value]>) -> Result<(Vec<u8>, usize), Error> {
+	let invalid = || Error::Protocol("invalid extranonce".to_string());
+	let [extranonce1, extranonce2_size] = values.ok_or_else(invalid)? else {
+		return Err(invalid());
+	};
+
+	let extranonce1 = extranonce1
+		.as_str()
+		.and_then(|extranonce1| hex::decode(extranonce1).ok())
+		.ok_or_else(invalid)?;
+	let extranonce2_size = extranonce2_size
+		.as_u64()
+		.and_then(|size| usize::try_from(size).ok())
+		.ok_or_else(invalid)?;
+
+	Ok((extranonce1, extranonce2_size))
+}
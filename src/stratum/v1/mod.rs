@@ -0,0 +1,371 @@
+//! Stratum V1, the newline-delimited JSON-RPC protocol spoken by most pools.
+//!
+//! See <https://en.bitcoin.it/wiki/Stratum_mining_protocol> for the messages.
+
+mod client;
+mod proxy;
+mod server;
+mod templates;
+mod vardiff;
+
+use std::{
+	io::{self, BufRead, Read as _, Write},
+	sync::Arc,
+};
+
+use bitcoin::hashes::Hash as _;
+use serde_json::{json, Value};
+
+pub use client::{Client, Pool};
+pub use proxy::Proxy;
+pub use server::{Server, Share, Source, Worker};
+pub use templates::Templates;
+pub use vardiff::Vardiff;
+
+use super::{lock, Error};
+use crate::work;
+
+/// Sent in `mining.subscribe` and `client.get_version`
+pub const USER_AGENT: &str = concat!("miner/", env!("CARGO_PKG_VERSION"));
+
+/// Error codes used in responses
+pub const OTHER: i64 = 20;
+pub const JOB_NOT_FOUND: i64 = 21;
+pub const DUPLICATE_SHARE: i64 = 22;
+pub const LOW_DIFFICULTY: i64 = 23;
+pub const UNAUTHORIZED: i64 = 24;
+pub const NOT_SUBSCRIBED: i64 = 25;
+
+/// The longest line we accept, which is well above the size of a job
+const MAX_LINE_SIZE: u64 = 1024 * 1024;
+
+/// A line of Stratum JSON-RPC.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+	/// A request, or a notification if `id` is null
+	Request {
+		id: Value,
+		method: String,
+		params: Value,
+	},
+	Response {
+		id: Value,
+		result: Value,
+		error: Value,
+	},
+}
+
+impl Message {
+	#[must_use]
+	pub fn notification(method: &str, params: Value) -> Self {
+		Self::Request {
+			id: Value::Null,
+			method: method.to_string(),
+			params,
+		}
+	}
+
+	/// A successful response to the request with `id`.
+	#[must_use]
+	pub fn result(id: Value, result: Value) -> Self {
+		Self::Response {
+			id,
+			result,
+			error: Value::Null,
+		}
+	}
+
+	/// An error response, in the `[code, message, traceback]` form pools use.
+	#[must_use]
+	pub fn error(id: Value, code: i64, message: &str) -> Self {
+		Self::Response {
+			id,
+			result: Value::Null,
+			error: json!([code, message, null]),
+		}
+	}
+
+	fn to_value(&self) -> Value {
+		match self {
+			Self::Request { id, method, params } => {
+				json!({ "id": id, "method": method, "params": params })
+			}
+			Self::Response { id, result, error } => {
+				json!({ "id": id, "result": result, "error": error })
+			}
+		}
+	}
+
+	fn from_value(mut value: Value) -> Result<Self, Error> {
+		let Some(object) = value.as_object_mut() else {
+			return Err(Error::Protocol("message is not an object".to_string()));
+		};
+
+		let id = object.remove("id").unwrap_or_default();
+
+		Ok(match object.remove("method") {
+			Some(Value::String(method)) => Self::Request {
+				id,
+				method,
+				params: object.remove("params").unwrap_or_default(),
+			},
+			Some(_) => return Err(Error::Protocol("method is not a string".to_string())),
+			None => Self::Response {
+				id,
+				result: object.remove("result").unwrap_or_default(),
+				error: object.remove("error").unwrap_or_default(),
+			},
+		})
+	}
+}
+
+/// Reads the next message, skipping blank lines.
+///
+/// # Errors
+/// Returns an error if the connection is closed or the line is not a valid message.
+pub fn read(reader: &mut impl BufRead) -> Result<Message, Error> {
+	let mut line = String::new();
+
+	loop {
+		line.clear();
+
+		if reader.take(MAX_LINE_SIZE).read_line(&mut line)? == 0 {
+			return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+		}
+
+		if !line.ends_with('\n') && line.len() as u64 == MAX_LINE_SIZE {
+			return Err(Error::Protocol("line too long".to_string()));
+		}
+
+		if !line.trim().is_empty() {
+			return Message::from_value(serde_json::from_str(&line)?);
+		}
+	}
+}
+
+/// Writes a message followed by a newline.
+///
+/// # Errors
+/// Returns an error if the connection is closed.
+pub fn write(mut writer: impl Write, message: &Message) -> Result<(), Error> {
+	let mut line = serde_json::to_vec(&message.to_value())?;
+
+	line.push(b'\n');
+	writer.write_all(&line)?;
+
+	Ok(())
+}
+
+/// Parses the `error` of a response, which is either `[code, message, traceback]`
+/// or a JSON-RPC 2.0 style object.
+fn rejection(error: &Value) -> Error {
+	let (code, message) = match error {
+		Value::Array(error) => (error.first(), error.get(1)),
+		Value::Object(error) => (error.get("code"), error.get("message")),
+		_ => (None, None),
+	};
+
+	Error::Rejected {
+		code: code.and_then(Value::as_i64).unwrap_or_default(),
+		message: match message {
+			Some(Value::String(message)) => message.clone(),
+			_ => error.to_string(),
+		},
+	}
+}
+
+/// The contents of a `mining.notify`, which describes everything in a header
+/// except the extranonces in the coinbase and the nonce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notify {
+	pub job_id: String,
+	pub previous_block: bitcoin::BlockHash,
+	/// The serialized coinbase up to the extranonces
+	pub coinbase1: Vec<u8>,
+	/// The serialized coinbase after the extranonces
+	pub coinbase2: Vec<u8>,
+	/// The hashes needed to compute the merkle root from the coinbase txid
+	pub merkle_branch: Vec<[u8; 32]>,
+	pub version: i32,
+	pub bits: bitcoin::CompactTarget,
+	pub time: u32,
+	/// Whether previous jobs are no longer valid
+	pub clean: bool,
+}
+
+impl Notify {
+	/// Parses the `params` of a `mining.notify`.
+	///
+	/// # Errors
+	/// Returns an error if a field is missing or has an invalid value.
+	pub fn from_params(params: Value) -> Result<Self, Error> {
+		#[allow(clippy::type_complexity)]
+		let (
+			job_id,
+			previous_block,
+			coinbase1,
+			coinbase2,
+			merkle_branch,
+			version,
+			bits,
+			time,
+			clean,
+		): (
+			String,
+			String,
+			String,
+			String,
+			Vec<String>,
+			String,
+			String,
+			String,
+			bool,
+		) = serde_json::from_value(params)?;
+
+		let merkle_branch = merkle_branch
+			.iter()
+			.map(|hash| decode_hex::<32>(hash))
+			.collect::<Result<_, _>>()?;
+
+		Ok(Self {
+			job_id,
+			previous_block: bitcoin::BlockHash::from_byte_array(swap_words(decode_hex(
+				&previous_block,
+			)?)),
+			coinbase1: hex::decode(coinbase1).map_err(invalid_hex)?,
+			coinbase2: hex::decode(coinbase2).map_err(invalid_hex)?,
+			merkle_branch,
+			version: i32::from_be_bytes(decode_hex(&version)?),
+			bits: bitcoin::CompactTarget::from_consensus(u32::from_be_bytes(decode_hex(&bits)?)),
+			time: u32::from_be_bytes(decode_hex(&time)?),
+			clean,
+		})
+	}
+
+	/// The `params` of a `mining.notify` for this job.
+	#[must_use]
+	pub fn to_params(&self) -> Value {
+		let merkle_branch = self
+			.merkle_branch
+			.iter()
+			.map(hex::encode)
+			.collect::<Vec<_>>();
+
+		json!([
+			self.job_id,
+			hex::encode(swap_words(self.previous_block.to_byte_array())),
+			hex::encode(&self.coinbase1),
+			hex::encode(&self.coinbase2),
+			merkle_branch,
+			format!("{:08x}", self.version),
+			format!("{:08x}", self.bits.to_consensus()),
+			format!("{:08x}", self.time),
+			self.clean,
+		])
+	}
+
+	/// The serialized coinbase transaction with the extranonces in place.
+	#[must_use]
+	pub fn coinbase(&self, extranonce1: &[u8], extranonce2: &[u8]) -> Vec<u8> {
+		[
+			&self.coinbase1[..],
+			extranonce1,
+			extranonce2,
+			&self.coinbase2[..],
+		]
+		.concat()
+	}
+
+	#[must_use]
+	pub fn merkle_root(&self, extranonce1: &[u8], extranonce2: &[u8]) -> bitcoin::TxMerkleNode {
+		super::merkle_root(
+			&self.coinbase(extranonce1, extranonce2),
+			&self.merkle_branch,
+		)
+	}
+
+	#[must_use]
+	pub fn header(
+		&self,
+		extranonce1: &[u8],
+		extranonce2: &[u8],
+		time: u32,
+		nonce: u32,
+	) -> bitcoin::block::Header {
+		bitcoin::block::Header {
+			version: bitcoin::block::Version::from_consensus(self.version),
+			prev_blockhash: self.previous_block,
+			merkle_root: self.merkle_root(extranonce1, extranonce2),
+			time,
+			bits: self.bits,
+			nonce,
+		}
+	}
+}
+
+/// A pool job with our extranonces filled in.
+#[derive(Debug, Clone)]
+pub struct Work {
+	pub client: Arc<Client>,
+	pub notify: Arc<Notify>,
+	pub extranonce1: Vec<u8>,
+	pub extranonce2: Vec<u8>,
+}
+
+impl Work {
+	/// Builds a job searching for shares at `target`.
+	#[must_use]
+	pub fn into_job(self, target: bitcoin::Target) -> work::Job {
+		work::Job {
+			header: self
+				.notify
+				.header(&self.extranonce1, &self.extranonce2, self.notify.time, 0),
+			target,
+			nonce_range: 0..u32::MAX,
+			kind: work::Kind::Stratum(self),
+		}
+	}
+
+	/// Takes an extranonce2 that hasn't been used in this session yet.
+	pub fn roll(&mut self) {
+		self.extranonce2 = self.client.next_extranonce2(self.extranonce2.len());
+	}
+
+	#[must_use]
+	pub fn merkle_root(&self) -> bitcoin::TxMerkleNode {
+		self.notify
+			.merkle_root(&self.extranonce1, &self.extranonce2)
+	}
+
+	/// Submits a header that meets the share target.
+	///
+	/// # Errors
+	/// Returns an error if the connection to the pool is closed.
+	pub fn submit(&self, header: &bitcoin::block::Header) -> Result<(), Error> {
+		self.client
+			.submit(&self.notify.job_id, &self.extranonce2, header)
+	}
+}
+
+/// Stratum sends the previous block hash with the bytes of each 32-bit word
+/// reversed, which is its own inverse.
+fn swap_words(mut hash: [u8; 32]) -> [u8; 32] {
+	for word in hash.chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	hash
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Result<[u8; N], Error> {
+	let mut bytes = [0; N];
+
+	hex::decode_to_slice(value, &mut bytes).map_err(invalid_hex)?;
+
+	Ok(bytes)
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn invalid_hex(error: hex::FromHexError) -> Error {
+	Error::Protocol(format!("invalid hex: {error}"))
+}
@@ -0,0 +1,307 @@
+use std::{
+	collections::{HashSet, VecDeque},
+	sync::{mpsc, Arc, Mutex},
+};
+
+use super::{
+	client::RECONNECT_INTERVAL, lock, Client, Notify, Pool, Server, Share, Source, JOB_NOT_FOUND,
+	OTHER,
+};
+use crate::{stratum::Error, work};
+
+/// The extranonce2 workers are left with before the prefix that tells them
+/// apart grows from one byte to two
+const WORKER_EXTRANONCE2_SIZE: usize = 4;
+/// How many jobs workers can still submit shares for
+const MAX_JOBS: usize = 8;
+
+/// A [`Source`] that relays jobs from a single pool session to many workers,
+/// forwarding the shares that meet the pool's difficulty.
+///
+/// Each worker rolls its own slice of the pool's extranonce2 space, behind a
+/// prefix that is one byte for pools with less than 6 bytes of extranonce2,
+/// and two bytes otherwise. That lets 256 or 65,536 workers share a session,
+/// and the pool's extranonce2 has to be at least 2 bytes.
+///
+/// The pool's extranonce1 is moved into the coinbase of the jobs sent to
+/// workers, so they stay connected when the pool session is replaced. If the
+/// new session's extranonce2 is split differently, workers are disconnected
+/// so they subscribe again.
+#[derive(Debug)]
+pub struct Proxy {
+	pool: Pool,
+	state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+	/// The current pool session, unset while reconnecting
+	client: Option<Arc<Client>>,
+	/// Counts pool sessions, to keep job ids unique across them
+	session: u64,
+	/// The pool's share target
+	target: Option<bitcoin::Target>,
+	/// The sizes of the prefix and of the extranonce2 workers roll, from the
+	/// current pool session
+	sizes: Option<(usize, usize)>,
+	/// Recent jobs, newest last
+	jobs: VecDeque<Job>,
+	/// The extranonce2 prefixes of connected workers
+	prefixes: HashSet<Vec<u8>>,
+	next_prefix: u32,
+}
+
+/// A pool job, along with the job sent to workers for it.
+#[derive(Debug)]
+struct Job {
+	client: Arc<Client>,
+	upstream: Arc<Notify>,
+	notify: Arc<Notify>,
+	/// The size of the pool's extranonce2, which workers' shares have to fill
+	extranonce2_size: usize,
+}
+
+/// What workers need after a job from the pool.
+#[derive(Debug)]
+enum Update {
+	/// Nothing, since only the difficulty changed
+	Unchanged,
+	/// A new job to send to workers
+	Job,
+	/// A new extranonce, since the pool's extranonce2 is split differently
+	Resubscribe,
+}
+
+impl Proxy {
+	#[must_use]
+	pub fn new(pool: Pool) -> Self {
+		Self {
+			pool,
+			state: Mutex::default(),
+		}
+	}
+
+	/// Keeps a session with the pool, sending its jobs to every worker and
+	/// reconnecting if the connection is lost.
+	///
+	/// # Errors
+	/// Returns an error if the pool does not authorize the worker.
+	pub fn run(server: &Server<Self>) -> Result<!, Error> {
+		let proxy = server.source();
+		let pool = &proxy.pool;
+
+		loop {
+			match Client::connect(&pool.url, &pool.worker, &pool.password) {
+				Ok(client) => {
+					tracing::info!(url = %pool.url, worker = %pool.worker, "connected to pool");
+
+					let (jobs, receiver) = mpsc::channel();
+					let runner = std::thread::spawn(move || client.run(&jobs));
+
+					// the channel closes once the client stops running
+					for job in receiver {
+						match proxy.update(job) {
+							Update::Unchanged => {}
+							Update::Job => server.broadcast(),
+							Update::Resubscribe => server.disconnect_all(),
+						}
+					}
+
+					lock(&proxy.state).client = None;
+
+					if let Ok(Err(e)) = runner.join() {
+						tracing::warn!(error = %e, url = %pool.url, "pool connection lost");
+					}
+				}
+				Err(e @ Error::Rejected { .. }) => return Err(e),
+				Err(e) => tracing::warn!(error = %e, url = %pool.url, "failed to connect to pool"),
+			}
+
+			std::thread::sleep(RECONNECT_INTERVAL);
+		}
+	}
+
+	/// Takes a job from the pool session, returning what workers need.
+	fn update(&self, job: work::Job) -> Update {
+		let work::Kind::Stratum(work) = job.kind else {
+			return Update::Unchanged;
+		};
+
+		let extranonce2_size = work.extranonce2.len();
+		let Some(sizes) = split(extranonce2_size) else {
+			tracing::warn!(
+				size = extranonce2_size,
+				"pool extranonce2 is too small to split between workers"
+			);
+			return Update::Unchanged;
+		};
+
+		let mut state = lock(&self.state);
+		// a new session or `mining.set_extranonce` can change the size
+		let resized = state.sizes.is_some_and(|current| current != sizes);
+
+		state.sizes = Some(sizes);
+		state.target = Some(job.target);
+
+		let new_session = !state
+			.client
+			.as_ref()
+			.is_some_and(|client| Arc::ptr_eq(client, &work.client));
+
+		if new_session {
+			state.client = Some(Arc::clone(&work.client));
+			state.session += 1;
+		} else if !resized
+			&& state
+				.jobs
+				.back()
+				.is_some_and(|last| Arc::ptr_eq(&last.upstream, &work.notify))
+		{
+			// only the difficulty changed, which workers don't see
+			return Update::Unchanged;
+		}
+
+		let upstream = work.notify;
+		let notify = Notify {
+			job_id: format!("{:x}.{}", state.session, upstream.job_id),
+			coinbase1: [&upstream.coinbase1[..], &work.extranonce1].concat(),
+			// jobs from a previous session or extranonce can't be submitted anymore
+			clean: upstream.clean || new_session || resized,
+			..(*upstream).clone()
+		};
+
+		if notify.clean {
+			state.jobs.clear();
+		} else if state.jobs.len() == MAX_JOBS {
+			state.jobs.pop_front();
+		}
+
+		tracing::debug!(job = %notify.job_id, clean = notify.clean, "new proxied job");
+
+		state.jobs.push_back(Job {
+			client: work.client,
+			upstream,
+			notify: Arc::new(notify),
+			extranonce2_size,
+		});
+
+		if resized {
+			let (prefix, size) = sizes;
+
+			tracing::info!(
+				prefix,
+				size,
+				"pool extranonce2 changed, reconnecting workers"
+			);
+
+			return Update::Resubscribe;
+		}
+
+		Update::Job
+	}
+}
+
+impl Source for Proxy {
+	fn extranonce(&self) -> Option<(Vec<u8>, usize)> {
+		let mut state = lock(&self.state);
+		let (prefix_size, size) = state.sizes?;
+		let count = 1 << (8 * prefix_size);
+
+		for _ in 0..count {
+			let prefix = state.next_prefix % count;
+
+			state.next_prefix = prefix + 1;
+
+			let prefix = prefix.to_be_bytes()[4 - prefix_size..].to_vec();
+
+			if state.prefixes.insert(prefix.clone()) {
+				return Some((prefix, size));
+			}
+		}
+
+		None
+	}
+
+	fn release(&self, extranonce1: &[u8]) {
+		lock(&self.state).prefixes.remove(extranonce1);
+	}
+
+	/// Rolled versions can't be forwarded, since the pool session doesn't
+	/// negotiate version rolling.
+	fn version_rolling_mask(&self) -> u32 {
+		0
+	}
+
+	fn job(&self, _worker: &str) -> Option<Arc<Notify>> {
+		lock(&self.state)
+			.jobs
+			.back()
+			.map(|job| Arc::clone(&job.notify))
+	}
+
+	fn submit(&self, share: &Share) -> Result<(), Error> {
+		let state = lock(&self.state);
+		let Some(job) = state
+			.jobs
+			.iter()
+			.find(|job| job.notify.job_id == share.notify.job_id)
+		else {
+			return Err(Error::Rejected {
+				code: JOB_NOT_FOUND,
+				message: "stale job".to_string(),
+			});
+		};
+
+		if !state
+			.client
+			.as_ref()
+			.is_some_and(|client| Arc::ptr_eq(client, &job.client))
+		{
+			return Err(Error::Rejected {
+				code: OTHER,
+				message: "pool is reconnecting".to_string(),
+			});
+		}
+
+		// a worker that subscribed before the extranonce2 changed may get a new
+		// job before it is disconnected
+		if share.extranonce1.len() + share.extranonce2.len() != job.extranonce2_size {
+			return Err(Error::Rejected {
+				code: OTHER,
+				message: "extranonce changed".to_string(),
+			});
+		}
+
+		if !state
+			.target
+			.is_some_and(|target| target.is_met_by(share.header.block_hash()))
+		{
+			return Ok(());
+		}
+
+		let client = Arc::clone(&job.client);
+		let upstream = Arc::clone(&job.upstream);
+		let extranonce2 = [share.extranonce1, share.extranonce2].concat();
+
+		drop(state);
+
+		tracing::debug!(worker = share.worker, job = %upstream.job_id, "forwarding share");
+
+		client.submit(&upstream.job_id, &extranonce2, &share.header)
+	}
+}
+
+/// Splits a pool's extranonce2 into the sizes of the prefix that tells workers
+/// apart and of the extranonce2 they roll, or `None` if it's too small.
+fn split(extranonce2_size: usize) -> Option<(usize, usize)> {
+	let prefix = if extranonce2_size >= 2 + WORKER_EXTRANONCE2_SIZE {
+		2
+	} else {
+		1
+	};
+
+	extranonce2_size
+		.checked_sub(prefix)
+		.filter(|&size| size > 0)
+		.map(|size| (prefix, size))
+}
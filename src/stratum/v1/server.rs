@@ -0,0 +1,599 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	io::BufReader,
+	net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use super::{
+	decode_hex, lock, read,
+	vardiff::{Controller, Vardiff, RETARGET_INTERVAL},
+	write, Message, Notify, DUPLICATE_SHARE, JOB_NOT_FOUND, LOW_DIFFICULTY, NOT_SUBSCRIBED, OTHER,
+	UNAUTHORIZED,
+};
+use crate::{miner::format_rate, stratum::Error, work};
+
+/// How many jobs a worker can still submit shares for
+const MAX_JOBS: usize = 8;
+/// How far past the job time a worker may roll `ntime`
+const MAX_TIME_ROLL: u32 = 2 * 60 * 60;
+/// The version bits workers may roll, from BIP320
+const VERSION_ROLLING_MASK: u32 = 0x1fff_e000;
+/// How long a write to a worker may block before it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a [`Server`] gets its jobs, and where valid shares go.
+pub trait Source: Send + Sync + 'static {
+	/// Reserves an extranonce1 for a new connection, along with the size of
+	/// the extranonce2 the worker rolls, or `None` if there is no space left.
+	fn extranonce(&self) -> Option<(Vec<u8>, usize)>;
+
+	/// Frees the extranonce1 of a connection that closed.
+	fn release(&self, _extranonce1: &[u8]) {}
+
+	/// The version bits workers may roll, or 0 if they can't.
+	fn version_rolling_mask(&self) -> u32 {
+		VERSION_ROLLING_MASK
+	}
+
+	/// Checks a worker name before it is authorized.
+	///
+	/// # Errors
+	/// Returns an error if the worker should not be authorized.
+	fn authorize(&self, _worker: &str) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// The job `worker` should be mining, if there is one yet.
+	fn job(&self, worker: &str) -> Option<Arc<Notify>>;
+
+	/// Handles a share that met the worker's difficulty or the network's.
+	///
+	/// # Errors
+	/// Returns an error if the share should be rejected.
+	fn submit(&self, share: &Share) -> Result<(), Error>;
+}
+
+/// A share that met the difficulty of the worker that submitted it, or the
+/// network's if that's lower.
+#[derive(Debug)]
+pub struct Share<'s> {
+	pub worker: &'s str,
+	pub notify: &'s Notify,
+	pub extranonce1: &'s [u8],
+	pub extranonce2: &'s [u8],
+	pub header: bitcoin::block::Header,
+}
+
+/// Statistics for a connected worker.
+#[derive(Debug, Clone)]
+pub struct Worker {
+	pub name: String,
+	pub peer: SocketAddr,
+	pub difficulty: f64,
+	/// Hash rate estimated from the worker's accepted shares
+	pub hash_rate: f64,
+	pub accepted: u64,
+	pub rejected: u64,
+}
+
+/// A Stratum V1 server for external miners, with jobs from a [`Source`].
+#[derive(Debug)]
+pub struct Server<S> {
+	source: S,
+	/// The difficulty new workers start at
+	difficulty: f64,
+	/// How worker difficulty is adjusted, if it isn't fixed
+	vardiff: Option<Vardiff>,
+	sessions: Mutex<HashMap<u64, Arc<Session>>>,
+	next_session: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Session {
+	id: u64,
+	peer: SocketAddr,
+	extranonce1: Vec<u8>,
+	extranonce2_size: usize,
+	writer: Mutex<TcpStream>,
+	state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+	subscribed: bool,
+	worker: Option<String>,
+	difficulty: f64,
+	/// The target of the last `mining.set_difficulty`
+	target: Option<bitcoin::Target>,
+	version_mask: u32,
+	/// Recent jobs, with the target their shares have to meet
+	jobs: VecDeque<(Arc<Notify>, bitcoin::Target)>,
+	/// Hashes of the shares submitted since the last clean job
+	shares: HashSet<bitcoin::BlockHash>,
+	controller: Controller,
+	accepted: u64,
+	rejected: u64,
+}
+
+impl<S: Source> Server<S> {
+	#[must_use]
+	pub fn new(source: S, difficulty: f64) -> Self {
+		Self {
+			source,
+			difficulty,
+			vardiff: None,
+			sessions: Mutex::default(),
+			next_session: AtomicU64::new(0),
+		}
+	}
+
+	/// Adjusts each worker's difficulty to keep its share rate near the target,
+	/// starting from the server's difficulty.
+	#[must_use]
+	pub fn with_vardiff(mut self, vardiff: Vardiff) -> Self {
+		self.vardiff = Some(vardiff);
+		self
+	}
+
+	pub fn source(&self) -> &S {
+		&self.source
+	}
+
+	/// Accepts workers on `listener`, serving each on its own thread.
+	pub fn listen(self: &Arc<Self>, listener: &TcpListener) -> ! {
+		std::thread::spawn({
+			let server = Arc::clone(self);
+
+			move || server.retarget()
+		});
+
+		loop {
+			match listener.accept() {
+				Ok((stream, peer)) => {
+					let server = Arc::clone(self);
+
+					std::thread::spawn(move || server.serve(stream, peer));
+				}
+				Err(e) => tracing::warn!(error = %e, "failed to accept stratum connection"),
+			}
+		}
+	}
+
+	/// Sends the current job to every worker, after the source's jobs change.
+	pub fn broadcast(&self) {
+		let sessions = lock(&self.sessions).values().cloned().collect::<Vec<_>>();
+
+		for session in sessions {
+			if let Err(e) = self.send_job(&session) {
+				tracing::debug!(error = %e, peer = %session.peer, "failed to send job");
+			}
+		}
+	}
+
+	/// Disconnects every worker, so they subscribe again after the source's
+	/// extranonce sizes change.
+	pub fn disconnect_all(&self) {
+		let sessions = lock(&self.sessions).values().cloned().collect::<Vec<_>>();
+
+		for session in sessions {
+			// the connection's thread cleans up once its read fails
+			if let Err(e) = lock(&session.writer).shutdown(Shutdown::Both) {
+				tracing::debug!(error = %e, peer = %session.peer, "failed to disconnect worker");
+			}
+		}
+	}
+
+	/// Statistics for every authorized worker.
+	pub fn workers(&self) -> Vec<Worker> {
+		let sessions = lock(&self.sessions).values().cloned().collect::<Vec<_>>();
+
+		sessions
+			.iter()
+			.filter_map(|session| {
+				let state = lock(&session.state);
+
+				Some(Worker {
+					name: state.worker.clone()?,
+					peer: session.peer,
+					difficulty: state.difficulty,
+					hash_rate: state.controller.hash_rate,
+					accepted: state.accepted,
+					rejected: state.rejected,
+				})
+			})
+			.collect()
+	}
+
+	/// Updates every worker's hash rate estimate and difficulty once per
+	/// interval, logging their statistics.
+	fn retarget(&self) -> ! {
+		loop {
+			std::thread::sleep(RETARGET_INTERVAL);
+
+			let sessions = lock(&self.sessions).values().cloned().collect::<Vec<_>>();
+
+			for session in sessions {
+				let mut state = lock(&session.state);
+
+				if state.worker.is_none() {
+					continue;
+				}
+
+				let difficulty = state.difficulty;
+
+				if let Some(updated) = state.controller.retarget(difficulty, self.vardiff.as_ref())
+				{
+					state.difficulty = updated;
+				}
+
+				tracing::info!(
+					worker = state.worker.as_deref(),
+					peer = %session.peer,
+					difficulty = state.difficulty,
+					rate = state.controller.hash_rate,
+					rate_pretty = format_rate(state.controller.hash_rate),
+					accepted = state.accepted,
+					rejected = state.rejected,
+					"worker status"
+				);
+
+				drop(state);
+
+				if let Err(e) = self.send_job(&session) {
+					tracing::debug!(error = %e, peer = %session.peer, "failed to send difficulty");
+				}
+			}
+		}
+	}
+
+	fn serve(&self, stream: TcpStream, peer: SocketAddr) {
+		let Some((extranonce1, extranonce2_size)) = self.source.extranonce() else {
+			tracing::warn!(%peer, "no extranonce space left for worker");
+			return;
+		};
+
+		let session = match stream.try_clone() {
+			Ok(writer) => Arc::new(Session {
+				id: self.next_session.fetch_add(1, Ordering::Relaxed),
+				peer,
+				extranonce1,
+				extranonce2_size,
+				writer: Mutex::new(writer),
+				state: Mutex::new(State {
+					subscribed: false,
+					worker: None,
+					difficulty: self.difficulty,
+					target: None,
+					version_mask: 0,
+					jobs: VecDeque::new(),
+					shares: HashSet::new(),
+					controller: Controller::new(),
+					accepted: 0,
+					rejected: 0,
+				}),
+			}),
+			Err(e) => {
+				tracing::warn!(error = %e, %peer, "failed to set up stratum connection");
+				return;
+			}
+		};
+
+		tracing::debug!(%peer, "worker connected");
+
+		lock(&self.sessions).insert(session.id, Arc::clone(&session));
+
+		let Err(e) = self.handle_connection(&session, stream);
+
+		lock(&self.sessions).remove(&session.id);
+		self.source.release(&session.extranonce1);
+
+		tracing::info!(error = %e, %peer, worker = ?lock(&session.state).worker, "worker disconnected");
+	}
+
+	fn handle_connection(&self, session: &Session, stream: TcpStream) -> Result<!, Error> {
+		stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+		stream.set_nodelay(true)?;
+
+		let mut reader = BufReader::new(stream);
+
+		loop {
+			let Message::Request { id, method, params } = read(&mut reader)? else {
+				continue;
+			};
+
+			let response = match self.handle(session, &method, &params) {
+				Ok(result) => Message::result(id.clone(), result),
+				Err(Error::Io(e)) => return Err(Error::Io(e)),
+				Err(Error::Rejected { code, message }) => {
+					Message::error(id.clone(), code, &message)
+				}
+				Err(e) => Message::error(id.clone(), OTHER, &e.to_string()),
+			};
+
+			if !id.is_null() {
+				session.send(&response)?;
+			}
+
+			// jobs can only be sent once the worker has an extranonce and a name,
+			// and a share can change the worker's difficulty
+			if matches!(
+				method.as_str(),
+				"mining.subscribe"
+					| "mining.authorize"
+					| "mining.suggest_difficulty"
+					| "mining.submit"
+			) {
+				self.send_job(session)?;
+			}
+		}
+	}
+
+	fn handle(&self, session: &Session, method: &str, params: &Value) -> Result<Value, Error> {
+		match method {
+			"mining.subscribe" => {
+				lock(&session.state).subscribed = true;
+
+				let subscription = format!("{:x}", session.id);
+
+				Ok(json!([
+					[
+						["mining.set_difficulty", subscription],
+						["mining.notify", subscription],
+					],
+					hex::encode(&session.extranonce1),
+					session.extranonce2_size,
+				]))
+			}
+			"mining.authorize" => {
+				let Some(worker) = params.get(0).and_then(Value::as_str) else {
+					return Err(rejected(UNAUTHORIZED, "missing worker name"));
+				};
+
+				self.source.authorize(worker)?;
+
+				tracing::info!(worker, peer = %session.peer, "worker authorized");
+
+				lock(&session.state).worker = Some(worker.to_string());
+
+				Ok(Value::Bool(true))
+			}
+			"mining.configure" => Ok(self.configure(session, params)),
+			"mining.extranonce.subscribe" => Ok(Value::Bool(true)),
+			"mining.suggest_difficulty" => {
+				if let Some(difficulty) = params
+					.get(0)
+					.and_then(Value::as_f64)
+					.filter(|difficulty| *difficulty > 0.0)
+				{
+					lock(&session.state).difficulty = difficulty;
+				}
+
+				Ok(Value::Bool(true))
+			}
+			"mining.submit" => {
+				let result = self.submit(session, params);
+				let mut state = lock(&session.state);
+
+				if result.is_ok() {
+					state.accepted += 1;
+				} else {
+					state.rejected += 1;
+				}
+
+				result.map(|()| Value::Bool(true))
+			}
+			_ => Err(rejected(OTHER, "unsupported method")),
+		}
+	}
+
+	/// Negotiates BIP310 extensions, of which only version rolling is supported.
+	fn configure(&self, session: &Session, params: &Value) -> Value {
+		let extensions = params.get(0).and_then(Value::as_array);
+		let mut result = serde_json::Map::new();
+
+		let allowed = self.source.version_rolling_mask();
+
+		for extension in extensions.into_iter().flatten().filter_map(Value::as_str) {
+			if extension == "version-rolling" && allowed != 0 {
+				let requested = params[1]
+					.get("version-rolling.mask")
+					.and_then(Value::as_str)
+					.and_then(|mask| decode_hex::<4>(mask).ok())
+					.map_or(u32::MAX, u32::from_be_bytes);
+				let mask = requested & allowed;
+
+				lock(&session.state).version_mask = mask;
+
+				result.insert(extension.to_string(), Value::Bool(true));
+				result.insert(
+					"version-rolling.mask".to_string(),
+					format!("{mask:08x}").into(),
+				);
+			} else {
+				result.insert(extension.to_string(), Value::Bool(false));
+			}
+		}
+
+		Value::Object(result)
+	}
+
+	/// Validates a `mining.submit`, passing it to the source if it meets the
+	/// worker's difficulty or the network's.
+	fn submit(&self, session: &Session, params: &Value) -> Result<(), Error> {
+		let param = |i: usize| params.get(i).and_then(Value::as_str);
+		let (Some(worker), Some(job_id), Some(extranonce2), Some(time), Some(nonce)) =
+			(param(0), param(1), param(2), param(3), param(4))
+		else {
+			return Err(rejected(OTHER, "invalid parameters"));
+		};
+
+		let mut state = lock(&session.state);
+
+		if !state.subscribed {
+			return Err(rejected(NOT_SUBSCRIBED, "not subscribed"));
+		}
+
+		if state.worker.as_deref() != Some(worker) {
+			return Err(rejected(UNAUTHORIZED, "unauthorized worker"));
+		}
+
+		let Some((notify, target)) = state
+			.jobs
+			.iter()
+			.find(|(notify, _)| notify.job_id == job_id)
+			.cloned()
+		else {
+			return Err(rejected(JOB_NOT_FOUND, "job not found"));
+		};
+
+		let extranonce2 = hex::decode(extranonce2).map_err(super::invalid_hex)?;
+
+		if extranonce2.len() != session.extranonce2_size {
+			return Err(rejected(OTHER, "invalid extranonce2 size"));
+		}
+
+		let time = u32::from_be_bytes(decode_hex(time)?);
+
+		if time < notify.time || time - notify.time > MAX_TIME_ROLL {
+			return Err(rejected(OTHER, "ntime out of range"));
+		}
+
+		let mut header = notify.header(
+			&session.extranonce1,
+			&extranonce2,
+			time,
+			u32::from_be_bytes(decode_hex(nonce)?),
+		);
+
+		if let Some(bits) = param(5) {
+			let bits = u32::from_be_bytes(decode_hex(bits)?);
+
+			if bits & !state.version_mask != 0 {
+				return Err(rejected(OTHER, "invalid version bits"));
+			}
+
+			#[allow(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+			let version = (notify.version as u32 & !state.version_mask) | bits;
+
+			#[allow(clippy::cast_possible_wrap)]
+			let version = bitcoin::block::Version::from_consensus(version as i32);
+
+			header.version = version;
+		}
+
+		let hash = header.block_hash();
+
+		if !state.shares.insert(hash) {
+			return Err(rejected(DUPLICATE_SHARE, "duplicate share"));
+		}
+
+		// the worker's difficulty can be above the network's, on regtest or
+		// once vardiff raises it, and a block is never thrown away
+		let block = bitcoin::Target::from_compact(notify.bits).is_met_by(hash);
+		let share = target.is_met_by(hash);
+
+		if !share && !block {
+			return Err(rejected(LOW_DIFFICULTY, "low difficulty share"));
+		}
+
+		// shares for older jobs count at the difficulty they were sent with
+		if share
+			&& state
+				.controller
+				.record(target.difficulty_float(), self.vardiff.as_ref())
+		{
+			let difficulty = state.difficulty;
+
+			if let Some(updated) = state.controller.retarget(difficulty, self.vardiff.as_ref()) {
+				tracing::debug!(worker, difficulty = updated, "raising worker difficulty");
+
+				state.difficulty = updated;
+			}
+		}
+
+		drop(state);
+
+		tracing::debug!(worker, job = job_id, ?hash, "share accepted");
+
+		self.source.submit(&Share {
+			worker,
+			notify: &notify,
+			extranonce1: &session.extranonce1,
+			extranonce2: &extranonce2,
+			header,
+		})
+	}
+
+	/// Sends the worker its current job, preceded by its difficulty if it changed.
+	fn send_job(&self, session: &Session) -> Result<(), Error> {
+		let mut state = lock(&session.state);
+
+		if !state.subscribed {
+			return Ok(());
+		}
+
+		let Some(notify) = state
+			.worker
+			.as_deref()
+			.and_then(|worker| self.source.job(worker))
+		else {
+			return Ok(());
+		};
+
+		let target = work::target_from_difficulty(state.difficulty);
+
+		// a worker that sent several requests may already have this job
+		if state
+			.jobs
+			.back()
+			.is_some_and(|(job, job_target)| Arc::ptr_eq(job, &notify) && *job_target == target)
+		{
+			return Ok(());
+		}
+
+		if state.target != Some(target) {
+			session.send(&Message::notification(
+				"mining.set_difficulty",
+				json!([state.difficulty]),
+			))?;
+			state.target = Some(target);
+		}
+
+		// shares for older jobs are stale once the previous block changes, but
+		// a job is only resent with a new target when the difficulty changes
+		if notify.clean && !state.jobs.iter().any(|(job, _)| Arc::ptr_eq(job, &notify)) {
+			state.jobs.clear();
+			state.shares.clear();
+		}
+
+		state.jobs.retain(|(job, _)| job.job_id != notify.job_id);
+
+		if state.jobs.len() == MAX_JOBS {
+			state.jobs.pop_front();
+		}
+
+		state.jobs.push_back((Arc::clone(&notify), target));
+
+		session.send(&Message::notification("mining.notify", notify.to_params()))
+	}
+}
+
+impl Session {
+	fn send(&self, message: &Message) -> Result<(), Error> {
+		write(&*lock(&self.writer), message)
+	}
+}
+
+fn rejected(code: i64, message: &str) -> Error {
+	Error::Rejected {
+		code,
+		message: message.to_string(),
+	}
+}
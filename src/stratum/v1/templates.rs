@@ -0,0 +1,332 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use super::{lock, Notify, Server, Share, Source, UNAUTHORIZED};
+use crate::{block, rpc, stratum::Error};
+
+/// The size of the extranonce space in the coinbase scriptSig
+const EXTRANONCE_SIZE: usize = 8;
+/// How much of the extranonce space is handed out per connection
+const EXTRANONCE1_SIZE: usize = 4;
+/// How many templates are kept around for assembling blocks from late shares
+const MAX_ROUNDS: usize = 16;
+/// How long to wait before retrying a failed template request
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A [`Source`] that turns block templates from a node into jobs, so the
+/// [`Server`] acts as a solo pool that submits blocks to the node.
+///
+/// Workers that authorize with an address as their name, optionally followed
+/// by `.` and a suffix, get jobs paying that address instead of the operator's.
+#[derive(Debug)]
+pub struct Templates {
+	rpc: rpc::Client,
+	payout: bitcoin::Address,
+	/// The operator's cut of rewards paid to worker addresses, in basis points
+	fee: u64,
+	rounds: Mutex<Rounds>,
+	next_extranonce1: AtomicU32,
+}
+
+#[derive(Debug, Default)]
+struct Rounds {
+	/// Recent templates, newest last
+	rounds: VecDeque<Round>,
+	next_id: u64,
+}
+
+/// A template, along with the jobs built from it for each payout.
+#[derive(Debug)]
+struct Round {
+	id: u64,
+	/// The coinbase without its payouts or witness
+	coinbase: bitcoin::Transaction,
+	coinbase_value: u64,
+	height: u32,
+	previous_block: bitcoin::BlockHash,
+	merkle_branch: Vec<[u8; 32]>,
+	version: i32,
+	bits: bitcoin::CompactTarget,
+	time: u32,
+	clean: bool,
+	target: bitcoin::Target,
+	/// The transactions after the coinbase
+	transactions: Vec<bitcoin::Transaction>,
+	/// Whether the coinbase needs the witness reserved value
+	segwit: bool,
+	/// Jobs by payout script, built when a worker first asks for one
+	jobs: HashMap<bitcoin::ScriptBuf, Arc<Notify>>,
+}
+
+impl Templates {
+	/// Creates a source paying block rewards to `payout`, unless a worker
+	/// authorizes with its own address.
+	#[must_use]
+	pub fn new(rpc: rpc::Client, payout: bitcoin::Address) -> Self {
+		Self {
+			rpc,
+			payout,
+			fee: 0,
+			rounds: Mutex::default(),
+			next_extranonce1: AtomicU32::new(0),
+		}
+	}
+
+	/// Pays `percent` of the reward to the operator's address when a block is
+	/// found by a worker mining to its own address.
+	#[must_use]
+	#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+	pub fn with_fee(mut self, percent: f64) -> Self {
+		self.fee = (percent.clamp(0.0, 100.0) * 100.0).round() as u64;
+		self
+	}
+
+	/// Fetches templates from the node with longpoll, sending a new job to
+	/// every worker whenever the template changes.
+	pub fn poll(server: &Server<Self>) -> ! {
+		let mut poll_id = None;
+
+		loop {
+			let mut template = match server.source().rpc.get_block_template(poll_id.as_deref()) {
+				Ok(template) => template,
+				Err(e) => {
+					tracing::warn!(error = %e, "failed to fetch block template");
+					std::thread::sleep(RETRY_INTERVAL);
+					continue;
+				}
+			};
+
+			poll_id = Some(std::mem::take(&mut template.longpoll_id));
+
+			match server.source().update(&template) {
+				Ok(()) => server.broadcast(),
+				Err(e) => tracing::warn!(error = %e, "invalid block template"),
+			}
+		}
+	}
+
+	/// Makes a template the current one that jobs are built from.
+	///
+	/// # Errors
+	/// Returns an error if the template contains an invalid transaction.
+	pub fn update(
+		&self,
+		template: &block::Template,
+	) -> Result<(), bitcoin::consensus::encode::Error> {
+		let transactions = template.transactions()?;
+		let mut coinbase = template.coinbase_with_outputs(Vec::new(), [0; EXTRANONCE_SIZE]);
+		let segwit = !coinbase.input[0].witness.is_empty();
+
+		// jobs carry the coinbase without its witness, which is how its txid is computed
+		coinbase.input[0].witness.clear();
+
+		let mut rounds = lock(&self.rounds);
+		let clean = rounds
+			.rounds
+			.back()
+			.is_none_or(|round| round.previous_block != template.previous_block);
+
+		if clean {
+			rounds.rounds.clear();
+		} else if rounds.rounds.len() == MAX_ROUNDS {
+			rounds.rounds.pop_front();
+		}
+
+		tracing::debug!(
+			round = rounds.next_id,
+			height = template.height,
+			clean,
+			"new block template"
+		);
+
+		let id = rounds.next_id;
+
+		rounds.next_id += 1;
+		rounds.rounds.push_back(Round {
+			id,
+			coinbase,
+			coinbase_value: template.coinbase_value,
+			height: template.height,
+			previous_block: template.previous_block,
+			merkle_branch: template.merkle_branch(),
+			version: template.version,
+			bits: template.bits(),
+			time: template.current_time,
+			clean,
+			target: template.target(),
+			transactions,
+			segwit,
+			jobs: HashMap::new(),
+		});
+
+		Ok(())
+	}
+
+	/// The address a worker is paid to, if its name starts with one.
+	fn worker_address(&self, worker: &str) -> Result<Option<bitcoin::Address>, Error> {
+		let name = worker
+			.split_once('.')
+			.map_or(worker, |(address, _)| address);
+		let Ok(address) = name.parse::<bitcoin::Address<_>>() else {
+			return Ok(None);
+		};
+
+		address
+			.require_network(*self.payout.network())
+			.map(Some)
+			.map_err(|_| Error::Rejected {
+				code: UNAUTHORIZED,
+				message: format!("address is not for {}", self.payout.network()),
+			})
+	}
+
+	/// The coinbase outputs paying a block's reward to `script_pubkey`, less
+	/// the operator's fee if it isn't the operator's own address.
+	fn payouts(&self, round: &Round, script_pubkey: &bitcoin::ScriptBuf) -> Vec<bitcoin::TxOut> {
+		let fee = if *script_pubkey == self.payout.script_pubkey() {
+			0
+		} else {
+			// split to avoid overflowing, since the fee is at most 10,000
+			round.coinbase_value / 10_000 * self.fee
+				+ round.coinbase_value % 10_000 * self.fee / 10_000
+		};
+		let mut outputs = vec![bitcoin::TxOut {
+			value: bitcoin::Amount::from_sat(round.coinbase_value - fee),
+			script_pubkey: script_pubkey.clone(),
+		}];
+
+		if fee > 0 {
+			outputs.push(bitcoin::TxOut {
+				value: bitcoin::Amount::from_sat(fee),
+				script_pubkey: self.payout.script_pubkey(),
+			});
+		}
+
+		outputs
+	}
+
+	/// Builds the job for a round that pays `script_pubkey`.
+	fn build_job(&self, round: &Round, script_pubkey: &bitcoin::ScriptBuf) -> Notify {
+		let mut coinbase = round.coinbase.clone();
+
+		// the witness commitment, if any, stays after the payouts
+		coinbase
+			.output
+			.splice(0..0, self.payouts(round, script_pubkey));
+
+		let serialized = bitcoin::consensus::serialize(&coinbase);
+		let script_sig = &coinbase.input[0].script_sig;
+		// the extranonce is at the end of the scriptSig, which follows the
+		// version, input count and previous output
+		let end = 4 + 1 + 36 + bitcoin::VarInt(script_sig.len() as u64).size() + script_sig.len();
+		let start = end - EXTRANONCE_SIZE;
+
+		let notify = Notify {
+			job_id: format!("{:x}.{:x}", round.id, round.jobs.len()),
+			previous_block: round.previous_block,
+			coinbase1: serialized[..start].to_vec(),
+			coinbase2: serialized[end..].to_vec(),
+			merkle_branch: round.merkle_branch.clone(),
+			version: round.version,
+			bits: round.bits,
+			time: round.time,
+			clean: round.clean,
+		};
+
+		tracing::debug!(job = %notify.job_id, height = round.height, clean = round.clean, "new stratum job");
+
+		notify
+	}
+}
+
+impl Source for Templates {
+	fn extranonce(&self) -> Option<(Vec<u8>, usize)> {
+		let extranonce1 = self.next_extranonce1.fetch_add(1, Ordering::Relaxed);
+
+		Some((
+			extranonce1.to_be_bytes().to_vec(),
+			EXTRANONCE_SIZE - EXTRANONCE1_SIZE,
+		))
+	}
+
+	fn authorize(&self, worker: &str) -> Result<(), Error> {
+		if let Some(address) = self.worker_address(worker)? {
+			tracing::info!(worker, %address, "paying worker's blocks to its address");
+		}
+
+		Ok(())
+	}
+
+	fn job(&self, worker: &str) -> Option<Arc<Notify>> {
+		let script_pubkey = match self.worker_address(worker) {
+			Ok(Some(address)) => address.script_pubkey(),
+			_ => self.payout.script_pubkey(),
+		};
+
+		let mut rounds = lock(&self.rounds);
+		let round = rounds.rounds.back_mut()?;
+
+		if let Some(notify) = round.jobs.get(&script_pubkey) {
+			return Some(Arc::clone(notify));
+		}
+
+		let notify = Arc::new(self.build_job(round, &script_pubkey));
+
+		round.jobs.insert(script_pubkey, Arc::clone(&notify));
+
+		Some(notify)
+	}
+
+	fn submit(&self, share: &Share) -> Result<(), Error> {
+		let hash = share.header.block_hash();
+		let rounds = lock(&self.rounds);
+		let Some(round) = rounds.rounds.iter().find(|round| {
+			round
+				.jobs
+				.values()
+				.any(|notify| notify.job_id == share.notify.job_id)
+		}) else {
+			// the job is from before the previous block changed
+			return Ok(());
+		};
+
+		if !round.target.is_met_by(hash) {
+			return Ok(());
+		}
+
+		let mut coinbase: bitcoin::Transaction = bitcoin::consensus::deserialize(
+			&share.notify.coinbase(share.extranonce1, share.extranonce2),
+		)
+		.map_err(|e| Error::Protocol(format!("invalid coinbase: {e}")))?;
+
+		if round.segwit {
+			coinbase.input[0].witness.push([0; 32]);
+		}
+
+		let mut txdata = Vec::with_capacity(round.transactions.len() + 1);
+
+		txdata.push(coinbase);
+		txdata.extend(round.transactions.iter().cloned());
+		drop(rounds);
+
+		let block = bitcoin::Block {
+			header: share.header,
+			txdata,
+		};
+
+		tracing::info!(worker = share.worker, ?hash, "found block hash");
+
+		match self.rpc.submit_block(&block) {
+			Ok(()) => tracing::info!(?hash, "block accepted"),
+			Err(e) => tracing::error!(?hash, error = %e, "block rejected"),
+		}
+
+		Ok(())
+	}
+}
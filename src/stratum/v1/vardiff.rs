@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+
+/// How long shares are counted before a worker's difficulty is reconsidered
+pub(super) const RETARGET_INTERVAL: Duration = Duration::from_mins(1);
+/// How far the share rate can drift from the target before the difficulty
+/// changes, so that noise in the share rate doesn't cause constant updates
+const HYSTERESIS: f64 = 2.0;
+/// The most the difficulty changes in a single update
+const MAX_STEP: f64 = 4.0;
+/// How many times the expected shares for a whole interval a worker can submit
+/// before its difficulty is raised early
+const EARLY_RETARGET: f64 = 4.0;
+
+/// Settings for adjusting each worker's difficulty to its hash rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Vardiff {
+	/// The share rate to aim for
+	pub shares_per_minute: f64,
+	pub minimum: f64,
+	pub maximum: f64,
+}
+
+impl Default for Vardiff {
+	fn default() -> Self {
+		Self {
+			shares_per_minute: 10.0,
+			minimum: 1.0 / 65536.0,
+			maximum: f64::MAX,
+		}
+	}
+}
+
+impl Vardiff {
+	/// The difficulty that brings the share rate back to the target, or `None`
+	/// if it's close enough already.
+	fn retarget(&self, difficulty: f64, shares: u32, elapsed: Duration) -> Option<f64> {
+		let rate = f64::from(shares) / (elapsed.as_secs_f64() / 60.0);
+		let ratio = rate / self.shares_per_minute;
+
+		if (1.0 / HYSTERESIS..=HYSTERESIS).contains(&ratio) {
+			return None;
+		}
+
+		let updated =
+			(difficulty * ratio.clamp(1.0 / MAX_STEP, MAX_STEP)).clamp(self.minimum, self.maximum);
+
+		#[allow(clippy::float_cmp)]
+		(updated != difficulty).then_some(updated)
+	}
+}
+
+/// Tracks a worker's accepted shares, for its hash rate and share rate.
+#[derive(Debug)]
+pub(super) struct Controller {
+	window_start: Instant,
+	/// Shares accepted since the window started
+	shares: u32,
+	/// The sum of the difficulty of those shares
+	work: f64,
+	/// Hash rate estimate, smoothed over past windows
+	pub hash_rate: f64,
+}
+
+impl Controller {
+	pub fn new() -> Self {
+		Self {
+			window_start: Instant::now(),
+			shares: 0,
+			work: 0.0,
+			hash_rate: 0.0,
+		}
+	}
+
+	/// Counts an accepted share, returning whether the worker is submitting
+	/// so quickly that its difficulty should be raised without waiting for
+	/// the interval to end.
+	pub fn record(&mut self, difficulty: f64, vardiff: Option<&Vardiff>) -> bool {
+		self.shares += 1;
+		self.work += difficulty;
+
+		vardiff.is_some_and(|vardiff| {
+			let expected = vardiff.shares_per_minute * RETARGET_INTERVAL.as_secs_f64() / 60.0;
+
+			f64::from(self.shares) >= expected * EARLY_RETARGET
+		})
+	}
+
+	/// Ends the current window, updating the hash rate estimate, and returns
+	/// the worker's new difficulty if it should change.
+	pub fn retarget(&mut self, difficulty: f64, vardiff: Option<&Vardiff>) -> Option<f64> {
+		let now = Instant::now();
+		let elapsed = now - self.window_start;
+
+		if elapsed.is_zero() {
+			return None;
+		}
+
+		// a difficulty 1 share takes 2^32 hashes on average
+		let hash_rate = self.work * 2f64.powi(32) / elapsed.as_secs_f64();
+
+		self.hash_rate = if self.hash_rate == 0.0 {
+			hash_rate
+		} else {
+			f64::midpoint(self.hash_rate, hash_rate)
+		};
+
+		let updated =
+			vardiff.and_then(|vardiff| vardiff.retarget(difficulty, self.shares, elapsed));
+
+		self.window_start = now;
+		self.shares = 0;
+		self.work = 0.0;
+
+		updated
+	}
+}
@@ -0,0 +1,504 @@
+use std::{
+	collections::HashMap,
+	net::TcpStream,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+		mpsc, Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use super::{noise, AuthorityKey, Merkle, Message, MiningJob, PrevHash, Work, VERSION};
+use crate::{
+	stratum::{lock, v1::USER_AGENT, Error},
+	work,
+};
+
+/// How long to wait before reconnecting to the pool
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// The hash rate reported when opening a channel, which pools use for the
+/// first target before adjusting it to the shares we submit
+const NOMINAL_HASH_RATE: f32 = 1e9;
+/// The extranonce size asked for on extended channels
+const EXTRANONCE_SIZE: u16 = 4;
+/// `SetupConnection` flag for devices that only understand standard jobs
+const REQUIRES_STANDARD_JOBS: u32 = 1;
+
+/// The kind of channel to open, which decides who builds the coinbase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Channel {
+	/// The pool sends a merkle root for every job
+	#[default]
+	Standard,
+	/// The pool sends the coinbase and merkle path, and we roll part of the
+	/// extranonce
+	Extended,
+}
+
+/// A Stratum V2 pool to mine shares for, reconnecting whenever the
+/// connection drops.
+#[derive(Debug, Clone)]
+pub struct Pool {
+	/// The pool address with its authority key, e.g.
+	/// `stratum2+tcp://pool.example.com:34254/9bXiEd8boQVhq7WddEcERUL5tyyJVFYdU8th3HfbNXK3Yw6GRXh`
+	pub url: String,
+	pub worker: String,
+	pub channel: Channel,
+}
+
+impl Pool {
+	/// Sends a job for every job from the pool, reconnecting if the
+	/// connection is lost.
+	///
+	/// # Errors
+	/// Returns an error if the pool refuses the connection or the channel.
+	pub fn run(&self, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		loop {
+			match Client::connect(&self.url, &self.worker, self.channel) {
+				Ok(client) => {
+					tracing::info!(url = %self.url, worker = %self.worker, "connected to pool");
+
+					let Err(e) = client.run(jobs);
+
+					tracing::warn!(error = %e, url = %self.url, "pool connection lost");
+				}
+				Err(e @ Error::Refused(..)) => return Err(e),
+				Err(e) => tracing::warn!(error = %e, url = %self.url, "failed to connect to pool"),
+			}
+
+			std::thread::sleep(RECONNECT_INTERVAL);
+		}
+	}
+}
+
+/// An encrypted session with a pool, with one open channel.
+#[derive(Debug)]
+pub struct Client {
+	reader: Mutex<noise::Reader>,
+	writer: Mutex<noise::Writer>,
+	session: Mutex<Session>,
+	next_sequence: AtomicU32,
+	extranonce: AtomicU64,
+	accepted: AtomicU64,
+	rejected: AtomicU64,
+	closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Session {
+	channel_id: u32,
+	target: bitcoin::Target,
+	extranonce_prefix: Vec<u8>,
+	/// The size of the extranonce we roll, which is zero on standard channels
+	extranonce_size: usize,
+	/// Jobs for the current or next block, by id
+	jobs: HashMap<u32, Arc<MiningJob>>,
+	prev_hash: Option<PrevHash>,
+	/// The job being mined
+	job: Option<Arc<MiningJob>>,
+}
+
+impl Client {
+	/// Connects to a pool, sets up the connection and opens a channel for `worker`.
+	///
+	/// # Errors
+	/// Returns an error if the pool cannot be reached or authenticated, or if
+	/// it refuses the connection or the channel.
+	pub fn connect(url: &str, worker: &str, channel: Channel) -> Result<Arc<Self>, Error> {
+		let url = url.strip_prefix("stratum2+tcp://").unwrap_or(url);
+		let (address, authority) = match url.split_once('/') {
+			Some((address, authority)) => (address, Some(authority.parse::<AuthorityKey>()?)),
+			None => (url, None),
+		};
+
+		let stream = TcpStream::connect(address)?;
+
+		stream.set_nodelay(true)?;
+
+		let (host, port) = address.rsplit_once(':').unwrap_or((address, ""));
+		let (reader, writer) = noise::connect(stream, authority.as_ref().map(|key| &key.0))?;
+		let client = Self {
+			reader: Mutex::new(reader),
+			writer: Mutex::new(writer),
+			session: Mutex::new(Session {
+				channel_id: 0,
+				target: bitcoin::Target::MAX,
+				extranonce_prefix: Vec::new(),
+				extranonce_size: 0,
+				jobs: HashMap::new(),
+				prev_hash: None,
+				job: None,
+			}),
+			next_sequence: AtomicU32::new(0),
+			extranonce: AtomicU64::new(0),
+			accepted: AtomicU64::new(0),
+			rejected: AtomicU64::new(0),
+			closed: AtomicBool::new(false),
+		};
+
+		client.send(&Message::SetupConnection {
+			protocol: 0,
+			min_version: VERSION,
+			max_version: VERSION,
+			flags: if channel == Channel::Standard {
+				REQUIRES_STANDARD_JOBS
+			} else {
+				0
+			},
+			endpoint_host: host.to_string(),
+			endpoint_port: port.parse().unwrap_or_default(),
+			vendor: "miner".to_string(),
+			hardware_version: String::new(),
+			firmware: USER_AGENT.to_string(),
+			device_id: String::new(),
+		})?;
+
+		match client.receive()? {
+			Message::SetupConnectionSuccess { .. } => {}
+			Message::SetupConnectionError { error_code, .. } => {
+				return Err(Error::Refused(error_code))
+			}
+			message => return Err(unexpected(&message)),
+		}
+
+		client.open_channel(worker, channel)?;
+
+		Ok(Arc::new(client))
+	}
+
+	/// Opens the channel that jobs and shares are sent on.
+	fn open_channel(&self, worker: &str, channel: Channel) -> Result<(), Error> {
+		self.send(&match channel {
+			Channel::Standard => Message::OpenStandardMiningChannel {
+				request_id: 0,
+				user_identity: worker.to_string(),
+				nominal_hash_rate: NOMINAL_HASH_RATE,
+				max_target: bitcoin::Target::from_le_bytes([0xff; 32]),
+			},
+			Channel::Extended => Message::OpenExtendedMiningChannel {
+				request_id: 0,
+				user_identity: worker.to_string(),
+				nominal_hash_rate: NOMINAL_HASH_RATE,
+				max_target: bitcoin::Target::from_le_bytes([0xff; 32]),
+				min_extranonce_size: EXTRANONCE_SIZE,
+			},
+		})?;
+
+		{
+			let mut session = lock(&self.session);
+
+			match self.receive()? {
+				Message::OpenStandardMiningChannelSuccess {
+					channel_id,
+					target,
+					extranonce_prefix,
+					..
+				} => {
+					session.channel_id = channel_id;
+					session.target = target;
+					session.extranonce_prefix = extranonce_prefix;
+				}
+				Message::OpenExtendedMiningChannelSuccess {
+					channel_id,
+					target,
+					extranonce_size,
+					extranonce_prefix,
+					..
+				} => {
+					session.channel_id = channel_id;
+					session.target = target;
+					session.extranonce_size = extranonce_size.into();
+					session.extranonce_prefix = extranonce_prefix;
+				}
+				Message::OpenMiningChannelError { error_code, .. } => {
+					return Err(Error::Refused(error_code))
+				}
+				message => return Err(unexpected(&message)),
+			}
+
+			tracing::debug!(
+				channel = session.channel_id,
+				target = ?session.target,
+				"opened mining channel"
+			);
+		}
+
+		Ok(())
+	}
+
+	/// Reads messages from the pool, sending a new job whenever the work or
+	/// target changes. Only returns once the connection fails.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed or the pool sends an invalid message.
+	pub fn run(self: &Arc<Self>, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		let Err(e) = self.read_jobs(jobs);
+
+		self.closed.store(true, Ordering::Relaxed);
+		Err(e)
+	}
+
+	fn read_jobs(self: &Arc<Self>, jobs: &mpsc::Sender<work::Job>) -> Result<!, Error> {
+		loop {
+			let message = self.receive()?;
+
+			if self.handle(message)? {
+				if let Some(job) = self.job() {
+					let _ = jobs.send(job);
+				}
+			}
+		}
+	}
+
+	/// The current share target.
+	#[must_use]
+	pub fn target(&self) -> bitcoin::Target {
+		lock(&self.session).target
+	}
+
+	/// The number of shares the pool has accepted.
+	#[must_use]
+	pub fn accepted(&self) -> u64 {
+		self.accepted.load(Ordering::Relaxed)
+	}
+
+	/// The number of shares the pool has rejected.
+	#[must_use]
+	pub fn rejected(&self) -> u64 {
+		self.rejected.load(Ordering::Relaxed)
+	}
+
+	/// Whether the connection has failed, after which [`Client::run`] has returned.
+	#[must_use]
+	pub fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::Relaxed)
+	}
+
+	/// Submits a share for a job. The pool's answer is logged once it arrives.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed.
+	pub fn submit(&self, work: &Work, header: &bitcoin::block::Header) -> Result<(), Error> {
+		tracing::info!(job = work.job.job_id, hash = ?header.block_hash(), "submitting share");
+
+		let sequence_number = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+		#[allow(clippy::cast_sign_loss)]
+		let version = header.version.to_consensus() as u32;
+
+		self.send(&match work.job.merkle {
+			Merkle::Root(..) => Message::SubmitSharesStandard {
+				channel_id: work.channel_id,
+				sequence_number,
+				job_id: work.job.job_id,
+				nonce: header.nonce,
+				time: header.time,
+				version,
+			},
+			Merkle::Path { .. } => Message::SubmitSharesExtended {
+				channel_id: work.channel_id,
+				sequence_number,
+				job_id: work.job.job_id,
+				nonce: header.nonce,
+				time: header.time,
+				version,
+				extranonce: work.extranonce.clone(),
+			},
+		})
+	}
+
+	/// An extranonce of `size` bytes that hasn't been used in this session.
+	pub(super) fn next_extranonce(&self, size: usize) -> Vec<u8> {
+		let counter = self
+			.extranonce
+			.fetch_add(1, Ordering::Relaxed)
+			.to_le_bytes();
+		let mut extranonce = vec![0; size];
+		let len = size.min(counter.len());
+
+		extranonce[..len].copy_from_slice(&counter[..len]);
+		extranonce
+	}
+
+	/// The current job at the current target, once the pool has said which
+	/// block it builds on.
+	fn job(self: &Arc<Self>) -> Option<work::Job> {
+		let session = lock(&self.session);
+		let work = Work {
+			client: Arc::clone(self),
+			channel_id: session.channel_id,
+			job: session.job.clone()?,
+			prev_hash: session.prev_hash?,
+			extranonce_prefix: session.extranonce_prefix.clone(),
+			extranonce: self.next_extranonce(session.extranonce_size),
+		};
+
+		Some(work.into_job(session.target))
+	}
+
+	fn send(&self, message: &Message) -> Result<(), Error> {
+		lock(&self.writer).write(&message.to_frame())
+	}
+
+	/// Reads the next supported message.
+	fn receive(&self) -> Result<Message, Error> {
+		let mut reader = lock(&self.reader);
+
+		loop {
+			let frame = reader.read()?;
+
+			if let Some(message) = Message::from_frame(&frame)? {
+				return Ok(message);
+			}
+
+			tracing::debug!(
+				extension = frame.extension_type,
+				message = frame.message_type,
+				"unsupported message from pool"
+			);
+		}
+	}
+
+	/// Handles a message from the pool, returning whether the job changed.
+	fn handle(&self, message: Message) -> Result<bool, Error> {
+		let mut session = lock(&self.session);
+
+		match message {
+			Message::NewMiningJob {
+				job_id,
+				min_time,
+				version,
+				merkle_root,
+				..
+			} => Ok(session.add_job(MiningJob {
+				job_id,
+				version,
+				min_time,
+				merkle: Merkle::Root(merkle_root),
+			})),
+			Message::NewExtendedMiningJob {
+				job_id,
+				min_time,
+				version,
+				merkle_path,
+				coinbase_prefix,
+				coinbase_suffix,
+				..
+			} => Ok(session.add_job(MiningJob {
+				job_id,
+				version,
+				min_time,
+				merkle: Merkle::Path {
+					coinbase_prefix,
+					coinbase_suffix,
+					merkle_path,
+				},
+			})),
+			Message::SetNewPrevHash {
+				job_id,
+				previous_block,
+				min_time,
+				bits,
+				..
+			} => {
+				tracing::debug!(job = job_id, ?previous_block, "new previous block");
+
+				// jobs for the previous block are stale now
+				let job = session.jobs.remove(&job_id);
+
+				session.jobs.clear();
+				session.prev_hash = Some(PrevHash {
+					job_id,
+					previous_block,
+					min_time,
+					bits,
+				});
+				session.job.clone_from(&job);
+
+				if let Some(job) = job {
+					session.jobs.insert(job_id, job);
+				}
+
+				Ok(session.job.is_some())
+			}
+			Message::SetTarget { maximum_target, .. } => {
+				tracing::info!(target = ?maximum_target, "pool target changed");
+
+				session.target = maximum_target;
+
+				Ok(true)
+			}
+			Message::SetExtranoncePrefix {
+				extranonce_prefix, ..
+			} => {
+				session.extranonce_prefix = extranonce_prefix;
+
+				Ok(true)
+			}
+			Message::SubmitSharesSuccess {
+				new_submits_accepted_count,
+				..
+			} => {
+				self.accepted
+					.fetch_add(new_submits_accepted_count.into(), Ordering::Relaxed);
+
+				tracing::info!(
+					accepted = self.accepted(),
+					rejected = self.rejected(),
+					"share accepted"
+				);
+
+				Ok(false)
+			}
+			Message::SubmitSharesError { error_code, .. } => {
+				self.rejected.fetch_add(1, Ordering::Relaxed);
+
+				tracing::warn!(
+					reason = %error_code,
+					accepted = self.accepted(),
+					rejected = self.rejected(),
+					"share rejected"
+				);
+
+				Ok(false)
+			}
+			Message::CloseChannel { reason_code, .. } => Err(Error::Protocol(format!(
+				"pool closed the channel: {reason_code}"
+			))),
+			Message::Reconnect { .. } => {
+				Err(Error::Protocol("pool asked us to reconnect".to_string()))
+			}
+			message => {
+				tracing::debug!(?message, "unexpected message from pool");
+
+				Ok(false)
+			}
+		}
+	}
+}
+
+impl Session {
+	/// Adds a job, returning whether it replaces the one being mined. Jobs
+	/// without a time are for the next block, so they wait for a `SetNewPrevHash`.
+	fn add_job(&mut self, job: MiningJob) -> bool {
+		let job = Arc::new(job);
+
+		tracing::debug!(
+			job = job.job_id,
+			future = job.min_time.is_none(),
+			"new pool job"
+		);
+
+		self.jobs.insert(job.job_id, Arc::clone(&job));
+
+		if job.min_time.is_none() {
+			return false;
+		}
+
+		self.job = Some(job);
+		true
+	}
+}
+
+fn unexpected(message: &Message) -> Error {
+	Error::Protocol(format!("unexpected message: {message:?}"))
+}
@@ -0,0 +1,897 @@
+use bitcoin::hashes::Hash as _;
+
+use super::{Frame, CHANNEL_BIT};
+use crate::stratum::Error;
+
+/// The messages of the common and mining protocols that are sent or handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+	SetupConnection {
+		/// 0 for the mining protocol, 2 for template distribution
+		protocol: u8,
+		min_version: u16,
+		max_version: u16,
+		flags: u32,
+		endpoint_host: String,
+		endpoint_port: u16,
+		vendor: String,
+		hardware_version: String,
+		firmware: String,
+		device_id: String,
+	},
+	SetupConnectionSuccess {
+		used_version: u16,
+		flags: u32,
+	},
+	SetupConnectionError {
+		flags: u32,
+		error_code: String,
+	},
+	OpenStandardMiningChannel {
+		request_id: u32,
+		user_identity: String,
+		nominal_hash_rate: f32,
+		max_target: bitcoin::Target,
+	},
+	OpenStandardMiningChannelSuccess {
+		request_id: u32,
+		channel_id: u32,
+		target: bitcoin::Target,
+		extranonce_prefix: Vec<u8>,
+		group_channel_id: u32,
+	},
+	OpenExtendedMiningChannel {
+		request_id: u32,
+		user_identity: String,
+		nominal_hash_rate: f32,
+		max_target: bitcoin::Target,
+		min_extranonce_size: u16,
+	},
+	OpenExtendedMiningChannelSuccess {
+		request_id: u32,
+		channel_id: u32,
+		target: bitcoin::Target,
+		extranonce_size: u16,
+		extranonce_prefix: Vec<u8>,
+	},
+	OpenMiningChannelError {
+		request_id: u32,
+		error_code: String,
+	},
+	CloseChannel {
+		channel_id: u32,
+		reason_code: String,
+	},
+	SetExtranoncePrefix {
+		channel_id: u32,
+		extranonce_prefix: Vec<u8>,
+	},
+	SubmitSharesStandard {
+		channel_id: u32,
+		sequence_number: u32,
+		job_id: u32,
+		nonce: u32,
+		time: u32,
+		version: u32,
+	},
+	SubmitSharesExtended {
+		channel_id: u32,
+		sequence_number: u32,
+		job_id: u32,
+		nonce: u32,
+		time: u32,
+		version: u32,
+		extranonce: Vec<u8>,
+	},
+	SubmitSharesSuccess {
+		channel_id: u32,
+		last_sequence_number: u32,
+		new_submits_accepted_count: u32,
+		new_shares_sum: u64,
+	},
+	SubmitSharesError {
+		channel_id: u32,
+		sequence_number: u32,
+		error_code: String,
+	},
+	NewMiningJob {
+		channel_id: u32,
+		job_id: u32,
+		/// Unset for future jobs, which start with a `SetNewPrevHash`
+		min_time: Option<u32>,
+		version: u32,
+		merkle_root: bitcoin::TxMerkleNode,
+	},
+	NewExtendedMiningJob {
+		channel_id: u32,
+		job_id: u32,
+		min_time: Option<u32>,
+		version: u32,
+		version_rolling_allowed: bool,
+		merkle_path: Vec<[u8; 32]>,
+		coinbase_prefix: Vec<u8>,
+		coinbase_suffix: Vec<u8>,
+	},
+	SetNewPrevHash {
+		channel_id: u32,
+		job_id: u32,
+		previous_block: bitcoin::BlockHash,
+		min_time: u32,
+		bits: bitcoin::CompactTarget,
+	},
+	SetTarget {
+		channel_id: u32,
+		maximum_target: bitcoin::Target,
+	},
+	Reconnect {
+		new_host: String,
+		new_port: u16,
+	},
+	/// How many bytes the client adds to the coinbase outputs, which templates
+	/// have to leave room for
+	CoinbaseOutputDataSize {
+		coinbase_output_max_additional_size: u32,
+	},
+	NewTemplate {
+		template_id: u64,
+		/// Set for templates that are mined once a `SetNewPrevHashTemplate`
+		/// refers to them
+		future_template: bool,
+		version: u32,
+		coinbase_tx_version: u32,
+		/// The start of the coinbase scriptSig, with the BIP34 height
+		coinbase_prefix: Vec<u8>,
+		coinbase_tx_input_sequence: u32,
+		/// The reward left for the client's own outputs
+		coinbase_tx_value_remaining: u64,
+		coinbase_tx_outputs_count: u32,
+		/// Serialized outputs the coinbase has to include, like the witness commitment
+		coinbase_tx_outputs: Vec<u8>,
+		coinbase_tx_locktime: u32,
+		merkle_path: Vec<[u8; 32]>,
+	},
+	/// `SetNewPrevHash` of the Template Distribution protocol
+	SetNewPrevHashTemplate {
+		template_id: u64,
+		previous_block: bitcoin::BlockHash,
+		header_timestamp: u32,
+		bits: bitcoin::CompactTarget,
+		target: bitcoin::Target,
+	},
+	RequestTransactionData {
+		template_id: u64,
+	},
+	RequestTransactionDataSuccess {
+		template_id: u64,
+		excess_data: Vec<u8>,
+		/// The serialized transactions after the coinbase
+		transaction_list: Vec<Vec<u8>>,
+	},
+	RequestTransactionDataError {
+		template_id: u64,
+		error_code: String,
+	},
+	SubmitSolution {
+		template_id: u64,
+		version: u32,
+		header_timestamp: u32,
+		header_nonce: u32,
+		/// The serialized coinbase
+		coinbase_tx: Vec<u8>,
+	},
+}
+
+impl Message {
+	/// The message type, and whether the message is addressed to a channel.
+	fn message_type(&self) -> (u8, bool) {
+		match self {
+			Self::SetupConnection { .. } => (0x00, false),
+			Self::SetupConnectionSuccess { .. } => (0x01, false),
+			Self::SetupConnectionError { .. } => (0x02, false),
+			Self::OpenStandardMiningChannel { .. } => (0x10, false),
+			Self::OpenStandardMiningChannelSuccess { .. } => (0x11, false),
+			Self::OpenMiningChannelError { .. } => (0x12, false),
+			Self::OpenExtendedMiningChannel { .. } => (0x13, false),
+			Self::OpenExtendedMiningChannelSuccess { .. } => (0x14, false),
+			Self::CloseChannel { .. } => (0x18, true),
+			Self::SetExtranoncePrefix { .. } => (0x19, true),
+			Self::SubmitSharesStandard { .. } => (0x1a, true),
+			Self::SubmitSharesExtended { .. } => (0x1b, true),
+			Self::SubmitSharesSuccess { .. } => (0x1c, true),
+			Self::SubmitSharesError { .. } => (0x1d, true),
+			Self::NewMiningJob { .. } => (0x1e, true),
+			Self::NewExtendedMiningJob { .. } => (0x1f, true),
+			Self::SetNewPrevHash { .. } => (0x20, true),
+			Self::SetTarget { .. } => (0x21, true),
+			Self::Reconnect { .. } => (0x25, false),
+			Self::CoinbaseOutputDataSize { .. } => (0x70, false),
+			Self::NewTemplate { .. } => (0x71, false),
+			Self::SetNewPrevHashTemplate { .. } => (0x72, false),
+			Self::RequestTransactionData { .. } => (0x73, false),
+			Self::RequestTransactionDataSuccess { .. } => (0x74, false),
+			Self::RequestTransactionDataError { .. } => (0x75, false),
+			Self::SubmitSolution { .. } => (0x76, false),
+		}
+	}
+
+	/// Serializes the message in its frame.
+	#[must_use]
+	#[allow(clippy::too_many_lines)]
+	pub fn to_frame(&self) -> Frame {
+		let mut encoder = Encoder::default();
+
+		match self {
+			Self::SetupConnection {
+				protocol,
+				min_version,
+				max_version,
+				flags,
+				endpoint_host,
+				endpoint_port,
+				vendor,
+				hardware_version,
+				firmware,
+				device_id,
+			} => {
+				encoder.u8(*protocol);
+				encoder.u16(*min_version);
+				encoder.u16(*max_version);
+				encoder.u32(*flags);
+				encoder.str(endpoint_host);
+				encoder.u16(*endpoint_port);
+				encoder.str(vendor);
+				encoder.str(hardware_version);
+				encoder.str(firmware);
+				encoder.str(device_id);
+			}
+			Self::SetupConnectionSuccess {
+				used_version,
+				flags,
+			} => {
+				encoder.u16(*used_version);
+				encoder.u32(*flags);
+			}
+			Self::SetupConnectionError { flags, error_code } => {
+				encoder.u32(*flags);
+				encoder.str(error_code);
+			}
+			Self::OpenStandardMiningChannel {
+				request_id,
+				user_identity,
+				nominal_hash_rate,
+				max_target,
+			} => {
+				encoder.u32(*request_id);
+				encoder.str(user_identity);
+				encoder.f32(*nominal_hash_rate);
+				encoder.bytes(&max_target.to_le_bytes());
+			}
+			Self::OpenStandardMiningChannelSuccess {
+				request_id,
+				channel_id,
+				target,
+				extranonce_prefix,
+				group_channel_id,
+			} => {
+				encoder.u32(*request_id);
+				encoder.u32(*channel_id);
+				encoder.bytes(&target.to_le_bytes());
+				encoder.b0_32(extranonce_prefix);
+				encoder.u32(*group_channel_id);
+			}
+			Self::OpenExtendedMiningChannel {
+				request_id,
+				user_identity,
+				nominal_hash_rate,
+				max_target,
+				min_extranonce_size,
+			} => {
+				encoder.u32(*request_id);
+				encoder.str(user_identity);
+				encoder.f32(*nominal_hash_rate);
+				encoder.bytes(&max_target.to_le_bytes());
+				encoder.u16(*min_extranonce_size);
+			}
+			Self::OpenExtendedMiningChannelSuccess {
+				request_id,
+				channel_id,
+				target,
+				extranonce_size,
+				extranonce_prefix,
+			} => {
+				encoder.u32(*request_id);
+				encoder.u32(*channel_id);
+				encoder.bytes(&target.to_le_bytes());
+				encoder.u16(*extranonce_size);
+				encoder.b0_32(extranonce_prefix);
+			}
+			Self::OpenMiningChannelError {
+				request_id,
+				error_code,
+			} => {
+				encoder.u32(*request_id);
+				encoder.str(error_code);
+			}
+			Self::CloseChannel {
+				channel_id,
+				reason_code,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.str(reason_code);
+			}
+			Self::SetExtranoncePrefix {
+				channel_id,
+				extranonce_prefix,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.b0_32(extranonce_prefix);
+			}
+			Self::SubmitSharesStandard {
+				channel_id,
+				sequence_number,
+				job_id,
+				nonce,
+				time,
+				version,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*sequence_number);
+				encoder.u32(*job_id);
+				encoder.u32(*nonce);
+				encoder.u32(*time);
+				encoder.u32(*version);
+			}
+			Self::SubmitSharesExtended {
+				channel_id,
+				sequence_number,
+				job_id,
+				nonce,
+				time,
+				version,
+				extranonce,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*sequence_number);
+				encoder.u32(*job_id);
+				encoder.u32(*nonce);
+				encoder.u32(*time);
+				encoder.u32(*version);
+				encoder.b0_32(extranonce);
+			}
+			Self::SubmitSharesSuccess {
+				channel_id,
+				last_sequence_number,
+				new_submits_accepted_count,
+				new_shares_sum,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*last_sequence_number);
+				encoder.u32(*new_submits_accepted_count);
+				encoder.u64(*new_shares_sum);
+			}
+			Self::SubmitSharesError {
+				channel_id,
+				sequence_number,
+				error_code,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*sequence_number);
+				encoder.str(error_code);
+			}
+			Self::NewMiningJob {
+				channel_id,
+				job_id,
+				min_time,
+				version,
+				merkle_root,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*job_id);
+				encoder.option_u32(*min_time);
+				encoder.u32(*version);
+				encoder.bytes(merkle_root.as_byte_array());
+			}
+			Self::NewExtendedMiningJob {
+				channel_id,
+				job_id,
+				min_time,
+				version,
+				version_rolling_allowed,
+				merkle_path,
+				coinbase_prefix,
+				coinbase_suffix,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*job_id);
+				encoder.option_u32(*min_time);
+				encoder.u32(*version);
+				encoder.u8((*version_rolling_allowed).into());
+				encoder.merkle_path(merkle_path);
+				encoder.b0_64k(coinbase_prefix);
+				encoder.b0_64k(coinbase_suffix);
+			}
+			Self::SetNewPrevHash {
+				channel_id,
+				job_id,
+				previous_block,
+				min_time,
+				bits,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.u32(*job_id);
+				encoder.bytes(previous_block.as_byte_array());
+				encoder.u32(*min_time);
+				encoder.u32(bits.to_consensus());
+			}
+			Self::SetTarget {
+				channel_id,
+				maximum_target,
+			} => {
+				encoder.u32(*channel_id);
+				encoder.bytes(&maximum_target.to_le_bytes());
+			}
+			Self::Reconnect { new_host, new_port } => {
+				encoder.str(new_host);
+				encoder.u16(*new_port);
+			}
+			Self::CoinbaseOutputDataSize {
+				coinbase_output_max_additional_size,
+			} => encoder.u32(*coinbase_output_max_additional_size),
+			Self::NewTemplate {
+				template_id,
+				future_template,
+				version,
+				coinbase_tx_version,
+				coinbase_prefix,
+				coinbase_tx_input_sequence,
+				coinbase_tx_value_remaining,
+				coinbase_tx_outputs_count,
+				coinbase_tx_outputs,
+				coinbase_tx_locktime,
+				merkle_path,
+			} => {
+				encoder.u64(*template_id);
+				encoder.u8((*future_template).into());
+				encoder.u32(*version);
+				encoder.u32(*coinbase_tx_version);
+				encoder.b0_255(coinbase_prefix);
+				encoder.u32(*coinbase_tx_input_sequence);
+				encoder.u64(*coinbase_tx_value_remaining);
+				encoder.u32(*coinbase_tx_outputs_count);
+				encoder.b0_64k(coinbase_tx_outputs);
+				encoder.u32(*coinbase_tx_locktime);
+				encoder.merkle_path(merkle_path);
+			}
+			Self::SetNewPrevHashTemplate {
+				template_id,
+				previous_block,
+				header_timestamp,
+				bits,
+				target,
+			} => {
+				encoder.u64(*template_id);
+				encoder.bytes(previous_block.as_byte_array());
+				encoder.u32(*header_timestamp);
+				encoder.u32(bits.to_consensus());
+				encoder.bytes(&target.to_le_bytes());
+			}
+			Self::RequestTransactionData { template_id } => encoder.u64(*template_id),
+			Self::RequestTransactionDataSuccess {
+				template_id,
+				excess_data,
+				transaction_list,
+			} => {
+				encoder.u64(*template_id);
+				encoder.b0_64k(excess_data);
+				encoder.u16(u16::try_from(transaction_list.len()).unwrap_or(u16::MAX));
+				for transaction in transaction_list.iter().take(u16::MAX.into()) {
+					encoder.b0_16m(transaction);
+				}
+			}
+			Self::RequestTransactionDataError {
+				template_id,
+				error_code,
+			} => {
+				encoder.u64(*template_id);
+				encoder.str(error_code);
+			}
+			Self::SubmitSolution {
+				template_id,
+				version,
+				header_timestamp,
+				header_nonce,
+				coinbase_tx,
+			} => {
+				encoder.u64(*template_id);
+				encoder.u32(*version);
+				encoder.u32(*header_timestamp);
+				encoder.u32(*header_nonce);
+				encoder.b0_64k(coinbase_tx);
+			}
+		}
+
+		let (message_type, channel) = self.message_type();
+
+		Frame {
+			extension_type: if channel { CHANNEL_BIT } else { 0 },
+			message_type,
+			payload: encoder.0,
+		}
+	}
+
+	/// Parses a message from its frame, or returns `None` for messages that
+	/// aren't supported.
+	///
+	/// # Errors
+	/// Returns an error if the payload is invalid.
+	#[allow(clippy::too_many_lines)]
+	pub fn from_frame(frame: &Frame) -> Result<Option<Self>, Error> {
+		// extensions other than the base protocol aren't supported
+		if frame.extension_type & !CHANNEL_BIT != 0 {
+			return Ok(None);
+		}
+
+		let mut decoder = Decoder(&frame.payload);
+		let d = &mut decoder;
+
+		let message = match frame.message_type {
+			0x00 => Self::SetupConnection {
+				protocol: d.u8()?,
+				min_version: d.u16()?,
+				max_version: d.u16()?,
+				flags: d.u32()?,
+				endpoint_host: d.str()?,
+				endpoint_port: d.u16()?,
+				vendor: d.str()?,
+				hardware_version: d.str()?,
+				firmware: d.str()?,
+				device_id: d.str()?,
+			},
+			0x01 => Self::SetupConnectionSuccess {
+				used_version: d.u16()?,
+				flags: d.u32()?,
+			},
+			0x02 => Self::SetupConnectionError {
+				flags: d.u32()?,
+				error_code: d.str()?,
+			},
+			0x10 => Self::OpenStandardMiningChannel {
+				request_id: d.u32()?,
+				user_identity: d.str()?,
+				nominal_hash_rate: d.f32()?,
+				max_target: d.target()?,
+			},
+			0x11 => Self::OpenStandardMiningChannelSuccess {
+				request_id: d.u32()?,
+				channel_id: d.u32()?,
+				target: d.target()?,
+				extranonce_prefix: d.b0_32()?,
+				group_channel_id: d.u32()?,
+			},
+			0x12 => Self::OpenMiningChannelError {
+				request_id: d.u32()?,
+				error_code: d.str()?,
+			},
+			0x13 => Self::OpenExtendedMiningChannel {
+				request_id: d.u32()?,
+				user_identity: d.str()?,
+				nominal_hash_rate: d.f32()?,
+				max_target: d.target()?,
+				min_extranonce_size: d.u16()?,
+			},
+			0x14 => Self::OpenExtendedMiningChannelSuccess {
+				request_id: d.u32()?,
+				channel_id: d.u32()?,
+				target: d.target()?,
+				extranonce_size: d.u16()?,
+				extranonce_prefix: d.b0_32()?,
+			},
+			0x18 => Self::CloseChannel {
+				channel_id: d.u32()?,
+				reason_code: d.str()?,
+			},
+			0x19 => Self::SetExtranoncePrefix {
+				channel_id: d.u32()?,
+				extranonce_prefix: d.b0_32()?,
+			},
+			0x1a => Self::SubmitSharesStandard {
+				channel_id: d.u32()?,
+				sequence_number: d.u32()?,
+				job_id: d.u32()?,
+				nonce: d.u32()?,
+				time: d.u32()?,
+				version: d.u32()?,
+			},
+			0x1b => Self::SubmitSharesExtended {
+				channel_id: d.u32()?,
+				sequence_number: d.u32()?,
+				job_id: d.u32()?,
+				nonce: d.u32()?,
+				time: d.u32()?,
+				version: d.u32()?,
+				extranonce: d.b0_32()?,
+			},
+			0x1c => Self::SubmitSharesSuccess {
+				channel_id: d.u32()?,
+				last_sequence_number: d.u32()?,
+				new_submits_accepted_count: d.u32()?,
+				new_shares_sum: d.u64()?,
+			},
+			0x1d => Self::SubmitSharesError {
+				channel_id: d.u32()?,
+				sequence_number: d.u32()?,
+				error_code: d.str()?,
+			},
+			0x1e => Self::NewMiningJob {
+				channel_id: d.u32()?,
+				job_id: d.u32()?,
+				min_time: d.option_u32()?,
+				version: d.u32()?,
+				merkle_root: bitcoin::TxMerkleNode::from_byte_array(d.array()?),
+			},
+			0x1f => Self::NewExtendedMiningJob {
+				channel_id: d.u32()?,
+				job_id: d.u32()?,
+				min_time: d.option_u32()?,
+				version: d.u32()?,
+				version_rolling_allowed: d.bool()?,
+				merkle_path: d.merkle_path()?,
+				coinbase_prefix: d.b0_64k()?,
+				coinbase_suffix: d.b0_64k()?,
+			},
+			0x20 => Self::SetNewPrevHash {
+				channel_id: d.u32()?,
+				job_id: d.u32()?,
+				previous_block: bitcoin::BlockHash::from_byte_array(d.array()?),
+				min_time: d.u32()?,
+				bits: bitcoin::CompactTarget::from_consensus(d.u32()?),
+			},
+			0x21 => Self::SetTarget {
+				channel_id: d.u32()?,
+				maximum_target: d.target()?,
+			},
+			0x25 => Self::Reconnect {
+				new_host: d.str()?,
+				new_port: d.u16()?,
+			},
+			0x70 => Self::CoinbaseOutputDataSize {
+				coinbase_output_max_additional_size: d.u32()?,
+			},
+			0x71 => Self::NewTemplate {
+				template_id: d.u64()?,
+				future_template: d.bool()?,
+				version: d.u32()?,
+				coinbase_tx_version: d.u32()?,
+				coinbase_prefix: d.b0_255()?,
+				coinbase_tx_input_sequence: d.u32()?,
+				coinbase_tx_value_remaining: d.u64()?,
+				coinbase_tx_outputs_count: d.u32()?,
+				coinbase_tx_outputs: d.b0_64k()?,
+				coinbase_tx_locktime: d.u32()?,
+				merkle_path: d.merkle_path()?,
+			},
+			0x72 => Self::SetNewPrevHashTemplate {
+				template_id: d.u64()?,
+				previous_block: bitcoin::BlockHash::from_byte_array(d.array()?),
+				header_timestamp: d.u32()?,
+				bits: bitcoin::CompactTarget::from_consensus(d.u32()?),
+				target: d.target()?,
+			},
+			0x73 => Self::RequestTransactionData {
+				template_id: d.u64()?,
+			},
+			0x74 => Self::RequestTransactionDataSuccess {
+				template_id: d.u64()?,
+				excess_data: d.b0_64k()?,
+				transaction_list: (0..d.u16()?)
+					.map(|_| d.b0_16m())
+					.collect::<Result<_, _>>()?,
+			},
+			0x75 => Self::RequestTransactionDataError {
+				template_id: d.u64()?,
+				error_code: d.str()?,
+			},
+			0x76 => Self::SubmitSolution {
+				template_id: d.u64()?,
+				version: d.u32()?,
+				header_timestamp: d.u32()?,
+				header_nonce: d.u32()?,
+				coinbase_tx: d.b0_64k()?,
+			},
+			_ => return Ok(None),
+		};
+
+		Ok(Some(message))
+	}
+}
+
+/// Serializes the primitive types, which are all little-endian.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+	fn u8(&mut self, value: u8) {
+		self.0.push(value);
+	}
+
+	fn u16(&mut self, value: u16) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn u32(&mut self, value: u32) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn u64(&mut self, value: u64) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn f32(&mut self, value: f32) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn bytes(&mut self, value: &[u8]) {
+		self.0.extend_from_slice(value);
+	}
+
+	/// A `STR0_255`, truncated if it is longer.
+	fn str(&mut self, value: &str) {
+		self.b0_32_or_255(value.as_bytes(), u8::MAX.into());
+	}
+
+	/// A `B0_32`, truncated if it is longer.
+	fn b0_32(&mut self, value: &[u8]) {
+		self.b0_32_or_255(value, 32);
+	}
+
+	fn b0_32_or_255(&mut self, value: &[u8], max: usize) {
+		let value = &value[..value.len().min(max)];
+
+		#[allow(clippy::cast_possible_truncation)]
+		self.u8(value.len() as u8);
+		self.bytes(value);
+	}
+
+	/// A `B0_255`, truncated if it is longer.
+	fn b0_255(&mut self, value: &[u8]) {
+		self.b0_32_or_255(value, u8::MAX.into());
+	}
+
+	/// A `B0_64K`, truncated if it is longer.
+	fn b0_64k(&mut self, value: &[u8]) {
+		let value = &value[..value.len().min(u16::MAX.into())];
+
+		#[allow(clippy::cast_possible_truncation)]
+		self.u16(value.len() as u16);
+		self.bytes(value);
+	}
+
+	/// A `B0_16M`, truncated if it is longer.
+	fn b0_16m(&mut self, value: &[u8]) {
+		let value = &value[..value.len().min(0xff_ffff)];
+
+		#[allow(clippy::cast_possible_truncation)]
+		self.bytes(&(value.len() as u32).to_le_bytes()[..3]);
+		self.bytes(value);
+	}
+
+	/// A `SEQ0_255[U256]` of merkle path hashes, truncated if it is longer.
+	fn merkle_path(&mut self, path: &[[u8; 32]]) {
+		self.u8(u8::try_from(path.len()).unwrap_or(u8::MAX));
+
+		for hash in path.iter().take(u8::MAX.into()) {
+			self.bytes(hash);
+		}
+	}
+
+	/// An `OPTION[U32]`, which is a sequence of zero or one values.
+	fn option_u32(&mut self, value: Option<u32>) {
+		self.u8(value.is_some().into());
+
+		if let Some(value) = value {
+			self.u32(value);
+		}
+	}
+}
+
+struct Decoder<'d>(&'d [u8]);
+
+impl Decoder<'_> {
+	fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+		if self.0.len() < len {
+			return Err(Error::Protocol("truncated message".to_string()));
+		}
+
+		let (value, rest) = self.0.split_at(len);
+
+		self.0 = rest;
+		Ok(value)
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+		let mut array = [0; N];
+
+		array.copy_from_slice(self.take(N)?);
+		Ok(array)
+	}
+
+	fn u8(&mut self) -> Result<u8, Error> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, Error> {
+		self.array().map(u16::from_le_bytes)
+	}
+
+	fn u32(&mut self) -> Result<u32, Error> {
+		self.array().map(u32::from_le_bytes)
+	}
+
+	fn u64(&mut self) -> Result<u64, Error> {
+		self.array().map(u64::from_le_bytes)
+	}
+
+	fn f32(&mut self) -> Result<f32, Error> {
+		self.array().map(f32::from_le_bytes)
+	}
+
+	fn target(&mut self) -> Result<bitcoin::Target, Error> {
+		self.array().map(bitcoin::Target::from_le_bytes)
+	}
+
+	fn str(&mut self) -> Result<String, Error> {
+		let len = self.u8()?.into();
+
+		String::from_utf8(self.take(len)?.to_vec())
+			.map_err(|_| Error::Protocol("invalid string".to_string()))
+	}
+
+	fn b0_32(&mut self) -> Result<Vec<u8>, Error> {
+		let len = self.u8()?;
+
+		if len > 32 {
+			return Err(Error::Protocol("byte string too long".to_string()));
+		}
+
+		Ok(self.take(len.into())?.to_vec())
+	}
+
+	fn b0_255(&mut self) -> Result<Vec<u8>, Error> {
+		let len = self.u8()?.into();
+
+		Ok(self.take(len)?.to_vec())
+	}
+
+	fn b0_64k(&mut self) -> Result<Vec<u8>, Error> {
+		let len = self.u16()?.into();
+
+		Ok(self.take(len)?.to_vec())
+	}
+
+	fn b0_16m(&mut self) -> Result<Vec<u8>, Error> {
+		let [lo, mid, hi] = self.array()?;
+		let len = u32::from_le_bytes([lo, mid, hi, 0]) as usize;
+
+		Ok(self.take(len)?.to_vec())
+	}
+
+	fn bool(&mut self) -> Result<bool, Error> {
+		match self.u8()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			_ => Err(Error::Protocol("invalid bool".to_string())),
+		}
+	}
+
+	fn merkle_path(&mut self) -> Result<Vec<[u8; 32]>, Error> {
+		(0..self.u8()?).map(|_| self.array()).collect()
+	}
+
+	fn option_u32(&mut self) -> Result<Option<u32>, Error> {
+		match self.u8()? {
+			0 => Ok(None),
+			1 => self.u32().map(Some),
+			_ => Err(Error::Protocol("invalid option".to_string())),
+		}
+	}
+}
@@ -0,0 +1,207 @@
+//! Stratum V2, the binary protocol with Noise encryption that pools are moving to.
+//!
+//! See <https://github.com/stratum-mining/sv2-spec> for the messages.
+
+mod client;
+mod messages;
+pub mod noise;
+mod provider;
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use bitcoin::secp256k1::XOnlyPublicKey;
+
+pub use client::{Channel, Client, Pool};
+pub use messages::Message;
+pub use provider::TemplateProvider;
+
+use super::Error;
+use crate::work;
+
+/// Set in the extension type of messages addressed to a channel
+pub const CHANNEL_BIT: u16 = 0x8000;
+/// The protocol version spoken, which is the only one defined so far
+pub const VERSION: u16 = 2;
+
+/// The size of a frame header: extension type, message type and payload length
+const HEADER_SIZE: usize = 6;
+
+/// A message along with the framing it is sent in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+	/// The extension the message belongs to, with [`CHANNEL_BIT`] set for
+	/// messages addressed to a channel
+	pub extension_type: u16,
+	pub message_type: u8,
+	pub payload: Vec<u8>,
+}
+
+impl Frame {
+	/// The serialized header, which is encrypted separately from the payload.
+	fn header(&self) -> [u8; HEADER_SIZE] {
+		let mut header = [0; HEADER_SIZE];
+
+		header[..2].copy_from_slice(&self.extension_type.to_le_bytes());
+		header[2] = self.message_type;
+		// the length is a 24-bit integer
+		#[allow(clippy::cast_possible_truncation)]
+		header[3..].copy_from_slice(&(self.payload.len() as u32).to_le_bytes()[..3]);
+		header
+	}
+
+	/// Parses a header, returning an empty frame along with its payload length.
+	fn from_header(header: &[u8]) -> Result<(Self, usize), Error> {
+		let &[extension_lo, extension_hi, message_type, length_lo, length_mid, length_hi] = header
+		else {
+			return Err(Error::Protocol("truncated frame header".to_string()));
+		};
+
+		Ok((
+			Self {
+				extension_type: u16::from_le_bytes([extension_lo, extension_hi]),
+				message_type,
+				payload: Vec::new(),
+			},
+			u32::from_le_bytes([length_lo, length_mid, length_hi, 0]) as usize,
+		))
+	}
+}
+
+/// The key that signs a pool's Noise certificates, written in pool urls as
+/// base58check with a two-byte version prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthorityKey(pub XOnlyPublicKey);
+
+/// The version prefix of an encoded [`AuthorityKey`]
+const AUTHORITY_KEY_VERSION: [u8; 2] = [1, 0];
+
+impl fmt::Display for AuthorityKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let data = [&AUTHORITY_KEY_VERSION[..], &self.0.serialize()].concat();
+
+		f.write_str(&bitcoin::base58::encode_check(&data))
+	}
+}
+
+impl FromStr for AuthorityKey {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let data = bitcoin::base58::decode_check(s)
+			.map_err(|e| Error::Protocol(format!("invalid authority key: {e}")))?;
+
+		match data.split_first_chunk() {
+			Some((version, key)) if *version == AUTHORITY_KEY_VERSION => {
+				XOnlyPublicKey::from_slice(key)
+					.map(Self)
+					.map_err(|e| Error::Protocol(format!("invalid authority key: {e}")))
+			}
+			_ => Err(Error::Protocol("invalid authority key version".to_string())),
+		}
+	}
+}
+
+/// A job from `NewMiningJob` or `NewExtendedMiningJob`, which is mined once
+/// a `SetNewPrevHash` says which block it builds on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MiningJob {
+	pub job_id: u32,
+	pub version: u32,
+	/// The earliest time the job can be mined with, unset for future jobs
+	pub min_time: Option<u32>,
+	pub merkle: Merkle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merkle {
+	/// Standard jobs have a fixed merkle root
+	Root(bitcoin::TxMerkleNode),
+	/// Extended jobs are built around the coinbase, with the extranonce after
+	/// `coinbase_prefix`
+	Path {
+		coinbase_prefix: Vec<u8>,
+		coinbase_suffix: Vec<u8>,
+		merkle_path: Vec<[u8; 32]>,
+	},
+}
+
+/// The block a channel's jobs build on, from `SetNewPrevHash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrevHash {
+	pub job_id: u32,
+	pub previous_block: bitcoin::BlockHash,
+	pub min_time: u32,
+	pub bits: bitcoin::CompactTarget,
+}
+
+/// A pool job on a channel, with our extranonce filled in.
+#[derive(Debug, Clone)]
+pub struct Work {
+	pub client: Arc<Client>,
+	pub channel_id: u32,
+	pub job: Arc<MiningJob>,
+	pub prev_hash: PrevHash,
+	/// The part of the extranonce assigned by the pool
+	pub extranonce_prefix: Vec<u8>,
+	/// The part of the extranonce we roll, which is empty on standard channels
+	pub extranonce: Vec<u8>,
+}
+
+impl Work {
+	/// Builds a job searching for shares at `target`.
+	#[must_use]
+	pub fn into_job(self, target: bitcoin::Target) -> work::Job {
+		#[allow(clippy::cast_possible_wrap)]
+		let version = bitcoin::block::Version::from_consensus(self.job.version as i32);
+
+		work::Job {
+			header: bitcoin::block::Header {
+				version,
+				prev_blockhash: self.prev_hash.previous_block,
+				merkle_root: self.merkle_root(),
+				time: self.job.min_time.map_or(self.prev_hash.min_time, |time| {
+					time.max(self.prev_hash.min_time)
+				}),
+				bits: self.prev_hash.bits,
+				nonce: 0,
+			},
+			target,
+			nonce_range: 0..u32::MAX,
+			kind: work::Kind::StratumV2(self),
+		}
+	}
+
+	/// Takes an extranonce that hasn't been used in this session yet.
+	pub fn roll(&mut self) {
+		self.extranonce = self.client.next_extranonce(self.extranonce.len());
+	}
+
+	#[must_use]
+	pub fn merkle_root(&self) -> bitcoin::TxMerkleNode {
+		match &self.job.merkle {
+			Merkle::Root(root) => *root,
+			Merkle::Path {
+				coinbase_prefix,
+				coinbase_suffix,
+				merkle_path,
+			} => super::merkle_root(
+				&[
+					&coinbase_prefix[..],
+					&self.extranonce_prefix,
+					&self.extranonce,
+					coinbase_suffix,
+				]
+				.concat(),
+				merkle_path,
+			),
+		}
+	}
+
+	/// Submits a header that meets the share target.
+	///
+	/// # Errors
+	/// Returns an error if the connection to the pool is closed.
+	pub fn submit(&self, header: &bitcoin::block::Header) -> Result<(), Error> {
+		self.client.submit(self, header)
+	}
+}
@@ -0,0 +1,530 @@
+//! The Noise handshake that Stratum V2 connections start with, and the
+//! encrypted transport after it.
+//!
+//! The handshake is `Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256`, where the
+//! responder proves its static key with a [`Certificate`] signed by an
+//! authority key the initiator knows ahead of time, usually from the pool url.
+
+use std::{
+	io::{Read as _, Write as _},
+	net::TcpStream,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{
+	hashes::{hmac, sha256, Hash as _, HashEngine as _},
+	secp256k1::{
+		ellswift::{ElligatorSwift, ElligatorSwiftParty},
+		rand, schnorr, Keypair, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey,
+	},
+};
+use chacha20poly1305::{
+	aead::{Aead as _, KeyInit as _, Payload},
+	ChaCha20Poly1305,
+};
+
+use super::{Frame, HEADER_SIZE};
+use crate::stratum::Error;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256";
+/// The size of an `ElligatorSwift` encoded public key
+const KEY_SIZE: usize = 64;
+/// The size of the tag after each encrypted message
+const MAC_SIZE: usize = 16;
+/// The size of an encoded [`Certificate`]
+const CERTIFICATE_SIZE: usize = 74;
+/// The largest encrypted message, so longer payloads are sent in chunks
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Vouches for a responder's static key for a period of time, signed by the
+/// authority key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Certificate {
+	pub version: u16,
+	/// Unix time the certificate is valid from
+	pub valid_from: u32,
+	/// Unix time the certificate expires at
+	pub not_valid_after: u32,
+	pub signature: schnorr::Signature,
+}
+
+impl Certificate {
+	/// Signs a certificate for `static_key`, valid between two unix times.
+	#[must_use]
+	pub fn sign(
+		authority: &Keypair,
+		static_key: &XOnlyPublicKey,
+		valid_from: u32,
+		not_valid_after: u32,
+	) -> Self {
+		let message = Self::message(0, valid_from, not_valid_after, static_key);
+
+		Self {
+			version: 0,
+			valid_from,
+			not_valid_after,
+			signature: Secp256k1::new().sign_schnorr(&message, authority),
+		}
+	}
+
+	/// Checks that the certificate is valid now, and that `authority` signed it
+	/// for `static_key`.
+	///
+	/// # Errors
+	/// Returns an error if the certificate is expired or the signature is invalid.
+	pub fn verify(
+		&self,
+		authority: &XOnlyPublicKey,
+		static_key: &XOnlyPublicKey,
+	) -> Result<(), Error> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |time| time.as_secs());
+
+		if now < self.valid_from.into() || now > self.not_valid_after.into() {
+			return Err(Error::Protocol("certificate is not valid now".to_string()));
+		}
+
+		let message = Self::message(
+			self.version,
+			self.valid_from,
+			self.not_valid_after,
+			static_key,
+		);
+
+		Secp256k1::verification_only()
+			.verify_schnorr(&self.signature, &message, authority)
+			.map_err(|_| Error::Protocol("invalid certificate signature".to_string()))
+	}
+
+	/// The hash that is signed, which commits to everything but the signature.
+	fn message(
+		version: u16,
+		valid_from: u32,
+		not_valid_after: u32,
+		static_key: &XOnlyPublicKey,
+	) -> bitcoin::secp256k1::Message {
+		let mut engine = sha256::Hash::engine();
+
+		engine.input(&version.to_le_bytes());
+		engine.input(&valid_from.to_le_bytes());
+		engine.input(&not_valid_after.to_le_bytes());
+		engine.input(&static_key.serialize());
+
+		bitcoin::secp256k1::Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+	}
+
+	fn encode(&self) -> [u8; CERTIFICATE_SIZE] {
+		let mut data = [0; CERTIFICATE_SIZE];
+
+		data[..2].copy_from_slice(&self.version.to_le_bytes());
+		data[2..6].copy_from_slice(&self.valid_from.to_le_bytes());
+		data[6..10].copy_from_slice(&self.not_valid_after.to_le_bytes());
+		data[10..].copy_from_slice(self.signature.as_ref());
+		data
+	}
+
+	fn decode(data: &[u8]) -> Result<Self, Error> {
+		let (Some(version), Some(valid_from), Some(not_valid_after), Some(signature)) = (
+			data.get(..2),
+			data.get(2..6),
+			data.get(6..10),
+			data.get(10..CERTIFICATE_SIZE),
+		) else {
+			return Err(Error::Protocol("truncated certificate".to_string()));
+		};
+
+		Ok(Self {
+			version: u16::from_le_bytes([version[0], version[1]]),
+			valid_from: u32::from_le_bytes(array(valid_from)),
+			not_valid_after: u32::from_le_bytes(array(not_valid_after)),
+			signature: schnorr::Signature::from_slice(signature)
+				.map_err(|_| Error::Protocol("invalid certificate signature".to_string()))?,
+		})
+	}
+}
+
+/// Reads and decrypts frames.
+#[derive(Debug)]
+pub struct Reader {
+	stream: TcpStream,
+	cipher: Cipher,
+}
+
+/// Encrypts and writes frames.
+#[derive(Debug)]
+pub struct Writer {
+	stream: TcpStream,
+	cipher: Cipher,
+}
+
+impl Reader {
+	/// Reads the next frame.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed or a message can't be decrypted.
+	pub fn read(&mut self) -> Result<Frame, Error> {
+		let mut header = [0; HEADER_SIZE + MAC_SIZE];
+
+		self.stream.read_exact(&mut header)?;
+
+		let (mut frame, mut remaining) = Frame::from_header(&self.cipher.decrypt(&[], &header)?)?;
+
+		frame.payload.reserve(remaining);
+
+		while remaining > 0 {
+			let len = remaining.min(MAX_MESSAGE_SIZE - MAC_SIZE);
+			let mut chunk = vec![0; len + MAC_SIZE];
+
+			self.stream.read_exact(&mut chunk)?;
+			frame
+				.payload
+				.extend_from_slice(&self.cipher.decrypt(&[], &chunk)?);
+			remaining -= len;
+		}
+
+		Ok(frame)
+	}
+}
+
+impl Writer {
+	/// Writes a frame, with the header and each chunk of the payload encrypted
+	/// separately.
+	///
+	/// # Errors
+	/// Returns an error if the connection is closed.
+	pub fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+		let mut data = self.cipher.encrypt(&[], &frame.header());
+
+		for chunk in frame.payload.chunks(MAX_MESSAGE_SIZE - MAC_SIZE) {
+			data.extend_from_slice(&self.cipher.encrypt(&[], chunk));
+		}
+
+		self.stream.write_all(&data)?;
+
+		Ok(())
+	}
+}
+
+/// Performs the handshake as the initiator, checking the responder's
+/// certificate against `authority` if it is known.
+///
+/// # Errors
+/// Returns an error if the connection is closed or the responder can't be authenticated.
+pub fn connect(
+	stream: TcpStream,
+	authority: Option<&XOnlyPublicKey>,
+) -> Result<(Reader, Writer), Error> {
+	connect_with_ephemeral(stream, authority, SecretKey::new(&mut rand::thread_rng()))
+}
+
+/// Like [`connect`], but with a given ephemeral key instead of a random one,
+/// so the handshake can be checked against known vectors.
+///
+/// # Errors
+/// Returns an error if the connection is closed or the responder can't be authenticated.
+pub fn connect_with_ephemeral(
+	mut stream: TcpStream,
+	authority: Option<&XOnlyPublicKey>,
+	secret: SecretKey,
+) -> Result<(Reader, Writer), Error> {
+	let mut handshake = Handshake::new();
+	let ephemeral = encode_key(secret);
+
+	// -> e
+	handshake.mix_hash(&ephemeral.to_array());
+	handshake.encrypt_and_hash(&[]);
+	stream.write_all(&ephemeral.to_array())?;
+
+	// <- e, ee, s, es, along with the certificate
+	let mut message = [0; KEY_SIZE + KEY_SIZE + MAC_SIZE + CERTIFICATE_SIZE + MAC_SIZE];
+
+	stream.read_exact(&mut message)?;
+
+	let (remote_ephemeral, rest) = message.split_at(KEY_SIZE);
+	let (remote_static, certificate) = rest.split_at(KEY_SIZE + MAC_SIZE);
+	let remote_ephemeral = ElligatorSwift::from_array(array(remote_ephemeral));
+
+	handshake.mix_hash(&remote_ephemeral.to_array());
+	handshake.mix_key(&ecdh(
+		ephemeral,
+		remote_ephemeral,
+		secret,
+		ElligatorSwiftParty::A,
+	));
+
+	let remote_static =
+		ElligatorSwift::from_array(array(&handshake.decrypt_and_hash(remote_static)?));
+
+	handshake.mix_key(&ecdh(
+		ephemeral,
+		remote_static,
+		secret,
+		ElligatorSwiftParty::A,
+	));
+
+	let certificate = Certificate::decode(&handshake.decrypt_and_hash(certificate)?)?;
+
+	if let Some(authority) = authority {
+		let (static_key, _) = PublicKey::from_ellswift(remote_static).x_only_public_key();
+
+		certificate.verify(authority, &static_key)?;
+	} else {
+		tracing::warn!("no authority key, so the pool is not authenticated");
+	}
+
+	let (send, receive) = handshake.split();
+
+	Ok((
+		Reader {
+			stream: stream.try_clone()?,
+			cipher: receive,
+		},
+		Writer {
+			stream,
+			cipher: send,
+		},
+	))
+}
+
+/// Performs the handshake as the responder, proving `static_key` with a
+/// certificate from the authority.
+///
+/// # Errors
+/// Returns an error if the connection is closed or the initiator's message is invalid.
+pub fn accept(
+	stream: TcpStream,
+	static_key: &Keypair,
+	certificate: &Certificate,
+) -> Result<(Reader, Writer), Error> {
+	accept_with_ephemeral(
+		stream,
+		static_key,
+		certificate,
+		SecretKey::new(&mut rand::thread_rng()),
+	)
+}
+
+/// Like [`accept`], but with a given ephemeral key instead of a random one,
+/// so the handshake can be checked against known vectors.
+///
+/// # Errors
+/// Returns an error if the connection is closed or the initiator's message is invalid.
+pub fn accept_with_ephemeral(
+	mut stream: TcpStream,
+	static_key: &Keypair,
+	certificate: &Certificate,
+	secret: SecretKey,
+) -> Result<(Reader, Writer), Error> {
+	let mut handshake = Handshake::new();
+
+	// -> e
+	let mut remote_ephemeral = [0; KEY_SIZE];
+
+	stream.read_exact(&mut remote_ephemeral)?;
+	handshake.mix_hash(&remote_ephemeral);
+	handshake.decrypt_and_hash(&[])?;
+
+	let remote_ephemeral = ElligatorSwift::from_array(remote_ephemeral);
+
+	// <- e, ee, s, es, along with the certificate
+	let ephemeral = encode_key(secret);
+
+	handshake.mix_hash(&ephemeral.to_array());
+	handshake.mix_key(&ecdh(
+		remote_ephemeral,
+		ephemeral,
+		secret,
+		ElligatorSwiftParty::B,
+	));
+
+	let encoded_static = encode_key(static_key.secret_key());
+	let mut message = ephemeral.to_array().to_vec();
+
+	message.extend(handshake.encrypt_and_hash(&encoded_static.to_array()));
+	handshake.mix_key(&ecdh(
+		remote_ephemeral,
+		encoded_static,
+		static_key.secret_key(),
+		ElligatorSwiftParty::B,
+	));
+	message.extend(handshake.encrypt_and_hash(&certificate.encode()));
+	stream.write_all(&message)?;
+
+	let (receive, send) = handshake.split();
+
+	Ok((
+		Reader {
+			stream: stream.try_clone()?,
+			cipher: receive,
+		},
+		Writer {
+			stream,
+			cipher: send,
+		},
+	))
+}
+
+/// ChaCha20-Poly1305 with a counter nonce.
+struct Cipher {
+	aead: ChaCha20Poly1305,
+	nonce: u64,
+}
+
+impl std::fmt::Debug for Cipher {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Cipher")
+			.field("nonce", &self.nonce)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Cipher {
+	fn new(key: [u8; 32]) -> Self {
+		Self {
+			aead: ChaCha20Poly1305::new(&key.into()),
+			nonce: 0,
+		}
+	}
+
+	/// The next nonce, which is the counter after four zero bytes.
+	fn next_nonce(&mut self) -> chacha20poly1305::Nonce {
+		let mut nonce = [0; 12];
+
+		nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+		self.nonce += 1;
+		nonce.into()
+	}
+
+	fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+		let nonce = self.next_nonce();
+
+		self.aead
+			.encrypt(
+				&nonce,
+				Payload {
+					msg: plaintext,
+					aad: ad,
+				},
+			)
+			.expect("message is within the size limit")
+	}
+
+	fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+		let nonce = self.next_nonce();
+
+		self.aead
+			.decrypt(
+				&nonce,
+				Payload {
+					msg: ciphertext,
+					aad: ad,
+				},
+			)
+			.map_err(|_| Error::Protocol("failed to decrypt message".to_string()))
+	}
+}
+
+/// The symmetric state of the handshake.
+struct Handshake {
+	chaining_key: [u8; 32],
+	hash: [u8; 32],
+	cipher: Option<Cipher>,
+}
+
+impl Handshake {
+	fn new() -> Self {
+		// the protocol name is longer than a hash, so it is hashed
+		let hash = sha256::Hash::hash(PROTOCOL_NAME).to_byte_array();
+		let mut handshake = Self {
+			chaining_key: hash,
+			hash,
+			cipher: None,
+		};
+
+		// there is no prologue
+		handshake.mix_hash(&[]);
+		handshake
+	}
+
+	fn mix_hash(&mut self, data: &[u8]) {
+		let mut engine = sha256::Hash::engine();
+
+		engine.input(&self.hash);
+		engine.input(data);
+		self.hash = sha256::Hash::from_engine(engine).to_byte_array();
+	}
+
+	fn mix_key(&mut self, input: &[u8]) {
+		let (chaining_key, key) = hkdf(&self.chaining_key, input);
+
+		self.chaining_key = chaining_key;
+		self.cipher = Some(Cipher::new(key));
+	}
+
+	fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+		let ciphertext = match &mut self.cipher {
+			Some(cipher) => cipher.encrypt(&self.hash, plaintext),
+			None => plaintext.to_vec(),
+		};
+
+		self.mix_hash(&ciphertext);
+		ciphertext
+	}
+
+	fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+		let plaintext = match &mut self.cipher {
+			Some(cipher) => cipher.decrypt(&self.hash, ciphertext)?,
+			None => ciphertext.to_vec(),
+		};
+
+		self.mix_hash(ciphertext);
+		Ok(plaintext)
+	}
+
+	/// The ciphers for messages from the initiator and from the responder.
+	fn split(self) -> (Cipher, Cipher) {
+		let (initiator, responder) = hkdf(&self.chaining_key, &[]);
+
+		(Cipher::new(initiator), Cipher::new(responder))
+	}
+}
+
+fn hkdf(chaining_key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+	let key = hmac_sha256(chaining_key, input);
+	let first = hmac_sha256(&key, &[1]);
+	let second = hmac_sha256(&key, &[&first[..], &[2]].concat());
+
+	(first, second)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+	let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+
+	engine.input(data);
+	hmac::Hmac::from_engine(engine).to_byte_array()
+}
+
+/// The BIP324 shared secret between the initiator's key `a` and the
+/// responder's key `b`.
+fn ecdh(
+	a: ElligatorSwift,
+	b: ElligatorSwift,
+	secret: SecretKey,
+	party: ElligatorSwiftParty,
+) -> [u8; 32] {
+	ElligatorSwift::shared_secret(a, b, secret, party, None).to_secret_bytes()
+}
+
+/// Encodes the public key of `secret` without extra randomness, like the SRI
+/// reference does, so the same keys always give the same handshake.
+fn encode_key(secret: SecretKey) -> ElligatorSwift {
+	ElligatorSwift::from_pubkey(secret.public_key(&Secp256k1::new()))
+}
+
+fn array<const N: usize>(data: &[u8]) -> [u8; N] {
+	let mut array = [0; N];
+
+	array.copy_from_slice(data);
+	array
+}
@@ -0,0 +1,525 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{
+	consensus::Encodable as _,
+	secp256k1::{rand, Keypair, Secp256k1},
+};
+
+use super::{
+	noise::{self, Certificate},
+	Message, VERSION,
+};
+use crate::{
+	block, rpc,
+	stratum::{lock, Error},
+};
+
+/// The `SetupConnection` protocol for template distribution
+const TEMPLATE_DISTRIBUTION: u8 = 2;
+/// How many templates on the current tip are kept around for transaction
+/// data requests and solutions
+const MAX_TEMPLATES: usize = 16;
+/// How many bytes of coinbase outputs fit in the space the node leaves for the
+/// coinbase, which is 1000 vbytes, after the rest of our coinbase
+const MAX_ADDITIONAL_COINBASE_SIZE: u32 = 800;
+/// How long to wait before retrying a failed template request
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How far back the certificate is valid from, for clients with slow clocks
+const CLOCK_SKEW: Duration = Duration::from_hours(1);
+
+/// Serves block templates from a node over the Stratum V2 Template
+/// Distribution protocol, so job declarators can build their own jobs
+/// without a patched node.
+#[derive(Debug)]
+pub struct TemplateProvider {
+	rpc: rpc::Client,
+	static_key: Keypair,
+	certificate: Certificate,
+	templates: Mutex<Templates>,
+	connections: Mutex<HashMap<u64, Arc<Connection>>>,
+	next_connection: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Templates {
+	/// Templates on the current tip, newest last
+	templates: VecDeque<Template>,
+	next_id: u64,
+}
+
+/// A template from the node, split into the parts sent to clients.
+#[derive(Debug)]
+struct Template {
+	id: u64,
+	previous_block: bitcoin::BlockHash,
+	version: i32,
+	bits: bitcoin::CompactTarget,
+	time: u32,
+	target: bitcoin::Target,
+	height: u32,
+	coinbase_value: u64,
+	/// Outputs the coinbase has to include, which is the witness commitment
+	/// for segwit templates
+	coinbase_outputs: Vec<bitcoin::TxOut>,
+	merkle_path: Vec<[u8; 32]>,
+	/// The transactions after the coinbase
+	transactions: Vec<bitcoin::Transaction>,
+}
+
+#[derive(Debug)]
+struct Connection {
+	id: u64,
+	peer: SocketAddr,
+	writer: Mutex<noise::Writer>,
+	state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+	/// Templates are only sent once the client says how much room its
+	/// coinbase outputs need
+	ready: bool,
+	/// The tip of the last template sent, which decides whether the next one
+	/// comes with a `SetNewPrevHashTemplate`
+	previous_block: Option<bitcoin::BlockHash>,
+	/// The id of the last template sent
+	template_id: Option<u64>,
+}
+
+impl TemplateProvider {
+	/// Creates a provider with a new static key, which clients authenticate
+	/// with a certificate signed by `authority`.
+	#[must_use]
+	pub fn new(rpc: rpc::Client, authority: &Keypair) -> Self {
+		let static_key = Keypair::new(&Secp256k1::new(), &mut rand::thread_rng());
+		#[allow(clippy::cast_possible_truncation)]
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.saturating_sub(CLOCK_SKEW)
+			.as_secs() as u32;
+		let certificate =
+			Certificate::sign(authority, &static_key.x_only_public_key().0, now, u32::MAX);
+
+		Self {
+			rpc,
+			static_key,
+			certificate,
+			templates: Mutex::default(),
+			connections: Mutex::default(),
+			next_connection: AtomicU64::new(0),
+		}
+	}
+
+	/// Accepts clients on `listener`, serving each on its own thread.
+	pub fn listen(self: &Arc<Self>, listener: &TcpListener) -> ! {
+		loop {
+			match listener.accept() {
+				Ok((stream, peer)) => {
+					let provider = Arc::clone(self);
+
+					std::thread::spawn(move || provider.serve(stream, peer));
+				}
+				Err(e) => {
+					tracing::warn!(error = %e, "failed to accept template distribution connection");
+				}
+			}
+		}
+	}
+
+	/// Fetches templates from the node with longpoll, sending each new one to
+	/// every client.
+	pub fn poll(&self) -> ! {
+		let mut poll_id = None;
+
+		loop {
+			let mut template = match self.rpc.get_block_template(poll_id.as_deref()) {
+				Ok(template) => template,
+				Err(e) => {
+					tracing::warn!(error = %e, "failed to fetch block template");
+					std::thread::sleep(RETRY_INTERVAL);
+					continue;
+				}
+			};
+
+			poll_id = Some(std::mem::take(&mut template.longpoll_id));
+
+			match self.update(&template) {
+				Ok(()) => self.broadcast(),
+				Err(e) => tracing::warn!(error = %e, "invalid block template"),
+			}
+		}
+	}
+
+	/// Makes a template the current one that is sent to clients.
+	///
+	/// # Errors
+	/// Returns an error if the template contains an invalid transaction.
+	pub fn update(
+		&self,
+		template: &block::Template,
+	) -> Result<(), bitcoin::consensus::encode::Error> {
+		let transactions = template.transactions()?;
+		let mut templates = lock(&self.templates);
+
+		if templates
+			.templates
+			.back()
+			.is_some_and(|last| last.previous_block != template.previous_block)
+		{
+			templates.templates.clear();
+		} else if templates.templates.len() == MAX_TEMPLATES {
+			templates.templates.pop_front();
+		}
+
+		let id = templates.next_id;
+
+		tracing::debug!(id, height = template.height, "new template");
+
+		templates.next_id += 1;
+		templates.templates.push_back(Template {
+			id,
+			previous_block: template.previous_block,
+			version: template.version,
+			bits: template.bits(),
+			time: template.current_time,
+			target: template.target(),
+			height: template.height,
+			coinbase_value: template.coinbase_value,
+			coinbase_outputs: template
+				.witness_commitment
+				.iter()
+				.map(|commitment| bitcoin::TxOut {
+					value: bitcoin::Amount::ZERO,
+					script_pubkey: commitment.clone(),
+				})
+				.collect(),
+			merkle_path: template.merkle_branch(),
+			transactions,
+		});
+
+		Ok(())
+	}
+
+	/// Sends the current template to every client that is ready for one.
+	pub fn broadcast(&self) {
+		let connections = lock(&self.connections)
+			.values()
+			.cloned()
+			.collect::<Vec<_>>();
+
+		for connection in connections {
+			if let Err(e) = self.send_template(&connection) {
+				tracing::debug!(error = %e, peer = %connection.peer, "failed to send template");
+			}
+		}
+	}
+
+	fn serve(&self, stream: TcpStream, peer: SocketAddr) {
+		let (reader, writer) = match stream
+			.set_write_timeout(Some(WRITE_TIMEOUT))
+			.and_then(|()| stream.set_nodelay(true))
+			.map_err(Error::from)
+			.and_then(|()| noise::accept(stream, &self.static_key, &self.certificate))
+		{
+			Ok(connection) => connection,
+			Err(e) => {
+				tracing::debug!(error = %e, %peer, "template distribution handshake failed");
+				return;
+			}
+		};
+
+		let connection = Arc::new(Connection {
+			id: self.next_connection.fetch_add(1, Ordering::Relaxed),
+			peer,
+			writer: Mutex::new(writer),
+			state: Mutex::default(),
+		});
+
+		tracing::debug!(%peer, "template distribution client connected");
+
+		lock(&self.connections).insert(connection.id, Arc::clone(&connection));
+
+		let Err(e) = self.handle_connection(&connection, reader);
+
+		lock(&self.connections).remove(&connection.id);
+
+		tracing::info!(error = %e, %peer, "template distribution client disconnected");
+	}
+
+	fn handle_connection(
+		&self,
+		connection: &Connection,
+		mut reader: noise::Reader,
+	) -> Result<!, Error> {
+		let mut receive = || loop {
+			let frame = reader.read()?;
+
+			if let Some(message) = Message::from_frame(&frame)? {
+				return Ok::<_, Error>(message);
+			}
+
+			tracing::debug!(
+				extension = frame.extension_type,
+				message = frame.message_type,
+				"unsupported message from client"
+			);
+		};
+
+		let Message::SetupConnection {
+			protocol,
+			min_version,
+			max_version,
+			..
+		} = receive()?
+		else {
+			return Err(Error::Protocol("expected SetupConnection".to_string()));
+		};
+
+		let error_code = if protocol != TEMPLATE_DISTRIBUTION {
+			Some("unsupported-protocol")
+		} else if !(min_version..=max_version).contains(&VERSION) {
+			Some("protocol-version-mismatch")
+		} else {
+			None
+		};
+
+		if let Some(error_code) = error_code {
+			connection.send(&Message::SetupConnectionError {
+				flags: 0,
+				error_code: error_code.to_string(),
+			})?;
+
+			return Err(Error::Refused(error_code.to_string()));
+		}
+
+		connection.send(&Message::SetupConnectionSuccess {
+			used_version: VERSION,
+			flags: 0,
+		})?;
+
+		loop {
+			match receive()? {
+				Message::CoinbaseOutputDataSize {
+					coinbase_output_max_additional_size,
+				} => {
+					if coinbase_output_max_additional_size > MAX_ADDITIONAL_COINBASE_SIZE {
+						tracing::warn!(
+							peer = %connection.peer,
+							size = coinbase_output_max_additional_size,
+							"coinbase outputs may not fit in templates from the node"
+						);
+					}
+
+					lock(&connection.state).ready = true;
+					self.send_template(connection)?;
+				}
+				Message::RequestTransactionData { template_id } => {
+					connection.send(&self.transaction_data(template_id))?;
+				}
+				Message::SubmitSolution {
+					template_id,
+					version,
+					header_timestamp,
+					header_nonce,
+					coinbase_tx,
+				} => {
+					#[allow(clippy::cast_possible_wrap)]
+					self.submit_solution(
+						template_id,
+						bitcoin::block::Version::from_consensus(version as i32),
+						header_timestamp,
+						header_nonce,
+						&coinbase_tx,
+					)?;
+				}
+				message => tracing::debug!(?message, "unexpected message from client"),
+			}
+		}
+	}
+
+	/// Sends the newest template to a client that hasn't seen it yet, after
+	/// a `SetNewPrevHashTemplate` if it builds on a new tip.
+	fn send_template(&self, connection: &Connection) -> Result<(), Error> {
+		let (new_template, prev_hash) = {
+			let templates = lock(&self.templates);
+			let mut state = lock(&connection.state);
+			let Some(template) = templates.templates.back() else {
+				return Ok(());
+			};
+
+			if !state.ready || state.template_id == Some(template.id) {
+				return Ok(());
+			}
+
+			let new_tip = state.previous_block != Some(template.previous_block);
+
+			state.template_id = Some(template.id);
+			state.previous_block = Some(template.previous_block);
+
+			(
+				template.new_template(new_tip),
+				new_tip.then_some(Message::SetNewPrevHashTemplate {
+					template_id: template.id,
+					previous_block: template.previous_block,
+					header_timestamp: template.time,
+					bits: template.bits,
+					target: template.target,
+				}),
+			)
+		};
+
+		connection.send(&new_template)?;
+
+		if let Some(prev_hash) = prev_hash {
+			connection.send(&prev_hash)?;
+		}
+
+		Ok(())
+	}
+
+	fn transaction_data(&self, template_id: u64) -> Message {
+		let templates = lock(&self.templates);
+
+		match templates.get(template_id) {
+			Some(template) => Message::RequestTransactionDataSuccess {
+				template_id,
+				excess_data: Vec::new(),
+				transaction_list: template
+					.transactions
+					.iter()
+					.map(bitcoin::consensus::serialize)
+					.collect(),
+			},
+			None => Message::RequestTransactionDataError {
+				template_id,
+				error_code: "template-id-not-found".to_string(),
+			},
+		}
+	}
+
+	/// Assembles the block for a solution and submits it to the node.
+	fn submit_solution(
+		&self,
+		template_id: u64,
+		version: bitcoin::block::Version,
+		time: u32,
+		nonce: u32,
+		coinbase: &[u8],
+	) -> Result<(), Error> {
+		let mut coinbase: bitcoin::Transaction = bitcoin::consensus::deserialize(coinbase)
+			.map_err(|e| Error::Protocol(format!("invalid coinbase: {e}")))?;
+		let templates = lock(&self.templates);
+		let Some(template) = templates.get(template_id) else {
+			tracing::warn!(template_id, "solution for an unknown template");
+			return Ok(());
+		};
+
+		// segwit blocks need the witness reserved value, which clients may leave out
+		if let Some(input) = coinbase.input.first_mut() {
+			if !template.coinbase_outputs.is_empty() && input.witness.is_empty() {
+				input.witness.push([0; 32]);
+			}
+		}
+
+		let header = bitcoin::block::Header {
+			version,
+			prev_blockhash: template.previous_block,
+			merkle_root: crate::stratum::merkle_root(
+				&bitcoin::consensus::serialize(&stripped(&coinbase)),
+				&template.merkle_path,
+			),
+			time,
+			bits: template.bits,
+			nonce,
+		};
+		let hash = header.block_hash();
+
+		if !template.target.is_met_by(hash) {
+			tracing::warn!(
+				template_id,
+				?hash,
+				"solution does not meet the block target"
+			);
+			return Ok(());
+		}
+
+		let mut txdata = Vec::with_capacity(template.transactions.len() + 1);
+
+		txdata.push(coinbase);
+		txdata.extend(template.transactions.iter().cloned());
+		drop(templates);
+
+		tracing::info!(template_id, ?hash, "found block hash");
+
+		match self.rpc.submit_block(&bitcoin::Block { header, txdata }) {
+			Ok(()) => tracing::info!(?hash, "block accepted"),
+			Err(e) => tracing::error!(?hash, error = %e, "block rejected"),
+		}
+
+		Ok(())
+	}
+}
+
+impl Templates {
+	fn get(&self, id: u64) -> Option<&Template> {
+		self.templates.iter().find(|template| template.id == id)
+	}
+}
+
+impl Template {
+	fn new_template(&self, future_template: bool) -> Message {
+		let mut coinbase_tx_outputs = Vec::new();
+
+		for output in &self.coinbase_outputs {
+			// writing to a vec can't fail
+			let _ = output.consensus_encode(&mut coinbase_tx_outputs);
+		}
+
+		#[allow(clippy::cast_sign_loss)]
+		Message::NewTemplate {
+			template_id: self.id,
+			future_template,
+			version: self.version as u32,
+			coinbase_tx_version: 2,
+			// the BIP34 height, which clients follow with their own extranonce
+			coinbase_prefix: bitcoin::script::Builder::new()
+				.push_int(i64::from(self.height))
+				.into_script()
+				.into_bytes(),
+			coinbase_tx_input_sequence: u32::MAX,
+			coinbase_tx_value_remaining: self.coinbase_value,
+			coinbase_tx_outputs_count: u32::try_from(self.coinbase_outputs.len())
+				.unwrap_or(u32::MAX),
+			coinbase_tx_outputs,
+			coinbase_tx_locktime: 0,
+			merkle_path: self.merkle_path.clone(),
+		}
+	}
+}
+
+impl Connection {
+	fn send(&self, message: &Message) -> Result<(), Error> {
+		lock(&self.writer).write(&message.to_frame())
+	}
+}
+
+/// The transaction without its witness, which is how its txid is computed.
+fn stripped(transaction: &bitcoin::Transaction) -> bitcoin::Transaction {
+	let mut transaction = transaction.clone();
+
+	for input in &mut transaction.input {
+		input.witness.clear();
+	}
+
+	transaction
+}
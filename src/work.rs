@@ -0,0 +1,104 @@
+//! Work handed to the hashers, independent of where it came from.
+
+use std::{ops::Range, sync::Arc};
+
+use bitcoin::hashes::Hash as _;
+
+use crate::{rpc, stratum};
+
+/// A header to search, along with where solutions are sent.
+#[derive(Debug, Clone)]
+pub struct Job {
+	pub header: bitcoin::block::Header,
+	/// Hashes that meet this target are submitted
+	pub target: bitcoin::Target,
+	/// The nonces that can be searched before the job has to be rolled
+	pub nonce_range: Range<u32>,
+	pub kind: Kind,
+}
+
+#[derive(Debug, Clone)]
+pub enum Kind {
+	/// A block built from a node's template, submitted with `submitblock`
+	Block {
+		rpc: rpc::Client,
+		/// Every transaction in the block, starting with the coinbase
+		transactions: Arc<[bitcoin::Transaction]>,
+	},
+	/// A pool job, submitted as a share
+	Stratum(stratum::v1::Work),
+	/// A Stratum V2 pool job, submitted as a share
+	StratumV2(stratum::v2::Work),
+}
+
+impl Job {
+	/// Moves to a header that hasn't been searched yet, once the nonce range
+	/// is exhausted. Blocks bump the time, and pool jobs take a new extranonce.
+	pub fn roll(&mut self) {
+		match &mut self.kind {
+			Kind::Block { .. } => self.header.time += 1,
+			// without an extranonce2 the only thing left to roll is the time
+			Kind::Stratum(work) if work.extranonce2.is_empty() => self.header.time += 1,
+			Kind::Stratum(work) => {
+				work.roll();
+				self.header.merkle_root = work.merkle_root();
+			}
+			// standard channels have no extranonce at all
+			Kind::StratumV2(work) if work.extranonce.is_empty() => self.header.time += 1,
+			Kind::StratumV2(work) => {
+				work.roll();
+				self.header.merkle_root = work.merkle_root();
+			}
+		}
+	}
+
+	/// Whether the job is finished after a single solution, since solving a
+	/// block makes the template stale.
+	#[must_use]
+	pub fn is_block(&self) -> bool {
+		matches!(self.kind, Kind::Block { .. })
+	}
+
+	/// The serialized header, with the nonce set to the start of the range.
+	#[must_use]
+	pub fn encode_header(&self) -> [u8; 80] {
+		let header = &self.header;
+		let mut data = [0; 80];
+
+		data[0..4].copy_from_slice(&header.version.to_consensus().to_le_bytes());
+		data[4..36].copy_from_slice(header.prev_blockhash.as_byte_array());
+		data[36..68].copy_from_slice(header.merkle_root.as_byte_array());
+		data[68..72].copy_from_slice(&header.time.to_le_bytes());
+		data[72..76].copy_from_slice(&header.bits.to_consensus().to_le_bytes());
+		data[76..80].copy_from_slice(&self.nonce_range.start.to_le_bytes());
+		data
+	}
+}
+
+/// The share target for a pool difficulty, where difficulty 1 is
+/// [`bitcoin::Target::MAX`].
+#[must_use]
+#[allow(
+	clippy::cast_possible_truncation,
+	clippy::cast_sign_loss,
+	clippy::cast_precision_loss
+)]
+pub fn target_from_difficulty(difficulty: f64) -> bitcoin::Target {
+	if difficulty <= 0.0 {
+		return bitcoin::Target::from_be_bytes([0xff; 32]);
+	}
+
+	let mut value = f64::from(0xffff) * 2f64.powi(208) / difficulty;
+	let mut bytes = [0; 32];
+
+	// split the value into 64-bit limbs, most significant first
+	for (i, limb) in (0..4).rev().zip(bytes.chunks_exact_mut(8)) {
+		let scale = 2f64.powi(64 * i);
+		let word = (value / scale).floor().min(u64::MAX as f64);
+
+		limb.copy_from_slice(&(word as u64).to_be_bytes());
+		value -= word * scale;
+	}
+
+	bitcoin::Target::from_be_bytes(bytes)
+}
@@ -0,0 +1,244 @@
+//! A minimal ZMQ `SUB` socket speaking ZMTP 3.0 over TCP, enough to receive
+//! bitcoind's `-zmqpubhashblock` and `-zmqpubrawblock` notifications.
+//!
+//! See <https://rfc.zeromq.org/spec/23/> for the wire protocol.
+
+use std::{
+	fmt,
+	io::{self, Read, Write},
+	net::TcpStream,
+	time::Duration,
+};
+
+/// Sent when a new block is connected, with the block hash as the body
+pub const HASH_BLOCK: &str = "hashblock";
+/// Sent when a new block is connected, with the serialized block as the body
+pub const RAW_BLOCK: &str = "rawblock";
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// The largest frame we accept, which is well above the maximum block size
+const MAX_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	Protocol(&'static str),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "io error: {e}"),
+			Self::Protocol(e) => write!(f, "protocol error: {e}"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+	/// Whether nothing was received within the subscriber's timeout.
+	#[must_use]
+	pub fn is_timeout(&self) -> bool {
+		// unix reports read timeouts as `WouldBlock`, and windows as `TimedOut`
+		matches!(
+			self,
+			Self::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+		)
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
+
+/// A multipart message published by bitcoind.
+#[derive(Debug)]
+pub struct Message {
+	pub topic: String,
+	pub body: Vec<u8>,
+	/// The per-topic sequence number, used to detect dropped messages
+	pub sequence: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Subscriber {
+	stream: TcpStream,
+}
+
+impl Subscriber {
+	/// Connects to a publisher at `address`, which can be in the same
+	/// `tcp://host:port` form that bitcoind is configured with.
+	///
+	/// Reads give up after `timeout`, since a publisher that went away without
+	/// closing the connection would otherwise block [`Subscriber::recv`] forever.
+	///
+	/// # Errors
+	/// Returns an error if the connection or handshake fails.
+	pub fn connect(address: &str, topics: &[&str], timeout: Duration) -> Result<Self, Error> {
+		let address = address.strip_prefix("tcp://").unwrap_or(address);
+		let stream = TcpStream::connect(address)?;
+
+		stream.set_nodelay(true)?;
+		stream.set_read_timeout(Some(timeout))?;
+
+		let mut subscriber = Self { stream };
+
+		subscriber.handshake()?;
+
+		for topic in topics {
+			subscriber.subscribe(topic)?;
+		}
+
+		Ok(subscriber)
+	}
+
+	/// Blocks until the next message is published.
+	///
+	/// # Errors
+	/// Returns an error if the connection fails, the publisher sends an
+	/// invalid frame, or nothing arrives within the timeout. The subscriber
+	/// should be dropped after any error, since a frame may be half read.
+	pub fn recv(&mut self) -> Result<Message, Error> {
+		let mut parts = Vec::with_capacity(3);
+
+		loop {
+			let (flags, body) = self.read_frame()?;
+
+			// commands can be interleaved with messages, but none of them
+			// are relevant to a subscriber
+			if flags & FLAG_COMMAND != 0 {
+				continue;
+			}
+
+			parts.push(body);
+
+			if flags & FLAG_MORE == 0 {
+				break;
+			}
+		}
+
+		let mut parts = parts.into_iter();
+		let topic = parts.next().ok_or(Error::Protocol("empty message"))?;
+		let body = parts.next().unwrap_or_default();
+		let sequence = parts
+			.next()
+			.and_then(|s| s.try_into().ok())
+			.map(u32::from_le_bytes);
+
+		Ok(Message {
+			topic: String::from_utf8(topic).map_err(|_| Error::Protocol("invalid topic"))?,
+			body,
+			sequence,
+		})
+	}
+
+	fn handshake(&mut self) -> Result<(), Error> {
+		let mut greeting = [0; 64];
+
+		// signature
+		greeting[0] = 0xff;
+		greeting[9] = 0x7f;
+		// version 3.0
+		greeting[10] = 3;
+		greeting[11] = 0;
+		// mechanism, followed by as-server = 0 and the filler
+		greeting[12..16].copy_from_slice(b"NULL");
+
+		self.stream.write_all(&greeting)?;
+		self.stream.read_exact(&mut greeting)?;
+
+		if greeting[0] != 0xff || greeting[9] != 0x7f {
+			return Err(Error::Protocol("invalid greeting signature"));
+		}
+
+		if greeting[10] < 3 {
+			return Err(Error::Protocol("unsupported ZMTP version"));
+		}
+
+		if &greeting[12..16] != b"NULL" || greeting[16..32].iter().any(|&b| b != 0) {
+			return Err(Error::Protocol("unsupported security mechanism"));
+		}
+
+		let mut ready = Vec::with_capacity(32);
+
+		ready.push(5);
+		ready.extend_from_slice(b"READY");
+		ready.push(11);
+		ready.extend_from_slice(b"Socket-Type");
+		ready.extend_from_slice(&3u32.to_be_bytes());
+		ready.extend_from_slice(b"SUB");
+
+		self.write_frame(FLAG_COMMAND, &ready)?;
+
+		let (flags, body) = self.read_frame()?;
+
+		if flags & FLAG_COMMAND == 0 {
+			return Err(Error::Protocol("expected a command"));
+		}
+
+		match body.get(1..=usize::from(body.first().copied().unwrap_or(0))) {
+			Some(b"READY") => Ok(()),
+			Some(b"ERROR") => Err(Error::Protocol("publisher rejected the handshake")),
+			_ => Err(Error::Protocol("expected a READY command")),
+		}
+	}
+
+	fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+		// in ZMTP 3.0, subscriptions are messages starting with 0x01
+		let mut body = Vec::with_capacity(topic.len() + 1);
+
+		body.push(1);
+		body.extend_from_slice(topic.as_bytes());
+
+		self.write_frame(0, &body)
+	}
+
+	fn write_frame(&mut self, flags: u8, body: &[u8]) -> Result<(), Error> {
+		if let Ok(size) = u8::try_from(body.len()) {
+			self.stream.write_all(&[flags, size])?;
+		} else {
+			self.stream.write_all(&[flags | FLAG_LONG])?;
+			self.stream.write_all(&(body.len() as u64).to_be_bytes())?;
+		}
+
+		self.stream.write_all(body)?;
+
+		Ok(())
+	}
+
+	fn read_frame(&mut self) -> Result<(u8, Vec<u8>), Error> {
+		let mut flags = [0; 1];
+
+		self.stream.read_exact(&mut flags)?;
+
+		let [flags] = flags;
+		let size = if flags & FLAG_LONG == 0 {
+			let mut size = [0; 1];
+
+			self.stream.read_exact(&mut size)?;
+			u64::from(size[0])
+		} else {
+			let mut size = [0; 8];
+
+			self.stream.read_exact(&mut size)?;
+			u64::from_be_bytes(size)
+		};
+
+		if size > MAX_FRAME_SIZE {
+			return Err(Error::Protocol("frame too large"));
+		}
+
+		let mut body =
+			vec![0; usize::try_from(size).map_err(|_| Error::Protocol("frame too large"))?];
+
+		self.stream.read_exact(&mut body)?;
+
+		Ok((flags, body))
+	}
+}
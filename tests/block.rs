@@ -0,0 +1,63 @@
+use miner::block;
+use serde_json::{json, Value};
+
+/// A `getblocktemplate` result on top of the genesis block.
+fn template() -> Value {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+
+	json!({
+		"version": 0x2000_0000,
+		"previousblockhash": genesis.block_hash().to_string(),
+		"transactions": [],
+		"longpollid": "",
+		"target": "00000000ffff0000000000000000000000000000000000000000000000000000",
+		"bits": "1d00ffff",
+		"curtime": 1_700_000_000,
+		"coinbasevalue": 5_000_000_000_u64,
+		"noncerange": "00000000ffffffff",
+		"height": 1,
+	})
+}
+
+#[test]
+fn parses_hashes_in_display_order() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let transaction = &genesis.txdata[0];
+	let mut template = template();
+
+	template["transactions"] = json!([{
+		"txid": transaction.txid().to_string(),
+		"data": bitcoin::consensus::encode::serialize_hex(transaction),
+		"hash": transaction.wtxid().to_string(),
+		"fee": 0,
+		"weight": transaction.weight().to_wu(),
+	}]);
+
+	let template: block::Template = serde_json::from_value(template).unwrap();
+
+	assert_eq!(template.previous_block, genesis.block_hash());
+	assert_eq!(template.transactions[0].id, transaction.txid());
+	assert_eq!(template.transactions[0].hash, transaction.wtxid());
+}
+
+#[test]
+fn parses_the_target_as_big_endian() {
+	// the target and bits of block 840,000
+	let mut template = template();
+
+	template["target"] = "0000000000000000000342190000000000000000000000000000000000000000".into();
+	template["bits"] = "17034219".into();
+
+	let template: block::Template = serde_json::from_value(template).unwrap();
+
+	assert_eq!(template.bits().to_consensus(), 0x1703_4219);
+	assert_eq!(
+		template.target(),
+		bitcoin::Target::from_compact(template.bits())
+	);
+	assert!(template.target().is_met_by(
+		"0000000000000000000320283a032748cef8227873ff4872689bf23f1cda83a5"
+			.parse()
+			.unwrap()
+	));
+}
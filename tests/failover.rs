@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use miner::failover::{Failover, SPLIT_PERIOD};
+
+#[test]
+fn stays_on_one_upstream_without_a_split() {
+	for failover in [Failover::new(vec![]), Failover::new(vec![]).with_split(0.0)] {
+		for elapsed in [Duration::ZERO, SPLIT_PERIOD / 2, SPLIT_PERIOD * 3] {
+			assert!(!failover.on_second(elapsed));
+			assert_eq!(failover.next_switch(elapsed), Duration::MAX);
+		}
+	}
+
+	let failover = Failover::new(vec![]).with_split(100.0);
+
+	for elapsed in [Duration::ZERO, SPLIT_PERIOD / 2, SPLIT_PERIOD * 3] {
+		assert!(failover.on_second(elapsed));
+		assert_eq!(failover.next_switch(elapsed), Duration::MAX);
+	}
+}
+
+#[test]
+fn splits_each_period_between_upstreams() {
+	let failover = Failover::new(vec![]).with_split(25.0);
+	let quarter = SPLIT_PERIOD / 4;
+
+	// the second upstream gets the first quarter of every period
+	assert!(failover.on_second(Duration::ZERO));
+	assert_eq!(failover.next_switch(Duration::ZERO), quarter);
+	assert!(failover.on_second(quarter / 2));
+	assert_eq!(failover.next_switch(quarter / 2), quarter / 2);
+
+	assert!(!failover.on_second(quarter));
+	assert_eq!(failover.next_switch(quarter), quarter * 3);
+	assert!(!failover.on_second(quarter * 3));
+	assert_eq!(failover.next_switch(quarter * 3), quarter);
+
+	assert!(failover.on_second(SPLIT_PERIOD));
+	assert_eq!(failover.next_switch(SPLIT_PERIOD * 2), quarter);
+}
@@ -0,0 +1,193 @@
+use std::{
+	io::{Read as _, Write as _},
+	net::{TcpListener, TcpStream},
+	sync::Arc,
+	thread,
+	time::Duration,
+};
+
+use bitcoin::hashes::Hash as _;
+use miner::{getwork::Getwork, mock};
+use serde_json::{json, Value};
+
+const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+/// Starts a getwork server on templates from `node`, returning its url.
+fn getwork(node: &mock::Server) -> String {
+	let address = ADDRESS
+		.parse::<bitcoin::Address<_>>()
+		.unwrap()
+		.require_network(bitcoin::Network::Bitcoin)
+		.unwrap();
+	let getwork = Arc::new(Getwork::new(node.client("user", "pass"), address));
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+
+	thread::spawn({
+		let getwork = Arc::clone(&getwork);
+
+		move || getwork.listen(&listener)
+	});
+	thread::spawn(move || getwork.poll());
+
+	url
+}
+
+fn call(url: &str, params: &Value) -> Value {
+	ureq::post(url)
+		.send_json(json!({ "id": 1, "method": "getwork", "params": params }))
+		.unwrap()
+		.into_json()
+		.unwrap()
+}
+
+/// Fetches a work unit, waiting for the server's first template.
+fn work(url: &str) -> (Vec<u8>, bitcoin::Target) {
+	for _ in 0..50 {
+		let response = call(url, &json!([]));
+
+		if let Some(data) = response["result"]["data"].as_str() {
+			let target = hex::decode(response["result"]["target"].as_str().unwrap()).unwrap();
+
+			return (
+				hex::decode(data).unwrap(),
+				bitcoin::Target::from_le_bytes(target.try_into().unwrap()),
+			);
+		}
+
+		thread::sleep(Duration::from_millis(100));
+	}
+
+	panic!("no work was served");
+}
+
+/// Unswaps the words of getwork data, giving back the header.
+fn header(data: &[u8]) -> bitcoin::block::Header {
+	let mut header = data[..80].to_vec();
+
+	for word in header.chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	bitcoin::consensus::deserialize(&header).unwrap()
+}
+
+/// Swaps the words of a header back, keeping the padding from `data`.
+fn data(header: &bitcoin::block::Header, data: &[u8]) -> String {
+	let mut data = data.to_vec();
+
+	data[..80].copy_from_slice(&bitcoin::consensus::serialize(header));
+
+	for word in data[..80].chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	hex::encode(data)
+}
+
+#[test]
+fn serves_work_and_submits_solved_headers() {
+	let node = mock::Server::start("user", "pass");
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let mut template = mock::template(840_000, genesis.block_hash());
+
+	mock::add_transactions(&mut template, 3);
+	node.push_template(template);
+
+	let url = getwork(&node);
+	let (first, target) = work(&url);
+	let (second, _) = work(&url);
+
+	assert_eq!(second.len(), 128);
+	assert_eq!(second[80..84], [0, 0, 0, 0x80]);
+	assert_eq!(second[124..], [0x80, 0x02, 0, 0]);
+
+	// every work unit has its own extranonce, and so its own merkle root
+	let first = header(&first);
+	let mut header = header(&second);
+
+	assert_ne!(header.merkle_root, first.merkle_root);
+	assert_eq!(header.prev_blockhash, genesis.block_hash());
+
+	while !target.is_met_by(header.block_hash()) {
+		header.nonce += 1;
+	}
+
+	let mut unknown = header;
+
+	unknown.merkle_root = bitcoin::TxMerkleNode::all_zeros();
+
+	assert_eq!(
+		call(&url, &json!([data(&unknown, &second)]))["result"],
+		false
+	);
+	assert_eq!(call(&url, &json!([data(&header, &second)]))["result"], true);
+
+	let blocks = node
+		.wait_for_blocks(1, Duration::from_secs(5))
+		.expect("no block was submitted");
+	let block = &blocks[0];
+
+	assert_eq!(block.header, header);
+	assert_eq!(block.txdata.len(), 4);
+	assert!(block.check_merkle_root());
+	assert!(block.check_witness_commitment());
+}
+
+/// Grinds the nonce of a work unit until it meets `target`.
+fn solve(data: &[u8], target: bitcoin::Target) -> bitcoin::block::Header {
+	let mut header = header(data);
+
+	while !target.is_met_by(header.block_hash()) {
+		header.nonce += 1;
+	}
+
+	header
+}
+
+#[test]
+fn drops_the_oldest_work_units() {
+	let node = mock::Server::start("user", "pass");
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+
+	node.push_template(mock::template(840_000, genesis.block_hash()));
+
+	let url = getwork(&node);
+	let (oldest, target) = work(&url);
+	let (kept, _) = work(&url);
+
+	// the server keeps 1024 work units per template
+	for _ in 0..1023 {
+		work(&url);
+	}
+
+	let header = solve(&oldest, target);
+
+	assert_eq!(
+		call(&url, &json!([data(&header, &oldest)]))["result"],
+		false
+	);
+
+	let header = solve(&kept, target);
+
+	assert_eq!(call(&url, &json!([data(&header, &kept)]))["result"], true);
+}
+
+#[test]
+fn rejects_large_request_bodies() {
+	let node = mock::Server::start("user", "pass");
+	let url = getwork(&node);
+	let mut stream = TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+
+	write!(
+		stream,
+		"POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 1000000000\r\n\r\n"
+	)
+	.unwrap();
+
+	let mut response = String::new();
+
+	stream.read_to_string(&mut response).unwrap();
+
+	assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+}
@@ -0,0 +1,465 @@
+use bitcoin::hashes::Hash as _;
+use miner::{
+	backend::{simd::Implementation, Backend, Cpu},
+	gpu, work,
+};
+
+/// The GPU hasher, or `None` on machines without an adapter, where these
+/// tests pass without checking anything.
+fn hasher() -> Option<gpu::Hasher> {
+	match gpu::Hasher::new() {
+		Ok(hasher) => Some(hasher),
+		Err(e) => {
+			eprintln!("skipping, {e}");
+			None
+		}
+	}
+}
+
+fn genesis_header() -> [u8; 80] {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+
+	bitcoin::consensus::serialize(&genesis.header)
+		.try_into()
+		.unwrap()
+}
+
+#[test]
+fn finds_nonces_in_the_searched_range() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let header = genesis_header();
+	let target = work::target_from_difficulty(1.0 / 65536.0);
+
+	for nonces in [0..1 << 18, 1 << 20..(1 << 20) + (1 << 18)] {
+		let hits = hasher
+			.search(header, nonces.clone(), target, bitcoin::Target::MAX)
+			.unwrap();
+		let nonce = hits.blocks().next().expect("no nonce was found");
+		let mut solved = header;
+
+		solved[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+		assert!(nonces.contains(&nonce));
+		assert!(target.is_met_by(bitcoin::BlockHash::hash(&solved)));
+	}
+}
+
+#[test]
+fn finds_the_genesis_nonce() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let nonce = 2_083_236_893;
+	let target = bitcoin::CompactTarget::from_consensus(0x1d00_ffff).into();
+	let hits = hasher
+		.search(
+			genesis_header(),
+			nonce - 1000..nonce + 1000,
+			target,
+			bitcoin::Target::MAX,
+		)
+		.unwrap();
+
+	assert_eq!(hits.blocks().collect::<Vec<_>>(), [nonce]);
+}
+
+#[test]
+fn counts_the_same_shares_as_the_cpu() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let header = genesis_header();
+	let share_target = work::target_from_difficulty(1.0 / 1_048_576.0);
+	// a range that doesn't split evenly between the threads
+	let nonces = 1000..1 << 18 | 3;
+	let expected = Cpu::new(Implementation::Scalar)
+		.unwrap()
+		.search(header, nonces.clone(), bitcoin::Target::ZERO, share_target)
+		.unwrap();
+
+	assert!(expected.shares > 0);
+	assert_eq!(
+		hasher
+			.search(header, nonces, bitcoin::Target::ZERO, share_target)
+			.unwrap(),
+		expected
+	);
+}
+
+#[test]
+fn reports_every_hit_in_a_dispatch() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let header = genesis_header();
+	// no nonce in the range meets it, so the dispatch isn't stopped early
+	let target = bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(0x1d00_ffff));
+	let share_target = work::target_from_difficulty(1.0 / 1_048_576.0);
+	let nonces = 0..1 << 18;
+	let output = hasher
+		.process(
+			header,
+			nonces.clone(),
+			target.to_le_bytes(),
+			share_target.to_le_bytes(),
+		)
+		.unwrap();
+
+	assert_eq!(output.searched, u64::from(nonces.end));
+	assert_eq!(output.dropped, 0);
+	assert!(output.hits.len() > 1);
+
+	for hit in &output.hits {
+		let mut solved = header;
+		solved[76..80].copy_from_slice(&hit.nonce.to_le_bytes());
+
+		let hash = bitcoin::BlockHash::hash(&solved);
+
+		assert!(nonces.contains(&hit.nonce));
+		assert_eq!(hit.kind == gpu::HitKind::Block, target.is_met_by(hash));
+		assert!(share_target.is_met_by(hash));
+	}
+}
+
+#[test]
+fn keeps_blocks_among_a_flood_of_shares() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let header = genesis_header();
+	let nonce = 2_083_236_893;
+	let nonces = nonce - 8192..nonce + 8192;
+	let target = bitcoin::CompactTarget::from_consensus(0x1d00_ffff).into();
+	// a quarter of the hashes are shares, far more than the GPU can report
+	let share_target = work::target_from_difficulty(1.0 / 1_073_741_824.0);
+	let expected = Cpu::new(Implementation::Scalar)
+		.unwrap()
+		.search(header, nonces.clone(), bitcoin::Target::ZERO, share_target)
+		.unwrap();
+	let hits = hasher.search(header, nonces, target, share_target).unwrap();
+
+	assert_eq!(hits.blocks().collect::<Vec<_>>(), [nonce]);
+	// the shares after the block aren't searched
+	assert!(hits.shares > 0 && hits.shares <= expected.shares);
+}
+
+#[test]
+fn searches_with_any_workgroup_layout() {
+	let Some(mut hasher) = hasher() else {
+		return;
+	};
+	let nonce = 2_083_236_893;
+	let target = bitcoin::CompactTarget::from_consensus(0x1d00_ffff).into();
+
+	hasher.set_config(gpu::Config {
+		workgroup_size: 64,
+		workgroups: 3,
+		dispatch_size: 1000,
+		batch_size: 1000,
+	});
+
+	let hits = hasher
+		.search(
+			genesis_header(),
+			nonce - 2500..nonce + 2500,
+			target,
+			bitcoin::Target::MAX,
+		)
+		.unwrap();
+
+	assert_eq!(hits.blocks().collect::<Vec<_>>(), [nonce]);
+	// the dispatch with the block is the third, and the ones after it that
+	// were already in flight stop without searching
+	assert!(hits.hashes > 2000 && hits.hashes <= 2501);
+}
+
+#[test]
+fn counts_hashes_up_to_the_block() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let nonce = 2_083_236_893;
+	let target = bitcoin::CompactTarget::from_consensus(0x1d00_ffff).into();
+	let hits = hasher
+		.search(
+			genesis_header(),
+			nonce..nonce + (1 << 20),
+			target,
+			bitcoin::Target::MAX,
+		)
+		.unwrap();
+
+	assert_eq!(hits.blocks().collect::<Vec<_>>(), [nonce]);
+	// the other threads searched past the block, but the miner resumes after
+	// it, so those hashes would be counted twice
+	assert_eq!(hits.hashes, 1);
+}
+
+#[test]
+fn reads_back_pipelined_dispatches_in_order() {
+	let Some(mut hasher) = hasher() else {
+		return;
+	};
+	let header = genesis_header();
+	let share_target = work::target_from_difficulty(1.0 / 1_048_576.0);
+	let expected = Cpu::new(Implementation::Scalar)
+		.unwrap()
+		.search(header, 0..1 << 15, bitcoin::Target::ZERO, share_target)
+		.unwrap();
+	let job = gpu::Job::new(&header, [0; 32], share_target.to_le_bytes());
+
+	hasher.set_config(gpu::Config {
+		dispatch_size: 1 << 12,
+		..hasher.config()
+	});
+
+	let mut pipeline = hasher.pipeline();
+	let mut outputs = Vec::new();
+
+	pipeline.submit(&job, 0..1 << 12);
+
+	// the first dispatch is polled for without blocking
+	loop {
+		if let Some(output) = pipeline.try_wait().unwrap() {
+			outputs.push(output);
+			break;
+		}
+	}
+
+	for start in (1 << 12..1 << 15).step_by(1 << 12) {
+		if pipeline.is_full() {
+			outputs.push(pipeline.wait().unwrap().unwrap());
+		}
+
+		pipeline.submit(&job, start..start + (1 << 12));
+	}
+
+	while let Some(output) = pipeline.wait().unwrap() {
+		outputs.push(output);
+	}
+
+	assert_eq!(outputs.len(), 8);
+
+	for (i, output) in (0..).zip(&outputs) {
+		assert_eq!(output.searched, 1 << 12);
+		assert!(output
+			.hits
+			.iter()
+			.all(|hit| hit.nonce >> 12 == i && hit.kind == gpu::HitKind::Share));
+	}
+
+	assert_eq!(
+		outputs
+			.iter()
+			.map(|output| output.hits.len())
+			.sum::<usize>() as u64,
+		expected.shares
+	);
+}
+
+#[test]
+fn searches_several_headers_in_a_dispatch() {
+	let Some(hasher) = hasher() else {
+		return;
+	};
+	let genesis = genesis_header();
+	let nonce = 2_083_236_893;
+	let nonces = nonce - 2048..nonce + 2048;
+	let target = bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(0x1d00_ffff));
+	let share_target = work::target_from_difficulty(1.0 / 16_777_216.0);
+	// the genesis header with each of the next few timestamps
+	let headers = (1..4u32)
+		.map(|offset| {
+			let mut header = genesis;
+			let time = u32::from_le_bytes(header[68..72].try_into().unwrap()) + offset;
+
+			header[68..72].copy_from_slice(&time.to_le_bytes());
+			header
+		})
+		.chain([genesis])
+		.collect::<Vec<_>>();
+	// nothing meets a zero target, so every share is searched for
+	let mut shares = gpu::Job::default();
+	let mut blocks = gpu::Job::default();
+
+	for header in &headers {
+		shares.push(header, [0; 32], share_target.to_le_bytes());
+		blocks.push(header, target.to_le_bytes(), share_target.to_le_bytes());
+	}
+
+	let output = hasher.process_job(&shares, nonces.clone()).unwrap();
+
+	assert_eq!(shares.len(), 4);
+	assert_eq!(output.searched, 4 * 4096);
+	assert_eq!(output.dropped, 0);
+
+	for (index, header) in headers.iter().enumerate() {
+		let expected = Cpu::new(Implementation::Scalar)
+			.unwrap()
+			.search(*header, nonces.clone(), bitcoin::Target::ZERO, share_target)
+			.unwrap();
+		let hits = output
+			.hits
+			.iter()
+			.filter(|hit| hit.header == index)
+			.collect::<Vec<_>>();
+
+		assert!(expected.shares > 0);
+		assert_eq!(hits.len() as u64, expected.shares);
+
+		for hit in hits {
+			let mut solved = *header;
+			solved[76..80].copy_from_slice(&hit.nonce.to_le_bytes());
+
+			assert!(share_target.is_met_by(bitcoin::BlockHash::hash(&solved)));
+		}
+	}
+
+	let output = hasher.process_job(&blocks, nonces).unwrap();
+	let found = output
+		.hits
+		.iter()
+		.filter(|hit| hit.kind == gpu::HitKind::Block)
+		.collect::<Vec<_>>();
+
+	assert_eq!(found.len(), 1);
+	assert_eq!((found[0].header, found[0].nonce), (3, nonce));
+}
+
+#[test]
+fn stops_a_dispatch_after_a_block_hit() {
+	let Some(mut hasher) = hasher() else {
+		return;
+	};
+	let nonce = 2_083_236_893;
+	let target = bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(0x1d00_ffff));
+	let job = gpu::Job::new(&genesis_header(), target.to_le_bytes(), [0; 32]);
+
+	hasher.set_config(gpu::Config {
+		dispatch_size: 1 << 20,
+		..hasher.config()
+	});
+
+	let mut pipeline = hasher.pipeline();
+
+	// the block is the first nonce of the first dispatch
+	for start in [nonce, nonce + (1 << 20), nonce + (2 << 20)] {
+		pipeline.submit(&job, start..start + (1 << 20));
+	}
+
+	let first = pipeline.wait().unwrap().unwrap();
+
+	assert!(first.found());
+	assert!(first.searched < 1 << 19);
+
+	// the dispatches behind it search a stale header, so they stop at once
+	while let Some(output) = pipeline.wait().unwrap() {
+		assert_eq!(output, gpu::Output::default());
+	}
+
+	drop(pipeline);
+
+	// a new pipeline searches again
+	assert!(hasher.process_job(&job, nonce..nonce + 1).unwrap().found());
+}
+
+#[test]
+fn tunes_and_caches_a_config() {
+	let Some(mut hasher) = hasher() else {
+		return;
+	};
+	let cache = std::env::temp_dir().join(format!("miner-gpu-tune-{}.json", std::process::id()));
+	let tuner = gpu::Tuner {
+		max_latency: std::time::Duration::from_secs(10),
+		workgroup_sizes: vec![32, 64],
+		workgroups: vec![4],
+		nonces_per_thread: vec![16, 64],
+		cache: Some(cache.clone()),
+		..Default::default()
+	};
+	let config = tuner.tune(&mut hasher).unwrap();
+
+	assert!([32, 64].contains(&config.workgroup_size));
+	assert_eq!(config.workgroups, 4);
+	assert!(
+		[16, 64].contains(&(config.dispatch_size / (config.workgroup_size * config.workgroups)))
+	);
+	assert_eq!(config.batch_size % config.dispatch_size, 0);
+	assert_eq!(hasher.config(), config);
+
+	// there's nothing left to benchmark, so the config has to come from the cache
+	hasher.set_config(gpu::Config::default());
+
+	let cached = gpu::Tuner {
+		workgroup_sizes: Vec::new(),
+		..tuner
+	}
+	.tune(&mut hasher);
+
+	std::fs::remove_file(&cache).unwrap();
+
+	assert_eq!(cached.unwrap(), config);
+}
+
+#[test]
+fn parses_adapter_selectors() {
+	assert_eq!("1".parse(), Ok(gpu::Selector::Index(1)));
+	assert_eq!(
+		"Vulkan".parse(),
+		Ok(gpu::Selector::Backend(wgpu::Backend::Vulkan))
+	);
+	assert_eq!("gl".parse(), Ok(gpu::Selector::Backend(wgpu::Backend::Gl)));
+	assert_eq!(
+		"rtx 4090".parse(),
+		Ok(gpu::Selector::Name("rtx 4090".to_string()))
+	);
+	assert!("".parse::<gpu::Selector>().is_err());
+}
+
+#[test]
+fn searches_on_every_selected_adapter() {
+	let adapters = gpu::adapters();
+
+	if adapters.is_empty() {
+		eprintln!("skipping, no adapters found");
+		return;
+	}
+
+	let selectors = (0..adapters.len())
+		.map(gpu::Selector::Index)
+		.collect::<Vec<_>>();
+	let devices = gpu::Devices::select(&selectors).unwrap();
+	let nonce = 2_083_236_893;
+	let target = bitcoin::CompactTarget::from_consensus(0x1d00_ffff).into();
+
+	assert_eq!(devices.hashers().len(), adapters.len());
+
+	// the second search splits the range by the rates from the first
+	for _ in 0..2 {
+		let hits = devices
+			.search(
+				genesis_header(),
+				nonce - 1000..nonce + 1000,
+				target,
+				bitcoin::Target::MAX,
+			)
+			.unwrap();
+
+		assert_eq!(hits.blocks().collect::<Vec<_>>(), [nonce]);
+		assert!(hits.hashes > 0 && hits.hashes <= 1001);
+		// the genesis block is the only difficulty 1 share in the range
+		assert_eq!(hits.shares, 1);
+	}
+
+	assert!(devices.hash_rates().iter().all(|rate| *rate > 0.0));
+}
+
+#[test]
+fn rejects_selectors_without_a_match() {
+	assert!(matches!(
+		gpu::Devices::select(&[gpu::Selector::Index(usize::MAX)]),
+		Err(gpu::Error::NoMatch(gpu::Selector::Index(usize::MAX)))
+	));
+}
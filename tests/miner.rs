@@ -0,0 +1,320 @@
+use std::{
+	ops::Range,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use bitcoin::hashes::Hash as _;
+use miner::{
+	backend::{Backend, Hit, HitKind, Hits},
+	block, mock, rpc,
+	solo::Solo,
+	work, Miner,
+};
+use serde_json::{json, Value};
+
+const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+#[test]
+fn mines_blocks_end_to_end() {
+	let server = mock::Server::start("user", "pass");
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+
+	server.push_template(mock::template(840_000, genesis.block_hash()));
+
+	let miner = Miner::new(Solo::new(server.client("user", "pass"), ADDRESS), false);
+
+	std::thread::spawn(move || miner.mine());
+
+	let blocks = server
+		.wait_for_blocks(1, Duration::from_secs(30))
+		.expect("no block was submitted");
+	let block = &blocks[0];
+	let target =
+		bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(mock::EASY_BITS));
+
+	assert_eq!(block.header.prev_blockhash, genesis.block_hash());
+	assert!(block.header.validate_pow(target).is_ok());
+	assert!(block.check_merkle_root());
+	assert_eq!(block.bip34_block_height(), Ok(840_000));
+
+	let address: bitcoin::Address<_> = ADDRESS.parse().unwrap();
+	assert_eq!(
+		block.txdata[0].output[0].script_pubkey,
+		address.assume_checked().script_pubkey()
+	);
+
+	// the miner switches to the next template once the block is found
+	server.push_template(mock::template(840_001, block.block_hash()));
+
+	let next = loop {
+		let blocks = server
+			.wait_for_blocks(server.submitted().len() + 1, Duration::from_secs(30))
+			.expect("no block was submitted");
+		let last = blocks.last().unwrap().clone();
+
+		if last.header.prev_blockhash == block.block_hash() {
+			break last;
+		}
+	};
+
+	assert_eq!(next.bip34_block_height(), Ok(840_001));
+	assert!(next.header.validate_pow(target).is_ok());
+}
+
+/// A solo upstream whose node is never reached, for building jobs directly.
+fn solo() -> Solo {
+	let rpc = rpc::Client::new(
+		"http://127.0.0.1:1".to_string(),
+		"user",
+		"pass",
+		&rpc::Options::default(),
+	)
+	.unwrap();
+
+	Solo::new(rpc, ADDRESS)
+}
+
+/// A `getblocktemplate` result on top of the genesis block at `bits`, with
+/// one nonce per header so the time has to be rolled.
+fn template(height: u32, bits: u32) -> Value {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let target = bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(bits));
+
+	json!({
+		"version": 0x2000_0000,
+		"previousblockhash": genesis.block_hash().to_string(),
+		"transactions": [],
+		"longpollid": "",
+		"target": hex::encode(target.to_be_bytes()),
+		"bits": format!("{bits:08x}"),
+		"curtime": 1_700_000_000,
+		"coinbasevalue": 312_500_000,
+		"noncerange": "0000000001000000",
+		"height": height,
+	})
+}
+
+/// Adds `count` segwit transactions spending made-up outputs to a template,
+/// along with the witness commitment the node would send for them.
+fn add_transactions(template: &mut Value, count: u8) {
+	let transactions = (0..count)
+		.map(|i| bitcoin::Transaction {
+			version: bitcoin::transaction::Version::TWO,
+			lock_time: bitcoin::absolute::LockTime::ZERO,
+			input: vec![bitcoin::TxIn {
+				previous_output: bitcoin::OutPoint {
+					txid: bitcoin::Txid::from_byte_array([i + 1; 32]),
+					vout: 0,
+				},
+				witness: bitcoin::Witness::from_slice(&[[i; 32]]),
+				..Default::default()
+			}],
+			output: vec![bitcoin::TxOut {
+				value: bitcoin::Amount::from_sat(1_000),
+				script_pubkey: bitcoin::ScriptBuf::new(),
+			}],
+		})
+		.collect::<Vec<_>>();
+
+	// the coinbase's wtxid is all zeros
+	let witness_root = bitcoin::merkle_tree::calculate_root(
+		std::iter::once(bitcoin::Wtxid::all_zeros())
+			.chain(transactions.iter().map(bitcoin::Transaction::wtxid))
+			.map(bitcoin::Wtxid::to_raw_hash),
+	)
+	.unwrap();
+	let commitment = bitcoin::Block::compute_witness_commitment(
+		&bitcoin::hash_types::WitnessMerkleNode::from_raw_hash(witness_root),
+		&[0; 32],
+	);
+	let mut script = b"\x6a\x24\xaa\x21\xa9\xed".to_vec();
+
+	script.extend_from_slice(commitment.as_byte_array());
+
+	template["default_witness_commitment"] = hex::encode(script).into();
+	template["transactions"] = transactions
+		.iter()
+		.map(|transaction| {
+			json!({
+				"txid": transaction.txid().to_string(),
+				"data": bitcoin::consensus::encode::serialize_hex(transaction),
+				"hash": transaction.wtxid().to_string(),
+				"fee": 1_000,
+				"weight": transaction.weight().to_wu(),
+			})
+		})
+		.collect();
+}
+
+/// The job for a template, along with the block it submits once solved.
+fn job(template: Value) -> (work::Job, bitcoin::Block) {
+	let template: block::Template = serde_json::from_value(template).unwrap();
+	let job = solo().job(&template).unwrap();
+	let work::Kind::Block { transactions, .. } = &job.kind else {
+		panic!("not a block job");
+	};
+	let block = bitcoin::Block {
+		header: job.header,
+		txdata: transactions.to_vec(),
+	};
+
+	(job, block)
+}
+
+#[test]
+fn rolls_the_time_when_the_nonces_run_out() {
+	let (mut job, _) = job(template(840_000, 0x1f00_ffff));
+
+	job.roll();
+
+	assert_eq!(job.header.time, 1_700_000_001);
+	assert_eq!(
+		job.encode_header().to_vec(),
+		bitcoin::consensus::serialize(&job.header)
+	);
+}
+
+#[test]
+fn commits_to_every_transaction() {
+	let mut template = template(840_000, 0x1f00_ffff);
+
+	add_transactions(&mut template, 3);
+
+	let (_, block) = job(template);
+
+	assert_eq!(block.txdata.len(), 4);
+	assert!(block.check_merkle_root());
+}
+
+#[test]
+fn commits_to_the_witnesses() {
+	let mut template = template(840_000, 0x1f00_ffff);
+
+	add_transactions(&mut template, 2);
+
+	let (_, block) = job(template);
+
+	assert!(block.check_witness_commitment());
+	assert_eq!(block.txdata[0].input[0].witness.to_vec(), [[0; 32]]);
+}
+
+#[test]
+fn pushes_the_height_in_the_coinbase() {
+	let (_, block) = job(template(840_000, 0x1f00_ffff));
+
+	assert_eq!(block.bip34_block_height(), Ok(840_000));
+}
+
+#[test]
+fn counts_shares_below_the_block_target() {
+	let server = mock::Server::start("user", "pass");
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let mut template = mock::template(840_000, genesis.block_hash());
+	// a block takes ~2^20 hashes, and a share ~2^12
+	let target = work::target_from_difficulty(1.0 / 4096.0);
+
+	template["bits"] = format!("{:08x}", target.to_compact_lossy().to_consensus()).into();
+	template["target"] = hex::encode(target.to_be_bytes()).into();
+	server.push_template(template);
+
+	let miner = Arc::new(
+		Miner::new(Solo::new(server.client("user", "pass"), ADDRESS), false)
+			.with_share_target(work::target_from_difficulty(1.0 / 1_048_576.0)),
+	);
+
+	std::thread::spawn({
+		let miner = Arc::clone(&miner);
+
+		move || miner.mine()
+	});
+
+	let blocks = server
+		.wait_for_blocks(1, Duration::from_secs(120))
+		.expect("no block was submitted");
+
+	assert!(target.is_met_by(blocks[0].block_hash()));
+	// the block's own hash is a share too, along with the ones before it
+	assert!(miner.shares() >= 16, "only {} shares found", miner.shares());
+}
+
+/// Searches one nonce at a time, in small batches.
+#[derive(Debug, Default)]
+struct Scalar {
+	searches: Arc<AtomicU64>,
+}
+
+impl Backend for Scalar {
+	fn search(
+		&self,
+		mut header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		share_target: bitcoin::Target,
+	) -> Result<Hits, miner::Error> {
+		let mut hits = Hits::default();
+
+		self.searches.fetch_add(1, Ordering::Relaxed);
+
+		for nonce in nonces {
+			header[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+			let hash = bitcoin::BlockHash::hash(&header);
+
+			hits.hashes += 1;
+			hits.shares += u64::from(share_target.is_met_by(hash));
+
+			if target.is_met_by(hash) {
+				hits.hits.push(Hit {
+					nonce,
+					kind: HitKind::Block,
+				});
+				break;
+			}
+
+			if share_target.is_met_by(hash) {
+				hits.hits.push(Hit {
+					nonce,
+					kind: HitKind::Share,
+				});
+			}
+		}
+
+		Ok(hits)
+	}
+
+	fn batch_size(&self) -> u32 {
+		1024
+	}
+}
+
+#[test]
+fn mines_with_custom_backends() {
+	let server = mock::Server::start("user", "pass");
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let mut template = mock::template(840_000, genesis.block_hash());
+	// enough hashes that the search takes a few batches
+	let target = work::target_from_difficulty(1.0 / 262_144.0);
+
+	template["bits"] = format!("{:08x}", target.to_compact_lossy().to_consensus()).into();
+	template["target"] = hex::encode(target.to_be_bytes()).into();
+	server.push_template(template);
+
+	let backend = Scalar::default();
+	let searches = Arc::clone(&backend.searches);
+	let miner =
+		Miner::new(Solo::new(server.client("user", "pass"), ADDRESS), false).with_backend(backend);
+
+	std::thread::spawn(move || miner.mine());
+
+	let blocks = server
+		.wait_for_blocks(1, Duration::from_secs(60))
+		.expect("no block was submitted");
+
+	assert!(target.is_met_by(blocks[0].block_hash()));
+	assert!(blocks[0].check_merkle_root());
+	assert!(searches.load(Ordering::Relaxed) > 0);
+}
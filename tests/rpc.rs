@@ -0,0 +1,453 @@
+use std::{
+	io::{BufRead as _, BufReader, Read as _, Write as _},
+	net::TcpListener,
+	sync::{Arc, Mutex},
+	thread,
+};
+
+use bitcoin::hashes::Hash as _;
+use miner::{info, mock, rpc};
+use serde_json::{json, Value};
+
+/// A node stand-in that answers each request with the next scripted
+/// response, and records the path and body of every request it gets.
+struct Stub {
+	url: String,
+	requests: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl Stub {
+	/// Starts serving `responses`, each as a status line and a JSON body.
+	fn start(responses: Vec<(&'static str, Value)>) -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		let requests = Arc::new(Mutex::new(Vec::new()));
+
+		thread::spawn({
+			let requests = Arc::clone(&requests);
+
+			move || {
+				for (status, body) in responses {
+					let (stream, _) = listener.accept().unwrap();
+					let mut reader = BufReader::new(stream);
+					let mut line = String::new();
+
+					reader.read_line(&mut line).unwrap();
+
+					let path = line.split(' ').nth(1).unwrap().to_string();
+					let mut length = 0;
+
+					loop {
+						line.clear();
+						reader.read_line(&mut line).unwrap();
+
+						let header = line.trim_end();
+
+						if header.is_empty() {
+							break;
+						}
+
+						if let Some((name, value)) = header.split_once(':') {
+							if name.eq_ignore_ascii_case("content-length") {
+								length = value.trim().parse().unwrap();
+							}
+						}
+					}
+
+					let mut request = vec![0; length];
+
+					reader.read_exact(&mut request).unwrap();
+					requests
+						.lock()
+						.unwrap()
+						.push((path, serde_json::from_slice(&request).unwrap()));
+
+					let body = body.to_string();
+
+					write!(
+						reader.get_mut(),
+						"HTTP/1.1 {status}\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+						body.len()
+					)
+					.unwrap();
+				}
+			}
+		});
+
+		Self { url, requests }
+	}
+
+	fn client(&self) -> rpc::Client {
+		rpc::Client::new(self.url.clone(), "user", "pass", &rpc::Options::default()).unwrap()
+	}
+
+	/// The path and body of every request so far.
+	fn requests(&self) -> Vec<(String, Value)> {
+		self.requests.lock().unwrap().clone()
+	}
+
+	/// Every request so far, as `<path> <method>`.
+	fn calls(&self) -> Vec<String> {
+		self.requests()
+			.into_iter()
+			.map(|(path, request)| format!("{path} {}", request["method"].as_str().unwrap()))
+			.collect()
+	}
+}
+
+/// A successful response to a single request.
+fn ok(result: Value) -> (&'static str, Value) {
+	(
+		"200 OK",
+		json!({ "result": result, "error": null, "id": "miner" }),
+	)
+}
+
+/// A failed response to a single request, sent with an error status like
+/// bitcoind does.
+fn fail(code: i32, message: &str) -> (&'static str, Value) {
+	(
+		"500 Internal Server Error",
+		json!({ "result": null, "error": { "code": code, "message": message }, "id": "miner" }),
+	)
+}
+
+fn wallet(name: &str) -> Value {
+	json!({ "name": name, "warnings": [] })
+}
+
+fn blockchain() -> Value {
+	json!({
+		"chain": "main",
+		"blocks": 840_000,
+		"headers": 840_000,
+		"verificationprogress": 1.0,
+		"initialblockdownload": false,
+	})
+}
+
+fn network() -> Value {
+	json!({ "version": 270_000, "subversion": "/Satoshi:27.0.0/", "connections": 8 })
+}
+
+#[test]
+fn batch_returns_results_in_order() {
+	let stub = Stub::start(vec![(
+		"200 OK",
+		json!([
+			{ "result": blockchain(), "error": null, "id": "0" },
+			{ "result": null, "error": { "code": -1, "message": "scripted failure" }, "id": "1" },
+			{ "result": "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "error": null, "id": "2" },
+		]),
+	)]);
+	let client = stub.client();
+
+	let mut batch = client.batch();
+	let blockchain = batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+	let network = batch.push::<info::Network>(&rpc::Request::get_network_info());
+	let address = batch.push::<String>(&rpc::Request::get_new_address());
+
+	let mut responses = batch.send().unwrap();
+
+	assert_eq!(responses.take(blockchain).unwrap().chain, "main");
+	assert_eq!(
+		responses.take(network).unwrap_err().message,
+		"scripted failure"
+	);
+	assert!(responses.take(address).unwrap().starts_with("bc1"));
+
+	// every request goes out in a single POST, identified by its index
+	let requests = stub.requests();
+	let methods = requests[0]
+		.1
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|request| (request["method"].clone(), request["id"].clone()))
+		.collect::<Vec<_>>();
+
+	assert_eq!(requests.len(), 1);
+	assert_eq!(
+		methods,
+		[
+			(json!("getblockchaininfo"), json!("0")),
+			(json!("getnetworkinfo"), json!("1")),
+			(json!("getnewaddress"), json!("2")),
+		]
+	);
+}
+
+#[test]
+fn batch_matches_responses_by_id() {
+	// bitcoind may answer the requests of a batch in any order
+	let stub = Stub::start(vec![(
+		"200 OK",
+		json!([
+			{ "result": ["miner"], "error": null, "id": "3" },
+			{ "result": null, "error": { "code": -12, "message": "Keypool ran out" }, "id": "2" },
+			{ "result": network(), "error": null, "id": "1" },
+			{ "result": null, "error": { "code": -28, "message": "Loading block index" }, "id": "0" },
+		]),
+	)]);
+	let client = stub.client();
+
+	let mut batch = client.batch();
+	let blockchain = batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+	let network = batch.push::<info::Network>(&rpc::Request::get_network_info());
+	let address = batch.push::<String>(&rpc::Request::get_new_address());
+	let wallets = batch.push::<Vec<String>>(&rpc::Request::list_wallets());
+
+	let mut responses = batch.send().unwrap();
+
+	assert_eq!(responses.take(wallets).unwrap(), ["miner"]);
+	assert_eq!(responses.take(address).unwrap_err().code, -12);
+	assert_eq!(responses.take(network).unwrap().connections, 8);
+	assert_eq!(responses.take(blockchain).unwrap_err().code, -28);
+}
+
+#[test]
+fn batch_skips_unmatched_responses() {
+	let stub = Stub::start(vec![(
+		"200 OK",
+		json!([
+			{ "result": null, "error": { "code": -32600, "message": "Invalid Request object" }, "id": null },
+			{ "result": network(), "error": null, "id": "1" },
+			{ "result": network(), "error": null, "id": "1" },
+			{ "result": "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "error": null, "id": "7" },
+		]),
+	)]);
+	let client = stub.client();
+
+	let mut batch = client.batch();
+	let blockchain = batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+	let network = batch.push::<info::Network>(&rpc::Request::get_network_info());
+
+	let mut responses = batch.send().unwrap();
+
+	assert_eq!(responses.take(network).unwrap().connections, 8);
+	assert_eq!(
+		responses.take(blockchain).unwrap_err().message,
+		"no response"
+	);
+}
+
+#[test]
+fn batch_reports_errors_sent_with_an_error_status() {
+	let stub = Stub::start(vec![
+		(
+			"500 Internal Server Error",
+			json!([
+				{ "result": null, "error": { "code": -28, "message": "Loading block index" }, "id": "0" },
+			]),
+		),
+		(
+			"500 Internal Server Error",
+			json!({ "result": null, "error": { "code": -32700, "message": "Parse error" }, "id": null }),
+		),
+	]);
+	let client = stub.client();
+
+	let mut batch = client.batch();
+	let blockchain = batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+
+	assert_eq!(
+		batch.send().unwrap().take(blockchain).unwrap_err().code,
+		-28
+	);
+
+	// a batch the node can't parse fails as a whole
+	let mut batch = client.batch();
+	batch.push::<info::Blockchain>(&rpc::Request::get_blockchain_info());
+
+	assert_eq!(batch.send().unwrap_err().code, -32700);
+}
+
+#[test]
+fn sends_wallet_rpcs_to_the_wallet_endpoint() {
+	let address = json!("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq");
+	let stub = Stub::start(vec![ok(address.clone()), ok(address), ok(blockchain())]);
+
+	stub.client().get_new_address().unwrap();
+
+	let client = stub.client().with_wallet("mining wallet/1");
+
+	client.get_new_address().unwrap();
+	client.get_blockchain_info().unwrap();
+
+	assert_eq!(
+		stub.calls(),
+		[
+			"/ getnewaddress",
+			"/wallet/mining%20wallet%2F1 getnewaddress",
+			"/ getblockchaininfo",
+		]
+	);
+}
+
+#[test]
+fn sends_batches_with_wallet_rpcs_to_the_wallet_endpoint() {
+	let stub = Stub::start(vec![
+		(
+			"200 OK",
+			json!([{ "result": network(), "error": null, "id": "0" }]),
+		),
+		(
+			"200 OK",
+			json!([
+				{ "result": network(), "error": null, "id": "0" },
+				{ "result": "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "error": null, "id": "1" },
+			]),
+		),
+	]);
+	let client = stub.client().with_wallet("miner");
+
+	let mut batch = client.batch();
+	batch.push::<info::Network>(&rpc::Request::get_network_info());
+	batch.send().unwrap();
+
+	let mut batch = client.batch();
+	batch.push::<info::Network>(&rpc::Request::get_network_info());
+	let address = batch.push::<String>(&rpc::Request::get_new_address());
+
+	assert!(batch.send().unwrap().take(address).is_ok());
+	assert_eq!(
+		stub.requests()
+			.into_iter()
+			.map(|(path, _)| path)
+			.collect::<Vec<_>>(),
+		["/", "/wallet/miner"]
+	);
+}
+
+#[test]
+fn uses_a_listed_wallet() {
+	let stub = Stub::start(vec![ok(json!(["miner"]))]);
+
+	stub.client().with_wallet("miner").ensure_wallet().unwrap();
+
+	assert_eq!(stub.calls(), ["/ listwallets"]);
+}
+
+#[test]
+fn loads_an_unlisted_wallet() {
+	let stub = Stub::start(vec![ok(json!([])), ok(wallet("miner"))]);
+
+	stub.client().with_wallet("miner").ensure_wallet().unwrap();
+
+	assert_eq!(stub.calls(), ["/ listwallets", "/ loadwallet"]);
+	assert_eq!(stub.requests()[1].1["params"], json!(["miner"]));
+}
+
+#[test]
+fn creates_a_wallet_that_does_not_exist() {
+	let stub = Stub::start(vec![
+		ok(json!([])),
+		fail(rpc::Error::WALLET_NOT_FOUND, "Wallet file not found"),
+		ok(wallet("miner")),
+	]);
+
+	stub.client().with_wallet("miner").ensure_wallet().unwrap();
+
+	assert_eq!(
+		stub.calls(),
+		["/ listwallets", "/ loadwallet", "/ createwallet"]
+	);
+}
+
+#[test]
+fn accepts_a_wallet_that_is_already_loaded() {
+	let stub = Stub::start(vec![
+		ok(json!([])),
+		fail(
+			rpc::Error::WALLET_ALREADY_LOADED,
+			"Wallet is already loaded",
+		),
+	]);
+
+	stub.client().with_wallet("miner").ensure_wallet().unwrap();
+
+	assert_eq!(stub.calls(), ["/ listwallets", "/ loadwallet"]);
+}
+
+#[test]
+fn reports_wallet_errors() {
+	let stub = Stub::start(vec![
+		ok(json!([])),
+		fail(-4, "Wallet file verification failed"),
+		ok(json!([])),
+		fail(rpc::Error::WALLET_NOT_FOUND, "Wallet file not found"),
+		fail(-4, "Wallet already exists"),
+	]);
+	let client = stub.client().with_wallet("miner");
+
+	assert_eq!(client.ensure_wallet().unwrap_err().code, -4);
+	assert_eq!(client.ensure_wallet().unwrap_err().code, -4);
+	assert_eq!(
+		stub.calls(),
+		[
+			"/ listwallets",
+			"/ loadwallet",
+			"/ listwallets",
+			"/ loadwallet",
+			"/ createwallet",
+		]
+	);
+}
+
+#[test]
+fn reports_rejected_blocks() {
+	let block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let stub = Stub::start(vec![ok(Value::Null), ok(json!("duplicate"))]);
+	let client = stub.client();
+
+	// a `null` result means the block was accepted
+	client.submit_block(&block).unwrap();
+	assert_eq!(
+		client.submit_block(&block).unwrap_err().message,
+		"duplicate"
+	);
+
+	assert_eq!(
+		stub.requests()[0].1["params"],
+		json!([bitcoin::consensus::encode::serialize_hex(&block)])
+	);
+}
+
+#[test]
+fn reports_node_errors() {
+	let server = mock::Server::start("user", "pass");
+	let client = server.client("user", "pass");
+
+	// no template has been pushed yet
+	assert_eq!(client.get_block_template(None).unwrap_err().code, -9);
+
+	server.fail("getnewaddress", -12, "Keypool ran out");
+	assert_eq!(client.get_new_address().unwrap_err().code, -12);
+	assert!(client.get_new_address().is_ok());
+}
+
+#[test]
+fn rejects_invalid_credentials() {
+	let server = mock::Server::start("user", "pass");
+	let client = server.client("user", "wrong");
+
+	assert!(client.get_blockchain_info().is_err());
+}
+
+#[test]
+fn longpoll_waits_for_new_template() {
+	let server = mock::Server::start("user", "pass");
+	let client = server.client("user", "pass");
+
+	server.push_template(mock::template(1, bitcoin::BlockHash::all_zeros()));
+
+	let template = client.get_block_template(None).unwrap();
+
+	std::thread::scope(|s| {
+		let poll = s.spawn(|| client.get_block_template(Some(&template.longpoll_id)));
+
+		server.push_template(mock::template(2, bitcoin::BlockHash::all_zeros()));
+
+		assert_eq!(poll.join().unwrap().unwrap().height, 2);
+	});
+}
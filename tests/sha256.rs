@@ -0,0 +1,208 @@
+use bitcoin::hashes::Hash as _;
+use miner::{
+	backend::{
+		sha256,
+		simd::{Implementation, MAX_LANES},
+		Backend, Cpu,
+	},
+	work,
+};
+
+fn genesis_header() -> [u8; 80] {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+
+	bitcoin::consensus::serialize(&genesis.header)
+		.try_into()
+		.unwrap()
+}
+
+#[test]
+fn compresses_the_empty_message() {
+	let mut state = sha256::H;
+	let mut block = [0; 16];
+	block[0] = 0x8000_0000;
+
+	sha256::compress(&mut state, &block);
+
+	// SHA-256("")
+	assert_eq!(
+		state,
+		[
+			0xe3b0_c442,
+			0x98fc_1c14,
+			0x9afb_f4c8,
+			0x996f_b924,
+			0x27ae_41e4,
+			0x649b_934c,
+			0xa495_991b,
+			0x7852_b855
+		]
+	);
+}
+
+#[test]
+fn hashes_the_genesis_header() {
+	let header = genesis_header();
+	let nonce = u32::from_le_bytes(header[76..80].try_into().unwrap());
+	let midstate = sha256::Midstate::new(&header);
+
+	assert_eq!(
+		bitcoin::BlockHash::from_byte_array(midstate.hash(nonce)).to_string(),
+		"000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+	);
+}
+
+#[test]
+fn matches_the_reference_hash_across_nonces() {
+	let mut header = genesis_header();
+
+	for (i, byte) in header[..76].iter_mut().enumerate() {
+		*byte ^= u8::try_from(i).unwrap().wrapping_mul(37);
+	}
+
+	let midstate = sha256::Midstate::new(&header);
+
+	for nonce in (0..u32::MAX).step_by(7_919_777).chain([u32::MAX]) {
+		header[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+		assert_eq!(
+			midstate.hash(nonce),
+			bitcoin::BlockHash::hash(&header).to_byte_array(),
+			"nonce {nonce}"
+		);
+	}
+}
+
+#[test]
+fn rejects_hashes_by_their_top_word() {
+	let header = genesis_header();
+	let nonce = u32::from_le_bytes(header[76..80].try_into().unwrap());
+	let midstate = sha256::Midstate::new(&header);
+	let top = sha256::top_word(bitcoin::Target::MAX);
+
+	assert_eq!(top, 0x0000_0000);
+	// the genesis hash starts with ten zero hex digits, so it passes a zero top word
+	assert_eq!(midstate.hash_below(nonce, top), Some(midstate.hash(nonce)));
+	assert_eq!(midstate.hash_below(nonce + 1, top), None);
+}
+
+#[test]
+fn finds_nonces_that_meet_the_target() {
+	let header = genesis_header();
+	let target = work::target_from_difficulty(1.0 / 65536.0);
+	let hits = Cpu::default()
+		.search(header, 0..1 << 20, target, bitcoin::Target::MAX)
+		.unwrap();
+	let nonce = hits.blocks().next().expect("no nonce was found");
+	let mut solved = header;
+
+	solved[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+	assert!(target.is_met_by(bitcoin::BlockHash::hash(&solved)));
+}
+
+/// The implementations this CPU can run, which always includes the scalar one.
+fn supported() -> Vec<Implementation> {
+	Implementation::ALL
+		.into_iter()
+		.filter(|implementation| implementation.is_supported())
+		.collect()
+}
+
+#[test]
+fn every_implementation_hashes_the_genesis_header() {
+	let midstate = sha256::Midstate::new(&genesis_header());
+	let nonce = 2_083_236_893;
+
+	for implementation in supported() {
+		let mut hashes = [[0; 8]; MAX_LANES];
+
+		implementation.hash(&midstate, nonce, &mut hashes);
+
+		assert_eq!(
+			bitcoin::BlockHash::from_byte_array(sha256::to_bytes(&hashes[0])),
+			bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin).block_hash(),
+			"{implementation}"
+		);
+	}
+}
+
+#[test]
+fn every_implementation_matches_the_scalar_path() {
+	let mut header = genesis_header();
+
+	header[4..36].copy_from_slice(&[0xa5; 32]);
+
+	let midstate = sha256::Midstate::new(&header);
+
+	for implementation in supported() {
+		// the last start wraps the lanes around u32::MAX
+		for start in (0..4096)
+			.step_by(implementation.lanes())
+			.chain([u32::MAX - 3])
+		{
+			let mut hashes = [[0; 8]; MAX_LANES];
+
+			implementation.hash(&midstate, start, &mut hashes);
+
+			for (nonce, hash) in (0..)
+				.map(|i| start.wrapping_add(i))
+				.zip(&hashes[..implementation.lanes()])
+			{
+				assert_eq!(
+					*hash,
+					midstate.hash_words(nonce),
+					"{implementation} at nonce {nonce}"
+				);
+			}
+		}
+	}
+}
+
+#[test]
+fn every_implementation_finds_the_same_hits() {
+	let header = genesis_header();
+	let target = work::target_from_difficulty(1.0 / 65536.0);
+	let share_target = work::target_from_difficulty(1.0 / 1_048_576.0);
+	// a range that isn't a multiple of any lane count
+	let nonces = 1000..1 << 18 | 3;
+	let expected = Cpu::new(Implementation::Scalar)
+		.unwrap()
+		.search(header, nonces.clone(), bitcoin::Target::ZERO, share_target)
+		.unwrap();
+
+	assert!(expected.shares > 0);
+
+	for implementation in supported() {
+		let cpu = Cpu::new(implementation).unwrap();
+
+		assert_eq!(
+			cpu.search(header, nonces.clone(), bitcoin::Target::ZERO, share_target)
+				.unwrap(),
+			expected,
+			"{implementation}"
+		);
+
+		let nonce = cpu
+			.search(header, nonces.clone(), target, share_target)
+			.unwrap()
+			.blocks()
+			.next()
+			.expect("no nonce was found");
+		let mut solved = header;
+
+		solved[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+		assert!(target.is_met_by(bitcoin::BlockHash::hash(&solved)));
+	}
+}
+
+#[test]
+fn detects_a_supported_implementation() {
+	assert!(Implementation::detect().is_supported());
+	assert_eq!(Cpu::default().implementation(), Implementation::detect());
+
+	for implementation in Implementation::ALL {
+		assert_eq!(implementation.to_string().parse(), Ok(implementation));
+	}
+}
@@ -0,0 +1,759 @@
+use std::{
+	io::{BufRead, BufReader, Write},
+	net::{TcpListener, TcpStream},
+	ops::Range,
+	sync::{mpsc, Arc, Mutex},
+	thread,
+	time::Duration,
+};
+
+use bitcoin::hashes::{sha256d, Hash as _};
+use miner::{
+	backend::{Backend, Cpu, HitKind, Hits},
+	failover::Failover,
+	mock, stratum, work, Miner,
+};
+use serde_json::{json, Value};
+
+const WORKER: &str = "worker.1";
+const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+/// One share for every ~2^16 hashes
+const DIFFICULTY: f64 = 1.0 / 65536.0;
+/// The share target for `DIFFICULTY`, `0xffff << 224`
+const SHARE_TARGET: [u8; 32] = {
+	let mut target = [0; 32];
+	target[2] = 0xff;
+	target[3] = 0xff;
+	target
+};
+/// Every share at this difficulty meets the mock node's target
+const POOL_DIFFICULTY: f64 = 1.0 / 65536.0;
+const BRANCH: [u8; 32] = [0x11; 32];
+const TIME: u32 = 0x6600_0000;
+
+/// The coinbase around the extranonces, which take the 4 byte extranonce1
+/// and `extranonce2_size` bytes of the scriptSig after a BIP34 height push.
+fn coinbase_parts(extranonce2_size: usize) -> (Vec<u8>, Vec<u8>) {
+	let mut coinbase1 = hex::decode(concat!(
+		"01000000",
+		"01",
+		"0000000000000000000000000000000000000000000000000000000000000000ffffffff",
+	))
+	.unwrap();
+
+	coinbase1.push(u8::try_from(8 + extranonce2_size).unwrap());
+	coinbase1.extend_from_slice(&hex::decode("0340d20c").unwrap());
+
+	let coinbase2 = hex::decode(concat!(
+		"ffffffff",
+		"01",
+		"00f2052a01000000",
+		"160014e8df018c7e326cc253faac7e46cdc51e68542c42",
+		"00000000",
+	))
+	.unwrap();
+
+	(coinbase1, coinbase2)
+}
+
+/// A stand-in for a pool that hands out one job per connection and checks
+/// every share independently of the client, sending the valid ones to the
+/// returned channel. Connections get 4 bytes of extranonce2, but with
+/// `reconnect`, the first connection is closed after its first valid share,
+/// and later ones get a different extranonce1 and that many bytes instead.
+fn pool(
+	previous_block: bitcoin::BlockHash,
+	reconnect: Option<usize>,
+) -> (String, mpsc::Receiver<bitcoin::block::Header>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+	let (tx, rx) = mpsc::channel();
+
+	thread::spawn(move || {
+		for (session, stream) in listener.incoming().enumerate() {
+			serve_pool(stream.unwrap(), session, reconnect, previous_block, &tx);
+		}
+	});
+
+	(address, rx)
+}
+
+fn serve_pool(
+	stream: TcpStream,
+	session: usize,
+	reconnect: Option<usize>,
+	previous_block: bitcoin::BlockHash,
+	shares: &mpsc::Sender<bitcoin::block::Header>,
+) {
+	let extranonce1 = format!("{:08x}", 0xf000_000f + (session << 4));
+	let extranonce2_size = reconnect.filter(|_| session > 0).unwrap_or(4);
+	let mut writer = stream.try_clone().unwrap();
+	// the client may already be gone, which ends the session below
+	let mut send = |message: Value| {
+		let _ = writer.write_all(format!("{message}\n").as_bytes());
+	};
+	let (coinbase1, coinbase2) = coinbase_parts(extranonce2_size);
+
+	// stratum swaps the bytes of each word of the previous block hash
+	let mut previous = previous_block.to_byte_array();
+	for word in previous.chunks_exact_mut(4) {
+		word.reverse();
+	}
+
+	for line in BufReader::new(stream).lines() {
+		let Ok(line) = line else {
+			break;
+		};
+		let request: Value = serde_json::from_str(&line).unwrap();
+		let id = request["id"].clone();
+		let params = &request["params"];
+
+		match request["method"].as_str().unwrap() {
+			"mining.subscribe" => send(json!({
+				"id": id,
+				"result": [[["mining.notify", "1"]], extranonce1, extranonce2_size],
+				"error": null,
+			})),
+			"mining.authorize" => {
+				assert_eq!(params[0], WORKER);
+
+				send(json!({ "id": id, "result": true, "error": null }));
+				send(json!({
+					"id": null,
+					"method": "mining.set_difficulty",
+					"params": [DIFFICULTY],
+				}));
+				send(json!({
+					"id": null,
+					"method": "mining.notify",
+					"params": [
+						"job",
+						hex::encode(previous),
+						hex::encode(&coinbase1),
+						hex::encode(&coinbase2),
+						[hex::encode(BRANCH)],
+						"20000000",
+						"1d00ffff",
+						format!("{TIME:08x}"),
+						true,
+					],
+				}));
+			}
+			"mining.extranonce.subscribe" => send(json!({
+				"id": id,
+				"result": null,
+				"error": [20, "unsupported method", null],
+			})),
+			"mining.submit" => {
+				assert_eq!(params[0], WORKER);
+				assert_eq!(params[1], "job");
+
+				let extranonce2 = hex::decode(params[2].as_str().unwrap()).unwrap();
+				let time = u32::from_str_radix(params[3].as_str().unwrap(), 16).unwrap();
+				let nonce = u32::from_str_radix(params[4].as_str().unwrap(), 16).unwrap();
+				assert_eq!(extranonce2.len(), extranonce2_size);
+
+				let coinbase = [
+					&coinbase1[..],
+					&hex::decode(&extranonce1).unwrap(),
+					&extranonce2,
+					&coinbase2,
+				]
+				.concat();
+				let transaction: bitcoin::Transaction =
+					bitcoin::consensus::deserialize(&coinbase).unwrap();
+
+				let mut data = transaction.txid().to_byte_array().to_vec();
+				data.extend_from_slice(&BRANCH);
+
+				let header = bitcoin::block::Header {
+					version: bitcoin::block::Version::from_consensus(0x2000_0000),
+					prev_blockhash: previous_block,
+					merkle_root: bitcoin::TxMerkleNode::from_byte_array(
+						sha256d::Hash::hash(&data).to_byte_array(),
+					),
+					time,
+					bits: bitcoin::CompactTarget::from_consensus(0x1d00_ffff),
+					nonce,
+				};
+				let valid =
+					bitcoin::Target::from_be_bytes(SHARE_TARGET).is_met_by(header.block_hash());
+
+				send(json!({ "id": id, "result": valid, "error": null }));
+
+				if valid {
+					let _ = shares.send(header);
+
+					if reconnect.is_some() && session == 0 {
+						send(json!({
+							"id": null,
+							"method": "client.reconnect",
+							"params": [],
+						}));
+						break;
+					}
+				}
+			}
+			method => panic!("unexpected method {method}"),
+		}
+	}
+}
+
+#[test]
+fn mines_shares_for_pool() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (url, shares) = pool(genesis.block_hash(), None);
+
+	let miner = Miner::new(
+		stratum::v1::Pool {
+			url,
+			worker: WORKER.to_string(),
+			password: "x".to_string(),
+		},
+		false,
+	);
+
+	thread::spawn(move || miner.mine());
+
+	let first = shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no valid share was submitted");
+	let second = shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no second share was submitted");
+
+	assert_eq!(first.time, TIME);
+	assert_ne!(first.block_hash(), second.block_hash());
+}
+
+/// A search's range, followed by the nonces it reported as hits.
+type Search = (Range<u32>, Vec<u32>);
+
+/// Searches the whole batch on the CPU, reporting every share the pool would
+/// accept as a hit, and records each search.
+#[derive(Debug, Default)]
+struct Exhaustive {
+	searches: Arc<Mutex<Vec<Search>>>,
+}
+
+impl Backend for Exhaustive {
+	fn search(
+		&self,
+		header: [u8; 80],
+		nonces: Range<u32>,
+		target: bitcoin::Target,
+		_share_target: bitcoin::Target,
+	) -> Result<Hits, miner::Error> {
+		// nothing meets a zero target, so the search doesn't stop at a share
+		let mut hits =
+			Cpu::default().search(header, nonces.clone(), bitcoin::Target::ZERO, target)?;
+
+		for hit in &mut hits.hits {
+			hit.kind = HitKind::Block;
+		}
+
+		hits.shares = 0;
+		self.searches
+			.lock()
+			.unwrap()
+			.push((nonces, hits.blocks().collect()));
+
+		Ok(hits)
+	}
+
+	fn batch_size(&self) -> u32 {
+		1 << 18
+	}
+}
+
+#[test]
+fn submits_every_share_in_a_batch() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (url, shares) = pool(genesis.block_hash(), None);
+	let backend = Exhaustive::default();
+	let searches = Arc::clone(&backend.searches);
+	let miner = Miner::new(
+		stratum::v1::Pool {
+			url,
+			worker: WORKER.to_string(),
+			password: "x".to_string(),
+		},
+		false,
+	)
+	.with_backend(backend);
+
+	thread::spawn(move || miner.mine());
+
+	// waits for a search to be recorded past the first `count`
+	let wait = |count: usize| {
+		let deadline = std::time::Instant::now() + Duration::from_secs(60);
+
+		loop {
+			let searches = searches.lock().unwrap().clone();
+
+			if searches.len() > count {
+				break searches;
+			}
+
+			assert!(std::time::Instant::now() < deadline, "no search was made");
+			thread::sleep(Duration::from_millis(10));
+		}
+	};
+	let first = wait(0).swap_remove(0).1;
+	let received = first
+		.iter()
+		.map(|_| {
+			shares
+				.recv_timeout(Duration::from_secs(60))
+				.expect("not every share was submitted")
+				.nonce
+		})
+		.collect::<Vec<_>>();
+
+	assert!(first.len() > 1);
+	assert_eq!(received, first);
+
+	let searches = wait(1);
+
+	// each search resumes after the last share of the one before it
+	for pair in searches.windows(2) {
+		let (range, nonces) = &pair[0];
+
+		assert_eq!(
+			pair[1].0.start,
+			nonces.last().map_or(range.end, |nonce| nonce + 1)
+		);
+	}
+}
+
+/// Starts a node with a template containing a few transactions, and a solo
+/// pool serving jobs for it, returning the pool's url.
+fn solo_pool(
+	node: &mock::Server,
+	difficulty: f64,
+	fee: f64,
+	vardiff: Option<stratum::v1::Vardiff>,
+) -> (String, Arc<stratum::v1::Server<stratum::v1::Templates>>) {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let mut template = mock::template(840_000, genesis.block_hash());
+
+	mock::add_transactions(&mut template, 3);
+	node.push_template(template);
+
+	let mut server = stratum::v1::Server::new(
+		stratum::v1::Templates::new(node.client("user", "pass"), address(ADDRESS)).with_fee(fee),
+		difficulty,
+	);
+
+	if let Some(vardiff) = vardiff {
+		server = server.with_vardiff(vardiff);
+	}
+
+	let server = Arc::new(server);
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+
+	thread::spawn({
+		let server = Arc::clone(&server);
+
+		move || server.listen(&listener)
+	});
+	thread::spawn({
+		let server = Arc::clone(&server);
+
+		move || stratum::v1::Templates::poll(&server)
+	});
+
+	(url, server)
+}
+
+fn address(address: &str) -> bitcoin::Address {
+	address
+		.parse::<bitcoin::Address<_>>()
+		.unwrap()
+		.require_network(bitcoin::Network::Bitcoin)
+		.unwrap()
+}
+
+fn mine(url: String, worker: &str) {
+	let miner = Miner::new(
+		stratum::v1::Pool {
+			url,
+			worker: worker.to_string(),
+			password: "x".to_string(),
+		},
+		false,
+	);
+
+	thread::spawn(move || miner.mine());
+}
+
+/// Mines on the pool at `url` until the node has a block, and returns it after
+/// checking that it is valid.
+fn mine_block(node: &mock::Server, url: String, worker: &str) -> bitcoin::Block {
+	mine(url, worker);
+
+	let mut blocks = node
+		.wait_for_blocks(1, Duration::from_secs(30))
+		.expect("no block was submitted");
+	let block = blocks.swap_remove(0);
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let target =
+		bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(mock::EASY_BITS));
+
+	assert_eq!(block.header.prev_blockhash, genesis.block_hash());
+	assert!(block.header.validate_pow(target).is_ok());
+	assert_eq!(block.txdata.len(), 4);
+	assert!(block.check_merkle_root());
+	assert!(block.check_witness_commitment());
+	assert_eq!(block.bip34_block_height(), Ok(840_000));
+
+	block
+}
+
+#[test]
+fn serves_jobs_and_submits_blocks() {
+	let node = mock::Server::start("user", "pass");
+	let (url, _server) = solo_pool(&node, POOL_DIFFICULTY, 1.0, None);
+	let block = mine_block(&node, url, WORKER);
+	let coinbase = &block.txdata[0];
+
+	// the operator's own blocks pay no fee
+	assert_eq!(coinbase.output.len(), 2);
+	assert_eq!(
+		coinbase.output[0].script_pubkey,
+		address(ADDRESS).script_pubkey()
+	);
+	assert_eq!(coinbase.output[0].value.to_sat(), 312_500_000);
+}
+
+#[test]
+fn pays_worker_addresses() {
+	const WORKER_ADDRESS: &str = "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh";
+
+	let node = mock::Server::start("user", "pass");
+	let (url, _server) = solo_pool(&node, POOL_DIFFICULTY, 1.0, None);
+	let block = mine_block(&node, url, &format!("{WORKER_ADDRESS}.rig"));
+	let coinbase = &block.txdata[0];
+
+	// the worker's payout and the fee, followed by the witness commitment
+	assert_eq!(coinbase.output.len(), 3);
+	assert_eq!(
+		coinbase.output[0].script_pubkey,
+		address(WORKER_ADDRESS).script_pubkey()
+	);
+	assert_eq!(coinbase.output[0].value.to_sat(), 309_375_000);
+	assert_eq!(
+		coinbase.output[1].script_pubkey,
+		address(ADDRESS).script_pubkey()
+	);
+	assert_eq!(coinbase.output[1].value.to_sat(), 3_125_000);
+}
+
+#[test]
+fn rejects_addresses_for_other_networks() {
+	let node = mock::Server::start("user", "pass");
+	let (url, _server) = solo_pool(&node, POOL_DIFFICULTY, 0.0, None);
+	let result =
+		stratum::v1::Client::connect(&url, "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", "x");
+
+	assert!(matches!(
+		result,
+		Err(stratum::Error::Rejected { code: 24, .. })
+	));
+}
+
+#[test]
+fn submits_blocks_below_the_worker_difficulty() {
+	let node = mock::Server::start("user", "pass");
+	// far above the node's difficulty, as on regtest or after vardiff
+	let (url, _server) = solo_pool(&node, 1_000_000.0, 0.0, None);
+	let client = stratum::v1::Client::connect(&url, WORKER, "x").unwrap();
+	let (tx, rx) = mpsc::channel();
+
+	thread::spawn({
+		let client = Arc::clone(&client);
+
+		move || client.run(&tx)
+	});
+
+	let job = rx.recv_timeout(Duration::from_secs(30)).unwrap();
+	let work::Kind::Stratum(work) = &job.kind else {
+		panic!("not a stratum job");
+	};
+	let target = bitcoin::Target::from_compact(job.header.bits);
+	let mut header = job.header;
+
+	while !target.is_met_by(header.block_hash()) {
+		header.nonce += 1;
+	}
+
+	work.submit(&header).unwrap();
+
+	let blocks = node
+		.wait_for_blocks(1, Duration::from_secs(30))
+		.expect("no block was submitted");
+
+	assert_eq!(blocks[0].header, header);
+
+	let deadline = std::time::Instant::now() + Duration::from_secs(30);
+
+	while client.accepted() == 0 {
+		assert!(
+			std::time::Instant::now() < deadline,
+			"share was not accepted"
+		);
+		thread::sleep(Duration::from_millis(10));
+	}
+
+	assert_eq!(client.rejected(), 0);
+}
+
+#[test]
+fn raises_difficulty_of_fast_workers() {
+	let node = mock::Server::start("user", "pass");
+	// almost every hash is a share, so the test doesn't wait on the hasher
+	let difficulty = 1.0 / f64::from(1 << 24);
+	let vardiff = stratum::v1::Vardiff {
+		// the first share is more than four times the expected rate
+		shares_per_minute: 0.25,
+		minimum: difficulty,
+		..Default::default()
+	};
+	let (url, server) = solo_pool(&node, difficulty, 0.0, Some(vardiff));
+
+	mine(url, WORKER);
+
+	let deadline = std::time::Instant::now() + Duration::from_secs(30);
+	let worker = loop {
+		let workers = server.workers();
+
+		if let Some(worker) = workers.iter().find(|worker| worker.difficulty > difficulty) {
+			break worker.clone();
+		}
+
+		assert!(
+			std::time::Instant::now() < deadline,
+			"difficulty was not raised: {workers:?}"
+		);
+		thread::sleep(Duration::from_millis(100));
+	};
+
+	assert_eq!(worker.name, WORKER);
+	assert!(worker.hash_rate > 0.0);
+}
+
+/// Starts a proxy for the pool at `url`, returning the proxy's url.
+fn proxy(url: String) -> (String, Arc<stratum::v1::Server<stratum::v1::Proxy>>) {
+	// workers find shares 16 times as often as the pool wants them
+	let difficulty = DIFFICULTY / 16.0;
+	let server = Arc::new(stratum::v1::Server::new(
+		stratum::v1::Proxy::new(stratum::v1::Pool {
+			url,
+			worker: WORKER.to_string(),
+			password: "x".to_string(),
+		}),
+		difficulty,
+	));
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let proxy_url = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+
+	thread::spawn({
+		let server = Arc::clone(&server);
+
+		move || server.listen(&listener)
+	});
+	thread::spawn({
+		let server = Arc::clone(&server);
+
+		move || stratum::v1::Proxy::run(&server)
+	});
+
+	(proxy_url, server)
+}
+
+/// Connects a worker to the proxy at `url` once it has a pool session, and
+/// returns the worker's jobs.
+fn proxy_worker(url: &str) -> mpsc::Receiver<work::Job> {
+	let deadline = std::time::Instant::now() + Duration::from_secs(30);
+	// the proxy turns workers away until it is connected to the pool
+	let client = loop {
+		if let Ok(client) = stratum::v1::Client::connect(url, "rig", "x") {
+			break client;
+		}
+
+		assert!(
+			std::time::Instant::now() < deadline,
+			"proxy did not accept the worker"
+		);
+		thread::sleep(Duration::from_millis(10));
+	};
+	let (tx, rx) = mpsc::channel();
+
+	thread::spawn(move || client.run(&tx));
+
+	rx
+}
+
+/// Waits for a job from the proxy's `session`th pool session.
+fn proxied_job(jobs: &mpsc::Receiver<work::Job>, session: u64) -> work::Job {
+	loop {
+		let job = jobs
+			.recv_timeout(Duration::from_secs(30))
+			.expect("no job was sent");
+		let work::Kind::Stratum(work) = &job.kind else {
+			panic!("not a stratum job");
+		};
+
+		if work.notify.job_id.starts_with(&format!("{session:x}.")) {
+			break job;
+		}
+	}
+}
+
+/// Submits the first share of `job` that meets the pool stand-in's difficulty.
+fn submit_share(job: &work::Job) -> bitcoin::block::Header {
+	let work::Kind::Stratum(work) = &job.kind else {
+		panic!("not a stratum job");
+	};
+	let target = bitcoin::Target::from_be_bytes(SHARE_TARGET);
+	let mut header = job.header;
+
+	while !target.is_met_by(header.block_hash()) {
+		header.nonce += 1;
+	}
+
+	work.submit(&header).unwrap();
+
+	header
+}
+
+#[test]
+fn proxies_shares_across_pool_reconnects() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (url, shares) = pool(genesis.block_hash(), Some(4));
+	let (proxy_url, server) = proxy(url);
+	let jobs = proxy_worker(&proxy_url);
+
+	// shares are only valid for the pool if they meet its difficulty and have
+	// the extranonce1 of the session they were submitted on
+	let first = submit_share(&proxied_job(&jobs, 1));
+
+	assert_eq!(shares.recv_timeout(Duration::from_secs(30)).unwrap(), first);
+
+	let workers = server.workers();
+	let second = submit_share(&proxied_job(&jobs, 2));
+
+	assert_eq!(
+		shares.recv_timeout(Duration::from_secs(30)).unwrap(),
+		second
+	);
+
+	let after = server.workers();
+
+	assert_eq!(workers.len(), 1);
+	assert_eq!(after.len(), 1);
+	assert_eq!(after[0].peer, workers[0].peer);
+}
+
+#[test]
+fn resubscribes_workers_when_the_pool_extranonce_changes() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	// 8 bytes are split into a 2 byte prefix, instead of 1 byte for 4
+	let (url, shares) = pool(genesis.block_hash(), Some(8));
+	let (proxy_url, _server) = proxy(url);
+	let jobs = proxy_worker(&proxy_url);
+	let job = proxied_job(&jobs, 1);
+	let work::Kind::Stratum(work) = &job.kind else {
+		panic!("not a stratum job");
+	};
+
+	assert_eq!((work.extranonce1.len(), work.extranonce2.len()), (1, 3));
+
+	let share = submit_share(&job);
+
+	assert_eq!(shares.recv_timeout(Duration::from_secs(30)).unwrap(), share);
+
+	// the worker is disconnected once the proxy has the new session
+	let error = loop {
+		if let Err(e) = jobs.recv_timeout(Duration::from_secs(30)) {
+			break e;
+		}
+	};
+
+	assert_eq!(error, mpsc::RecvTimeoutError::Disconnected);
+
+	let jobs = proxy_worker(&proxy_url);
+	let job = proxied_job(&jobs, 2);
+	let work::Kind::Stratum(work) = &job.kind else {
+		panic!("not a stratum job");
+	};
+
+	assert_eq!((work.extranonce1.len(), work.extranonce2.len()), (2, 6));
+
+	let share = submit_share(&job);
+
+	assert_eq!(shares.recv_timeout(Duration::from_secs(30)).unwrap(), share);
+}
+
+#[test]
+fn fails_over_to_backup_pool_and_back() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	// the primary pool drops the connection after its first share
+	let (primary, primary_shares) = pool(genesis.block_hash(), Some(4));
+	let (backup, backup_shares) = pool(genesis.block_hash(), None);
+	let pool = |url| {
+		miner::Upstream::from(stratum::v1::Pool {
+			url,
+			worker: WORKER.to_string(),
+			password: "x".to_string(),
+		})
+	};
+	let miner = Miner::new(Failover::new(vec![pool(primary), pool(backup)]), false);
+
+	thread::spawn(move || miner.mine());
+
+	primary_shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no share was found for the primary pool");
+
+	// the backup may have been mined before the primary pool sent a job
+	while backup_shares.try_recv().is_ok() {}
+
+	backup_shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no share was found for the backup pool");
+	primary_shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no share was found after the primary pool came back");
+}
+
+#[test]
+fn splits_shares_between_two_pools() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (first, first_shares) = pool(genesis.block_hash(), None);
+	let (second, second_shares) = pool(genesis.block_hash(), None);
+	let pool = |url| {
+		miner::Upstream::from(stratum::v1::Pool {
+			url,
+			worker: WORKER.to_string(),
+			password: "x".to_string(),
+		})
+	};
+	// without the split, only the first pool would get shares
+	let mut failover = Failover::new(vec![pool(first), pool(second)]).with_split(50.0);
+
+	// switch every second, so the test doesn't wait on the default period
+	failover.split_period = Duration::from_secs(2);
+
+	let miner = Miner::new(failover, false);
+
+	thread::spawn(move || miner.mine());
+
+	first_shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no share was found for the first pool");
+	second_shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no share was found for the second pool");
+}
@@ -0,0 +1,759 @@
+use std::{
+	io::{Read as _, Write as _},
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{mpsc, Arc},
+	thread,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{
+	consensus::Decodable as _,
+	hashes::{sha256d, Hash as _},
+	secp256k1::{Keypair, Secp256k1, SecretKey, XOnlyPublicKey},
+};
+use miner::{
+	mock,
+	stratum::{
+		self,
+		v2::{noise, AuthorityKey, Channel, Client, Message, Pool, TemplateProvider},
+	},
+	Miner,
+};
+
+const WORKER: &str = "worker.1";
+const CHANNEL_ID: u32 = 7;
+const JOB_ID: u32 = 3;
+const EXTRANONCE_PREFIX: [u8; 4] = [0xf0, 0, 0, 0x0f];
+/// The share target for difficulty 1/4096, `0xffff << 220`, one share for
+/// every ~2^20 hashes
+const SHARE_TARGET: [u8; 32] = {
+	let mut target = [0; 32];
+	target[2] = 0x0f;
+	target[3] = 0xff;
+	target[4] = 0xf0;
+	target
+};
+const MERKLE_ROOT: [u8; 32] = [0x22; 32];
+const BRANCH: [u8; 32] = [0x11; 32];
+const TIME: u32 = 0x6600_0000;
+const VERSION: u32 = 0x2000_0000;
+const BITS: u32 = 0x1d00_ffff;
+
+fn keypair(byte: u8) -> Keypair {
+	Keypair::from_secret_key(
+		&Secp256k1::new(),
+		&SecretKey::from_slice(&[byte; 32]).unwrap(),
+	)
+}
+
+/// The coinbase around the extranonces, which take 8 bytes of the scriptSig
+/// after a BIP34 height push.
+fn coinbase_parts() -> (Vec<u8>, Vec<u8>) {
+	let prefix = hex::decode(concat!(
+		"01000000",
+		"01",
+		"0000000000000000000000000000000000000000000000000000000000000000ffffffff",
+		"0c",
+		"0340d20c",
+	))
+	.unwrap();
+	let suffix = hex::decode(concat!(
+		"ffffffff",
+		"01",
+		"00f2052a01000000",
+		"160014e8df018c7e326cc253faac7e46cdc51e68542c42",
+		"00000000",
+	))
+	.unwrap();
+
+	(prefix, suffix)
+}
+
+/// A stand-in for a pool that opens one channel, hands out one job and checks
+/// every share independently of the client, sending the valid ones to the
+/// returned channel.
+fn pool(previous_block: bitcoin::BlockHash) -> (String, mpsc::Receiver<bitcoin::block::Header>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let authority = keypair(1);
+	let url = format!(
+		"stratum2+tcp://{}/{}",
+		listener.local_addr().unwrap(),
+		AuthorityKey(authority.x_only_public_key().0)
+	);
+	let (tx, rx) = mpsc::channel();
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			// clients that fail the handshake just close the connection
+			let _ = serve(stream.unwrap(), &authority, previous_block, &tx);
+		}
+	});
+
+	(url, rx)
+}
+
+fn serve(
+	stream: std::net::TcpStream,
+	authority: &Keypair,
+	previous_block: bitcoin::BlockHash,
+	shares: &mpsc::Sender<bitcoin::block::Header>,
+) -> Result<(), stratum::Error> {
+	let static_key = keypair(2);
+	#[allow(clippy::cast_possible_truncation)]
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.as_secs() as u32;
+	let certificate = noise::Certificate::sign(
+		authority,
+		&static_key.x_only_public_key().0,
+		now - 60,
+		now + 3600,
+	);
+	let (mut reader, mut writer) = noise::accept(stream, &static_key, &certificate)?;
+	let mut receive = || Message::from_frame(&reader.read()?).map(Option::unwrap);
+	let (coinbase_prefix, coinbase_suffix) = coinbase_parts();
+
+	let Message::SetupConnection { protocol: 0, .. } = receive()? else {
+		panic!("expected SetupConnection");
+	};
+	writer.write(
+		&Message::SetupConnectionSuccess {
+			used_version: 2,
+			flags: 0,
+		}
+		.to_frame(),
+	)?;
+
+	let extended = match receive()? {
+		Message::OpenStandardMiningChannel {
+			request_id,
+			user_identity,
+			..
+		} => {
+			assert_eq!(user_identity, WORKER);
+
+			writer.write(
+				&Message::OpenStandardMiningChannelSuccess {
+					request_id,
+					channel_id: CHANNEL_ID,
+					target: bitcoin::Target::from_be_bytes(SHARE_TARGET),
+					extranonce_prefix: EXTRANONCE_PREFIX.to_vec(),
+					group_channel_id: 0,
+				}
+				.to_frame(),
+			)?;
+			writer.write(
+				&Message::NewMiningJob {
+					channel_id: CHANNEL_ID,
+					job_id: JOB_ID,
+					min_time: None,
+					version: VERSION,
+					merkle_root: bitcoin::TxMerkleNode::from_byte_array(MERKLE_ROOT),
+				}
+				.to_frame(),
+			)?;
+
+			false
+		}
+		Message::OpenExtendedMiningChannel {
+			request_id,
+			user_identity,
+			min_extranonce_size,
+			..
+		} => {
+			assert_eq!(user_identity, WORKER);
+			assert!(min_extranonce_size <= 4);
+
+			writer.write(
+				&Message::OpenExtendedMiningChannelSuccess {
+					request_id,
+					channel_id: CHANNEL_ID,
+					target: bitcoin::Target::from_be_bytes(SHARE_TARGET),
+					extranonce_size: 4,
+					extranonce_prefix: EXTRANONCE_PREFIX.to_vec(),
+				}
+				.to_frame(),
+			)?;
+			writer.write(
+				&Message::NewExtendedMiningJob {
+					channel_id: CHANNEL_ID,
+					job_id: JOB_ID,
+					min_time: None,
+					version: VERSION,
+					version_rolling_allowed: false,
+					merkle_path: vec![BRANCH],
+					coinbase_prefix: coinbase_prefix.clone(),
+					coinbase_suffix: coinbase_suffix.clone(),
+				}
+				.to_frame(),
+			)?;
+
+			true
+		}
+		message => panic!("unexpected message {message:?}"),
+	};
+
+	writer.write(
+		&Message::SetNewPrevHash {
+			channel_id: CHANNEL_ID,
+			job_id: JOB_ID,
+			previous_block,
+			min_time: TIME,
+			bits: bitcoin::CompactTarget::from_consensus(BITS),
+		}
+		.to_frame(),
+	)?;
+
+	loop {
+		let (sequence_number, job_id, nonce, time, version, merkle_root) = match receive()? {
+			Message::SubmitSharesStandard {
+				channel_id,
+				sequence_number,
+				job_id,
+				nonce,
+				time,
+				version,
+			} if !extended => {
+				assert_eq!(channel_id, CHANNEL_ID);
+
+				(
+					sequence_number,
+					job_id,
+					nonce,
+					time,
+					version,
+					bitcoin::TxMerkleNode::from_byte_array(MERKLE_ROOT),
+				)
+			}
+			Message::SubmitSharesExtended {
+				channel_id,
+				sequence_number,
+				job_id,
+				nonce,
+				time,
+				version,
+				extranonce,
+			} if extended => {
+				assert_eq!(channel_id, CHANNEL_ID);
+				assert_eq!(extranonce.len(), 4);
+
+				let coinbase = [
+					&coinbase_prefix[..],
+					&EXTRANONCE_PREFIX,
+					&extranonce,
+					&coinbase_suffix,
+				]
+				.concat();
+				let transaction: bitcoin::Transaction =
+					bitcoin::consensus::deserialize(&coinbase).unwrap();
+
+				let mut data = transaction.txid().to_byte_array().to_vec();
+				data.extend_from_slice(&BRANCH);
+
+				(
+					sequence_number,
+					job_id,
+					nonce,
+					time,
+					version,
+					bitcoin::TxMerkleNode::from_byte_array(
+						sha256d::Hash::hash(&data).to_byte_array(),
+					),
+				)
+			}
+			message => panic!("unexpected message {message:?}"),
+		};
+
+		assert_eq!(job_id, JOB_ID);
+
+		#[allow(clippy::cast_possible_wrap)]
+		let header = bitcoin::block::Header {
+			version: bitcoin::block::Version::from_consensus(version as i32),
+			prev_blockhash: previous_block,
+			merkle_root,
+			time,
+			bits: bitcoin::CompactTarget::from_consensus(BITS),
+			nonce,
+		};
+		let valid = bitcoin::Target::from_be_bytes(SHARE_TARGET).is_met_by(header.block_hash());
+
+		writer.write(
+			&if valid {
+				Message::SubmitSharesSuccess {
+					channel_id: CHANNEL_ID,
+					last_sequence_number: sequence_number,
+					new_submits_accepted_count: 1,
+					new_shares_sum: 1,
+				}
+			} else {
+				Message::SubmitSharesError {
+					channel_id: CHANNEL_ID,
+					sequence_number,
+					error_code: "invalid-share".to_string(),
+				}
+			}
+			.to_frame(),
+		)?;
+
+		if valid {
+			let _ = shares.send(header);
+		}
+	}
+}
+
+fn mine(channel: Channel) -> (bitcoin::block::Header, bitcoin::block::Header) {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (url, shares) = pool(genesis.block_hash());
+
+	let miner = Miner::new(
+		Pool {
+			url,
+			worker: WORKER.to_string(),
+			channel,
+		},
+		false,
+	);
+
+	thread::spawn(move || miner.mine());
+
+	let first = shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no valid share was submitted");
+	let second = shares
+		.recv_timeout(Duration::from_secs(60))
+		.expect("no second share was submitted");
+
+	assert_eq!(first.prev_blockhash, genesis.block_hash());
+	assert_ne!(first.block_hash(), second.block_hash());
+
+	(first, second)
+}
+
+#[test]
+fn mines_shares_on_standard_channel() {
+	let (first, _) = mine(Channel::Standard);
+
+	assert_eq!(first.merkle_root.to_byte_array(), MERKLE_ROOT);
+	assert!(first.time >= TIME);
+}
+
+#[test]
+fn mines_shares_on_extended_channel() {
+	let (first, second) = mine(Channel::Extended);
+
+	assert_eq!(first.time, TIME);
+	assert_eq!(second.time, TIME);
+}
+
+#[test]
+fn rejects_pool_with_wrong_authority() {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (url, _) = pool(genesis.block_hash());
+	let (address, _) = url.rsplit_once('/').unwrap();
+	let url = format!(
+		"{address}/{}",
+		AuthorityKey(keypair(3).x_only_public_key().0)
+	);
+
+	assert!(matches!(
+		Client::connect(&url, WORKER, Channel::Standard),
+		Err(stratum::Error::Protocol(_))
+	));
+}
+
+/// Known-answer vectors for the Noise handshake and transport, generated with
+/// the SRI reference implementation (`noise_sv2` 1.4.0) from seeded keys
+mod vectors {
+	/// The authority secret key is 32 bytes of this
+	pub const AUTHORITY: u8 = 0x11;
+	pub const INITIATOR_EPHEMERAL: &str =
+		"023f37203a2476c42566a61cc55c3ca875dbb4cc41c0deb789f8e7bf88183638";
+	pub const RESPONDER_EPHEMERAL: &str =
+		"7f82dd63f4f75c33da444b72372be3aa43c0027a076bf9675eb7932695d127a4";
+	pub const RESPONDER_STATIC: &str =
+		"4aca33714d944be16e8a66e255e856aef7560b44a07d92cbc7ae12618b54d5ea";
+	pub const VALID_FROM: u32 = 1_700_000_000;
+	pub const NOT_VALID_AFTER: u32 = u32::MAX;
+	pub const SIGNATURE: &str = "bd28ac18d03f567f245e344f9660bce396fb9761da55d6729104769c5a4028331e93e31e0a43e48d4d9fc2542f4331b1fd0ca50a1e3cfc02b464f6d9e67346ea";
+	/// -> e
+	pub const MESSAGE_1: &str = "bf755a0c055771d46627a3936009cec5df8b8332670872aaabd97be18c5b82bf4caee5f2d0be31f283ecff0771eee34fee61add33675a42a41ba0a046aa6c060";
+	/// <- e, ee, s, es, along with the certificate
+	pub const MESSAGE_2: &str = "fb6d140df68aa006c9184112611f64e3e4ce51df8ebc3b01ff26cd9935eb2d4f6bd5a4e2ef0988a838bf1fc5c8a45bc7450a356e4fb21817569650ce9e68b9d4dd1993c8a1e1694a941820ff6b0b4fb6563a4c1d4c9da5727d75fb074d83e558c8c64753545b671a8c2e032484d720b9bc37c2740e150992f8db13ce9a00e62e5e1427cf2be0df8982894f3d38e4cfc5da25558f37d6926a48c33e9e70c34e23280eba45c05b003fe7855c83fb5158ee83523e8f7a05879145d900f18b8e79ec3929754969277d8bc5610237228c9c32d7cd443cce96a8b3ecf4d1e8702d85fbc75f0ff4c095f778509f";
+	/// The first transport frame from the initiator: message type `0x1f` with
+	/// payload `abc`
+	pub const INITIATOR_FRAME: &str =
+		"c7780cd1ae17b040a2c0e773d8c1d049f5498a63db599568ca438fede8daf43f9e20496614d2dfdd5f";
+	/// The first transport frame from the responder: message type `0x20` with
+	/// payload `ok`
+	pub const RESPONDER_FRAME: &str =
+		"060d3ed0c6e970cc17158b955d5b7b5cca51e08e1c7a8ce00514943f086535e52b8ea27146d87bad";
+}
+
+fn secret_key(key: &str) -> SecretKey {
+	SecretKey::from_slice(&hex::decode(key).unwrap()).unwrap()
+}
+
+fn initiator_frame() -> stratum::v2::Frame {
+	stratum::v2::Frame {
+		extension_type: 0,
+		message_type: 0x1f,
+		payload: b"abc".to_vec(),
+	}
+}
+
+fn responder_frame() -> stratum::v2::Frame {
+	stratum::v2::Frame {
+		extension_type: 0,
+		message_type: 0x20,
+		payload: b"ok".to_vec(),
+	}
+}
+
+#[test]
+fn noise_initiator_matches_reference_vectors() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let responder = thread::spawn(move || {
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut message = [0; 64];
+
+		stream.read_exact(&mut message).unwrap();
+		stream
+			.write_all(&hex::decode(vectors::MESSAGE_2).unwrap())
+			.unwrap();
+		stream
+			.write_all(&hex::decode(vectors::RESPONDER_FRAME).unwrap())
+			.unwrap();
+
+		let mut frame = vec![0; vectors::INITIATOR_FRAME.len() / 2];
+
+		stream.read_exact(&mut frame).unwrap();
+		(message, frame)
+	});
+
+	let authority = keypair(vectors::AUTHORITY).x_only_public_key().0;
+	let (mut reader, mut writer) = noise::connect_with_ephemeral(
+		TcpStream::connect(address).unwrap(),
+		Some(&authority),
+		secret_key(vectors::INITIATOR_EPHEMERAL),
+	)
+	.unwrap();
+
+	assert_eq!(reader.read().unwrap(), responder_frame());
+
+	writer.write(&initiator_frame()).unwrap();
+
+	let (message, frame) = responder.join().unwrap();
+
+	assert_eq!(hex::encode(message), vectors::MESSAGE_1);
+	assert_eq!(hex::encode(frame), vectors::INITIATOR_FRAME);
+}
+
+#[test]
+fn noise_responder_matches_reference_vectors() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let responder = thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		let static_key =
+			Keypair::from_secret_key(&Secp256k1::new(), &secret_key(vectors::RESPONDER_STATIC));
+		let certificate = noise::Certificate {
+			version: 0,
+			valid_from: vectors::VALID_FROM,
+			not_valid_after: vectors::NOT_VALID_AFTER,
+			signature: bitcoin::secp256k1::schnorr::Signature::from_slice(
+				&hex::decode(vectors::SIGNATURE).unwrap(),
+			)
+			.unwrap(),
+		};
+		let (mut reader, mut writer) = noise::accept_with_ephemeral(
+			stream,
+			&static_key,
+			&certificate,
+			secret_key(vectors::RESPONDER_EPHEMERAL),
+		)
+		.unwrap();
+
+		writer.write(&responder_frame()).unwrap();
+		reader.read().unwrap()
+	});
+
+	let mut stream = TcpStream::connect(address).unwrap();
+
+	stream
+		.write_all(&hex::decode(vectors::MESSAGE_1).unwrap())
+		.unwrap();
+
+	let mut message = vec![0; vectors::MESSAGE_2.len() / 2];
+	let mut frame = vec![0; vectors::RESPONDER_FRAME.len() / 2];
+
+	stream.read_exact(&mut message).unwrap();
+	stream.read_exact(&mut frame).unwrap();
+	stream
+		.write_all(&hex::decode(vectors::INITIATOR_FRAME).unwrap())
+		.unwrap();
+
+	assert_eq!(hex::encode(message), vectors::MESSAGE_2);
+	assert_eq!(hex::encode(frame), vectors::RESPONDER_FRAME);
+	assert_eq!(responder.join().unwrap(), initiator_frame());
+}
+
+/// Starts a node with a template containing a few transactions, and a
+/// template provider serving it, returning the provider's address along with
+/// its authority key.
+fn template_provider(node: &mock::Server) -> (SocketAddr, XOnlyPublicKey) {
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let mut template = mock::template(840_000, genesis.block_hash());
+
+	mock::add_transactions(&mut template, 3);
+	node.push_template(template);
+
+	let authority = keypair(4);
+	let provider = Arc::new(TemplateProvider::new(
+		node.client("user", "pass"),
+		&authority,
+	));
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+
+	thread::spawn({
+		let provider = Arc::clone(&provider);
+
+		move || provider.listen(&listener)
+	});
+	thread::spawn(move || provider.poll());
+
+	(address, authority.x_only_public_key().0)
+}
+
+/// Connects to a template provider, asking for `protocol`.
+fn setup_connection(
+	address: SocketAddr,
+	authority: &XOnlyPublicKey,
+	protocol: u8,
+) -> (noise::Reader, noise::Writer, Message) {
+	let (mut reader, mut writer) =
+		noise::connect(TcpStream::connect(address).unwrap(), Some(authority)).unwrap();
+
+	writer
+		.write(
+			&Message::SetupConnection {
+				protocol,
+				min_version: 2,
+				max_version: 2,
+				flags: 0,
+				endpoint_host: address.ip().to_string(),
+				endpoint_port: address.port(),
+				vendor: String::new(),
+				hardware_version: String::new(),
+				firmware: String::new(),
+				device_id: String::new(),
+			}
+			.to_frame(),
+		)
+		.unwrap();
+
+	let response = receive(&mut reader);
+
+	(reader, writer, response)
+}
+
+fn receive(reader: &mut noise::Reader) -> Message {
+	Message::from_frame(&reader.read().unwrap())
+		.unwrap()
+		.unwrap()
+}
+
+/// Receives the template and previous block hash sent for a new tip,
+/// returning the template.
+fn receive_new_tip(reader: &mut noise::Reader, previous_block: bitcoin::BlockHash) -> Message {
+	let template = receive(reader);
+	let Message::NewTemplate {
+		template_id,
+		future_template: true,
+		..
+	} = template
+	else {
+		panic!("expected a future NewTemplate, got {template:?}");
+	};
+	let Message::SetNewPrevHashTemplate {
+		template_id: prev_hash_template_id,
+		previous_block: prev_hash,
+		..
+	} = receive(reader)
+	else {
+		panic!("expected SetNewPrevHash");
+	};
+
+	assert_eq!(prev_hash_template_id, template_id);
+	assert_eq!(prev_hash, previous_block);
+
+	template
+}
+
+/// Builds the coinbase for a template paying `script_pubkey`, without the
+/// witness reserved value.
+fn template_coinbase(
+	template: &Message,
+	script_pubkey: bitcoin::ScriptBuf,
+) -> bitcoin::Transaction {
+	let Message::NewTemplate {
+		coinbase_tx_version,
+		coinbase_prefix,
+		coinbase_tx_input_sequence,
+		coinbase_tx_value_remaining,
+		coinbase_tx_outputs_count,
+		coinbase_tx_outputs,
+		coinbase_tx_locktime,
+		..
+	} = template
+	else {
+		unreachable!()
+	};
+
+	let mut script_sig = coinbase_prefix.clone();
+	script_sig.extend_from_slice(&[8, 0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1]);
+
+	let mut outputs = &coinbase_tx_outputs[..];
+	let mut output = vec![bitcoin::TxOut {
+		value: bitcoin::Amount::from_sat(*coinbase_tx_value_remaining),
+		script_pubkey,
+	}];
+
+	for _ in 0..*coinbase_tx_outputs_count {
+		output.push(bitcoin::TxOut::consensus_decode(&mut outputs).unwrap());
+	}
+
+	#[allow(clippy::cast_possible_wrap)]
+	bitcoin::Transaction {
+		version: bitcoin::transaction::Version(*coinbase_tx_version as i32),
+		lock_time: bitcoin::absolute::LockTime::from_consensus(*coinbase_tx_locktime),
+		input: vec![bitcoin::TxIn {
+			previous_output: bitcoin::OutPoint::null(),
+			script_sig: bitcoin::ScriptBuf::from_bytes(script_sig),
+			sequence: bitcoin::Sequence(*coinbase_tx_input_sequence),
+			witness: bitcoin::Witness::new(),
+		}],
+		output,
+	}
+}
+
+#[test]
+fn provides_templates_and_submits_solutions() {
+	let node = mock::Server::start("user", "pass");
+	let (address, authority) = template_provider(&node);
+	let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+	let (mut reader, mut writer, response) = setup_connection(address, &authority, 2);
+
+	assert!(matches!(
+		response,
+		Message::SetupConnectionSuccess {
+			used_version: 2,
+			..
+		}
+	));
+
+	writer
+		.write(
+			&Message::CoinbaseOutputDataSize {
+				coinbase_output_max_additional_size: 100,
+			}
+			.to_frame(),
+		)
+		.unwrap();
+
+	let template = receive_new_tip(&mut reader, genesis.block_hash());
+	let Message::NewTemplate {
+		template_id,
+		version,
+		ref merkle_path,
+		..
+	} = template
+	else {
+		unreachable!()
+	};
+
+	writer
+		.write(&Message::RequestTransactionData { template_id }.to_frame())
+		.unwrap();
+
+	let Message::RequestTransactionDataSuccess {
+		template_id: data_template_id,
+		transaction_list,
+		..
+	} = receive(&mut reader)
+	else {
+		panic!("expected RequestTransactionData.Success");
+	};
+
+	assert_eq!(data_template_id, template_id);
+	assert_eq!(transaction_list.len(), 3);
+
+	let coinbase = template_coinbase(&template, bitcoin::ScriptBuf::new_op_return([]));
+	let mut hash = coinbase.txid().to_byte_array();
+
+	for sibling in merkle_path {
+		hash = sha256d::Hash::hash(&[hash, *sibling].concat()).to_byte_array();
+	}
+
+	#[allow(clippy::cast_possible_wrap)]
+	let mut header = bitcoin::block::Header {
+		version: bitcoin::block::Version::from_consensus(version as i32),
+		prev_blockhash: genesis.block_hash(),
+		merkle_root: bitcoin::TxMerkleNode::from_byte_array(hash),
+		time: 1_700_000_000,
+		bits: bitcoin::CompactTarget::from_consensus(mock::EASY_BITS),
+		nonce: 0,
+	};
+	let target = bitcoin::Target::from_compact(header.bits);
+
+	while !target.is_met_by(header.block_hash()) {
+		header.nonce += 1;
+	}
+
+	writer
+		.write(
+			&Message::SubmitSolution {
+				template_id,
+				version,
+				header_timestamp: header.time,
+				header_nonce: header.nonce,
+				coinbase_tx: bitcoin::consensus::serialize(&coinbase),
+			}
+			.to_frame(),
+		)
+		.unwrap();
+
+	let block = node
+		.wait_for_blocks(1, Duration::from_secs(10))
+		.expect("no block was submitted")
+		.swap_remove(0);
+
+	assert_eq!(block.header, header);
+	assert_eq!(block.txdata.len(), 4);
+	assert!(block.check_merkle_root());
+	assert!(block.check_witness_commitment());
+	assert_eq!(block.bip34_block_height(), Ok(840_000));
+
+	for (transaction, data) in block.txdata[1..].iter().zip(&transaction_list) {
+		assert_eq!(&bitcoin::consensus::serialize(transaction), data);
+	}
+
+	// a new tip comes with a new previous block hash
+	node.push_template(mock::template(840_001, header.block_hash()));
+
+	receive_new_tip(&mut reader, header.block_hash());
+}
+
+#[test]
+fn refuses_mining_protocol_connections() {
+	let node = mock::Server::start("user", "pass");
+	let (address, authority) = template_provider(&node);
+	let (_, _, response) = setup_connection(address, &authority, 0);
+
+	assert!(matches!(
+		response,
+		Message::SetupConnectionError { error_code, .. } if error_code == "unsupported-protocol"
+	));
+}
@@ -0,0 +1,155 @@
+use std::{path::PathBuf, sync::Arc};
+
+use miner::{mock, rpc};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+struct Pki {
+	server: Arc<rustls::ServerConfig>,
+	tls: rpc::Tls,
+}
+
+/// Creates a CA that issues a certificate for `node.test` to the server and
+/// one to the client, and writes the client side to temporary files.
+fn pki(name: &str) -> Pki {
+	let ca_key = KeyPair::generate().unwrap();
+	let mut ca = CertificateParams::new(Vec::<String>::new()).unwrap();
+	ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+	let ca = ca.self_signed(&ca_key).unwrap();
+
+	let server_key = KeyPair::generate().unwrap();
+	let server = CertificateParams::new(vec!["node.test".to_string()])
+		.unwrap()
+		.signed_by(&server_key, &ca, &ca_key)
+		.unwrap();
+
+	let client_key = KeyPair::generate().unwrap();
+	let client = CertificateParams::new(vec!["miner".to_string()])
+		.unwrap()
+		.signed_by(&client_key, &ca, &ca_key)
+		.unwrap();
+
+	let mut roots = rustls::RootCertStore::empty();
+	roots.add(ca.der().clone()).unwrap();
+
+	let server = rustls::ServerConfig::builder()
+		.with_client_cert_verifier(
+			rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+				.build()
+				.unwrap(),
+		)
+		.with_single_cert(
+			vec![CertificateDer::from(server.der().to_vec())],
+			PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+		)
+		.unwrap();
+
+	let dir = std::env::temp_dir().join(format!("miner-tls-{name}-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+
+	let write = |file: &str, contents: String| -> PathBuf {
+		let path = dir.join(file);
+		std::fs::write(&path, contents).unwrap();
+		path
+	};
+
+	Pki {
+		server: Arc::new(server),
+		tls: rpc::Tls {
+			ca: Some(write("ca.pem", ca.pem())),
+			certificate: Some(write("client.pem", client.pem())),
+			key: Some(write("client.key", client_key.serialize_pem())),
+			server_name: Some("node.test".to_string()),
+		},
+	}
+}
+
+fn client(server: &mock::Server, options: &rpc::Options) -> rpc::Client {
+	rpc::Client::new(server.url(), "user", "pass", options).unwrap()
+}
+
+#[test]
+fn connects_over_tls_with_client_certificate() {
+	let pki = pki("client-cert");
+	let server = mock::Server::start_tls("user", "pass", pki.server);
+	let options = rpc::Options {
+		tls: pki.tls,
+		proxy: None,
+	};
+
+	assert!(server.url().starts_with("https://127.0.0.1"));
+	assert_eq!(
+		client(&server, &options)
+			.get_blockchain_info()
+			.unwrap()
+			.chain,
+		"main"
+	);
+}
+
+#[test]
+fn rejects_certificate_for_another_name() {
+	let pki = pki("wrong-name");
+	let server = mock::Server::start_tls("user", "pass", pki.server);
+	let options = rpc::Options {
+		tls: rpc::Tls {
+			server_name: None,
+			..pki.tls.clone()
+		},
+		proxy: None,
+	};
+
+	// the certificate is for node.test, not 127.0.0.1
+	assert!(client(&server, &options).get_blockchain_info().is_err());
+
+	let options = rpc::Options {
+		tls: rpc::Tls {
+			certificate: None,
+			key: None,
+			..pki.tls
+		},
+		proxy: None,
+	};
+
+	// the server requires a client certificate
+	assert!(client(&server, &options).get_blockchain_info().is_err());
+}
+
+#[test]
+fn connects_through_socks5_proxy() {
+	let server = mock::Server::start("user", "pass");
+	let proxy = mock::Proxy::socks5();
+	let options = rpc::Options {
+		tls: rpc::Tls::default(),
+		proxy: Some(proxy.url()),
+	};
+
+	assert_eq!(
+		client(&server, &options)
+			.get_blockchain_info()
+			.unwrap()
+			.chain,
+		"main"
+	);
+	assert_eq!(proxy.connections(), 1);
+}
+
+#[test]
+fn connects_over_tls_through_http_proxy() {
+	let pki = pki("http-proxy");
+	let server = mock::Server::start_tls("user", "pass", pki.server);
+	let proxy = mock::Proxy::http();
+	let options = rpc::Options {
+		tls: pki.tls,
+		proxy: Some(proxy.url()),
+	};
+
+	assert_eq!(
+		client(&server, &options)
+			.get_blockchain_info()
+			.unwrap()
+			.chain,
+		"main"
+	);
+	assert_eq!(proxy.connections(), 1);
+}
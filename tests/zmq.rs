@@ -0,0 +1,144 @@
+use std::{
+	io::{Read, Write},
+	net::{TcpListener, TcpStream},
+	sync::mpsc,
+	thread,
+	time::Duration,
+};
+
+use bitcoin::hashes::Hash as _;
+use miner::{mock, solo::Solo, zmq};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A stand-in for bitcoind's ZMQ `PUB` socket that accepts one subscriber
+/// and publishes `messages` once it has subscribed, keeping the connection
+/// open until they run out.
+fn publish(messages: impl IntoIterator<Item = (&'static str, Vec<u8>)> + Send + 'static) -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = format!("tcp://{}", listener.local_addr().unwrap());
+
+	thread::spawn(move || {
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut greeting = [0; 64];
+
+		stream.read_exact(&mut greeting).unwrap();
+		assert_eq!(&greeting[12..16], b"NULL");
+
+		// libzmq answers with version 3.1
+		greeting[11] = 1;
+		stream.write_all(&greeting).unwrap();
+
+		let (flags, ready) = read_frame(&mut stream);
+		assert_eq!(flags, 0x04);
+		assert!(ready.ends_with(b"Socket-Type\0\0\0\x03SUB"));
+
+		let mut ready = b"\x05READY\x0bSocket-Type".to_vec();
+		ready.extend_from_slice(&3u32.to_be_bytes());
+		ready.extend_from_slice(b"PUB");
+		write_frame(&mut stream, 0x04, &ready);
+
+		let (_, subscription) = read_frame(&mut stream);
+		assert_eq!(subscription, b"\x01hashblock");
+		let (_, subscription) = read_frame(&mut stream);
+		assert_eq!(subscription, b"\x01rawblock");
+
+		for (sequence, (topic, body)) in (0u32..).zip(messages) {
+			write_frame(&mut stream, 0x01, topic.as_bytes());
+			write_frame(&mut stream, 0x01, &body);
+			write_frame(&mut stream, 0x00, &sequence.to_le_bytes());
+		}
+	});
+
+	address
+}
+
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+	let mut header = [0; 2];
+	stream.read_exact(&mut header).unwrap();
+
+	let mut body = vec![0; usize::from(header[1])];
+	stream.read_exact(&mut body).unwrap();
+
+	(header[0], body)
+}
+
+fn write_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) {
+	if let Ok(size) = u8::try_from(body.len()) {
+		stream.write_all(&[flags, size]).unwrap();
+	} else {
+		stream.write_all(&[flags | 0x02]).unwrap();
+		stream
+			.write_all(&(body.len() as u64).to_be_bytes())
+			.unwrap();
+	}
+
+	stream.write_all(body).unwrap();
+}
+
+#[test]
+fn receives_block_notifications() {
+	let hash = vec![0xab; 32];
+	let block = vec![0xcd; 1_000];
+	let address = publish(vec![
+		(zmq::HASH_BLOCK, hash.clone()),
+		(zmq::RAW_BLOCK, block.clone()),
+	]);
+
+	let mut subscriber =
+		zmq::Subscriber::connect(&address, &[zmq::HASH_BLOCK, zmq::RAW_BLOCK], TIMEOUT).unwrap();
+
+	let message = subscriber.recv().unwrap();
+	assert_eq!(message.topic, zmq::HASH_BLOCK);
+	assert_eq!(message.body, hash);
+	assert_eq!(message.sequence, Some(0));
+
+	// bodies over 255 bytes use the long frame encoding
+	let message = subscriber.recv().unwrap();
+	assert_eq!(message.topic, zmq::RAW_BLOCK);
+	assert_eq!(message.body, block);
+	assert_eq!(message.sequence, Some(1));
+
+	assert!(subscriber.recv().is_err());
+}
+
+#[test]
+fn times_out_on_a_silent_publisher() {
+	let (_publisher, messages) = mpsc::channel();
+	let address = publish(messages);
+
+	let mut subscriber = zmq::Subscriber::connect(
+		&address,
+		&[zmq::HASH_BLOCK, zmq::RAW_BLOCK],
+		Duration::from_millis(100),
+	)
+	.unwrap();
+
+	assert!(subscriber.recv().unwrap_err().is_timeout());
+}
+
+#[test]
+fn solo_sends_a_job_on_new_blocks() {
+	let server = mock::Server::start("user", "pass");
+	let (publisher, messages) = mpsc::channel();
+	let solo = Solo::new(
+		server.client("user", "pass"),
+		"bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+	)
+	.with_zmq(publish(messages));
+	let (jobs, rx) = mpsc::channel();
+
+	server.push_template(mock::template(840_000, bitcoin::BlockHash::all_zeros()));
+	thread::spawn(move || solo.run(&jobs));
+
+	let job = rx.recv_timeout(TIMEOUT).unwrap();
+
+	// longpoll won't return this one, so only the notification can get it to the miner
+	server.set_template(mock::template(840_001, bitcoin::BlockHash::all_zeros()));
+
+	publisher.send((zmq::HASH_BLOCK, vec![0xab; 32])).unwrap();
+
+	let next = rx.recv_timeout(TIMEOUT).unwrap();
+
+	assert_ne!(next.header.merkle_root, job.header.merkle_root);
+}